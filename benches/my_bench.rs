@@ -1,4 +1,4 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
 use bustools_cli::multinomial::{multinomial_sample, multinomial_sample_binary_search};
 
 /*
@@ -55,34 +55,34 @@ fn multinomial_speed(c: &mut Criterion){
 
     use probability::prelude::*;
 
-    fn binary_search_dummy(N: u64, d: u64){
+    fn binary_search_dummy(n: u64, d: u64){
 
         let p: Vec<_> = (1..d).map(|x| x as f64).collect();
 
         let mut random_source = source::default(4);   
-        multinomial_sample_binary_search(N, &p, &mut random_source);
+        multinomial_sample_binary_search(n, &p, &mut random_source);
     }
 
-    fn binomial_dummy(N: u64, d: u64){
+    fn binomial_dummy(n: u64, d: u64){
         let p: Vec<_> = (1..d).map(|x| x as f64).collect();
 
         let mut random_source = source::default(4);   
-        multinomial_sample(N, &p, &mut random_source);
+        multinomial_sample(n, &p, &mut random_source);
     }
 
     let dims = vec![10_000, 100_000, 1_000_000];
-    let N = 1_000_000;
+    let n = 1_000_000;
     for d in dims{
 
         let name = format!("Binary, dim {}", d);
         c.bench_function(&name, |b| b.iter(|| 
-            binary_search_dummy(black_box(N), 
+            binary_search_dummy(black_box(n), 
                             black_box(d), 
             )));
 
         let name = format!("Binomial, dim {}", d);
         c.bench_function(&name, |b| b.iter(|| 
-            binomial_dummy(black_box(N), 
+            binomial_dummy(black_box(n), 
                             black_box(d), 
             )));
     }
@@ -90,5 +90,168 @@ fn multinomial_speed(c: &mut Criterion){
 }
 
 
-criterion_group!(benches, multinomial_speed);
+fn sort_speed(c: &mut Criterion){
+    use bustools::io::BusRecord;
+    use bustools_cli::sort::{sort_into_btree, sort_into_vec, SortKey};
+    use rand::distributions::{Distribution, Uniform};
+
+    let cb_distr = Uniform::from(0..10_000);
+    let umi_distr = Uniform::from(0..10_000);
+    let mut rng = rand::thread_rng();
+    let records: Vec<BusRecord> = (0..1_000_000)
+        .map(|_| BusRecord { CB: cb_distr.sample(&mut rng), UMI: umi_distr.sample(&mut rng), EC: 0, COUNT: 1, FLAG: 0 })
+        .collect();
+
+    c.bench_function("sort BTree", |b| b.iter(||
+        sort_into_btree(black_box(records.clone()).into_iter(), false, SortKey::CbUmiEc)
+    ));
+    c.bench_function("sort Vec", |b| b.iter(||
+        sort_into_vec(black_box(records.clone()).into_iter(), false, false, SortKey::CbUmiEc)
+    ));
+}
+
+fn group_umi_speed(c: &mut Criterion){
+    use bustools::io::BusRecord;
+    use bustools_cli::count::{group_by_umi, group_sorted_by_umi};
+    use rand::distributions::{Distribution, Uniform};
+
+    // a single (large) cell, sorted by UMI as in a sorted busfile
+    let umi_distr = Uniform::from(0..20_000);
+    let mut rng = rand::thread_rng();
+    let mut records: Vec<BusRecord> = (0..100_000)
+        .map(|_| BusRecord { CB: 0, UMI: umi_distr.sample(&mut rng), EC: 0, COUNT: 1, FLAG: 0 })
+        .collect();
+    records.sort_by_key(|r| r.UMI);
+
+    // cloning the input is not part of the measurement
+    c.bench_function("group UMI HashMap", |b| b.iter_batched(
+        || records.clone(),
+        |r| group_by_umi(black_box(r)).len(),
+        BatchSize::LargeInput
+    ));
+    c.bench_function("group UMI sorted", |b| b.iter(||
+        group_sorted_by_umi(black_box(&records)).count()
+    ));
+}
+
+fn count_panel_speed(c: &mut Criterion){
+    use bustools::consistent_genes::{Ec2GeneMapper, Genename, InconsistentResolution, MappingMode, EC};
+    use bustools::io::{setup_busfile, BusFolder, BusRecord};
+    use bustools_cli::count::CountMode;
+    use bustools_cli::count2;
+    use std::collections::{HashMap, HashSet};
+
+    // a targeted panel: 300 genes, one EC per gene
+    let ngenes = 300;
+    let ec_dict: HashMap<EC, HashSet<Genename>> = (0..ngenes)
+        .map(|i| (EC(i), HashSet::from([Genename(format!("G{}", i))])))
+        .collect();
+    let ecmapper = Ec2GeneMapper::new(ec_dict);
+
+    // 2000 cells, 100 molecules each
+    let records: Vec<BusRecord> = (0..200_000_u64)
+        .map(|i| BusRecord { CB: i / 100, UMI: i % 100, EC: ((i * 7) % ngenes as u64) as u32, COUNT: 1, FLAG: 0 })
+        .collect();
+    let (_busname, dir) = setup_busfile(&records);
+    let bfolder = BusFolder::new(dir.path().to_str().unwrap());
+
+    c.bench_function("count2 sparse panel", |b| b.iter(|| {
+        let mapping_mode = MappingMode::Gene(ecmapper.clone(), InconsistentResolution::IgnoreInconsistent);
+        count2::count(black_box(&bfolder), mapping_mode, false, None, CountMode::Umis, 0)
+    }));
+    c.bench_function("count2 dense panel", |b| b.iter(|| {
+        let mapping_mode = MappingMode::Gene(ecmapper.clone(), InconsistentResolution::IgnoreInconsistent);
+        count2::count_dense(black_box(&bfolder), mapping_mode, false, None, CountMode::Umis)
+    }));
+}
+
+fn write_buffer_speed(c: &mut Criterion){
+    use bustools::io::{BusParams, BusRecord, DEFAULT_BUF_SIZE};
+    use bustools_cli::util::plain_buswriter_with_capacity;
+
+    // 2M records, 64MB on disk
+    let records: Vec<BusRecord> = (0..2_000_000_u64)
+        .map(|i| BusRecord { CB: i / 100, UMI: i % 100, EC: 0, COUNT: 1, FLAG: 0 })
+        .collect();
+    let dir = tempfile::tempdir().unwrap();
+    let outfile = dir.path().join("out.bus");
+    let outfile = outfile.to_str().unwrap();
+
+    let mut group = c.benchmark_group("write buffer");
+    group.sample_size(10);
+    for (name, bytes) in [("default", DEFAULT_BUF_SIZE), ("16MB", 16 * 1024 * 1024)] {
+        group.bench_function(name, |b| b.iter(|| {
+            let mut writer = plain_buswriter_with_capacity(outfile, BusParams { cb_len: 16, umi_len: 12 }, bytes);
+            writer.write_iterator(black_box(records.iter().cloned()));
+        }));
+    }
+    group.finish();
+}
+
+fn mapping_cache_speed(c: &mut Criterion){
+    use bustools::consistent_genes::{Ec2GeneMapper, Genename, MappingResult, EC};
+    use bustools::io::BusRecord;
+    use bustools_cli::count::{map_record_list, MappingCache};
+    use std::collections::{HashMap, HashSet};
+
+    // 2000 genes; EC i < 2000 is unique to gene i, EC 2000+i is shared by genes i and i+1
+    let ngenes = 2000;
+    let ec_dict: HashMap<EC, HashSet<Genename>> = (0..ngenes)
+        .flat_map(|i| [
+            (EC(i), HashSet::from([Genename(format!("G{}", i))])),
+            (EC(ngenes + i), HashSet::from([Genename(format!("G{}", i)), Genename(format!("G{}", (i + 1) % ngenes))])),
+        ])
+        .collect();
+    let ecmapper = Ec2GeneMapper::new(ec_dict);
+
+    // 500k molecules with 1-3 records each, a unique and a shared EC of the same gene
+    let records: Vec<BusRecord> = (0..500_000_u64)
+        .flat_map(|i| {
+            let gene = ((i * 7) % ngenes as u64) as u32;
+            let nrecords = 1 + i % 3;
+            (0..nrecords).map(move |j| {
+                let ec = if j == 1 { ngenes + gene } else { gene };
+                BusRecord { CB: i / 100, UMI: i % 100, EC: ec, COUNT: 1, FLAG: 0 }
+            })
+        })
+        .collect();
+    let molecules: Vec<&[BusRecord]> = records.chunk_by(|a, b| a.CB == b.CB && a.UMI == b.UMI).collect();
+
+    let mut group = c.benchmark_group("map molecules");
+    group.sample_size(10);
+    group.bench_function("uncached", |b| b.iter(|| {
+        black_box(&molecules).iter().filter(|rs| matches!(map_record_list(rs, &ecmapper, false), MappingResult::SingleGene(_))).count()
+    }));
+    group.bench_function("cached", |b| b.iter(|| {
+        let mut cache = MappingCache::default();
+        black_box(&molecules).iter().filter(|rs| matches!(cache.map_record_list(rs, &ecmapper, false), MappingResult::SingleGene(_))).count()
+    }));
+    group.finish();
+}
+
+fn inspect_speed(c: &mut Criterion){
+    use bustools::io::{setup_busfile, BusReader, BusRecord};
+    use bustools::iterators::{CbUmiGroupIterator, CellGroupIterator};
+    use bustools_cli::inspect::bus_statistics;
+
+    // 1M records, 20k cells
+    let records: Vec<BusRecord> = (0..1_000_000_u64)
+        .map(|i| BusRecord { CB: i / 50, UMI: (i % 50) / 2, EC: (i % 2) as u32, COUNT: 1 + (i % 3) as u32, FLAG: 0 })
+        .collect();
+    let (busname, _dir) = setup_busfile(&records);
+
+    let mut group = c.benchmark_group("inspect");
+    group.sample_size(10);
+    group.bench_function("single pass", |b| b.iter(|| bus_statistics(black_box(&busname))));
+    // what bus_statistics used to do: separate (allocating) grouping passes for CBs and CB/UMIs
+    group.bench_function("grouping passes", |b| b.iter(|| {
+        let n_cells = BusReader::new(black_box(&busname)).groupby_cb().count();
+        let n_cbumi = BusReader::new(black_box(&busname)).groupby_cbumi().count();
+        let nreads: usize = BusReader::new(black_box(&busname)).map(|r| r.COUNT as usize).sum();
+        (n_cells, n_cbumi, nreads)
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, multinomial_speed, sort_speed, group_umi_speed, count_panel_speed, write_buffer_speed, mapping_cache_speed, inspect_speed);
 criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,25 @@
+//! Embeds the version of the `bustools` dependency this is built against as `BUSTOOLS_VERSION`,
+//! reported by `bustools --version`.
+//!
+//! The version is taken from Cargo.lock (the crate doesn't expose it); `unknown` if there's no lockfile
+use std::{env, fs, path::Path};
+
+fn main() {
+    let lockfile = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lockfile.display());
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let version = fs::read_to_string(&lockfile)
+        .ok()
+        .and_then(|lock| bustools_version(&lock))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=BUSTOOLS_VERSION={}", version);
+}
+
+/// the `version = "..."` line following `name = "bustools"`
+fn bustools_version(lock: &str) -> Option<String> {
+    let mut lines = lock.lines();
+    lines.find(|l| l.trim() == "name = \"bustools\"")?;
+    let version = lines.next()?.trim().strip_prefix("version = \"")?.strip_suffix('"')?;
+    Some(version.to_string())
+}
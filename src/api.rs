@@ -0,0 +1,34 @@
+//! Convenience API for using the crate as a library
+//!
+//! Busfiles store CB/UMI 2bit-encoded as integers; [decoded_records] turns them back into sequences.
+use bustools::{io::BusReader, utils::int_to_seq};
+
+/// Iterate over the records of `busfile`, decoding CB and UMI into sequences,
+/// yielding `(CB, UMI, EC, COUNT, FLAG)`.
+///
+/// The CB/UMI lengths are taken from the busfile's header.
+///
+/// # Example
+/// ```rust
+/// # use bustools::io::{setup_busfile, BusRecord};
+/// use bustools_cli::api::decoded_records;
+/// # let records = vec![BusRecord { CB: 1, UMI: 2, EC: 3, COUNT: 4, FLAG: 0 }];
+/// # let (busfile, _dir) = setup_busfile(&records);
+/// for (cb, umi, ec, count, flag) in decoded_records(&busfile) {
+///     println!("{cb} {umi} {ec} {count} {flag}");
+/// }
+/// # let decoded: Vec<_> = decoded_records(&busfile).collect();
+/// # assert_eq!(
+/// #     decoded,
+/// #     vec![("AAAAAAAAAAAAAAAC".to_string(), "AAAAAAAAAAAG".to_string(), 3, 4, 0)]
+/// # );
+/// ```
+pub fn decoded_records(busfile: &str) -> impl Iterator<Item = (String, String, u32, u32, u32)> + '_ {
+    let reader = BusReader::new(busfile);
+    let params = reader.get_params().clone();
+    let cb_len = params.cb_len as usize;
+    let umi_len = params.umi_len as usize;
+    reader.map(move |r| {
+        (int_to_seq(r.CB, cb_len), int_to_seq(r.UMI, umi_len), r.EC, r.COUNT, r.FLAG)
+    })
+}
@@ -1,65 +1,79 @@
 //! Filtering/Merging busfiles on CB/UMI overlap
 use bustools::{
-    io::{BusParams, BusReader, BusWriterPlain}, iterators::CbUmiGroupIterator, merger::MultiIterator
+    io::{BusReader, BusWriterPlain}, iterators::CbUmiGroupIterator, merger::MultiIterator
 };
 use std::collections::HashMap;
+use crate::util::create_plain_buswriter;
+
+/// Which CB/UMIs to write out when merging busfiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum MergeMode {
+    /// only CB/UMIs present in all inputs
+    #[default]
+    Intersection,
+    /// every CB/UMI present in any of the inputs
+    Union,
+}
 
 /// will extract all busrecords that appear in both inputs and write them to the respective outputs
 ///
 /// there'll be two output files, each contining the shared reads from the respective input file
+/// (or, with [MergeMode::Union], all reads from the respective input file)
 /// ## Parameters:
 /// * busfile1: first input
 /// * busfile2: 2nd input
 /// * outfile1: 1st output: will contain all CB/UMI that also appear in busfile2 (not the records itself (EC,COUNT) can be different from busfile2)
 /// * outfile2: 2st output: will contain all CB/UMI that also appear in busfile1 (not the records itself (EC,COUNT) can be different from busfile2)
-pub fn merge_busfiles_on_overlap(busfile1: &str, busfile2: &str, outfile1: &str, outfile2: &str) {
-    //
-    // let h: HashMap<String, String> = HashMap::from([
-    //     ("f1".to_string(), busfile1.to_string()),
-    //     ("f2".to_string(), busfile2.to_string()),
-    // ]);
-    // let cbumi_merge_iter = CellUmiIteratorMulti::new(&h);
+/// * merge_mode: Intersection (default) or union of the CB/UMIs
+pub fn merge_busfiles_on_overlap(busfile1: &str, busfile2: &str, outfile1: &str, outfile2: &str, merge_mode: MergeMode) {
+    merge_busfiles_on_overlap_multi(
+        vec![busfile1.to_string(), busfile2.to_string()],
+        vec![outfile1.to_string(), outfile2.to_string()],
+        merge_mode,
+    )
+}
 
+/// will extract all busrecords whose CB/UMI appears in ALL of the inputs and write them to the respective outputs
+///
+/// Generalizes [merge_busfiles_on_overlap] to K busfiles (e.g. triplicate experiments).
+/// ## Parameters:
+/// * inputs: K input busfiles
+/// * outputs: K output busfiles; `outputs[i]` will contain the records of `inputs[i]` whose CB/UMI is present in all other inputs
+/// * merge_mode: with [MergeMode::Union], every CB/UMI of `inputs[i]` is written to `outputs[i]`, regardless of the other inputs
+pub fn merge_busfiles_on_overlap_multi(inputs: Vec<String>, outputs: Vec<String>, merge_mode: MergeMode) {
+    assert_eq!(
+        inputs.len(),
+        outputs.len(),
+        "number of inputs and outputs must be the same"
+    );
 
     // curently only avaialbale for plain writers
     // The BusZWriter cannot `write_records` (we need to assert that we correcrlty closed the file)
-    let params = BusParams {cb_len: 16, umi_len: 12};
-    let mut writers: HashMap<String, BusWriterPlain> = HashMap::from([
-        (
-            "f1".to_string(),
-            BusWriterPlain::new(outfile1, params.clone()),
-        ),
-        (
-            "f2".to_string(),
-            BusWriterPlain::new(outfile2, params),
-        ),
-    ]);
-
-
-    let h: HashMap<String, _> = HashMap::from([
-        ("f1".to_string(), BusReader::new(busfile1).groupby_cbumi()),
-        ("f2".to_string(), BusReader::new(busfile2).groupby_cbumi()),
-    ]);
-    let cbumi_merge_iter = MultiIterator::new(h);
+    // files are keyed by their position, in case the same file is supplied twice
+    let mut writers: HashMap<String, BusWriterPlain> = HashMap::new();
+    let mut iterators = HashMap::new();
+    for (i, (infile, outfile)) in inputs.iter().zip(outputs.iter()).enumerate() {
+        let reader = BusReader::new(infile);
+        let params = reader.get_params().clone();
+        writers.insert(i.to_string(), create_plain_buswriter(outfile, params));
+        iterators.insert(i.to_string(), reader.groupby_cbumi());
+    }
+
+    let cbumi_merge_iter = MultiIterator::new(iterators);
 
     for (_cbumi, record_map) in cbumi_merge_iter {
-        // if the CB/UMI is present in both files, write
-        if record_map.len() == 2 {
+        // if the CB/UMI is present in all files (or we take the union), write
+        let write = match merge_mode {
+            MergeMode::Intersection => record_map.len() == inputs.len(),
+            MergeMode::Union => true,
+        };
+        if write {
             for (name, records) in record_map {
                 let w1 = writers.get_mut(&name).unwrap();
                 w1.write_records(&records)
             }
         }
     }
-
-    // cbumi_merge_iter.iter().map(|(_cbumi, record_map)| {
-    //     if record_map.len() == 2 {
-    //         let w1 = writers.get_mut(&name).unwrap();
-
-    //     }
-    // })
-
-
 }
 
 #[cfg(test)]
@@ -100,9 +114,68 @@ mod tests {
         let output2_path = _dir2.path().join("merge2_out.bus");
         let output2 = output2_path.to_str().unwrap();
 
-        merge_busfiles_on_overlap(&input1, &input2, output1, output2);
+        merge_busfiles_on_overlap(&input1, &input2, output1, output2, MergeMode::Intersection);
 
         assert_eq!(get_records(output1), vec![r2, r4, r5]);
         assert_eq!(get_records(output2), vec![s2, s4]);
     }
+
+    #[test]
+    fn test_merge_union() {
+        let r1 = BusRecord { CB: 0, UMI: 21, EC: 0, COUNT: 2, FLAG: 0 };
+        let r2 = BusRecord { CB: 1, UMI: 2, EC: 0, COUNT: 12, FLAG: 0 };
+        let v1 = vec![r1.clone(), r2.clone()];
+
+        let s2 = BusRecord { CB: 1, UMI: 2, EC: 1, COUNT: 12, FLAG: 0 };
+        let s3 = BusRecord { CB: 2, UMI: 3, EC: 1, COUNT: 2, FLAG: 0 };
+        let v2 = vec![s2.clone(), s3.clone()];
+
+        let (input1, _dir1) = setup_busfile(&v1);
+        let (input2, _dir2) = setup_busfile(&v2);
+
+        let output1_path = _dir1.path().join("merge1_out.bus");
+        let output1 = output1_path.to_str().unwrap();
+        let output2_path = _dir2.path().join("merge2_out.bus");
+        let output2 = output2_path.to_str().unwrap();
+
+        merge_busfiles_on_overlap(&input1, &input2, output1, output2, MergeMode::Union);
+
+        assert_eq!(get_records(output1), vec![r1, r2]);
+        assert_eq!(get_records(output2), vec![s2, s3]);
+    }
+
+    #[test]
+    fn test_merge_multi() {
+        let r1 = BusRecord { CB: 0, UMI: 21, EC: 0, COUNT: 2, FLAG: 0 };
+        let r2 = BusRecord { CB: 1, UMI: 2, EC: 0, COUNT: 12, FLAG: 0 };
+        let r3 = BusRecord { CB: 1, UMI: 3, EC: 0, COUNT: 2, FLAG: 0 };
+        let r4 = BusRecord { CB: 3, UMI: 0, EC: 0, COUNT: 2, FLAG: 0 };
+        let r5 = BusRecord { CB: 3, UMI: 0, EC: 1, COUNT: 2, FLAG: 0 };
+        let v1 = vec![r1.clone(), r2.clone(), r3.clone(), r4.clone(), r5.clone()];
+
+        let s2 = BusRecord { CB: 1, UMI: 2, EC: 1, COUNT: 12, FLAG: 0 };
+        let s3 = BusRecord { CB: 2, UMI: 3, EC: 1, COUNT: 2, FLAG: 0 };
+        let s4 = BusRecord { CB: 3, UMI: 0, EC: 1, COUNT: 2, FLAG: 0 };
+        let v2 = vec![s2.clone(), s3.clone(), s4.clone()];
+
+        // only shares CB/UMI (3,0) with the other two files
+        let t1 = BusRecord { CB: 2, UMI: 3, EC: 1, COUNT: 2, FLAG: 0 };
+        let t2 = BusRecord { CB: 3, UMI: 0, EC: 5, COUNT: 1, FLAG: 0 };
+        let v3 = vec![t1.clone(), t2.clone()];
+
+        let (input1, _dir1) = setup_busfile(&v1);
+        let (input2, _dir2) = setup_busfile(&v2);
+        let (input3, _dir3) = setup_busfile(&v3);
+
+        let outputs: Vec<String> = [&_dir1, &_dir2, &_dir3]
+            .iter()
+            .map(|d| d.path().join("merge_out.bus").to_str().unwrap().to_string())
+            .collect();
+
+        merge_busfiles_on_overlap_multi(vec![input1, input2, input3], outputs.clone(), MergeMode::Intersection);
+
+        assert_eq!(get_records(&outputs[0]), vec![r4, r5]);
+        assert_eq!(get_records(&outputs[1]), vec![s4]);
+        assert_eq!(get_records(&outputs[2]), vec![t2]);
+    }
 }
@@ -25,12 +25,12 @@
 //! # Examples
 //! ```rust, no_run
 //! # use bustools::io::BusFolder;
-//! # use bustools::butterfly::make_ecs;
-//! let b = BusFolder::new(
-//!     "/path/to/bus/output",
-//!     "/path/to/transcripts_to_genes.txt",
-//! );
-//! let h = make_ecs(&b, true);
+//! # use bustools::consistent_genes::{MappingMode, InconsistentResolution};
+//! # use bustools_cli::butterfly::make_ecs;
+//! let b = BusFolder::new("/path/to/bus/output");
+//! let ecmapper = b.make_mapper("/path/to/transcripts_to_genes.txt");
+//! let mapping_mode = MappingMode::Gene(ecmapper, InconsistentResolution::IgnoreInconsistent);
+//! let h = make_ecs(&b.get_busfile(), mapping_mode, 1);
 //! // save the resulting frequency of frequency histogram to disk
 //! // can be read in python for further processing (e.g. plot the saturation curves)
 //! h.to_disk("/tmp/CU.csv")
@@ -38,10 +38,14 @@
 
 #![deny(missing_docs)]
 use bustools::{
-    consistent_genes::{find_consistent, InconsistentResolution, MappingMode, MappingResult}, consistent_transcripts::{find_consistent_transcripts, MappingResultTranscript}, io::BusReader, iterators::CbUmiGroupIterator
+    consistent_genes::{groubygene, Ec2GeneMapper, Genename, InconsistentResolution, MappingMode, MappingResult}, consistent_transcripts::{find_consistent_transcripts, MappingResultTranscript}, io::{BusFolder, BusRecord}, iterators::CbUmiGroupIterator
 };
+use crate::count::MappingCache;
+use crate::util::read_busfile;
+use itertools::Itertools;
 use core::panic;
-use std::{collections::HashMap, fs::File, io::Write};
+use serde::Serialize;
+use std::{collections::HashMap, fs::File, io::{self, BufRead, BufReader, Write}};
 
 /// The basic unit of this module, a frequency of frequency histogram
 ///
@@ -77,6 +81,41 @@ impl CUHistogram {
         (n1 as f64) / (self.get_numis() as f64)
     }
 
+    /// summary statistics of the histogram, e.g. as a QC record per sample
+    pub fn summary(&self) -> CUSummary {
+        let nreads = self.get_nreads();
+        let numis = self.get_numis();
+
+        // median over molecules, i.e. each amplification weighted by its frequency
+        let sorted: Vec<(usize, usize)> = self.into_iter().filter(|(_a, f)| *f > 0).collect();
+        let nth = |k: usize| -> usize {
+            let mut cumsum = 0;
+            for (amp, freq) in sorted.iter() {
+                cumsum += freq;
+                if cumsum > k {
+                    return *amp;
+                }
+            }
+            unreachable!()
+        };
+        let median_amplification = if numis == 0 {
+            f64::NAN
+        } else if numis % 2 == 1 {
+            nth(numis / 2) as f64
+        } else {
+            (nth(numis / 2 - 1) + nth(numis / 2)) as f64 / 2.0
+        };
+
+        CUSummary {
+            nreads,
+            numis,
+            fscm: self.get_fscm(),
+            mean_amplification: nreads as f64 / numis as f64,
+            median_amplification,
+            max_amplification: sorted.last().map(|(a, _f)| *a).unwrap_or(0),
+        }
+    }
+
     /// write the CU histogram into a csv on disk
     pub fn to_disk(&self, fname: &str) {
         let mut fh = File::create(fname).unwrap();
@@ -90,18 +129,92 @@ impl CUHistogram {
         }
     }
 
+    /// Like [CUHistogram::to_disk], but if `fname` exists already, its histogram (see [CUHistogram::from_disk]) is merged with this one,
+    /// e.g. to accumulate the histograms of several chunks/runs.
+    ///
+    /// Fails if the existing file can't be read/parsed; it's left untouched in that case
+    pub fn to_disk_append(&self, fname: &str) -> io::Result<()> {
+        let mut merged = if std::path::Path::new(fname).exists() {
+            CUHistogram::from_disk(fname)?
+        } else {
+            CUHistogram::new()
+        };
+        merged.merge(self);
+        merged.to_disk(fname);
+        Ok(())
+    }
+
+    /// read a CU histogram from a csv on disk, as written by [CUHistogram::to_disk]
+    pub fn from_disk(fname: &str) -> io::Result<CUHistogram> {
+        let reader = BufReader::new(File::open(fname)?);
+        let mut histogram = HashMap::new();
+
+        // skip the header
+        for line in reader.lines().skip(1) {
+            let line = line?;
+            let parsed = line
+                .split_once(',')
+                .and_then(|(a, f)| Some((a.parse::<usize>().ok()?, f.parse::<usize>().ok()?)));
+            match parsed {
+                Some((n_reads, n_umis)) => {
+                    histogram.insert(n_reads, n_umis);
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("cant parse line {} in {}", line, fname),
+                    ))
+                }
+            }
+        }
+        Ok(CUHistogram { histogram })
+    }
+
     /// Update an entry in the histogram, ADDING the count
     pub fn add_counts(&mut self, freq: usize, count: usize) {
         let v =self.histogram.entry(freq).or_insert(0);
         *v += count
     }
 
+    /// merge another histogram into this one, summing the frequencies per amplification,
+    /// e.g. to combine histograms of several lanes
+    pub fn merge(&mut self, other: &CUHistogram) {
+        for (&n_reads, &n_umis) in other.histogram.iter() {
+            self.add_counts(n_reads, n_umis);
+        }
+    }
+
     /// pops out the underlying histogram/hashmap
     pub fn get_histogram(self) -> HashMap<usize, usize>{
         self.histogram
     }
 }
 
+/// Summary statistics of a [CUHistogram], see [CUHistogram::summary]
+///
+/// For an empty histogram, the fscm, mean and median are NaN.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CUSummary {
+    /// number of reads
+    pub nreads: usize,
+    /// number of molecules (CB/UMI)
+    pub numis: usize,
+    /// fraction of single-copy molecules
+    pub fscm: f64,
+    /// mean number of reads per molecule
+    pub mean_amplification: f64,
+    /// median number of reads per molecule
+    pub median_amplification: f64,
+    /// largest number of reads of a single molecule
+    pub max_amplification: usize,
+}
+
+impl Default for CUHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // convert from hashmap to CU
 impl From<HashMap<usize, usize>> for CUHistogram {
     fn from(value: HashMap<usize, usize>) -> Self {
@@ -115,6 +228,18 @@ impl From<CUHistogram> for HashMap<usize, usize> {
     }
 }
 
+/// (amplification, frequency) pairs, sorted by amplification
+impl IntoIterator for &CUHistogram {
+    type Item = (usize, usize);
+    type IntoIter = std::vec::IntoIter<(usize, usize)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let mut pairs: Vec<(usize, usize)> = self.histogram.iter().map(|(a, f)| (*a, *f)).collect();
+        pairs.sort_unstable();
+        pairs.into_iter()
+    }
+}
+
 // /// Convenience, allows us to return CUHistograms directly
 // /// in PyO3, they'll just come out as dictionaries
 // use pyo3::{types::PyDict, ToPyObject};
@@ -131,88 +256,223 @@ impl From<CUHistogram> for HashMap<usize, usize> {
 /// * `mapping_mode`: How to handle identical CB-UMI but different EC:
 ///     - EC(InconsistentResolution): EC level, resolve incosistencies according to `InconsistentResolution`
 ///     - Gene(InconsistentResolution): aggregate on the gene level, handle inconsistency according to `InconsistentResolution` 
+/// * `min_reads`: molecules with fewer reads (summed COUNT) are not counted, e.g. spurious single reads from index hopping.
+///   `1` counts all molecules
 // pub fn make_ecs(busfolder: &BusFolder, mapping_mode: MappingMode) -> CUHistogram {
-pub fn make_ecs(busfile: &str, mapping_mode: MappingMode) -> CUHistogram {
+pub fn make_ecs(busfile: &str, mapping_mode: MappingMode, min_reads: usize) -> CUHistogram {
+    make_ecs_from_iter(read_busfile(busfile), mapping_mode, min_reads)
+}
+
+/// Same as [make_ecs], but for any source of records (e.g. in memory), no busfile needed.
+///
+/// The records must be sorted by CB/UMI (just like a busfile for [make_ecs]), panics otherwise
+pub fn make_ecs_from_iter<I: Iterator<Item = BusRecord>>(iter: I, mapping_mode: MappingMode, min_reads: usize) -> CUHistogram {
     let mut h: CUHistogram = CUHistogram::new();    
 
-    let reader = BusReader::new(busfile);
+    let mut multimapped = 0;
+    let mut inconsistent = 0;
+    let mut below_min_reads = 0;
+    let mut total = 0;
+    let mut cache = MappingCache::default();
+    let mut last_cbumi: Option<(u64, u64)> = None;
+
+    for (cbumi, group) in &iter.chunk_by(|r| (r.CB, r.UMI)) {
+        // consecutive groups differ, hence must increase. Otherwise the same molecule could show up in several groups
+        if let Some(last) = last_cbumi {
+            assert!(cbumi > last, "Unsorted busfile: {:?} -> {:?}", last, cbumi);
+        }
+        last_cbumi = Some(cbumi);
+        let recordlist: Vec<BusRecord> = group.collect();
+        total += 1;
+        match resolve_molecule(&recordlist, &mapping_mode, &mut cache) {
+            Molecule::Counted(nreads) if nreads < min_reads => below_min_reads += 1,
+            Molecule::Counted(nreads) => h.add_counts(nreads, 1),
+            Molecule::Multimapped => multimapped += 1,
+            Molecule::Inconsistent => inconsistent += 1,
+        }
+    }
+
+    println!(
+        "Total CB-UMI {}, Multimapped {} ({}%), Discarded/Inconsistent {} ({}%), below {} reads {}",
+        total,
+        multimapped,
+        100.0 * (multimapped as f32) / (total as f32),
+        inconsistent,
+        100.0 * (inconsistent as f32) / (total as f32),
+        min_reads,
+        below_min_reads
+    );
+    h
+}
+
+/// Same as [make_ecs], but with a separate [CUHistogram] per FLAG value, e.g. to compare amplification
+/// between assay modalities encoded in FLAG.
+///
+/// The records of a CB-UMI are partitioned by FLAG, each partition counting as a molecule of its own (just like `count_split_by_flag`).
+/// CB-UMIs with mixed FLAGs are reported with a warning.
+pub fn make_ecs_by_flag(busfile: &str, mapping_mode: MappingMode, min_reads: usize) -> HashMap<u32, CUHistogram> {
+    let mut histograms: HashMap<u32, CUHistogram> = HashMap::new();
 
     let mut multimapped = 0;
     let mut inconsistent = 0;
+    let mut below_min_reads = 0;
+    let mut mixed_flags = 0;
     let mut total = 0;
+    let mut cache = MappingCache::default();
 
-    for ((_cb, _umi), recordlist) in reader.groupby_cbumi() {
+    for ((_cb, _umi), recordlist) in read_busfile(busfile).groupby_cbumi() {
         total += 1;
-        match &mapping_mode {
-
-            // check if we can uniquely match those read to the same gene
-            // if not its either multimapped or inconsistent (could be a CB/UMI collision)            
-            MappingMode::Gene(ecmapper, resolution_mode) => {
-                match find_consistent(&recordlist, ecmapper) {
-                    MappingResult::SingleGene(_) => {
-                        // increment our histogram
-                        let nreads: usize = recordlist.iter().map(|x| x.COUNT as usize).sum();
-                        h.add_counts(nreads, 1);
+        let mut by_flag: HashMap<u32, Vec<BusRecord>> = HashMap::new();
+        for r in recordlist {
+            by_flag.entry(r.FLAG).or_default().push(r);
+        }
+        if by_flag.len() > 1 {
+            mixed_flags += 1;
+        }
+        for (flag, records) in by_flag {
+            match resolve_molecule(&records, &mapping_mode, &mut cache) {
+                Molecule::Counted(nreads) if nreads < min_reads => below_min_reads += 1,
+                Molecule::Counted(nreads) => histograms.entry(flag).or_default().add_counts(nreads, 1),
+                Molecule::Multimapped => multimapped += 1,
+                Molecule::Inconsistent => inconsistent += 1,
+            }
+        }
+    }
+
+    println!(
+        "Total CB-UMI {}, Multimapped {}, Discarded/Inconsistent {}, below {} reads {}",
+        total, multimapped, inconsistent, min_reads, below_min_reads
+    );
+    if mixed_flags > 0 {
+        eprintln!(
+            "Warning: {} CB-UMIs ({}%) have records with different FLAGs, counted separately in each FLAG",
+            mixed_flags,
+            100.0 * (mixed_flags as f32) / (total as f32)
+        );
+    }
+    histograms
+}
+
+/// Outcome of a single CB-UMI in [make_ecs]
+enum Molecule {
+    /// a proper molecule with that many reads
+    Counted(usize),
+    Multimapped,
+    /// inconsistent (or multiple ECs in EC mode), discarded
+    Inconsistent,
+}
+
+/// Resolve the records of a single CB-UMI according to `mapping_mode`:
+/// - EC(InconsistentResolution): EC level, resolve incosistencies according to `InconsistentResolution`
+/// - Gene(InconsistentResolution): check if we can uniquely match those reads to the same gene;
+///   if not its either multimapped or inconsistent (could be a CB/UMI collision)
+/// - Transcript(InconsistentResolution): same on the transcript level
+fn resolve_molecule(recordlist: &[BusRecord], mapping_mode: &MappingMode, cache: &mut MappingCache) -> Molecule {
+    let nreads = || recordlist.iter().map(|x| x.COUNT as usize).sum();
+    match mapping_mode {
+        MappingMode::Gene(ecmapper, resolution_mode) => {
+            match cache.find_consistent(recordlist, ecmapper) {
+                MappingResult::SingleGene(_) => Molecule::Counted(nreads()),
+                MappingResult::Multimapped(_) => Molecule::Multimapped,
+                // inconsistent, i.e mapping to two distinct genes
+                MappingResult::Inconsistent => {
+                    match resolution_mode {
+                        InconsistentResolution::IgnoreInconsistent => Molecule::Inconsistent,
+                        InconsistentResolution::AsDistinct => panic!("not implemented"),
+                        InconsistentResolution::AsSingle => Molecule::Counted(nreads()),
                     }
-                    MappingResult::Multimapped(_) => multimapped += 1,
-                    // inconsistent, i.e mapping to two distinct genes
-                    // the reasonable thin
-                    MappingResult::Inconsistent => {
-                        match resolution_mode {
-                            InconsistentResolution::IgnoreInconsistent => {inconsistent += 1},
-                            InconsistentResolution::AsDistinct => panic!("not implemented"),
-                            InconsistentResolution::AsSingle => {
-                                let nreads: usize = recordlist.iter().map(|x| x.COUNT as usize).sum();
-                                h.add_counts(nreads, 1);
-                            },
-                        }
-                    },
-                }
-            },
-            MappingMode::EC(mapping_mode) => {
-                // one could get cb/umi with multiple ECs
-                match mapping_mode{
-
-                    // just check if its a single bus record (multiple records would indicate multiple ECs)
-                    InconsistentResolution::IgnoreInconsistent => {
-                        if recordlist.len() == 1 {
-                            let nreads = recordlist[0].COUNT as usize;
-                            h.add_counts(nreads, 1);
-
-                        } else {
-                            inconsistent += 1
-                        }
-                    },
-                    InconsistentResolution::AsDistinct => panic!("not implemented"),
-                    InconsistentResolution::AsSingle => {
-                        let nreads: usize = recordlist.iter().map(|x| x.COUNT as usize).sum();
-                        h.add_counts(nreads, 1);
-                    },
-                }
+                },
             }
-            MappingMode::Transcript(ecmapper, resolution_mode) => {
-                match find_consistent_transcripts(&recordlist, ecmapper) {
-                    MappingResultTranscript::SingleTranscript(_) => {
-                        // increment our histogram
-                        let nreads: usize = recordlist.iter().map(|x| x.COUNT as usize).sum();
-                        h.add_counts(nreads, 1);
+        },
+        MappingMode::EC(mapping_mode) => {
+            // one could get cb/umi with multiple ECs
+            match mapping_mode{
+                // just check if its a single bus record (multiple records would indicate multiple ECs)
+                InconsistentResolution::IgnoreInconsistent => {
+                    if recordlist.len() == 1 {
+                        Molecule::Counted(recordlist[0].COUNT as usize)
+                    } else {
+                        Molecule::Inconsistent
                     }
-                    MappingResultTranscript::Multimapped(_) => multimapped += 1,
-                    // inconsistent, i.e mapping to two distinct genes
-                    // the reasonable thin
-                    MappingResultTranscript::Inconsistent => {
-                        match resolution_mode {
-                            InconsistentResolution::IgnoreInconsistent => {inconsistent += 1},
-                            InconsistentResolution::AsDistinct => panic!("not implemented"),
-                            InconsistentResolution::AsSingle => {
-                                let nreads: usize = recordlist.iter().map(|x| x.COUNT as usize).sum();
-                                h.add_counts(nreads, 1);
-                            },
+                },
+                InconsistentResolution::AsDistinct => panic!("not implemented"),
+                InconsistentResolution::AsSingle => Molecule::Counted(nreads()),
+            }
+        }
+        MappingMode::Transcript(ecmapper, resolution_mode) => {
+            match find_consistent_transcripts(recordlist, ecmapper) {
+                MappingResultTranscript::SingleTranscript(_) => Molecule::Counted(nreads()),
+                MappingResultTranscript::Multimapped(_) => Molecule::Multimapped,
+                // inconsistent, i.e mapping to two distinct transcripts
+                MappingResultTranscript::Inconsistent => {
+                    match resolution_mode {
+                        InconsistentResolution::IgnoreInconsistent => Molecule::Inconsistent,
+                        InconsistentResolution::AsDistinct => panic!("not implemented"),
+                        InconsistentResolution::AsSingle => Molecule::Counted(nreads()),
+                    }
+                },
+            }
+        },
+    }
+}
+
+/// Like [make_ecs] in `MappingMode::Gene`, but keeps a separate [CUHistogram] for each gene.
+///
+/// Each CB-UMI resolved to a single gene (via [bustools::consistent_genes::find_consistent]) is added to that gene's histogram.
+/// Multimapped and inconsistent molecules can't be attributed to a gene and are only tallied.
+/// # Arguments
+/// * `busfolder`: The folder containing the busfile, matric.ec etc...
+/// * `ecmapper`: the EC -> gene mapping
+/// * `resolution`: How to handle CB-UMIs mapping to distinct genes:
+///     - IgnoreInconsistent: discard the molecule
+///     - AsSingle: there's no single gene to attribute it to, hence also discarded
+///     - AsDistinct: split the molecule by consistent genes, each part counting as a molecule of its own
+pub fn make_ecs_per_gene(
+    busfolder: &BusFolder,
+    ecmapper: &Ec2GeneMapper,
+    resolution: InconsistentResolution,
+) -> HashMap<Genename, CUHistogram> {
+    let mut histograms: HashMap<Genename, CUHistogram> = HashMap::new();
+
+    let busfile = busfolder.get_busfile();
+    let reader = read_busfile(&busfile);
+
+    let mut multimapped = 0;
+    let mut inconsistent = 0;
+    let mut total = 0;
+    let mut cache = MappingCache::default();
+
+    for ((_cb, _umi), recordlist) in reader.groupby_cbumi() {
+        total += 1;
+        match cache.find_consistent(&recordlist, ecmapper) {
+            MappingResult::SingleGene(g) => {
+                let nreads: usize = recordlist.iter().map(|x| x.COUNT as usize).sum();
+                histograms
+                    .entry(ecmapper.resolve_gene_id(g))
+                    .or_default()
+                    .add_counts(nreads, 1);
+            }
+            MappingResult::Multimapped(_) => multimapped += 1,
+            MappingResult::Inconsistent => {
+                match resolution {
+                    InconsistentResolution::IgnoreInconsistent | InconsistentResolution::AsSingle => {
+                        inconsistent += 1
+                    }
+                    InconsistentResolution::AsDistinct => {
+                        for cug in groubygene(recordlist, ecmapper) {
+                            if cug.GENESET.len() == 1 {
+                                let gene = cug.GENESET.into_iter().next().unwrap();
+                                histograms
+                                    .entry(gene)
+                                    .or_default()
+                                    .add_counts(cug.COUNT as usize, 1);
+                            } else {
+                                multimapped += 1
+                            }
                         }
-                    },
+                    }
                 }
-            },
-            // MappingMode::IgnoreMultipleCbUmi => todo!(),
+            }
         }
     }
 
@@ -224,21 +484,36 @@ pub fn make_ecs(busfile: &str, mapping_mode: MappingMode) -> CUHistogram {
         inconsistent,
         100.0 * (inconsistent as f32) / (total as f32)
     );
-    h
+    histograms
 }
 
 #[cfg(test)]
 mod testing {
-    use crate::butterfly::{make_ecs, CUHistogram};
+    use crate::butterfly::{make_ecs, make_ecs_by_flag, make_ecs_from_iter, make_ecs_per_gene, CUHistogram};
+    use crate::util::busfolder_from_path;
     use bustools::{
         consistent_genes::{Ec2GeneMapper, Genename, EC, MappingMode, InconsistentResolution},
-        io::{BusFolder, BusRecord},
+        io::{setup_busfile, BusFolder, BusRecord},
         utils::vec2set,
     };
 
     use statrs::assert_almost_eq;
     use std::collections::{HashMap, HashSet};
 
+    #[test]
+    fn test_cuhistogram_into_iter() {
+        let h = CUHistogram::from(HashMap::from([(5, 1), (1, 10), (3, 2), (2, 4)]));
+        let pairs: Vec<(usize, usize)> = (&h).into_iter().collect();
+        assert_eq!(pairs, vec![(1, 10), (2, 4), (3, 2), (5, 1)]);
+
+        // e.g. the cumulative number of molecules
+        let cumulative: Vec<usize> = h.into_iter().scan(0, |acc, (_amp, freq)| {
+            *acc += freq;
+            Some(*acc)
+        }).collect();
+        assert_eq!(cumulative, vec![10, 14, 16, 17]);
+    }
+
     #[test]
     pub fn testing() {
         let h: HashMap<usize, usize> = vec![(1, 2), (3, 3)].into_iter().collect();
@@ -249,6 +524,82 @@ mod testing {
         assert_almost_eq!(c.get_fscm(), 2.0 / 5.0, 0.00000000000000001);
     }
 
+    #[test]
+    fn test_summary() {
+        let h: HashMap<usize, usize> = vec![(1, 2), (3, 3)].into_iter().collect();
+        let c = CUHistogram { histogram: h };
+        let s = c.summary();
+
+        assert_eq!(s.nreads, 11);
+        assert_eq!(s.numis, 5);
+        assert_almost_eq!(s.fscm, 2.0 / 5.0, 1e-15);
+        assert_almost_eq!(s.mean_amplification, 11.0 / 5.0, 1e-15);
+        assert_eq!(s.median_amplification, 3.0);
+        assert_eq!(s.max_amplification, 3);
+        // one-line json QC record
+        let json = serde_json::to_value(&s).unwrap();
+        assert_eq!(json["numis"], 5);
+        assert_eq!(json["max_amplification"], 3);
+
+        // even number of molecules: 1,1,2,10
+        let h: HashMap<usize, usize> = vec![(1, 2), (2, 1), (10, 1)].into_iter().collect();
+        let s = CUHistogram::from(h).summary();
+        assert_eq!(s.median_amplification, 1.5);
+        assert_eq!(s.max_amplification, 10);
+    }
+
+    #[test]
+    fn test_merge() {
+        let h1: HashMap<usize, usize> = vec![(1, 2), (3, 3)].into_iter().collect();
+        let h2: HashMap<usize, usize> = vec![(1, 1), (2, 4)].into_iter().collect();
+        let mut c1 = CUHistogram::from(h1);
+        let c2 = CUHistogram::from(h2);
+
+        let (nreads, numis) = (c1.get_nreads() + c2.get_nreads(), c1.get_numis() + c2.get_numis());
+        c1.merge(&c2);
+        assert_eq!(c1.get_nreads(), nreads);
+        assert_eq!(c1.get_numis(), numis);
+
+        let expected: HashMap<usize, usize> = vec![(1, 3), (2, 4), (3, 3)].into_iter().collect();
+        assert_eq!(c1.histogram, expected);
+    }
+
+    #[test]
+    fn test_to_from_disk() {
+        let h: HashMap<usize, usize> = vec![(1, 2), (3, 3), (10, 1)].into_iter().collect();
+        let c = CUHistogram::from(h.clone());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cu.csv");
+        let fname = path.to_str().unwrap();
+        c.to_disk(fname);
+
+        let c2 = CUHistogram::from_disk(fname).unwrap();
+        assert_eq!(c2.get_histogram(), h);
+    }
+
+    #[test]
+    fn test_to_disk_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("cu.csv");
+        let fname = fname.to_str().unwrap();
+
+        let mut h1 = CUHistogram::new();
+        h1.add_counts(1, 10);
+        h1.add_counts(2, 3);
+        let mut h2 = CUHistogram::new();
+        h2.add_counts(2, 1);
+        h2.add_counts(5, 1);
+
+        // first one creates the file
+        h1.to_disk_append(fname).unwrap();
+        assert_eq!(CUHistogram::from_disk(fname).unwrap().histogram, h1.histogram);
+
+        h2.to_disk_append(fname).unwrap();
+        let expected: HashMap<usize, usize> = vec![(1, 10), (2, 4), (5, 1)].into_iter().collect();
+        assert_eq!(CUHistogram::from_disk(fname).unwrap().histogram, expected);
+    }
+
     #[test]
     fn test_butterfly() {
         // create some fake EC-> Gene mapping
@@ -289,16 +640,16 @@ mod testing {
         ];
 
         let (_busname, _dir) = bustools::io::setup_busfile(&records);
-        let b = BusFolder::new(&_dir.path().to_str().unwrap().to_owned());
+        let b = BusFolder::new(_dir.path().to_str().unwrap());
         // collapsing ECS, ignoreing inconsistents
         let mapping_mode = MappingMode::Gene(es.clone(), InconsistentResolution::IgnoreInconsistent);
-        let h = make_ecs(&b.get_busfile(), mapping_mode);
+        let h = make_ecs(&b.get_busfile(), mapping_mode, 1);
         let expected: HashMap<usize, usize> = vec![(12, 1), (2, 2), (4, 1)].into_iter().collect();
         assert_eq!(h.histogram, expected);
 
         // collapsing ECS, counting inconsistens as a single molecule
         let mapping_mode = MappingMode::Gene(es, InconsistentResolution::AsSingle);
-        let h = make_ecs(&b.get_busfile(), mapping_mode);
+        let h = make_ecs(&b.get_busfile(), mapping_mode, 1);
         let expected: HashMap<usize, usize> = vec![(12, 1), (2, 2), (4, 1), (14,1)].into_iter().collect();
         assert_eq!(h.histogram, expected);
 
@@ -306,7 +657,7 @@ mod testing {
 
         // not collapsing ECs
         let mapping_mode = MappingMode::EC(InconsistentResolution::IgnoreInconsistent);
-        let h = make_ecs(&b.get_busfile(), mapping_mode);
+        let h = make_ecs(&b.get_busfile(), mapping_mode, 1);
         let expected: HashMap<usize, usize> = vec![
             (12, 1),
             (2, 2),
@@ -315,5 +666,161 @@ mod testing {
         .collect();
 
         assert_eq!(h.histogram, expected);
+
+        // same from memory
+        let mapping_mode = MappingMode::EC(InconsistentResolution::IgnoreInconsistent);
+        let h = make_ecs_from_iter(records.into_iter(), mapping_mode, 1);
+        assert_eq!(h.histogram, expected);
+    }
+
+    #[test]
+    fn test_butterfly_from_iter() {
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![Genename("A".to_string())])),
+            (EC(1), vec2set(vec![Genename("B".to_string())])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 1, FLAG: 1 },
+            BusRecord { CB: 0, UMI: 2, EC: 1, COUNT: 1, FLAG: 0 },
+            // same UMI, different cell
+            BusRecord { CB: 1, UMI: 2, EC: 1, COUNT: 1, FLAG: 0 },
+        ];
+        let mapping_mode = MappingMode::Gene(es, InconsistentResolution::IgnoreInconsistent);
+        let h = make_ecs_from_iter(records.into_iter(), mapping_mode, 1);
+        let expected: HashMap<usize, usize> = vec![(4, 1), (1, 2)].into_iter().collect();
+        assert_eq!(h.histogram, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsorted busfile")]
+    fn test_butterfly_unsorted() {
+        // CB/UMI (0, 1) shows up twice, non-consecutively
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+        make_ecs(&busname, MappingMode::EC(InconsistentResolution::AsSingle), 1);
+    }
+
+    #[test]
+    fn test_butterfly_min_reads() {
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 3, EC: 0, COUNT: 2, FLAG: 0 },
+            // two single-read records, but a molecule of 2 reads
+            BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 1, FLAG: 1 },
+            BusRecord { CB: 1, UMI: 2, EC: 0, COUNT: 5, FLAG: 0 },
+        ];
+        let mapping_mode = || MappingMode::EC(InconsistentResolution::AsSingle);
+
+        let h = make_ecs_from_iter(records.clone().into_iter(), mapping_mode(), 1);
+        assert_eq!(h.histogram, HashMap::from([(1, 2), (2, 2), (5, 1)]));
+
+        // the singleton bucket is gone, everything else untouched
+        let h = make_ecs_from_iter(records.into_iter(), mapping_mode(), 2);
+        assert_eq!(h.histogram, HashMap::from([(2, 2), (5, 1)]));
+    }
+
+    #[test]
+    fn test_butterfly_by_flag() {
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 3, FLAG: 0 },
+            // mixed FLAGs: one molecule in each FLAG
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 1 },
+            BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 1, FLAG: 1 },
+            BusRecord { CB: 1, UMI: 2, EC: 1, COUNT: 5, FLAG: 1 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+        let mapping_mode = MappingMode::EC(InconsistentResolution::IgnoreInconsistent);
+        let h = make_ecs_by_flag(&busname, mapping_mode, 1);
+
+        assert_eq!(h.len(), 2);
+        let expected0: HashMap<usize, usize> = vec![(3, 1), (2, 1)].into_iter().collect();
+        let expected1: HashMap<usize, usize> = vec![(1, 2), (5, 1)].into_iter().collect();
+        assert_eq!(h[&0].histogram, expected0);
+        assert_eq!(h[&1].histogram, expected1);
+    }
+
+    #[test]
+    fn test_butterfly_folder_or_file() {
+        let records = vec![
+            // inconsistent: A and B
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 12, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 },
+        ];
+        let (busname, dir) = setup_busfile(&records);
+        std::fs::write(dir.path().join("matrix.ec"), "0\t0\n1\t1\n").unwrap();
+        std::fs::write(dir.path().join("transcripts.txt"), "T1\nT2\n").unwrap();
+        let t2g = dir.path().join("t2g.txt");
+        std::fs::write(&t2g, "T1\tA\tgeneA\nT2\tB\tgeneB\n").unwrap();
+
+        let histogram = |path: &str| {
+            let bfolder = busfolder_from_path(path);
+            let ecmapper = bfolder.make_mapper(t2g.to_str().unwrap());
+            let mapping_mode = MappingMode::Gene(ecmapper, InconsistentResolution::IgnoreInconsistent);
+            make_ecs(&bfolder.get_busfile(), mapping_mode, 1).histogram
+        };
+        let from_folder = histogram(dir.path().to_str().unwrap());
+        let from_file = histogram(&busname);
+
+        let expected: HashMap<usize, usize> = vec![(3, 1), (2, 1)].into_iter().collect();
+        assert_eq!(from_folder, expected);
+        assert_eq!(from_file, expected);
+    }
+
+    #[test]
+    fn test_butterfly_per_gene() {
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![Genename("A".to_string())])),
+            (EC(1), vec2set(vec![Genename("B".to_string())])),
+            (EC(2), vec2set(vec![Genename("A".to_string()), Genename("B".to_string())])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        let records = vec![
+            // inconsistent: A and B
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 12, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 },
+            // single gene A
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 3, FLAG: 0 },
+            // single gene B
+            BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 2, EC: 1, COUNT: 2, FLAG: 0 },
+            // multimapped
+            BusRecord { CB: 2, UMI: 1, EC: 2, COUNT: 5, FLAG: 0 },
+        ];
+        let (_busname, _dir) = bustools::io::setup_busfile(&records);
+        let b = BusFolder::new(_dir.path().to_str().unwrap());
+
+        let h = make_ecs_per_gene(&b, &es, InconsistentResolution::IgnoreInconsistent);
+        assert_eq!(h.len(), 2);
+        assert_eq!(
+            h[&Genename("A".to_string())].histogram,
+            vec![(3, 1)].into_iter().collect::<HashMap<usize, usize>>()
+        );
+        assert_eq!(
+            h[&Genename("B".to_string())].histogram,
+            vec![(2, 2)].into_iter().collect::<HashMap<usize, usize>>()
+        );
+
+        // splitting the inconsistent molecule across A and B
+        let h = make_ecs_per_gene(&b, &es, InconsistentResolution::AsDistinct);
+        assert_eq!(
+            h[&Genename("A".to_string())].histogram,
+            vec![(3, 1), (12, 1)].into_iter().collect::<HashMap<usize, usize>>()
+        );
+        assert_eq!(
+            h[&Genename("B".to_string())].histogram,
+            vec![(2, 3)].into_iter().collect::<HashMap<usize, usize>>()
+        );
     }
 }
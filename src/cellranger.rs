@@ -0,0 +1,244 @@
+//! Reading CellRanger's `filtered_feature_bc_matrix.h5` (or `raw_feature_bc_matrix.h5`) into a [CountMatrix],
+//! e.g. to compare it to the output of `bustools count`.
+//!
+//! Only available with the `hdf5` feature, which links against the system's libhdf5.
+//!
+//! CellRanger (v3+) stores a genes-by-cells CSC matrix in the `/matrix` group:
+//! * `data`, `indices`, `indptr`: the non-zero counts, their gene (row) index and where each cell (column) starts
+//! * `shape`: `[ngenes, ncells]`
+//! * `barcodes`: the cell barcodes, including CellRanger's `-1` suffix
+//! * `features/id`: the gene ids (e.g. ENSG...), used as gene names of the [CountMatrix]
+//!
+//! ## Example
+//! ```rust, no_run
+//! # use bustools_cli::countmatrix::CountMatrix;
+//! let cmat = CountMatrix::from_cellranger_h5("/path/to/filtered_feature_bc_matrix.h5").unwrap();
+//! println!("{}", cmat);
+//! ```
+use crate::countmatrix::{CountMatrix, CountMatrixError};
+use hdf5_metno_sys::{
+    h5::{herr_t, H5open},
+    h5d::{H5Dclose, H5Dget_space, H5Dget_type, H5Dopen2, H5Dread},
+    h5f::{H5Fclose, H5Fopen, H5F_ACC_RDONLY},
+    h5i::hid_t,
+    h5p::H5P_DEFAULT,
+    h5s::{H5Sclose, H5Sget_simple_extent_npoints, H5S_ALL},
+    h5t::{H5Tclose, H5Tcopy, H5Tget_size, H5Tis_variable_str, H5Tset_size, H5T_C_S1, H5T_NATIVE_INT32, H5T_NATIVE_INT64},
+};
+use std::ffi::{c_void, CString};
+
+/// an open HDF5 object (file, dataset, ...), closed when dropped
+struct Handle {
+    id: hid_t,
+    close: unsafe extern "C" fn(hid_t) -> herr_t,
+}
+
+impl Handle {
+    /// wraps the id returned by an `H5*open`/`H5*get_*` call, which is negative on failure
+    fn new(id: hid_t, close: unsafe extern "C" fn(hid_t) -> herr_t, what: &str) -> Result<Self, String> {
+        if id < 0 {
+            return Err(format!("cant open {}", what));
+        }
+        Ok(Handle { id, close })
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            (self.close)(self.id);
+        }
+    }
+}
+
+/// opens the dataset `name` and returns it together with its number of elements
+fn open_dataset(file: &Handle, name: &str) -> Result<(Handle, usize), String> {
+    let cname = CString::new(name).unwrap();
+    let dset = Handle::new(unsafe { H5Dopen2(file.id, cname.as_ptr(), H5P_DEFAULT) }, H5Dclose, name)?;
+    let space = Handle::new(unsafe { H5Dget_space(dset.id) }, H5Sclose, name)?;
+    let n = unsafe { H5Sget_simple_extent_npoints(space.id) };
+    if n < 0 {
+        return Err(format!("cant get the size of {}", name));
+    }
+    Ok((dset, n as usize))
+}
+
+/// reads an integer dataset, converting it to `mem_type` (one of the `H5T_NATIVE_*` types, matching `T`)
+fn read_ints<T: Default + Clone>(file: &Handle, name: &str, mem_type: hid_t) -> Result<Vec<T>, String> {
+    let (dset, n) = open_dataset(file, name)?;
+    let mut buf = vec![T::default(); n];
+    let status = unsafe { H5Dread(dset.id, mem_type, H5S_ALL, H5S_ALL, H5P_DEFAULT, buf.as_mut_ptr() as *mut c_void) };
+    if status < 0 {
+        return Err(format!("cant read {}", name));
+    }
+    Ok(buf)
+}
+
+/// reads a dataset of fixed-length strings (as written by CellRanger), dropping the NUL padding
+fn read_strings(file: &Handle, name: &str) -> Result<Vec<String>, String> {
+    let (dset, n) = open_dataset(file, name)?;
+    let file_type = Handle::new(unsafe { H5Dget_type(dset.id) }, H5Tclose, name)?;
+    if unsafe { H5Tis_variable_str(file_type.id) } != 0 {
+        return Err(format!("{}: only fixed-length strings are supported", name));
+    }
+    let size = unsafe { H5Tget_size(file_type.id) };
+
+    let mem_type = Handle::new(unsafe { H5Tcopy(*H5T_C_S1) }, H5Tclose, "string type")?;
+    unsafe { H5Tset_size(mem_type.id, size) };
+    let mut buf = vec![0_u8; n * size];
+    let status = unsafe { H5Dread(dset.id, mem_type.id, H5S_ALL, H5S_ALL, H5P_DEFAULT, buf.as_mut_ptr() as *mut c_void) };
+    if status < 0 {
+        return Err(format!("cant read {}", name));
+    }
+    buf.chunks(size)
+        .map(|s| {
+            let end = s.iter().position(|&c| c == 0).unwrap_or(s.len());
+            String::from_utf8(s[..end].to_vec()).map_err(|e| format!("{}: {}", name, e))
+        })
+        .collect()
+}
+
+/// reads the `/matrix` group, see the module docs
+fn read_matrix(path: &str) -> Result<CountMatrix, String> {
+    let cpath = CString::new(path).map_err(|e| e.to_string())?;
+    let file = unsafe {
+        H5open();
+        Handle::new(H5Fopen(cpath.as_ptr(), H5F_ACC_RDONLY, H5P_DEFAULT), H5Fclose, "file")?
+    };
+
+    let shape: Vec<i64> = read_ints(&file, "/matrix/shape", *H5T_NATIVE_INT64)?;
+    let data: Vec<i32> = read_ints(&file, "/matrix/data", *H5T_NATIVE_INT32)?;
+    let indices: Vec<i64> = read_ints(&file, "/matrix/indices", *H5T_NATIVE_INT64)?;
+    let indptr: Vec<i64> = read_ints(&file, "/matrix/indptr", *H5T_NATIVE_INT64)?;
+    let cbs = read_strings(&file, "/matrix/barcodes")?;
+    let genes = read_strings(&file, "/matrix/features/id")?;
+
+    let (ngenes, ncells) = match shape[..] {
+        [ngenes, ncells] => (ngenes as usize, ncells as usize),
+        _ => return Err(format!("/matrix/shape must have 2 entries, not {}", shape.len())),
+    };
+    if cbs.len() != ncells || genes.len() != ngenes {
+        return Err(format!(
+            "{} barcodes and {} features, but the matrix is {}x{} (genes x cells)",
+            cbs.len(), genes.len(), ngenes, ncells
+        ));
+    }
+
+    // genes x cells (CSC) -> cells x genes (CSR), without copying
+    let matrix = sprs::CsMat::try_new_csc(
+        (ngenes, ncells),
+        indptr.into_iter().map(|i| i as usize).collect(),
+        indices.into_iter().map(|i| i as usize).collect(),
+        data,
+    )
+    .map_err(|(.., e)| e.to_string())?
+    .transpose_into();
+
+    Ok(CountMatrix::new(matrix, cbs, genes))
+}
+
+impl CountMatrix {
+    /// Load a CellRanger `.h5` count matrix (see the [module docs](crate::cellranger) for the layout).
+    ///
+    /// Fails if the file can't be opened, or any of the datasets is missing or inconsistent with `/matrix/shape`
+    pub fn from_cellranger_h5(path: &str) -> Result<Self, CountMatrixError> {
+        read_matrix(path).map_err(|message| CountMatrixError::Parse { file: path.to_string(), message })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Handle;
+    use crate::countmatrix::CountMatrix;
+    use hdf5_metno_sys::{
+        h5d::{H5Dclose, H5Dcreate2, H5Dwrite},
+        h5f::{H5Fclose, H5Fcreate, H5F_ACC_TRUNC},
+        h5g::{H5Gclose, H5Gcreate2},
+        h5p::H5P_DEFAULT,
+        h5s::{H5Sclose, H5Screate_simple, H5S_ALL},
+        h5t::{H5Tclose, H5Tcopy, H5Tset_size, H5T_C_S1, H5T_NATIVE_INT32, H5T_NATIVE_INT64},
+    };
+    use hdf5_metno_sys::{h5::H5open, h5i::hid_t};
+    use sprs::TriMat;
+    use std::ffi::{c_void, CString};
+    use tempfile::tempdir;
+
+    /// writes `buf` (n elements of `mem_type`) into a new 1D dataset
+    fn write_dataset(file: &Handle, name: &str, mem_type: hid_t, n: usize, buf: *const c_void) {
+        let cname = CString::new(name).unwrap();
+        let dims = [n as u64];
+        unsafe {
+            let space = Handle::new(H5Screate_simple(1, dims.as_ptr(), std::ptr::null()), H5Sclose, name).unwrap();
+            let dset = Handle::new(
+                H5Dcreate2(file.id, cname.as_ptr(), mem_type, space.id, H5P_DEFAULT, H5P_DEFAULT, H5P_DEFAULT),
+                H5Dclose,
+                name,
+            )
+            .unwrap();
+            assert!(H5Dwrite(dset.id, mem_type, H5S_ALL, H5S_ALL, H5P_DEFAULT, buf) >= 0);
+        }
+    }
+
+    fn write_strings(file: &Handle, name: &str, strings: &[&str]) {
+        // fixed-length, NUL-padded, just like CellRanger
+        let size = strings.iter().map(|s| s.len()).max().unwrap();
+        let mut buf = vec![0_u8; strings.len() * size];
+        for (chunk, s) in buf.chunks_mut(size).zip(strings) {
+            chunk[..s.len()].copy_from_slice(s.as_bytes());
+        }
+        let dtype = Handle::new(unsafe { H5Tcopy(*H5T_C_S1) }, H5Tclose, "string type").unwrap();
+        unsafe { H5Tset_size(dtype.id, size) };
+        write_dataset(file, name, dtype.id, strings.len(), buf.as_ptr() as *const c_void);
+    }
+
+    /// a 3 genes x 2 cells matrix in CellRanger's layout
+    fn write_fixture(path: &str) {
+        let cpath = CString::new(path).unwrap();
+        let file = unsafe {
+            H5open();
+            Handle::new(H5Fcreate(cpath.as_ptr(), H5F_ACC_TRUNC, H5P_DEFAULT, H5P_DEFAULT), H5Fclose, path).unwrap()
+        };
+        for group in ["/matrix", "/matrix/features"] {
+            let cgroup = CString::new(group).unwrap();
+            let g = unsafe { H5Gcreate2(file.id, cgroup.as_ptr(), H5P_DEFAULT, H5P_DEFAULT, H5P_DEFAULT) };
+            Handle::new(g, H5Gclose, group).unwrap();
+        }
+
+        // cell AAAC-1: G1=1, G3=5; cell TTTG-1: G2=2
+        let shape: [i64; 2] = [3, 2];
+        let data: [i32; 3] = [1, 5, 2];
+        let indices: [i64; 3] = [0, 2, 1];
+        let indptr: [i64; 3] = [0, 2, 3];
+        write_dataset(&file, "/matrix/shape", *H5T_NATIVE_INT64, 2, shape.as_ptr() as *const c_void);
+        write_dataset(&file, "/matrix/data", *H5T_NATIVE_INT32, 3, data.as_ptr() as *const c_void);
+        write_dataset(&file, "/matrix/indices", *H5T_NATIVE_INT64, 3, indices.as_ptr() as *const c_void);
+        write_dataset(&file, "/matrix/indptr", *H5T_NATIVE_INT64, 3, indptr.as_ptr() as *const c_void);
+        write_strings(&file, "/matrix/barcodes", &["AAAC-1", "TTTG-1"]);
+        write_strings(&file, "/matrix/features/id", &["G1", "G2", "G3"]);
+        write_strings(&file, "/matrix/features/name", &["gene1", "gene2", "gene3"]);
+    }
+
+    #[test]
+    fn test_from_cellranger_h5() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("filtered_feature_bc_matrix.h5");
+        write_fixture(path.to_str().unwrap());
+
+        let cmat = CountMatrix::from_cellranger_h5(path.to_str().unwrap()).unwrap();
+        assert_eq!(cmat.get_shape(), (2, 3));
+
+        let expected = CountMatrix::new(
+            TriMat::from_triplets((2, 3), vec![0, 0, 1], vec![0, 2, 1], vec![1, 5, 2]).to_csr(),
+            vec!["AAAC-1".to_string(), "TTTG-1".to_string()],
+            vec!["G1".to_string(), "G2".to_string(), "G3".to_string()],
+        );
+        assert_eq!(cmat, expected);
+    }
+
+    #[test]
+    fn test_from_cellranger_h5_missing() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("nonexistent.h5");
+        assert!(CountMatrix::from_cellranger_h5(path.to_str().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Collapsing UMIs with sequencing errors, UMI-tools' "directional" method
+//!
+//! Within a cell, a UMI `b` is merged into UMI `a` if they are at most `max_dist` (Hamming) apart
+//! and `a` is much more abundant: `n_reads(a) >= 2 * n_reads(b) - 1`.
+//! Merges are followed transitively (a -> b -> c), starting from the most abundant UMIs.
+//!
+//! # References
+//! Smith, Heger, Sudbery (2017) [UMI-tools](https://genome.cshlp.org/content/27/3/491)
+use crate::sort::{sort_into_vec, SortKey};
+use crate::util::create_buswriter;
+use bustools::{
+    io::{BusReader, BusRecord},
+    iterators::CellGroupIterator,
+};
+use std::collections::HashMap;
+
+/// Hamming distance of two 2bit-encoded sequences
+fn umi_hamming(a: u64, b: u64) -> isize {
+    let x = a ^ b;
+    // a base differs if either of its two bits differs
+    ((x | (x >> 1)) & 0x5555_5555_5555_5555).count_ones() as isize
+}
+
+/// For the records of a single cell, map each UMI to the UMI it gets collapsed into
+fn directional_mapping(records: &[BusRecord], max_dist: isize) -> HashMap<u64, u64> {
+    // reads per UMI (across ECs)
+    let mut umi_counts: HashMap<u64, usize> = HashMap::new();
+    for r in records {
+        *umi_counts.entry(r.UMI).or_insert(0) += r.COUNT as usize;
+    }
+
+    // most abundant UMIs first, ties by UMI to be deterministic
+    let mut umis: Vec<(u64, usize)> = umi_counts.iter().map(|(u, c)| (*u, *c)).collect();
+    umis.sort_by(|(u1, c1), (u2, c2)| c2.cmp(c1).then(u1.cmp(u2)));
+
+    let mut mapping: HashMap<u64, u64> = HashMap::with_capacity(umis.len());
+    for &(root, _) in umis.iter() {
+        if mapping.contains_key(&root) {
+            continue;
+        }
+        mapping.insert(root, root);
+
+        // everything reachable from root (via directed edges) gets collapsed into root
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            let node_count = umi_counts[&node];
+            for &(other, other_count) in umis.iter() {
+                if !mapping.contains_key(&other)
+                    && node_count + 1 >= 2 * other_count
+                    && umi_hamming(node, other) <= max_dist
+                {
+                    mapping.insert(other, root);
+                    stack.push(other);
+                }
+            }
+        }
+    }
+    mapping
+}
+
+/// Collapse UMIs within `max_dist` (Hamming) in each cell of the (sorted) `input`, writing the result to `output`
+///
+/// Records that end up with the same CB/UMI/EC/FLAG are aggregated (COUNT summed).
+pub fn collapse_umis(input: &str, output: &str, max_dist: isize) {
+    let reader = BusReader::new(input);
+    let mut writer = create_buswriter(output, reader.get_params().clone());
+
+    let mut n_umis_before = 0;
+    let mut n_umis_after = 0;
+    for (_cb, records) in reader.groupby_cb() {
+        let mapping = directional_mapping(&records, max_dist);
+        n_umis_before += mapping.len();
+        n_umis_after += mapping.iter().filter(|(umi, root)| umi == root).count();
+
+        let collapsed = sort_into_vec(
+            records.into_iter().map(|mut r| {
+                r.UMI = mapping[&r.UMI];
+                r
+            }),
+            false,
+            false,
+            SortKey::CbUmiEc,
+        );
+        writer.write_iterator(collapsed.into_iter());
+    }
+    println!("Collapsed {} UMIs into {}", n_umis_before, n_umis_after);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{collapse_umis, directional_mapping, umi_hamming};
+    use bustools::{
+        io::{setup_busfile, BusReader, BusRecord},
+        utils::seq_to_int,
+    };
+
+    #[test]
+    fn test_umi_hamming() {
+        assert_eq!(umi_hamming(seq_to_int("AAAA"), seq_to_int("AAAA")), 0);
+        assert_eq!(umi_hamming(seq_to_int("AAAA"), seq_to_int("AAAT")), 1);
+        assert_eq!(umi_hamming(seq_to_int("ACGT"), seq_to_int("TGCA")), 4);
+    }
+
+    #[test]
+    fn test_directional_mapping() {
+        let a = seq_to_int("AAAAAAAAAAAA");
+        let b = seq_to_int("AAAAAAAAAAAC"); // 1 away from a
+        let c = seq_to_int("AAAAAAAAAACC"); // 1 away from b, 2 from a
+        let d = seq_to_int("TTTTTTTTTTTT");
+        let records = vec![
+            BusRecord { CB: 0, UMI: a, EC: 0, COUNT: 20, FLAG: 0 },
+            BusRecord { CB: 0, UMI: b, EC: 0, COUNT: 5, FLAG: 0 },
+            BusRecord { CB: 0, UMI: c, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: d, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let m = directional_mapping(&records, 1);
+        assert_eq!(m[&a], a);
+        assert_eq!(m[&b], a);
+        assert_eq!(m[&c], a); // transitively via b
+        assert_eq!(m[&d], d);
+
+        // similar abundance: not collapsed
+        let records = vec![
+            BusRecord { CB: 0, UMI: a, EC: 0, COUNT: 5, FLAG: 0 },
+            BusRecord { CB: 0, UMI: b, EC: 0, COUNT: 4, FLAG: 0 },
+        ];
+        let m = directional_mapping(&records, 1);
+        assert_eq!(m[&b], b);
+    }
+
+    #[test]
+    fn test_collapse_umis() {
+        let a = seq_to_int("AAAAAAAAAAAA");
+        let b = seq_to_int("AAAAAAAAAAAC");
+        let records = vec![
+            BusRecord { CB: 0, UMI: a, EC: 0, COUNT: 10, FLAG: 0 },
+            BusRecord { CB: 0, UMI: b, EC: 0, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 0, UMI: b, EC: 1, COUNT: 1, FLAG: 0 },
+            // different cell, not collapsed into a
+            BusRecord { CB: 1, UMI: b, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+        let outpath = _dir.path().join("collapsed.bus");
+        let outfile = outpath.to_str().unwrap();
+
+        collapse_umis(&busname, outfile, 1);
+
+        let collapsed: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(
+            collapsed,
+            vec![
+                BusRecord { CB: 0, UMI: a, EC: 0, COUNT: 12, FLAG: 0 },
+                BusRecord { CB: 0, UMI: a, EC: 1, COUNT: 1, FLAG: 0 },
+                BusRecord { CB: 1, UMI: b, EC: 0, COUNT: 1, FLAG: 0 },
+            ]
+        );
+    }
+}
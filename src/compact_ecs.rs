@@ -0,0 +1,102 @@
+//! Dropping unused equivalence classes from a busfolder
+//!
+//! After filtering records (e.g. by EC), most entries of `matrix.ec` are usually not referenced anymore.
+//! [compact_ecs] keeps only the ECs used in the busfile, renumbers them `0..n` and rewrites the records accordingly.
+//!
+//! The renumbering preserves the order of ECs: a sorted busfile stays sorted.
+use bustools::{
+    consistent_genes::EC,
+    io::{BusFolder, BusReader},
+};
+use crate::util::create_buswriter;
+use itertools::Itertools;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+/// Write a copy of the busfile in `bfolder` (to `out_busfile`) and its EC matrix (to `out_ecfile`),
+/// retaining only the ECs that occur in the busfile, with new ids `0..n`.
+///
+/// Panics if the busfile references an EC that's not in the EC matrix.
+pub fn compact_ecs(bfolder: &BusFolder, out_busfile: &str, out_ecfile: &str) {
+    let busfile = bfolder.get_busfile();
+
+    // first pass: collect the used ECs
+    let used_ecs: BTreeSet<u32> = BusReader::new(&busfile).map(|r| r.EC).collect();
+
+    let ecmatrix = bfolder.parse_ecmatrix();
+    let old2new: HashMap<u32, u32> = used_ecs
+        .iter()
+        .enumerate()
+        .map(|(new, old)| (*old, new as u32))
+        .collect();
+
+    // the reduced EC matrix
+    let fh = File::create(out_ecfile).unwrap();
+    let mut writer = BufWriter::new(fh);
+    for (new, old) in used_ecs.iter().enumerate() {
+        let transcripts = ecmatrix
+            .get(&EC(*old))
+            .unwrap_or_else(|| panic!("EC {} not present in {}", old, bfolder.get_ecmatrix_file()));
+        writeln!(writer, "{}\t{}", new, transcripts.iter().map(|t| t.0).join(",")).unwrap();
+    }
+
+    // second pass: rewrite the records
+    let reader = BusReader::new(&busfile);
+    let mut buswriter = create_buswriter(out_busfile, reader.get_params().clone());
+    buswriter.write_iterator(reader.map(|mut r| {
+        r.EC = old2new[&r.EC];
+        r
+    }));
+
+    println!("Compacted {} ECs into {}", ecmatrix.len(), used_ecs.len());
+}
+
+#[cfg(test)]
+mod test {
+    use super::compact_ecs;
+    use bustools::{
+        consistent_genes::EC,
+        consistent_transcripts::TranscriptId,
+        io::{parse_ecmatrix, setup_busfile, BusFolder, BusReader, BusRecord},
+    };
+
+    #[test]
+    fn test_compact_ecs() {
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 3, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+
+        let ecpath = _dir.path().join("matrix.ec");
+        let ecfile = ecpath.to_str().unwrap();
+        std::fs::write(ecfile, "0\t0\n1\t1\n2\t2\n3\t1,2\n4\t0,3\n").unwrap();
+        let bfolder = BusFolder::from_files(&busname, ecfile, "transcripts.txt");
+
+        let outbus_path = _dir.path().join("compact.bus");
+        let outbus = outbus_path.to_str().unwrap();
+        let outec_path = _dir.path().join("compact.ec");
+        let outec = outec_path.to_str().unwrap();
+        compact_ecs(&bfolder, outbus, outec);
+
+        // unused ECs 0, 2, 4 are dropped, 1->0, 3->1
+        let ecs = parse_ecmatrix(outec);
+        assert_eq!(ecs.len(), 2);
+        assert_eq!(ecs[&EC(0)], vec![TranscriptId(1)]);
+        assert_eq!(ecs[&EC(1)], vec![TranscriptId(1), TranscriptId(2)]);
+
+        let compacted: Vec<BusRecord> = BusReader::new(outbus).collect();
+        assert_eq!(
+            compacted,
+            vec![
+                BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 },
+                BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 3, FLAG: 0 },
+                BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+            ]
+        );
+    }
+}
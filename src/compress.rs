@@ -0,0 +1,232 @@
+//! Compression of busfiles into the busz format (and back)
+//!
+//! Optionally (see [Codec]), the busz output is additionally compressed with zstd, trading speed for space.
+//! The zstd frame magic differs from the busz magic (`BUS\x01`), hence decompression detects the codec automatically.
+use bustools::{
+    busz::{BuszReader, BuszWriter},
+    io::{BusReaderPlain, BusRecord},
+};
+use crate::util::{create_plain_buswriter, ProgressIter};
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+};
+use tempfile::tempdir;
+
+/// How often (#records) to update the progress of compress/decompress
+const PROGRESS_EVERY: u64 = 1_000_000;
+
+/// magic bytes at the start of every zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// zstd compression level for [Codec::BuszZstd]
+const ZSTD_LEVEL: i32 = 3;
+
+/// How the records are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Codec {
+    /// plain busz
+    #[default]
+    Busz,
+    /// busz, wrapped in zstd (smaller, slower)
+    #[value(name = "busz+zstd")]
+    BuszZstd,
+}
+
+/// Compress `input` busfile into `output` busz-file using `blocksize`
+/// 
+/// # Parameters
+/// * blocksize: How many elements are grouped together and compressed together
+/// * show_progress: report the throughput (records/sec) while compressing
+/// * codec: plain busz, or busz wrapped in zstd
+pub fn compress_busfile(input: &str, output: &str, blocksize: usize, show_progress: bool, codec: Codec) {
+    match codec {
+        Codec::Busz => write_busz(input, output, blocksize, show_progress),
+        Codec::BuszZstd => {
+            // BuszWriter can only write into a file: write plain busz first, then zstd it into the output
+            let tmpdir = tempdir().unwrap();
+            let tmpfile = tmpdir.path().join("tmp.busz");
+            let tmpname = tmpfile.to_str().unwrap();
+            write_busz(input, tmpname, blocksize, show_progress);
+
+            let mut busz = BufReader::new(File::open(tmpname).unwrap());
+            let out = File::create(output).unwrap();
+            zstd::stream::copy_encode(&mut busz, out, ZSTD_LEVEL).unwrap();
+        }
+    }
+}
+
+/// write `input` as plain busz into `output`
+fn write_busz(input: &str, output: &str, blocksize: usize, show_progress: bool) {
+    let reader = BusReaderPlain::new(input);
+    let mut writer = BuszWriter::new(output, reader.params.clone(), blocksize);
+    if show_progress {
+        writer.write_iterator(ProgressIter::new(reader, None, PROGRESS_EVERY));
+    } else {
+        writer.write_iterator(reader.into_iter());
+    }
+}
+
+/// open a busz file, decoding zstd on the fly if the file is zstd-compressed ([Codec::BuszZstd])
+fn open_busz(filename: &str) -> BuszReader<'static> {
+    let mut magic = [0_u8; 4];
+    let is_zstd = File::open(filename)
+        .unwrap_or_else(|_| panic!("file not found: {filename}"))
+        .read_exact(&mut magic)
+        .is_ok()
+        && magic == ZSTD_MAGIC;
+
+    if is_zstd {
+        let decoder = zstd::stream::read::Decoder::new(File::open(filename).unwrap()).unwrap();
+        BuszReader::from_read(decoder)
+    } else {
+        BuszReader::new(filename)
+    }
+}
+
+/// Decompress the `input` busz file into a plain busfile, `output`
+///
+/// Works with both [Codec]s, detected from the file's magic bytes
+pub fn decompress_busfile(input: &str, output: &str, show_progress: bool) {
+    let reader = open_busz(input);
+    let mut writer = create_plain_buswriter(output, reader.get_params().clone());
+
+    let records: Box<dyn Iterator<Item = BusRecord>> = if show_progress {
+        Box::new(ProgressIter::new(reader, None, PROGRESS_EVERY))
+    } else {
+        Box::new(reader)
+    };
+    for r in records {
+        writer.write_record(&r);
+    }
+}
+
+/// Why a busz file failed [verify_compression]
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    /// index of the first differing record, with the plain and the busz record
+    Mismatch(usize, BusRecord, BusRecord),
+    /// the files contain a different number of records (e.g. a truncated busz), all shared records being identical
+    LengthDiffers {
+        /// number of records in the plain busfile
+        plain: usize,
+        /// number of records in the busz file
+        busz: usize,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Mismatch(i, plain, busz) => {
+                write!(f, "Mismatch at record {}: {:?} (plain) vs {:?} (busz)", i, plain, busz)
+            }
+            VerifyError::LengthDiffers { plain, busz } => {
+                write!(f, "Different number of records: {} (plain) vs {} (busz)", plain, busz)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Check that `busz` decompresses into exactly the records of the `plain` busfile
+///
+/// Reads both files in lockstep and stops at the first differing record.
+/// Returns the number of verified records, or a [VerifyError] describing the first difference.
+pub fn verify_compression(plain: &str, busz: &str) -> Result<usize, VerifyError> {
+    let mut plain_reader = BusReaderPlain::new(plain);
+    let mut busz_reader = open_busz(busz);
+
+    let mut n = 0;
+    loop {
+        match (plain_reader.next(), busz_reader.next()) {
+            (Some(r1), Some(r2)) => {
+                if r1 != r2 {
+                    return Err(VerifyError::Mismatch(n, r1, r2));
+                }
+                n += 1;
+            }
+            (None, None) => return Ok(n),
+            (Some(_), None) => {
+                return Err(VerifyError::LengthDiffers { plain: n + 1 + plain_reader.count(), busz: n })
+            }
+            (None, Some(_)) => {
+                return Err(VerifyError::LengthDiffers { plain: n, busz: n + 1 + busz_reader.count() })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress_busfile, decompress_busfile, verify_compression, Codec, VerifyError, ZSTD_MAGIC};
+    use bustools::io::{setup_busfile, BusReader, BusRecord};
+
+    #[test]
+    fn test_verify_compression() {
+        let records: Vec<BusRecord> = (0..100)
+            .map(|i| BusRecord { CB: i / 10, UMI: i, EC: (i % 3) as u32, COUNT: 1, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+        let buszpath = _dir.path().join("compressed.busz");
+        let busz = buszpath.to_str().unwrap();
+
+        compress_busfile(&busname, busz, 7, false, Codec::Busz);
+        assert_eq!(verify_compression(&busname, busz), Ok(100));
+
+        // compare against a file differing in a single record
+        let mut other_records = records.clone();
+        other_records[42].COUNT = 2;
+        let (othername, _otherdir) = setup_busfile(&other_records);
+        assert_eq!(
+            verify_compression(&othername, busz),
+            Err(VerifyError::Mismatch(42, other_records[42].clone(), records[42].clone()))
+        );
+    }
+
+    #[test]
+    fn test_verify_compression_truncated() {
+        let records: Vec<BusRecord> = (0..100)
+            .map(|i| BusRecord { CB: i / 10, UMI: i, EC: (i % 3) as u32, COUNT: 1, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+
+        // a busz missing the last records
+        let (shortname, _shortdir) = setup_busfile(&records[..60].to_vec());
+        let buszpath = _dir.path().join("truncated.busz");
+        let busz = buszpath.to_str().unwrap();
+        compress_busfile(&shortname, busz, 7, false, Codec::Busz);
+
+        assert_eq!(verify_compression(&busname, busz), Err(VerifyError::LengthDiffers { plain: 100, busz: 60 }));
+        assert_eq!(verify_compression(&shortname, busz), Ok(60));
+
+        // the other way around
+        let fullpath = _dir.path().join("full.busz");
+        let full = fullpath.to_str().unwrap();
+        compress_busfile(&busname, full, 7, false, Codec::Busz);
+        assert_eq!(verify_compression(&shortname, full), Err(VerifyError::LengthDiffers { plain: 60, busz: 100 }));
+    }
+
+    #[test]
+    fn test_zstd_roundtrip() {
+        let records: Vec<BusRecord> = (0..100)
+            .map(|i| BusRecord { CB: i / 10, UMI: i, EC: (i % 3) as u32, COUNT: 1, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+        let buszpath = _dir.path().join("compressed.busz");
+        let busz = buszpath.to_str().unwrap();
+
+        compress_busfile(&busname, busz, 7, false, Codec::BuszZstd);
+        let bytes = std::fs::read(busz).unwrap();
+        assert_eq!(bytes[..4], ZSTD_MAGIC);
+        assert_eq!(verify_compression(&busname, busz), Ok(100));
+
+        let outpath = _dir.path().join("decompressed.bus");
+        let out = outpath.to_str().unwrap();
+        decompress_busfile(busz, out, false);
+        let decompressed: Vec<BusRecord> = BusReader::new(out).collect();
+        assert_eq!(decompressed, records);
+    }
+}
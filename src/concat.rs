@@ -1,32 +1,103 @@
 //! concatenate busfiles
 //! 
 
-use std::collections::HashMap;
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
 
-use bustools::{io::{BusReader, BusWriter}, iterators::CbUmiGroupIterator, merger::MultiIterator};
+use bustools::{io::{BusParams, BusReader, BusRecord, CUGIterator}, iterators::CbUmiGroupIterator};
 
-use crate::sort::merge_chunks;
+use crate::merge::merge_and_aggregate;
+use crate::util::create_buswriter;
+use tempfile::tempdir;
 
+/// Errors that can occur when concatenating busfiles
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConcatError {
+    /// a busfile isn't sorted: `current` CB/UMI came after the larger `previous` CB/UMI
+    Unsorted {
+        /// the offending file
+        file: String,
+        /// CB/UMI of the previous record
+        previous: (u64, u64),
+        /// CB/UMI of the out-of-order record
+        current: (u64, u64),
+    },
+    /// a busfile's header (CB/UMI length) differs from the first busfile
+    HeaderMismatch {
+        /// the offending file
+        file: String,
+        /// params of the first busfile
+        expected: BusParams,
+        /// params of the offending file
+        found: BusParams,
+    },
+}
+
+impl fmt::Display for ConcatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConcatError::Unsorted { file, previous, current } => write!(
+                f,
+                "busfile {} is not sorted: CB/UMI {:?} after {:?}",
+                file, current, previous
+            ),
+            ConcatError::HeaderMismatch { file, expected, found } => write!(
+                f,
+                "busfile {} has mismatched header: expected {:?}, found {:?}",
+                file, expected, found
+            ),
+        }
+    }
+}
 
+impl std::error::Error for ConcatError {}
+
+/// Wraps the records of a busfile, checking that they come in CB/UMI order.
 ///
-// fn concat_internal(x: HashMap<String, impl CUGIterator>) -> impl Iterator<Item=BusRecord> {
+/// On the first out-of-order record, the error is stored in `error` (shared across all files)
+/// and the iteration stops
+struct SortedCheck<I: Iterator<Item = BusRecord>> {
+    iter: I,
+    file: String,
+    last_cbumi: Option<(u64, u64)>,
+    error: Rc<RefCell<Option<ConcatError>>>,
+}
+
+impl<I: Iterator<Item = BusRecord>> Iterator for SortedCheck<I> {
+    type Item = BusRecord;
 
-//     let itermap: HashMap<String,_> = x.into_iter().map(|(s, iter)| (s, iter.groupby_cbumi())).collect();
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.borrow().is_some() {
+            return None;
+        }
+        let record = self.iter.next()?;
+        let cbumi = (record.CB, record.UMI);
+        if let Some(previous) = self.last_cbumi {
+            if cbumi < previous {
+                *self.error.borrow_mut() = Some(ConcatError::Unsorted {
+                    file: self.file.clone(),
+                    previous,
+                    current: cbumi,
+                });
+                return None;
+            }
+        }
+        self.last_cbumi = Some(cbumi);
+        Some(record)
+    }
+}
+impl<I: Iterator<Item = BusRecord>> CUGIterator for SortedCheck<I> {}
 
-//     let it = MultiIterator::new(itermap)
-//         .map(|(_cbumi, rdict)|
-//             merge_chunks(rdict)
-//         )
-//         .flatten();
-//     it
-// }
 
 /// Concatenate several busfiles
 /// 
 /// Assumes that each file is sorted
 /// If a record (CB/UMI/EC) is found in more than one busfile, its count is aggregated
 /// (also if the same CB/UMI/EC is present in the same file)
-pub fn concat_bus(filenames: Vec<String>, outfile: &str) {
+///
+/// All files must have the same header, otherwise [ConcatError::HeaderMismatch] is returned.
+/// Sortedness is checked on the fly: If a file turns out to be unsorted,
+/// [ConcatError::Unsorted] is returned and `outfile` is incomplete
+pub fn concat_bus(filenames: Vec<String>, outfile: &str) -> Result<(), ConcatError> {
 
     let mut readers = HashMap::new();
     for f in filenames.iter() {
@@ -38,39 +109,87 @@ pub fn concat_bus(filenames: Vec<String>, outfile: &str) {
 
     let params = readers[&filenames[0]].get_params().clone();
     
-    // assert all busfiles have ethe same parameters
-    for (_, r) in readers.iter() {
-        let pa = r.get_params().clone();
-        assert_eq!(pa, params, "missmatched Header parameters in busfiles")
+    // all busfiles must have the same parameters
+    for f in filenames.iter() {
+        let pa = readers[f].get_params();
+        if *pa != params {
+            return Err(ConcatError::HeaderMismatch { file: f.clone(), expected: params, found: pa.clone() });
+        }
     }
 
     // merge all chunks
     println!("Merging {} chunks", filenames.len());
-    let mut writer = BusWriter::new(outfile, params);
+    let mut writer = create_buswriter(outfile, params);
 
+    let error = Rc::new(RefCell::new(None));
     let iterator_map: HashMap<String, _> = readers
         .into_iter()
-        .map(|(f, read)| 
-            (f.to_owned(), read.groupby_cbumi())
-         ).collect();
+        .map(|(f, read)| {
+            let checked = SortedCheck { iter: read, file: f.clone(), last_cbumi: None, error: Rc::clone(&error) };
+            (f, checked.groupby_cbumi())
+        }).collect();
     
     // each file itself is sorted
     // now we only have to merge them
     // if a single cb/umi is split over multiple records, this will put them back together
     // however, we need to aggregate their counts and sort them by EC
 
-    let it = MultiIterator::new(iterator_map)
-        .flat_map(|(_cbumi, rdict)|
-            merge_chunks(rdict)
-        );
+    let it = merge_and_aggregate(iterator_map)
+        .take_while(|_| error.borrow().is_none());
     writer.write_iterator(it);
+
+    match error.take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Same as [concat_bus], but never has more than `max_open_files` input files open at the same time
+///
+/// With more inputs than `max_open_files`, groups of `max_open_files` files are concatenated
+/// into temporary files, which get concatenated in turn (a tree of merges) until a single file is left.
+/// Costs an extra pass over the data per level of the tree.
+pub fn concat_bus_streaming(filenames: Vec<String>, outfile: &str, max_open_files: usize) -> Result<(), ConcatError> {
+    assert!(max_open_files >= 2, "max_open_files must be at least 2");
+
+    if filenames.len() <= max_open_files {
+        return concat_bus(filenames, outfile);
+    }
+
+    // check the headers upfront (one file at a time):
+    // intermediate files would hide which of the original files is the culprit
+    let params = BusReader::new(&filenames[0]).get_params().clone();
+    for f in filenames.iter() {
+        let pa = BusReader::new(f).get_params().clone();
+        if pa != params {
+            return Err(ConcatError::HeaderMismatch { file: f.clone(), expected: params, found: pa });
+        }
+    }
+
+    let tmpdir = tempdir().unwrap();
+    let mut level = 0;
+    let mut current = filenames;
+    while current.len() > max_open_files {
+        println!("Concatenating {} files in groups of {}", current.len(), max_open_files);
+        let mut merged = Vec::new();
+        for (i, group) in current.chunks(max_open_files).enumerate() {
+            let tmpfile = tmpdir.path().join(format!("concat_{}_{}.bus", level, i));
+            let tmpfilename = tmpfile.to_str().unwrap().to_string();
+            concat_bus(group.to_vec(), &tmpfilename)?;
+            merged.push(tmpfilename);
+        }
+        current = merged;
+        level += 1;
+    }
+    concat_bus(current, outfile)
+    //tmpfiles get clean up once tmpdir is dropped!
 }
 
 #[cfg(test)]
 mod test {
-    use bustools::io::{setup_busfile, BusReader, BusRecord};
+    use bustools::io::{setup_busfile, BusParams, BusReader, BusRecord, BusWriter};
 
-    use super::concat_bus;
+    use super::{concat_bus, concat_bus_streaming, ConcatError};
 
     #[test]
     fn test_concat(){
@@ -90,7 +209,7 @@ mod test {
         let (busname1, _dir1) = setup_busfile(&vec![r1.clone() ,r2.clone() ,r3.clone() ,r4.clone() , r5.clone()]);
         let (busname2, _dir2) = setup_busfile(&vec![s1.clone(), s2.clone()]);
 
-        concat_bus(vec![busname1, busname2], "/tmp/concat.bus");
+        concat_bus(vec![busname1, busname2], "/tmp/concat.bus").unwrap();
 
         let reader = BusReader::new("/tmp/concat.bus");
 
@@ -105,4 +224,98 @@ mod test {
         assert_eq!(exp , reader.collect::<Vec<_>>());
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_concat_unsorted(){
+        let r1 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 12, FLAG: 0 };
+        let r2 = BusRecord { CB: 1, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 };
+        let r3 = BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 0 };  // out of order
+        let s1 = BusRecord { CB: 1, UMI: 0, EC: 0, COUNT: 2, FLAG: 0 };
+
+        let (busname1, dir1) = setup_busfile(&vec![r1, r2, r3]);
+        let (busname2, _dir2) = setup_busfile(&vec![s1]);
+        let outpath = dir1.path().join("concat.bus");
+
+        let res = concat_bus(vec![busname1.clone(), busname2], outpath.to_str().unwrap());
+        assert_eq!(
+            res,
+            Err(ConcatError::Unsorted { file: busname1, previous: (1, 0), current: (0, 2) })
+        );
+    }
+
+    #[test]
+    fn test_concat_header_mismatch(){
+        let r1 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 12, FLAG: 0 };
+        let (busname1, dir1) = setup_busfile(&vec![r1.clone()]);
+
+        let path2 = dir1.path().join("short_cb.bus");
+        let busname2 = path2.to_str().unwrap().to_string();
+        let mut writer = BusWriter::new(&busname2, BusParams { cb_len: 14, umi_len: 12 });
+        writer.write_iterator(vec![r1].into_iter());
+        drop(writer);
+
+        let outpath = dir1.path().join("concat.bus");
+        let res = concat_bus(vec![busname1, busname2.clone()], outpath.to_str().unwrap());
+        assert_eq!(
+            res,
+            Err(ConcatError::HeaderMismatch {
+                file: busname2,
+                expected: BusParams { cb_len: 16, umi_len: 12 },
+                found: BusParams { cb_len: 14, umi_len: 12 },
+            })
+        );
+    }
+
+    #[test]
+    fn test_concat_streaming(){
+        // many tiny files, overlapping in CB/UMI
+        let mut files = Vec::new();
+        for i in 0..50 {
+            let records = vec![
+                BusRecord { CB: i % 7, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+                BusRecord { CB: 10 + i, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            ];
+            files.push(setup_busfile(&records));
+        }
+        let filenames: Vec<String> = files.iter().map(|(f, _dir)| f.clone()).collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let out_direct = dir.path().join("direct.bus");
+        let out_streaming = dir.path().join("streaming.bus");
+
+        concat_bus(filenames.clone(), out_direct.to_str().unwrap()).unwrap();
+        concat_bus_streaming(filenames, out_streaming.to_str().unwrap(), 4).unwrap();
+
+        let direct: Vec<BusRecord> = BusReader::new(out_direct.to_str().unwrap()).collect();
+        let streaming: Vec<BusRecord> = BusReader::new(out_streaming.to_str().unwrap()).collect();
+        assert_eq!(direct, streaming);
+        assert_eq!(streaming.len(), 57);
+        assert_eq!(streaming.iter().map(|r| r.COUNT).sum::<u32>(), 100);
+    }
+
+    #[test]
+    fn test_concat_streaming_header_mismatch(){
+        let r1 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 12, FLAG: 0 };
+        let files: Vec<_> = (0..5).map(|_| setup_busfile(&vec![r1.clone()])).collect();
+        let mut filenames: Vec<String> = files.iter().map(|(f, _dir)| f.clone()).collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("short_cb.bus");
+        let short_cb = path.to_str().unwrap().to_string();
+        let mut writer = BusWriter::new(&short_cb, BusParams { cb_len: 14, umi_len: 12 });
+        writer.write_iterator(vec![r1].into_iter());
+        drop(writer);
+        filenames.push(short_cb.clone());
+
+        let outpath = dir.path().join("concat.bus");
+        let res = concat_bus_streaming(filenames, outpath.to_str().unwrap(), 2);
+        assert_eq!(
+            res,
+            Err(ConcatError::HeaderMismatch {
+                file: short_cb,
+                expected: BusParams { cb_len: 16, umi_len: 12 },
+                found: BusParams { cb_len: 14, umi_len: 12 },
+            })
+        );
+    }
+}
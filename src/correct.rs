@@ -6,17 +6,22 @@
 #![deny(missing_docs)]
 use bktree::BkTree;
 use bustools::{
-    io::{BusReader, BusWriter, BusRecord},
-    utils::{get_progressbar, int_to_seq, seq_to_int},
+    io::{BusReader, BusRecord, BusWriter},
+    iterators::CellGroupIterator,
+    utils::{int_to_seq, seq_to_int},
 };
 use std::{
     collections::{HashMap, HashSet},
+    fmt,
     fs::File,
-    io::{BufRead, BufReader},
+    io::{BufRead, BufReader, BufWriter, Write},
 };
+use crate::util::{create_buswriter, get_progressbar, get_spinner};
 
 const MAX_DIST: isize = 1; // maximum distance where we consider a barcode correctable
 
+// BkTree<String> dictates the &String signature
+#[allow(clippy::ptr_arg)]
 fn my_hamming(a: &String, b: &String) -> isize {
     // hamming distance for two strings of the same size
     assert_eq!(a.len(), b.len());
@@ -30,6 +35,38 @@ fn my_hamming(a: &String, b: &String) -> isize {
     counter
 }
 
+/// What to do with a barcode that is equally close to several whitelisted barcodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AmbiguityPolicy {
+    /// drop the barcode (and its records)
+    #[default]
+    Drop,
+    /// correct to the lexicographically first candidate
+    First,
+    /// correct to the candidate with the highest abundance (see [load_abundance]); dropped if that's a tie
+    MostAbundant,
+}
+
+impl AmbiguityPolicy {
+    /// pick one of the equidistant `candidates`, `None` if the barcode gets dropped
+    fn resolve(&self, candidates: &[String], abundance: Option<&HashMap<String, usize>>) -> Option<String> {
+        match self {
+            AmbiguityPolicy::Drop => None,
+            AmbiguityPolicy::First => candidates.iter().min().cloned(),
+            AmbiguityPolicy::MostAbundant => {
+                let abundance = abundance.expect("AmbiguityPolicy::MostAbundant requires barcode abundances");
+                let freq = |cb: &String| abundance.get(cb).copied().unwrap_or(0);
+                let max = candidates.iter().map(freq).max()?;
+                let mut best = candidates.iter().filter(|cb| freq(cb) == max);
+                match (best.next(), best.next()) {
+                    (Some(cb), None) => Some(cb.clone()),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 enum CorrectionResult {
     SingleHit(String), // a single match in the whitelist: either the barcode itself (0 error) or MAX_DIST away from a whitelisted BC
@@ -77,12 +114,47 @@ fn correct_single_cb(cb: String, bk: &BkTree<String>) -> CorrectionResult {
     }
 }
 
+/// Errors when the whitelist doesn't fit the busfile, see [correct]
+#[derive(Debug)]
+pub enum CorrectError {
+    /// a whitelisted barcode doesn't have the length of the busfile's CBs (`cb_len` in the header)
+    WhitelistLength {
+        /// the offending barcode
+        barcode: String,
+        /// the busfile's CB length
+        cb_len: usize,
+    },
+}
+
+impl fmt::Display for CorrectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorrectError::WhitelistLength { barcode, cb_len } => write!(
+                f,
+                "Whitelist barcode {} has length {}, but the busfile's CBs have length {} (cb_len in the header)",
+                barcode,
+                barcode.len(),
+                cb_len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CorrectError {}
+
 /// Corrects observed barcodes in the busfile using a whitelist of barcodes and writes the results to disk
 ///
 /// # Parameters
 /// * `busfile`: filename of the busfile to be corrected
 /// * `busfile_out`: file where the corrected records are written
-/// * `whitelist_filename` : the file with the whitelisted barcodes (one per line)
+/// * `whitelist_filenames` : the files with the whitelisted barcodes (one per line), merged via [load_whitelists].
+///   A barcode within distance 1 of barcodes from different lists is ambiguous, just as within a single list
+/// * `ambiguity`: how to handle barcodes equally close to several whitelisted barcodes
+/// * `abundance`: frequency of the whitelisted barcodes, only needed for [AmbiguityPolicy::MostAbundant]
+/// * `dump_map`: if set, the uncorrected->corrected CB mapping is saved to this file, see [save_correction_map]
+/// * `uncorrected_out`: if set, the records that can't be corrected (and hence are dropped) are written to this busfile, unmodified
+///
+/// Fails if the whitelisted barcodes don't have the busfile's CB length, see [CorrectError]
 ///
 /// # Overview/Performance tricks
 /// The CBs are highly repetitive; would be slow to query the BKtree for each CB (they'll repeat ALOt)
@@ -90,26 +162,54 @@ fn correct_single_cb(cb: String, bk: &BkTree<String>) -> CorrectionResult {
 /// 2. correct them and create a HashMap<uncorrected, corrected>
 /// 3. iterate over the bus file, correct the individual entries and write to disk
 ///
-pub fn correct(busfile: &str, busfile_out: &str, whitelist_filename: &str) {
+#[allow(clippy::too_many_arguments)]
+pub fn correct(
+    busfile: &str,
+    busfile_out: &str,
+    whitelist_filenames: &[String],
+    ambiguity: AmbiguityPolicy,
+    abundance: Option<&HashMap<String, usize>>,
+    dump_map: Option<&str>,
+    uncorrected_out: Option<&str>,
+) -> Result<(), CorrectError> {
     println!("Loading whitelist");
-    let whitelist = load_whitelist(whitelist_filename);
+    let whitelist = load_whitelists(whitelist_filenames);
     println!("Loaded whitelist");
+    check_whitelist_length(&whitelist, BusReader::new(busfile).get_params().cb_len as usize)?;
 
-    let breader = BusReader::new(busfile);
-    let cb_len = breader.get_params().cb_len as usize;
+    let unique_cbs = collect_cbs(busfile);
+    let corrector = build_correct_map(&unique_cbs, &whitelist, ambiguity, abundance);
+    if let Some(map_file) = dump_map {
+        let cb_len = BusReader::new(busfile).get_params().cb_len as usize;
+        save_correction_map(&corrector, map_file, cb_len);
+        println!("saved correction map to {}", map_file);
+    }
 
-    // note the file might be unsorted, so cant realy on groupby_cb
-    println!("collecting CBs");
-    let unique_cbs: HashSet<String> = breader.map(|r| int_to_seq(r.CB, cb_len)).collect();
-    println!("collected CBs");
+    // now with a map of uncorrected->corrected fix the busfile
+    apply_correction_map(busfile, busfile_out, &corrector, uncorrected_out);
+    Ok(())
+}
 
-    let corrector = build_correct_map(&unique_cbs, &whitelist);
+/// Like [correct], but using a precomputed uncorrected->corrected mapping (see [save_correction_map]) instead of a whitelist.
+///
+/// Skips collecting the CBs and building the BKTree, i.e. only does the rewrite pass.
+/// Records whose CB is not in the map get dropped (or written to `uncorrected_out`), just as in [correct]
+pub fn correct_with_map(busfile: &str, busfile_out: &str, map_path: &str, uncorrected_out: Option<&str>) {
+    println!("Loading correction map");
+    let corrector = load_correction_map(map_path);
+    println!("Loaded correction map: {} CBs", corrector.len());
+    apply_correction_map(busfile, busfile_out, &corrector, uncorrected_out);
+}
 
-    // now with a map of uncorrected->corrected fix the busfile
+/// rewrite the CBs of `busfile` according to `corrector`, dropping records whose CB isn't in there
+/// (or writing them to `uncorrected_out`, if set)
+fn apply_correction_map(busfile: &str, busfile_out: &str, corrector: &HashMap<u64, u64>, uncorrected_out: Option<&str>) {
     let breader = BusReader::new(busfile);
-    let mut bwriter = BusWriter::new(busfile_out, breader.get_params().clone());
+    let params = breader.get_params().clone();
+    let mut bwriter = create_buswriter(busfile_out, params.clone());
+    let mut uncorrected_writer = uncorrected_out.map(|f| create_buswriter(f, params));
 
-    fn fix_record(record: BusRecord,  corrector: &HashMap<u64, u64>) -> Option<BusRecord> {
+    fn fix_record(record: &BusRecord,  corrector: &HashMap<u64, u64>) -> Option<BusRecord> {
         if let Some(corrected_cb) = corrector.get(&record.CB) {
             let mut new_record = record.clone();
             new_record.CB = *corrected_cb;
@@ -118,16 +218,167 @@ pub fn correct(busfile: &str, busfile_out: &str, whitelist_filename: &str) {
             None
         }
     }
-    let it = breader
-        .filter_map(|record| fix_record(record, &corrector));
+    let it = breader.filter_map(|record| {
+        let fixed = fix_record(&record, corrector);
+        if let (None, Some(writer)) = (&fixed, &mut uncorrected_writer) {
+            match writer {
+                BusWriter::Plain(w) => w.write_record(&record),
+                BusWriter::Compressed(w) => w.write_record(record),
+            }
+        }
+        fixed
+    });
 
     bwriter.write_iterator(it);
     println!("wrote corrected busfile");
+    if let Some(f) = uncorrected_out {
+        // dropping the writer flushes it
+        drop(uncorrected_writer);
+        println!("wrote uncorrectable records to {}", f);
+    }
+}
+
+/// Like [correct], but without collecting all unique CBs up front: memory stays bounded even for
+/// files with huge numbers of (erroneous) barcodes.
+///
+/// **Requires a CB-sorted busfile**: records are processed per CB (via `groupby_cb`), correcting each CB
+/// when its group comes up, and only holding the current CB's records.
+/// Note that the output is not necessarily sorted anymore, as corrected CBs can move.
+pub fn correct_streaming(
+    busfile: &str,
+    busfile_out: &str,
+    whitelist_filenames: &[String],
+    ambiguity: AmbiguityPolicy,
+    abundance: Option<&HashMap<String, usize>>,
+) -> Result<(), CorrectError> {
+    println!("Loading whitelist");
+    let whitelist = load_whitelists(whitelist_filenames);
+    println!("Loaded whitelist");
+    let breader = BusReader::new(busfile);
+    let params = breader.get_params().clone();
+    let cb_len = params.cb_len as usize;
+    check_whitelist_length(&whitelist, cb_len)?;
+
+    println!("Building BKTree");
+    let mut bk: BkTree<String> = BkTree::new(my_hamming);
+    bk.insert_all(whitelist.clone());
+    println!("Built BKTree");
+
+    let mut bwriter = create_buswriter(busfile_out, params);
+    let it = breader
+        .groupby_cb()
+        .filter_map(|(cb, records)| {
+            let cb_seq = int_to_seq(cb, cb_len);
+            let corrected = if whitelist.contains(&cb_seq) {
+                Some(cb)
+            } else {
+                match correct_single_cb(cb_seq, &bk) {
+                    CorrectionResult::SingleHit(corrected_cb) => Some(seq_to_int(&corrected_cb)),
+                    CorrectionResult::NoHit => None,
+                    CorrectionResult::Ambigous(candidates) => {
+                        ambiguity.resolve(&candidates, abundance).map(|c| seq_to_int(&c))
+                    }
+                }
+            };
+            corrected.map(|new_cb| {
+                records.into_iter().map(move |mut r| {
+                    r.CB = new_cb;
+                    r
+                })
+            })
+        })
+        .flatten();
+    bwriter.write_iterator(it);
+    println!("wrote corrected busfile");
+    Ok(())
+}
+
+/// Like [correct], but only reports how many of the busfile's (unique) CBs would be corrected, without writing anything.
+///
+/// Skips the second pass over the busfile, hence much faster than [correct]
+pub fn correct_dry_run(
+    busfile: &str,
+    whitelist_filenames: &[String],
+    ambiguity: AmbiguityPolicy,
+    abundance: Option<&HashMap<String, usize>>,
+) -> Result<CorrectionStats, CorrectError> {
+    println!("Loading whitelist");
+    let whitelist = load_whitelists(whitelist_filenames);
+    println!("Loaded whitelist");
+    check_whitelist_length(&whitelist, BusReader::new(busfile).get_params().cb_len as usize)?;
+
+    let unique_cbs = collect_cbs(busfile);
+    let (_corrector, stats) = build_correct_map_with_stats(&unique_cbs, &whitelist, ambiguity, abundance);
+    Ok(stats)
+}
+
+/// make sure all whitelisted barcodes have the length of the busfile's CBs.
+/// Otherwise `seq_to_int` silently produces CBs that never match/get corrected
+fn check_whitelist_length(whitelist: &HashSet<String>, cb_len: usize) -> Result<(), CorrectError> {
+    match whitelist.iter().find(|cb| cb.len() != cb_len) {
+        Some(bad) => Err(CorrectError::WhitelistLength { barcode: bad.clone(), cb_len }),
+        None => Ok(()),
+    }
+}
+
+/// gather all unique CBs (as strings) in the busfile
+fn collect_cbs(busfile: &str) -> HashSet<String> {
+    let breader = BusReader::new(busfile);
+    let cb_len = breader.get_params().cb_len as usize;
+
+    // note the file might be unsorted, so cant realy on groupby_cb
+    // size of the file is unknown without an extra pass, hence just a spinner
+    println!("collecting CBs");
+    let bar = get_spinner();
+    let mut unique_cbs: HashSet<String> = HashSet::new();
+    for (counter, r) in breader.enumerate() {
+        unique_cbs.insert(int_to_seq(r.CB, cb_len));
+        if counter % 1_000_000 == 0 {
+            bar.inc(1_000_000)
+        }
+    }
+    bar.finish();
+    println!("collected {} CBs", unique_cbs.len());
+    unique_cbs
+}
+
+/// How the unique CBs of a busfile fared during correction
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CorrectionStats {
+    /// CBs that are in the whitelist already
+    pub whitelisted: usize,
+    /// CBs corrected to a single whitelisted barcode (incl. ambiguous ones resolved by the [AmbiguityPolicy])
+    pub corrected: usize,
+    /// CBs too far from any whitelisted barcode
+    pub uncorrectable: usize,
+    /// CBs close to several whitelisted barcodes, dropped
+    pub ambiguous: usize,
 }
 
 /// creates the `mutated`->`true` mapping of every element in the cbs to the whiteslist
-/// Uses a BKTree
-pub fn build_correct_map(cbs: &HashSet<String>, whitelist: &HashSet<String>) -> HashMap<u64, u64> {
+/// Uses a BKTree. Barcodes equally close to several whitelisted ones are handled according to `ambiguity`
+/// (`abundance` is only needed for [AmbiguityPolicy::MostAbundant])
+pub fn build_correct_map(
+    cbs: &HashSet<String>,
+    whitelist: &HashSet<String>,
+    ambiguity: AmbiguityPolicy,
+    abundance: Option<&HashMap<String, usize>>,
+) -> HashMap<u64, u64> {
+    let (corrector, _stats) = build_correct_map_with_stats(cbs, whitelist, ambiguity, abundance);
+    corrector
+}
+
+/// same as [build_correct_map], but also reports how many CBs could(nt) be corrected
+pub fn build_correct_map_with_stats(
+    cbs: &HashSet<String>,
+    whitelist: &HashSet<String>,
+    ambiguity: AmbiguityPolicy,
+    abundance: Option<&HashMap<String, usize>>,
+) -> (HashMap<u64, u64>, CorrectionStats) {
+    assert!(
+        ambiguity != AmbiguityPolicy::MostAbundant || abundance.is_some(),
+        "AmbiguityPolicy::MostAbundant requires barcode abundances"
+    );
 
     println!("Building BKTree");
     let mut bk: BkTree<String> = BkTree::new(my_hamming);
@@ -138,33 +389,90 @@ pub fn build_correct_map(cbs: &HashSet<String>, whitelist: &HashSet<String>) ->
     // mapping on the int represnetation of the barcodes! saves some time
     let mut corrector: HashMap<u64, u64> = HashMap::with_capacity(cbs.len());
     let bar = get_progressbar(cbs.len() as u64);
-    let mut cb_correct = 0;
-    let mut cb_total = 0;
+    let mut stats = CorrectionStats::default();
     for (counter, cb) in cbs.iter().enumerate() {
-        cb_total += 1;
-
         // to save time (BKtree is slow) check if we have a direct match
         if whitelist.contains(cb) {
             let cbint = seq_to_int(cb);
             corrector.insert(cbint, cbint);
-            cb_correct += 1
+            stats.whitelisted += 1
         // if its not a direct match, check the BKTree for 1 error
-        } else if let CorrectionResult::SingleHit(corrected_cb) = correct_single_cb(cb.clone(), &bk)
-        {
-            corrector.insert(seq_to_int(cb), seq_to_int(&corrected_cb));
-            cb_correct += 1
         } else {
-            // simply dont do anything. Later if we look up a query-CB and cant find it in the map
-            // it cant be corrected!
+            match correct_single_cb(cb.clone(), &bk) {
+                CorrectionResult::SingleHit(corrected_cb) => {
+                    corrector.insert(seq_to_int(cb), seq_to_int(&corrected_cb));
+                    stats.corrected += 1
+                }
+                // simply dont do anything. Later if we look up a query-CB and cant find it in the map
+                // it cant be corrected!
+                CorrectionResult::NoHit => stats.uncorrectable += 1,
+                CorrectionResult::Ambigous(candidates) => match ambiguity.resolve(&candidates, abundance) {
+                    Some(corrected_cb) => {
+                        corrector.insert(seq_to_int(cb), seq_to_int(&corrected_cb));
+                        stats.corrected += 1
+                    }
+                    None => stats.ambiguous += 1,
+                },
+            }
         }
 
         if counter % 1_000 == 0 {
             bar.inc(1_000)
         }
     };
-    println!("corrected unique CBs: {cb_correct}/{cb_total}");
-    corrector
+    println!(
+        "corrected unique CBs: {}/{}",
+        stats.whitelisted + stats.corrected,
+        cbs.len()
+    );
+    (corrector, stats)
+}
+
+/// Save the uncorrected->corrected mapping (see [build_correct_map]) as a two-column TSV of barcode sequences,
+/// e.g. to inspect what changed or to apply the same correction to other files.
+///
+/// Barcodes not in the map (uncorrectable/ambiguous) dont show up in the file. Lines are sorted by the uncorrected barcode
+pub fn save_correction_map(map: &HashMap<u64, u64>, path: &str, cb_len: usize) {
+    let mut fh = BufWriter::new(File::create(path).unwrap_or_else(|e| panic!("cant create {}: {}", path, e)));
+    let mut entries: Vec<(&u64, &u64)> = map.iter().collect();
+    entries.sort_unstable();
+    for (uncorrected, corrected) in entries {
+        writeln!(fh, "{}\t{}", int_to_seq(*uncorrected, cb_len), int_to_seq(*corrected, cb_len)).unwrap();
+    }
+}
+
+/// Load a mapping written by [save_correction_map]
+pub fn load_correction_map(path: &str) -> HashMap<u64, u64> {
+    let fh = File::open(path).unwrap_or_else(|_| panic!("{} not found", path));
+    BufReader::new(fh)
+        .lines()
+        .map(|line| {
+            let line = line.unwrap();
+            let (uncorrected, corrected) = line
+                .split_once('\t')
+                .unwrap_or_else(|| panic!("malformed line in {}: {}", path, line));
+            (seq_to_int(uncorrected), seq_to_int(corrected))
+        })
+        .collect()
+}
 
+/// Load barcode abundances (for [AmbiguityPolicy::MostAbundant]): whitespace separated, barcode and count per line
+pub fn load_abundance(path: &str) -> HashMap<String, usize> {
+    let fh = File::open(path).unwrap_or_else(|_| panic!("{} not found", path));
+    BufReader::new(fh)
+        .lines()
+        .map(|line| line.unwrap())
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let cb = fields.next().unwrap().to_string();
+            let count = fields
+                .next()
+                .and_then(|c| c.parse::<usize>().ok())
+                .unwrap_or_else(|| panic!("malformed line in {}: {}", path, line));
+            (cb, count)
+        })
+        .collect()
 }
 
 /// Parse the whitelist-file (one whitelisted barcode per line) into a HashSet
@@ -174,18 +482,36 @@ pub fn load_whitelist(whitelist_filename: &str) -> HashSet<String> {
     whitelist_header
 }
 
+/// Parse several whitelist-files (e.g. CBs and feature barcodes) into a single HashSet, i.e. their union
+///
+/// Since correction uses the Hamming distance, all barcodes must have the same length
+pub fn load_whitelists(whitelist_filenames: &[String]) -> HashSet<String> {
+    let mut whitelist = HashSet::new();
+    for fname in whitelist_filenames {
+        whitelist.extend(load_whitelist(fname));
+    }
+    whitelist
+}
+
 #[cfg(test)]
 mod testing {
     use bktree::BkTree;
 
-    use crate::correct::{correct_single_cb, CorrectionResult};
+    use crate::correct::{
+        build_correct_map, build_correct_map_with_stats, correct, correct_dry_run, correct_single_cb, correct_streaming, correct_with_map,
+        load_abundance, load_correction_map, load_whitelists, save_correction_map, AmbiguityPolicy, CorrectError, CorrectionResult,
+        CorrectionStats,
+    };
+    use bustools::io::{setup_busfile, BusReader, BusRecord};
+    use bustools::utils::seq_to_int;
+    use std::{collections::{HashMap, HashSet}, fs::File, io::Write};
 
     use super::my_hamming;
     #[test]
     fn test_correct() {
         let whitelist = vec!["AAAA".to_string(), "BBBB".to_string()];
         let mut bk: BkTree<String> = BkTree::new(my_hamming);
-        bk.insert_all(whitelist.into_iter());
+        bk.insert_all(whitelist);
 
         // perfect match
         assert_eq!(
@@ -207,7 +533,7 @@ mod testing {
 
         let whitelist = vec!["AAAA".to_string(), "AABB".to_string()];
         let mut bk: BkTree<String> = BkTree::new(my_hamming);
-        bk.insert_all(whitelist.into_iter());
+        bk.insert_all(whitelist);
         // two hits, not clear which one
         assert_eq!(
             correct_single_cb("AABA".to_string(), &bk),
@@ -217,14 +543,269 @@ mod testing {
         // make sure that a perfect match is respected too
         let whitelist = vec!["AAAA".to_string(), "AAAB".to_string()];
         let mut bk: BkTree<String> = BkTree::new(my_hamming);
-        bk.insert_all(whitelist.into_iter());
+        bk.insert_all(whitelist);
         assert_eq!(
             correct_single_cb("AAAA".to_string(), &bk),
             CorrectionResult::SingleHit("AAAA".to_string())
         );
     }
 
+    #[test]
+    fn test_load_whitelists() {
+        let dir = tempfile::tempdir().unwrap();
+        let f1 = dir.path().join("wl1.txt");
+        let f2 = dir.path().join("wl2.txt");
+        writeln!(File::create(&f1).unwrap(), "AAAA\nCCCC").unwrap();
+        writeln!(File::create(&f2).unwrap(), "CCCC\nGGGG").unwrap();
+
+        let whitelist = load_whitelists(&[
+            f1.to_str().unwrap().to_string(),
+            f2.to_str().unwrap().to_string(),
+        ]);
+        let expected: HashSet<String> = ["AAAA", "CCCC", "GGGG"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(whitelist, expected);
+
+        // correcting against the merged lists
+        let mut bk: BkTree<String> = BkTree::new(my_hamming);
+        bk.insert_all(whitelist);
+        assert_eq!(
+            correct_single_cb("GGGA".to_string(), &bk),
+            CorrectionResult::SingleHit("GGGG".to_string())
+        );
+    }
+
+    #[test]
+    fn test_correct_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let wl = dir.path().join("wl.txt");
+        writeln!(File::create(&wl).unwrap(), "AAAAAAAAAAAAAAAA\nAAAAAAAAAAAAAACC").unwrap();
+
+        let cb = |s: &str| seq_to_int(s);
+        let records = vec![
+            // whitelisted
+            BusRecord { CB: cb("AAAAAAAAAAAAAAAA"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            // 1 away from AAAAAAAAAAAAAAAA
+            BusRecord { CB: cb("AAAAAAAAAAAAAAAG"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            // 1 away from both
+            BusRecord { CB: cb("AAAAAAAAAAAAAAAC"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            // far away
+            BusRecord { CB: cb("TTTTTTTTTTTTTTTT"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _busdir) = setup_busfile(&records);
+
+        let stats = correct_dry_run(&busname, &[wl.to_str().unwrap().to_string()], AmbiguityPolicy::Drop, None).unwrap();
+        assert_eq!(
+            stats,
+            CorrectionStats { whitelisted: 1, corrected: 1, uncorrectable: 1, ambiguous: 1 }
+        );
+    }
+
+    #[test]
+    fn test_ambiguity_policy() {
+        let whitelist: HashSet<String> = vec!["AAAAAAAAAAAAAAAA".to_string(), "AAAAAAAAAAAAAACC".to_string()].into_iter().collect();
+        // 1 away from both whitelisted barcodes
+        let cbs: HashSet<String> = vec!["AAAAAAAAAAAAAAAC".to_string()].into_iter().collect();
+        let ambiguous = seq_to_int("AAAAAAAAAAAAAAAC");
+
+        let (map, stats) = build_correct_map_with_stats(&cbs, &whitelist, AmbiguityPolicy::Drop, None);
+        assert!(map.is_empty());
+        assert_eq!(stats.ambiguous, 1);
+
+        let (map, stats) = build_correct_map_with_stats(&cbs, &whitelist, AmbiguityPolicy::First, None);
+        assert_eq!(map[&ambiguous], seq_to_int("AAAAAAAAAAAAAAAA"));
+        assert_eq!((stats.corrected, stats.ambiguous), (1, 0));
 
+        let mut abundance: HashMap<String, usize> = HashMap::new();
+        abundance.insert("AAAAAAAAAAAAAAAA".to_string(), 10);
+        abundance.insert("AAAAAAAAAAAAAACC".to_string(), 100);
+        let map = build_correct_map(&cbs, &whitelist, AmbiguityPolicy::MostAbundant, Some(&abundance));
+        assert_eq!(map[&ambiguous], seq_to_int("AAAAAAAAAAAAAACC"));
+
+        // a tie is still ambiguous
+        abundance.insert("AAAAAAAAAAAAAAAA".to_string(), 100);
+        let map = build_correct_map(&cbs, &whitelist, AmbiguityPolicy::MostAbundant, Some(&abundance));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_load_abundance() {
+        let dir = tempfile::tempdir().unwrap();
+        let f = dir.path().join("abundance.tsv");
+        writeln!(File::create(&f).unwrap(), "AAAA\t10\nCCCC 3\n").unwrap();
+        let abundance = load_abundance(f.to_str().unwrap());
+        assert_eq!(abundance.len(), 2);
+        assert_eq!(abundance["AAAA"], 10);
+        assert_eq!(abundance["CCCC"], 3);
+    }
+
+    #[test]
+    fn test_correction_map_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist: HashSet<String> = vec!["AAAAAAAAAAAAAAAA".to_string(), "CCCCCCCCCCCCCCCC".to_string()].into_iter().collect();
+        let cbs: HashSet<String> = vec![
+            "AAAAAAAAAAAAAAAA".to_string(),
+            "AAAAAAAAAAAAAAAG".to_string(),
+            "CCCCCCCCCCCCCCCA".to_string(),
+            "TTTTTTTTTTTTTTTT".to_string(),
+        ]
+        .into_iter()
+        .collect();
+        let map = build_correct_map(&cbs, &whitelist, AmbiguityPolicy::Drop, None);
+        assert_eq!(map.len(), 3);
+
+        let mapfile = dir.path().join("map.tsv");
+        let mapfile = mapfile.to_str().unwrap();
+        save_correction_map(&map, mapfile, 16);
+        assert_eq!(load_correction_map(mapfile), map);
+
+        let content = std::fs::read_to_string(mapfile).unwrap();
+        assert!(content.contains("AAAAAAAAAAAAAAAG\tAAAAAAAAAAAAAAAA\n"));
+    }
+
+    #[test]
+    fn test_correct_dump_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let wl = dir.path().join("wl.txt");
+        writeln!(File::create(&wl).unwrap(), "AAAAAAAAAAAAAAAA").unwrap();
+        let records = vec![
+            BusRecord { CB: seq_to_int("AAAAAAAAAAAAAAAA"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: seq_to_int("AAAAAAAAAAAAAAAT"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _busdir) = setup_busfile(&records);
+        let outpath = dir.path().join("corrected.bus");
+        let mapfile = dir.path().join("map.tsv");
+
+        correct(
+            &busname,
+            outpath.to_str().unwrap(),
+            &[wl.to_str().unwrap().to_string()],
+            AmbiguityPolicy::Drop,
+            None,
+            Some(mapfile.to_str().unwrap()),
+            None,
+        )
+        .unwrap();
+        let map = load_correction_map(mapfile.to_str().unwrap());
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&seq_to_int("AAAAAAAAAAAAAAAT")], seq_to_int("AAAAAAAAAAAAAAAA"));
+    }
+
+    #[test]
+    fn test_correct_with_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let wl = dir.path().join("wl.txt");
+        writeln!(File::create(&wl).unwrap(), "AAAAAAAAAAAAAAAA\nCCCCCCCCCCCCCCCC").unwrap();
+        let records = vec![
+            BusRecord { CB: seq_to_int("AAAAAAAAAAAAAAAA"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: seq_to_int("AAAAAAAAAAAAAAAT"), UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: seq_to_int("CCCCCCCCCCCCCCCA"), UMI: 0, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: seq_to_int("GGGGGGGGGGGGGGGG"), UMI: 0, EC: 1, COUNT: 2, FLAG: 0 },
+        ];
+        let (busname, _busdir) = setup_busfile(&records);
+        let out_whitelist = dir.path().join("corrected_wl.bus");
+        let out_map = dir.path().join("corrected_map.bus");
+        let mapfile = dir.path().join("map.tsv");
+
+        correct(
+            &busname,
+            out_whitelist.to_str().unwrap(),
+            &[wl.to_str().unwrap().to_string()],
+            AmbiguityPolicy::Drop,
+            None,
+            Some(mapfile.to_str().unwrap()),
+            None,
+        )
+        .unwrap();
+        correct_with_map(&busname, out_map.to_str().unwrap(), mapfile.to_str().unwrap(), None);
+
+        let r_whitelist: Vec<BusRecord> = BusReader::new(out_whitelist.to_str().unwrap()).collect();
+        let r_map: Vec<BusRecord> = BusReader::new(out_map.to_str().unwrap()).collect();
+        assert_eq!(r_whitelist.len(), 3);
+        assert_eq!(r_whitelist, r_map);
+    }
+
+    #[test]
+    fn test_correct_uncorrected_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let wl = dir.path().join("wl.txt");
+        writeln!(File::create(&wl).unwrap(), "AAAAAAAAAAAAAAAA\nCCCCCCCCCCCCCCCC").unwrap();
+        let records = vec![
+            BusRecord { CB: seq_to_int("AAAAAAAAAAAAAAAA"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: seq_to_int("AAAAAAAAAAAAAAAT"), UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: seq_to_int("GGGGGGGGGGGGGGGG"), UMI: 0, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: seq_to_int("TTTTTTTTTTTTTTTT"), UMI: 3, EC: 2, COUNT: 5, FLAG: 1 },
+        ];
+        let (busname, _busdir) = setup_busfile(&records);
+        let out = dir.path().join("corrected.bus");
+        let out_uncorrected = dir.path().join("uncorrected.bus");
+
+        correct(
+            &busname,
+            out.to_str().unwrap(),
+            &[wl.to_str().unwrap().to_string()],
+            AmbiguityPolicy::Drop,
+            None,
+            None,
+            Some(out_uncorrected.to_str().unwrap()),
+        )
+        .unwrap();
+
+        let corrected: Vec<BusRecord> = BusReader::new(out.to_str().unwrap()).collect();
+        let uncorrected: Vec<BusRecord> = BusReader::new(out_uncorrected.to_str().unwrap()).collect();
+        assert_eq!(corrected.len() + uncorrected.len(), records.len());
+        // written as they were
+        assert_eq!(uncorrected, records[2..].to_vec());
+    }
+
+    #[test]
+    fn test_correct_streaming() {
+        let dir = tempfile::tempdir().unwrap();
+        let wl = dir.path().join("wl.txt");
+        writeln!(File::create(&wl).unwrap(), "AAAAAAAAAAAAAAAA\nAAAAAAAAAAAAAACC\nCCCCCCCCCCCCCCCC").unwrap();
+        let cb = |s: &str| seq_to_int(s);
+        // CB sorted
+        let mut records = vec![
+            BusRecord { CB: cb("AAAAAAAAAAAAAAAA"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            // ambiguous
+            BusRecord { CB: cb("AAAAAAAAAAAAAAAC"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: cb("AAAAAAAAAAAAAAAG"), UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: cb("AAAAAAAAAAAAAAAG"), UMI: 1, EC: 1, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: cb("CCCCCCCCCCCCCCCA"), UMI: 0, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: cb("TTTTTTTTTTTTTTTT"), UMI: 0, EC: 1, COUNT: 2, FLAG: 0 },
+        ];
+        records.sort_by_key(|r| (r.CB, r.UMI));
+        let (busname, _busdir) = setup_busfile(&records);
+        let wl = [wl.to_str().unwrap().to_string()];
+        let out = dir.path().join("corrected.bus");
+        let out_streaming = dir.path().join("corrected_streaming.bus");
+
+        correct(&busname, out.to_str().unwrap(), &wl, AmbiguityPolicy::Drop, None, None, None).unwrap();
+        correct_streaming(&busname, out_streaming.to_str().unwrap(), &wl, AmbiguityPolicy::Drop, None).unwrap();
+
+        let expected: Vec<BusRecord> = BusReader::new(out.to_str().unwrap()).collect();
+        let r_streaming: Vec<BusRecord> = BusReader::new(out_streaming.to_str().unwrap()).collect();
+        assert_eq!(r_streaming.len(), 4);
+        assert_eq!(r_streaming, expected);
+    }
+
+    #[test]
+    fn test_correct_whitelist_length_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let wl = dir.path().join("wl.txt");
+        // 14bp whitelist, 16bp CBs in the busfile
+        writeln!(File::create(&wl).unwrap(), "AAAAAAAAAAAAAA\nCCCCCCCCCCCCCC").unwrap();
+
+        let records = vec![BusRecord { CB: 0, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 }];
+        let (busname, _busdir) = setup_busfile(&records);
+        let outpath = dir.path().join("corrected.bus");
+
+        let wl = [wl.to_str().unwrap().to_string()];
+        let res = correct(&busname, outpath.to_str().unwrap(), &wl, AmbiguityPolicy::Drop, None, None, None);
+        assert!(matches!(res, Err(CorrectError::WhitelistLength { cb_len: 16, .. })));
+        assert!(correct_dry_run(&busname, &wl, AmbiguityPolicy::Drop, None).is_err());
+        // nothing written
+        assert!(!outpath.exists());
+    }
 }
 
 /*
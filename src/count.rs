@@ -3,22 +3,132 @@
 //! This turns a busfolder into a count matrix.
 //!
 //! The strategy:
-//! 1. iterate over CBs, turn each cell (Busrecords from same cell) into an [ExpressionVector] (HashMap<Genename, u32>).
+//! 1. iterate over CBs, turn each cell (Busrecords from same cell) into an `ExpressionVector` (HashMap<Genename, u32>).
 //!    Those all have slightly different key/genenes sets
 //! 2. Determine ALL genes: from the EC2Gene file
 //! 3. turn into a big sparse [crate::countmatrix::CountMatrix] via `expression_vectors_to_matrix()`
 
+use crate::count2::em_single_cell;
 use crate::countmatrix::CountMatrix;
-use bustools::consistent_genes::{find_consistent, Ec2GeneMapper, Genename, MappingResult, CB, MappingMode};
-use bustools::io::{group_record_by_cb_umi, BusFolder, BusReader, BusRecord};
+use bustools::consistent_genes::{find_consistent, Ec2GeneMapper, GeneId, Genename, MappingResult, CB, MappingMode};
+use bustools::io::{BusFolder, BusReader, BusRecord};
 use bustools::iterators::CellGroupIterator;
-use bustools::utils::{get_progressbar, int_to_seq};
+use crate::util::{make_mapper_maybe_gz, pack_cb_umi, read_busfile, ProgressIter};
+use bustools::utils::int_to_seq;
+use itertools::Itertools;
+use serde::Serialize;
 use sprs;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::AddAssign;
 use std::time::Instant;
 
 type ExpressionVector = HashMap<Genename, u32>;
 
+/// What a (consistently mapped) molecule contributes to its gene's count
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum CountMode {
+    /// 1 per molecule, i.e. the number of distinct UMIs
+    #[default]
+    Umis,
+    /// the number of reads, i.e. the summed COUNT of the molecule's records
+    Reads,
+    /// the number of records of the molecule, i.e. reads with each record's COUNT capped at 1.
+    /// For pre-deduplicated busfiles, where a record represents a single molecule regardless of COUNT
+    CollapsedReads,
+}
+
+impl CountMode {
+    /// value added to the gene's count for a molecule made up of `records`
+    pub(crate) fn molecule_value(&self, records: &[BusRecord]) -> u32 {
+        match self {
+            CountMode::Umis => 1,
+            CountMode::Reads => records.iter().map(|r| r.COUNT).sum(),
+            CountMode::CollapsedReads => records.iter().map(|r| r.COUNT.min(1)).sum(),
+        }
+    }
+}
+
+/// What to do with multimapped molecules (consistent with several genes) in [count]
+///
+/// Unique counts always come first: the candidates of a multimapped molecule are weighted by
+/// their unique counts within the same cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum MultimapPolicy {
+    /// drop multimapped molecules
+    #[default]
+    Discard,
+    /// assign the molecule to a candidate gene if it's the only one with unique counts in the cell; drop it otherwise
+    Unique,
+    /// distribute the molecule across its candidate genes, proportional to their unique counts in the cell
+    /// (uniformly if none has unique counts).
+    ///
+    /// This is a single iteration of the expectation-maximization of [crate::count2::count_em];
+    /// the per-cell fractional counts are rounded to the nearest integer
+    #[clap(name = "em")]
+    EM,
+}
+
+impl MultimapPolicy {
+    /// counts assigned to each gene from the multimapped `molecules` (candidate genes, value of the molecule),
+    /// given the `unique` counts of the cell
+    fn assign(&self, unique: &HashMap<GeneId, u32>, molecules: &[(Vec<GeneId>, u32)]) -> HashMap<GeneId, u32> {
+        let mut assigned: HashMap<GeneId, u32> = HashMap::new();
+        match self {
+            MultimapPolicy::Discard => {}
+            MultimapPolicy::Unique => {
+                for (genes, value) in molecules {
+                    let mut with_counts = genes.iter().filter(|g| unique.contains_key(g));
+                    if let (Some(g), None) = (with_counts.next(), with_counts.next()) {
+                        *assigned.entry(*g).or_insert(0) += value;
+                    }
+                }
+            }
+            MultimapPolicy::EM => {
+                let unique_f: HashMap<GeneId, f32> = unique.iter().map(|(g, c)| (*g, *c as f32)).collect();
+                let weighted: Vec<(Vec<GeneId>, f32)> = molecules.iter().map(|(genes, v)| (genes.clone(), *v as f32)).collect();
+                for (g, abundance) in em_single_cell(&unique_f, &weighted, 1) {
+                    let extra = (abundance - unique_f.get(&g).unwrap_or(&0.0)).round() as u32;
+                    if extra > 0 {
+                        assigned.insert(g, extra);
+                    }
+                }
+            }
+        }
+        assigned
+    }
+}
+
+/// Summary of how the CB/UMIs (molecules) got mapped to genes during [count]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct CountStats {
+    /// CB/UMIs mapped to a single gene (these end up in the count matrix)
+    pub mapped: usize,
+    /// CB/UMIs consistent with multiple genes
+    pub multimapped: usize,
+    /// CB/UMIs whose records map to different genes
+    pub inconsistent: usize,
+}
+
+impl CountStats {
+    /// write the stats as a json file
+    pub fn to_disk(&self, fname: &str) -> std::io::Result<()> {
+        let fh = File::create(fname)?;
+        serde_json::to_writer(fh, self)?;
+        Ok(())
+    }
+}
+
+impl AddAssign for CountStats {
+    fn add_assign(&mut self, other: Self) {
+        self.mapped += other.mapped;
+        self.multimapped += other.multimapped;
+        self.inconsistent += other.inconsistent;
+    }
+}
+
 #[allow(dead_code)]
 fn count_bayesian(bfolder: BusFolder) {
     let bfile = bfolder.get_busfile();
@@ -60,52 +170,259 @@ fn count_bayesian(bfolder: BusFolder) {
     // println!("{:?}", n[0]);
 }
 
+/// Options of [count] (and its variants) on top of the EC->gene mapping.
+///
+/// `CountOptions::default()` counts the UMIs of all CBs and FLAGs, discarding multimapped molecules
+#[derive(Debug, Clone, Default)]
+pub struct CountOptions {
+    /// if set, only count these CBs (e.g. the called cells); all other CBs are skipped
+    pub cb_whitelist: Option<HashSet<u64>>,
+    /// if set, only count records whose FLAG equals this value (e.g. 0, excluding records flagged as filtered)
+    pub flag_filter: Option<u32>,
+    /// what to do with multimapped molecules (by default, they're discarded), see [MultimapPolicy]
+    pub multimap_policy: MultimapPolicy,
+    /// count molecules (UMIs) or reads per gene
+    pub count_mode: CountMode,
+    /// also tally which ECs contributed to the count matrix, see [CountOutput::ec_usage]
+    pub ec_usage: bool,
+}
+
+/// Result of [count]
+#[derive(Debug)]
+pub struct CountOutput {
+    /// the count matrix (rows sorted by CB, columns sorted by Genename)
+    pub matrix: CountMatrix,
+    /// summary of how many CB/UMIs were mapped/multimapped/inconsistent
+    pub stats: CountStats,
+    /// if [CountOptions::ec_usage] is set: for each EC, the number of molecules mapped to a single gene
+    /// (see [CountStats::mapped]) with a record of that EC.
+    /// ECs absent from the tally never contributed, e.g. to prune the EC matrix. See [write_ec_usage]
+    pub ec_usage: Option<HashMap<u32, usize>>,
+}
+
+/// Errors of [count] on inputs that don't fit together
+#[derive(Debug)]
+pub enum CountError {
+    /// the EC->gene mapping contains no genes, which would silently produce an empty matrix
+    EmptyMapping {
+        /// the busfile being counted
+        busfile: String,
+    },
+}
+
+impl fmt::Display for CountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CountError::EmptyMapping { busfile } => write!(
+                f,
+                "EC->gene mapping of {} contains no genes. Does the t2g file match transcripts.txt?",
+                busfile
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CountError {}
+
+/// make sure the mapping has genes (e.g. an empty one if the t2g doesn't match transcripts.txt)
+fn check_mapper(ecmapper: &Ec2GeneMapper, busfile: &str) -> Result<(), CountError> {
+    if ecmapper.get_gene_list().is_empty() {
+        return Err(CountError::EmptyMapping { busfile: busfile.to_string() });
+    }
+    Ok(())
+}
+
 /// busfile to count matrix, analogous to "bustools count"
 /// ## Parameters
 /// * bfolder: Busfolder (containing busfile, matric.ec and transcripts.txt) to count
-/// *  ignore_multi_ec:
-///     if true, discard CB/UMIs that have multipel records with different EC
-///     if false: Try to consolidate those records: Different fragments from the same mRNA might map differently,
-///         e.g some parts of the mRNA are ambigous (mapping to more than one gene), but others might be unique
-///     Kallisto operates with `ignore_multimapped=false`
-pub fn count(bfolder: &BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bool) -> CountMatrix {
-    let cb_iter = bfolder.get_iterator().groupby_cb();
+/// * mapping_mode: only `MappingMode::Gene` is supported
+/// * ignore_multi_ec:
+///   if true, discard CB/UMIs that have multipel records with different EC
+///   if false: Try to consolidate those records: Different fragments from the same mRNA might map differently,
+///   e.g some parts of the mRNA are ambigous (mapping to more than one gene), but others might be unique
+///   Kallisto operates with `ignore_multimapped=false`
+/// * options: whitelist, FLAG filter, multimapper policy etc, see [CountOptions]
+///
+/// ## Returns
+/// The count matrix and a summary of how many CB/UMIs were mapped/multimapped/inconsistent, see [CountOutput].
+/// Fails if the EC->gene mapping contains no genes, see [CountError]
+pub fn count(bfolder: &BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bool, options: &CountOptions) -> Result<CountOutput, CountError> {
+    count_from_parts(&bfolder.get_busfile(), gene_mapper(mapping_mode), ignore_multi_ec, options)
+}
+
+/// the EC->gene mapping of `MappingMode::Gene`, the only mode supported by [count]
+fn gene_mapper(mapping_mode: MappingMode) -> Ec2GeneMapper {
+    match mapping_mode {
+        MappingMode::Gene(ecmapper, _inconstsistent_mode) => ecmapper,
+        MappingMode::EC(_) => panic!("not implemented"),
+        MappingMode::Transcript(_, _) => todo!(),
+    }
+}
+
+/// write the EC usage of [count] (see [CountOutput::ec_usage]) as tsv (`ec`, `n_molecules`), sorted by EC
+pub fn write_ec_usage(ec_usage: &HashMap<u32, usize>, fname: &str) -> std::io::Result<()> {
+    let mut fh = BufWriter::new(File::create(fname)?);
+    writeln!(fh, "ec\tn_molecules")?;
+    for (ec, n) in ec_usage.iter().sorted() {
+        writeln!(fh, "{}\t{}", ec, n)?;
+    }
+    fh.flush()
+}
+
+/// Same as [count], but not assuming the busfolder layout:
+/// counts `busfile` using an explicitly constructed EC->gene mapping, e.g. when matrix.ec/transcripts.txt live elsewhere.
+pub fn count_from_parts(busfile: &str, ecmapper: Ec2GeneMapper, ignore_multi_ec: bool, options: &CountOptions) -> Result<CountOutput, CountError> {
+    check_mapper(&ecmapper, busfile)?;
+    let cb_iter = read_busfile(busfile).groupby_cb();
 
     println!("determine size of iterator");
     let now = Instant::now();
-    let total_records = bfolder.get_cb_size();
+    let total_records = read_busfile(busfile).groupby_cb().count();
     let elapsed_time: std::time::Duration = now.elapsed();
     println!(
         "determined size of iterator {} in {:?}",
         total_records, elapsed_time
     );
 
-    let (ecmapper, _inconstsistent_mode) = match mapping_mode {
-        MappingMode::EC(_) => panic!("not implemented"),
-        MappingMode::Gene(ecmapper, inconstsistent_mode) => {(ecmapper, inconstsistent_mode)}
-        MappingMode::Transcript(_, _) => todo!(),
-        
-    };
-
     let mut all_expression_vector: HashMap<CB, ExpressionVector> = HashMap::new();
+    let mut stats = CountStats::default();
+    let mut ec_usage = options.ec_usage.then(HashMap::new);
+    let mut cache = MappingCache::default();
     let now = Instant::now();
 
-    let bar = get_progressbar(total_records as u64);
+    // progress in cells, matching the iteration
+    for (cb, mut record_list) in ProgressIter::new(cb_iter, Some(total_records as u64), 10_000) {
+        if let Some(whitelist) = &options.cb_whitelist {
+            if !whitelist.contains(&cb) {
+                continue;
+            }
+        }
+        filter_flag(&mut record_list, options.flag_filter);
+        if record_list.is_empty() {
+            continue;
+        }
 
-    for (counter, (cb, record_list)) in cb_iter.enumerate() {
-        let s = records_to_expression_vector(record_list, &ecmapper, ignore_multi_ec);
+        let (s, cell_stats) = records_to_expression_vector(
+            record_list,
+            &ecmapper,
+            ignore_multi_ec,
+            options.multimap_policy,
+            options.count_mode,
+            &mut cache,
+            ec_usage.as_mut(),
+        );
+        stats += cell_stats;
 
         // this will also insert emtpy cells (i.e. their records are all multimapped)
         all_expression_vector.insert(CB(cb), s);
-
-        if counter % 10_000 == 0 {
-            bar.inc(10_000)
-        }
     }
 
     let elapsed_time = now.elapsed();
     println!("done in {:?}", elapsed_time);
+    println!(
+        "Mapped {}, multimapped {}, inconsistent {}",
+        stats.mapped, stats.multimapped, stats.inconsistent
+    );
 
+    let matrix = build_countmatrix(all_expression_vector, &ecmapper);
+    Ok(CountOutput { matrix, stats, ec_usage })
+}
+
+/// Same as [count], but counting each FLAG value separately, e.g. when FLAG encodes a category like spliced/unspliced.
+///
+/// The records of each cell are partitioned by FLAG before grouping them into CB/UMIs.
+/// Hence a UMI with mixed FLAGs is resolved (consistent/multimapped/inconsistent) separately in each FLAG partition,
+/// using the same logic as [count], and can end up in the matrices of several FLAGs.
+/// If [CountOptions::flag_filter] is set, only that FLAG is counted.
+///
+/// ## Returns
+/// For each FLAG value in the busfile, the count matrix and the mapping summary (and EC usage, if requested)
+pub fn count_split_by_flag(
+    bfolder: &BusFolder,
+    mapping_mode: MappingMode,
+    ignore_multi_ec: bool,
+    options: &CountOptions,
+) -> Result<BTreeMap<u32, CountOutput>, CountError> {
+    let ecmapper = gene_mapper(mapping_mode);
+    check_mapper(&ecmapper, &bfolder.get_busfile())?;
+
+    type FlagPartition = (HashMap<CB, ExpressionVector>, CountStats, Option<HashMap<u32, usize>>);
+    let mut per_flag: BTreeMap<u32, FlagPartition> = BTreeMap::new();
+    let mut cache = MappingCache::default();
+    for (cb, mut record_list) in read_busfile(&bfolder.get_busfile()).groupby_cb() {
+        if let Some(whitelist) = &options.cb_whitelist {
+            if !whitelist.contains(&cb) {
+                continue;
+            }
+        }
+        filter_flag(&mut record_list, options.flag_filter);
+
+        let mut by_flag: BTreeMap<u32, Vec<BusRecord>> = BTreeMap::new();
+        for r in record_list {
+            by_flag.entry(r.FLAG).or_default().push(r);
+        }
+        for (flag, records) in by_flag {
+            let (expression_vectors, stats, ec_usage) = per_flag
+                .entry(flag)
+                .or_insert_with(|| (HashMap::new(), CountStats::default(), options.ec_usage.then(HashMap::new)));
+            let (s, cell_stats) = records_to_expression_vector(
+                records,
+                &ecmapper,
+                ignore_multi_ec,
+                options.multimap_policy,
+                options.count_mode,
+                &mut cache,
+                ec_usage.as_mut(),
+            );
+            *stats += cell_stats;
+            expression_vectors.insert(CB(cb), s);
+        }
+    }
+
+    Ok(per_flag
+        .into_iter()
+        .map(|(flag, (expression_vectors, stats, ec_usage))| {
+            println!(
+                "FLAG {}: Mapped {}, multimapped {}, inconsistent {}",
+                flag, stats.mapped, stats.multimapped, stats.inconsistent
+            );
+            let matrix = build_countmatrix(expression_vectors, &ecmapper);
+            (flag, CountOutput { matrix, stats, ec_usage })
+        })
+        .collect())
+}
+
+/// Same as [count], but for the same sample spread over several busfolders (e.g. sequencing runs),
+/// summing the matrices (see [CountMatrix::sum]) instead of concatenating the busfiles first.
+///
+/// Each busfolder has its own ECs (matrix.ec), hence the EC->gene mapping is built per folder from `t2g_file`
+/// (optionally gzipped) rather than passing a single [MappingMode].
+/// For the same reason, the EC usage ([CountOptions::ec_usage]) is not reported.
+///
+/// ## Returns
+/// The summed count matrix (union of barcodes and genes) and the mapping summary, summed over all folders
+pub fn count_multi(folders: &[BusFolder], t2g_file: &str, ignore_multi_ec: bool, options: &CountOptions) -> Result<CountOutput, CountError> {
+    let options = CountOptions { ec_usage: false, ..options.clone() };
+    let mut matrices = Vec::with_capacity(folders.len());
+    let mut stats = CountStats::default();
+    for bfolder in folders {
+        let ecmapper = make_mapper_maybe_gz(bfolder, t2g_file);
+        let out = count_from_parts(&bfolder.get_busfile(), ecmapper, ignore_multi_ec, &options)?;
+        matrices.push(out.matrix);
+        stats += out.stats;
+    }
+    Ok(CountOutput { matrix: CountMatrix::sum(&matrices), stats, ec_usage: None })
+}
+
+/// keeps only the records whose FLAG equals `flag_filter` (if set)
+pub(crate) fn filter_flag(records: &mut Vec<BusRecord>, flag_filter: Option<u32>) {
+    if let Some(flag) = flag_filter {
+        records.retain(|r| r.FLAG == flag);
+    }
+}
+
+/// turns the expression vectors of all cells into a countmatrix with all genes of the mapper (sorted) as columns
+fn build_countmatrix(all_expression_vector: HashMap<CB, ExpressionVector>, ecmapper: &Ec2GeneMapper) -> CountMatrix {
     //collect all genes
     let genelist_vector: Vec<Genename> = ecmapper.get_gene_list();
     println!(" genes {}", genelist_vector.len());
@@ -119,12 +436,11 @@ pub fn count(bfolder: &BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bo
 
     let countmatrix = expression_vectors_to_matrix(all_expression_vector, genelist_vector2);
     println!("{}", countmatrix);
-
     countmatrix
 }
 
 /// try to map the records to a gene
-pub (crate) fn map_record_list(records: &[BusRecord], eg_mapper: &Ec2GeneMapper, ignore_multi_ec:bool) -> MappingResult {
+pub fn map_record_list(records: &[BusRecord], eg_mapper: &Ec2GeneMapper, ignore_multi_ec:bool) -> MappingResult {
     let m: MappingResult = if ignore_multi_ec {
         // means: If the records map to more than one gene, just treat as unmappable
         match records.len() {
@@ -138,39 +454,118 @@ pub (crate) fn map_record_list(records: &[BusRecord], eg_mapper: &Ec2GeneMapper,
     m
 }
 
+/// Memoizes [find_consistent] over a counting run: many molecules share the same ECs,
+/// and the result only depends on the set of (distinct) ECs of a CB/UMI, not on CB/UMI/COUNT.
+///
+/// Cached by the sorted, deduplicated ECs of the molecule
+#[derive(Default)]
+pub struct MappingCache {
+    cache: HashMap<Vec<u32>, MappingResult>,
+}
+
+impl MappingCache {
+    /// Same as [map_record_list], looking up/storing the result in the cache
+    pub fn map_record_list(&mut self, records: &[BusRecord], eg_mapper: &Ec2GeneMapper, ignore_multi_ec: bool) -> MappingResult {
+        if ignore_multi_ec && records.len() > 1 {
+            return MappingResult::Inconsistent;
+        }
+        self.find_consistent(records, eg_mapper)
+    }
+
+    /// Same as [find_consistent], looking up/storing the result in the cache
+    pub fn find_consistent(&mut self, records: &[BusRecord], eg_mapper: &Ec2GeneMapper) -> MappingResult {
+        let mut key: Vec<u32> = records.iter().map(|r| r.EC).collect();
+        key.sort_unstable();
+        key.dedup();
+        let m = self
+            .cache
+            .entry(key)
+            .or_insert_with(|| find_consistent(records, eg_mapper));
+        // MappingResult isn't Clone
+        match m {
+            MappingResult::SingleGene(g) => MappingResult::SingleGene(*g),
+            MappingResult::Multimapped(genes) => MappingResult::Multimapped(genes.clone()),
+            MappingResult::Inconsistent => MappingResult::Inconsistent,
+        }
+    }
+}
+
+/// Group the records by CB/UMI, assuming they are sorted by CB/UMI (as within a cell of a sorted busfile):
+/// a linear scan, yielding consecutive runs of the same CB/UMI.
+///
+/// Unsorted input silently splits a CB/UMI into several groups, use [group_by_umi] in that case
+pub fn group_sorted_by_umi(records: &[BusRecord]) -> impl Iterator<Item = &[BusRecord]> {
+    records.chunk_by(|a, b| a.CB == b.CB && a.UMI == b.UMI)
+}
+
+/// Group the records by CB/UMI, in any order, via a HashMap. The order of the groups is arbitrary
+pub fn group_by_umi(records: Vec<BusRecord>) -> Vec<Vec<BusRecord>> {
+    // packed CB/UMI keys hash faster than (CB, UMI) tuples
+    let mut cb_umi_grouped: HashMap<u128, Vec<BusRecord>> = HashMap::new();
+    for r in records {
+        cb_umi_grouped.entry(pack_cb_umi(r.CB, r.UMI, 32)).or_default().push(r);
+    }
+    cb_umi_grouped.into_values().collect()
+}
+
 /// Turns a set of Busrecords from a single cell (sahred CB() into an expression vector:
-/// per gene, how many umis are observed
-fn records_to_expression_vector(
+/// per gene, how many umis (or reads, see [CountMode]) are observed. Also reports how the cell's CB/UMIs were mapped
+pub(crate) fn records_to_expression_vector(
     record_list: Vec<BusRecord>,
     eg_mapper: &Ec2GeneMapper,
     ignore_multi_ec: bool,
-) -> ExpressionVector {
+    multimap_policy: MultimapPolicy,
+    count_mode: CountMode,
+    cache: &mut MappingCache,
+    mut ec_usage: Option<&mut HashMap<u32, usize>>,
+) -> (ExpressionVector, CountStats) {
     /*
     TODO this doesnt consider multiple records with same umi/cb + EC mapping to different genes, i.e. a colision
     */
-    let mut expression_vector: ExpressionVector = HashMap::new(); // gene -> count
-    let mut _multimapped = 0_u32;
-    let mut _inconsistant = 0_u32;
-
-    // first, group the records by UMI
-    // TODO: EXPENSIVE!! 25k/s
-    let cb_umi_grouped = group_record_by_cb_umi(record_list);
+    let mut stats = CountStats::default();
+    // unique counts, and candidate genes and value of the multimapped molecules:
+    // those get assigned once all unique counts are in
+    let mut unique: HashMap<GeneId, u32> = HashMap::new();
+    let mut multimapped: Vec<(Vec<GeneId>, u32)> = Vec::new();
 
-    for ((_cb, _umi), records) in cb_umi_grouped {
+    let mut add_molecule = |records: &[BusRecord]| {
         // all records coresponding to the same UMI
-
-        match map_record_list(&records, eg_mapper, ignore_multi_ec) {
+        match cache.map_record_list(records, eg_mapper, ignore_multi_ec) {
             // mapped to a single gene: update count!
             MappingResult::SingleGene(g) => {
-                let gname = eg_mapper.resolve_gene_id(g);
-                let val = expression_vector.entry(gname).or_insert(0);
-                *val += 1;
+                *unique.entry(g).or_insert(0) += count_mode.molecule_value(records);
+                stats.mapped += 1;
+                if let Some(usage) = ec_usage.as_deref_mut() {
+                    for ec in records.iter().map(|r| r.EC).unique() {
+                        *usage.entry(ec).or_insert(0) += 1;
+                    }
+                }
+            }
+            MappingResult::Multimapped(genes) => {
+                if multimap_policy != MultimapPolicy::Discard {
+                    multimapped.push((genes.into_iter().sorted().collect(), count_mode.molecule_value(records)));
+                }
+                stats.multimapped += 1
             }
-            MappingResult::Multimapped(_) => _multimapped += 1,
-            MappingResult::Inconsistent => _inconsistant += 1,
+            MappingResult::Inconsistent => stats.inconsistent += 1,
         }
+    };
+
+    // first, group the records by UMI.
+    // Coming from a sorted busfile, they're sorted by UMI already and a linear scan does;
+    // otherwise fall back to hashing (EXPENSIVE!! 25k/s)
+    if record_list.is_sorted_by_key(|r| (r.CB, r.UMI)) {
+        group_sorted_by_umi(&record_list).for_each(add_molecule);
+    } else {
+        group_by_umi(record_list).iter().for_each(|records| add_molecule(records));
+    }
+
+    let assigned = multimap_policy.assign(&unique, &multimapped);
+    let mut expression_vector: ExpressionVector = HashMap::new(); // gene -> count
+    for (g, c) in unique.into_iter().chain(assigned) {
+        *expression_vector.entry(eg_mapper.resolve_gene_id(g)).or_insert(0) += c;
     }
-    expression_vector
+    (expression_vector, stats)
 }
 
 /// turn an collection of expression vectors (from many cells)
@@ -198,7 +593,12 @@ fn expression_vectors_to_matrix(
         gene2index.insert(g, i);
     }
 
-    for (i, (cb, expr_vec)) in all_expression_vector.iter().enumerate() {
+    // sorted CBs, so the output doesn't depend on the HashMap's iteration order
+    let mut sorted_cbs: Vec<&CB> = all_expression_vector.keys().collect();
+    sorted_cbs.sort_by_key(|cb| cb.0);
+
+    for (i, cb) in sorted_cbs.into_iter().enumerate() {
+        let expr_vec = &all_expression_vector[cb];
         for (gene, count) in expr_vec {
             ii.push(i);
 
@@ -222,15 +622,52 @@ fn expression_vectors_to_matrix(
 
 #[cfg(test)]
 mod test {
-    use super::count;
+    use super::{
+        count, count_from_parts, count_multi, count_split_by_flag, group_by_umi, group_sorted_by_umi, map_record_list,
+        write_ec_usage, CountError, CountMode, CountOptions, CountOutput, CountStats, MappingCache, MultimapPolicy,
+    };
     use crate::{count::records_to_expression_vector, count2::countmap_to_matrix};
     use bustools::{
         consistent_genes::{Ec2GeneMapper, GeneId, Genename, CB, EC, MappingMode, InconsistentResolution},
         io::{setup_busfile, BusFolder, BusRecord},
         utils::vec2set,
     };
+    use itertools::Itertools;
     use std::collections::{HashMap, HashSet};
 
+    #[test]
+    fn test_count_stats_to_disk() {
+        let stats = CountStats { mapped: 3, multimapped: 1, inconsistent: 2 };
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("count_stats.json");
+        stats.to_disk(fname.to_str().unwrap()).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(fname).unwrap()).unwrap();
+        assert_eq!(json, serde_json::json!({"mapped": 3, "multimapped": 1, "inconsistent": 2}));
+
+        let missing_dir = dir.path().join("missing").join("count_stats.json");
+        assert!(stats.to_disk(missing_dir.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_group_sorted_by_umi() {
+        let mut records: Vec<BusRecord> = (0..50)
+            .map(|i| BusRecord { CB: i % 3, UMI: (i * 7) % 5, EC: i as u32, COUNT: 1, FLAG: 0 })
+            .collect();
+        records.sort_by_key(|r| (r.CB, r.UMI, r.EC));
+
+        let mut sorted: Vec<Vec<BusRecord>> = group_sorted_by_umi(&records).map(|g| g.to_vec()).collect();
+        let mut hashed = group_by_umi(records);
+        // groups as well as the records within are in arbitrary order for the HashMap version
+        for g in hashed.iter_mut() {
+            g.sort_by_key(|r| r.EC);
+        }
+        hashed.sort_by_key(|g| (g[0].CB, g[0].UMI));
+        sorted.sort_by_key(|g| (g[0].CB, g[0].UMI));
+        assert_eq!(sorted.len(), 15);
+        assert_eq!(sorted, hashed);
+    }
+
     #[test]
     fn test_records_to_expression_vector() {
         let ec0: HashSet<Genename> =
@@ -272,19 +709,19 @@ mod test {
         let r13 = BusRecord { CB: 0, UMI: 4, EC: 0, COUNT: 2, FLAG: 0 };
 
         let records0 = vec![r1.clone(), r2.clone()];
-        let c0 = records_to_expression_vector(records0, &es, false);
+        let (c0, _) = records_to_expression_vector(records0, &es, false, MultimapPolicy::Discard, CountMode::Umis, &mut MappingCache::default(), None);
         assert_eq!(c0, HashMap::from([(Genename("G1".to_string()), 1)]));
 
         let records1 = vec![r1.clone(), r2.clone(), r10.clone(), r11.clone()];
-        let c1 = records_to_expression_vector(records1, &es, false);
+        let (c1, _) = records_to_expression_vector(records1, &es, false, MultimapPolicy::Discard, CountMode::Umis, &mut MappingCache::default(), None);
         assert_eq!(c1, HashMap::from([(Genename("G1".to_string()), 2)]));
 
         let records2 = vec![r4.clone(), r5.clone(), r6.clone()];
-        let c2 = records_to_expression_vector(records2, &es, false);
+        let (c2, _) = records_to_expression_vector(records2, &es, false, MultimapPolicy::Discard, CountMode::Umis, &mut MappingCache::default(), None);
         assert_eq!(c2, HashMap::from([]));
 
         let records3 = vec![r1, r2, r4, r5, r6, r7, r8, r9, r10, r11, r12, r13];
-        let c3 = records_to_expression_vector(records3, &es, false);
+        let (c3, stats) = records_to_expression_vector(records3, &es, false, MultimapPolicy::Discard, CountMode::Umis, &mut MappingCache::default(), None);
         assert_eq!(
             c3,
             HashMap::from([
@@ -292,6 +729,7 @@ mod test {
                 (Genename("G2".to_string()), 1)
             ])
         );
+        assert_eq!(stats, CountStats { mapped: 3, multimapped: 1, inconsistent: 1 });
     }
 
     #[test]
@@ -329,10 +767,10 @@ mod test {
         let records = vec![r1, r2, r3, r4, r5, r6];
         let (_bname, _dir) = setup_busfile(&records);
 
-        let bfolder = BusFolder::new(&_dir.path().to_str().unwrap().to_owned());
+        let bfolder = BusFolder::new(_dir.path().to_str().unwrap());
 
         let mapping_mode = MappingMode::Gene(es, InconsistentResolution::IgnoreInconsistent);
-        let cmat = count(&bfolder, mapping_mode, false);
+        let CountOutput { matrix: cmat, stats, .. } = count(&bfolder, mapping_mode, false, &CountOptions::default()).unwrap();
 
         let exp: HashMap<_, _> = vec![((CB(0), GeneId(0)), 2), ((CB(1), GeneId(1)), 1)]
             .into_iter()
@@ -343,5 +781,319 @@ mod test {
         );
 
         assert_eq!(cmat, exp_cmat);
+        assert_eq!(stats, CountStats { mapped: 3, multimapped: 1, inconsistent: 0 });
+    }
+
+    #[test]
+    fn test_count_from_parts() {
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![Genename("G1".to_string())])),
+            (EC(1), vec2set(vec![Genename("G2".to_string())])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        // a busfile not named like in a busfolder
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 },
+        ];
+        let (bname, _dir) = setup_busfile(&records);
+        let busfile = _dir.path().join("somewhere_else.bus");
+        std::fs::rename(bname, &busfile).unwrap();
+
+        let CountOutput { matrix: cmat, stats, .. } = count_from_parts(busfile.to_str().unwrap(), es, false, &CountOptions::default()).unwrap();
+        let exp: HashMap<_, _> = vec![((CB(0), GeneId(0)), 1), ((CB(1), GeneId(1)), 1)]
+            .into_iter()
+            .collect();
+        let exp_cmat = countmap_to_matrix(
+            &exp,
+            vec![Genename("G1".to_string()), Genename("G2".to_string())],
+        );
+        assert_eq!(cmat, exp_cmat);
+        assert_eq!(stats.mapped, 2);
+    }
+
+    #[test]
+    fn test_multimap_policy() {
+        let g = |name: &str| Genename(name.to_string());
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![g("G1")])),
+            (EC(1), vec2set(vec![g("G2")])),
+            (EC(2), vec2set(vec![g("G1"), g("G2")])),
+            (EC(3), vec2set(vec![g("G2"), g("G3")])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        // unique: G1 x3, G2 x1; multimapped: G1/G2 x4, G2/G3 x2
+        let ecs = [0, 0, 0, 1, 2, 2, 2, 2, 3, 3];
+        let records: Vec<BusRecord> = ecs
+            .iter()
+            .enumerate()
+            .map(|(umi, ec)| BusRecord { CB: 0, UMI: umi as u64, EC: *ec, COUNT: 1, FLAG: 0 })
+            .collect();
+
+        let expression = |policy| {
+            let (ev, stats) = records_to_expression_vector(records.clone(), &es, false, policy, CountMode::Umis, &mut MappingCache::default(), None);
+            assert_eq!(stats, CountStats { mapped: 4, multimapped: 6, inconsistent: 0 });
+            ev
+        };
+        assert_eq!(expression(MultimapPolicy::Discard), HashMap::from([(g("G1"), 3), (g("G2"), 1)]));
+        // G1/G2 is ambiguous (both have unique counts), G2/G3 goes to G2
+        assert_eq!(expression(MultimapPolicy::Unique), HashMap::from([(g("G1"), 3), (g("G2"), 3)]));
+        // G1/G2 split 3:1, G2/G3 all to G2
+        assert_eq!(expression(MultimapPolicy::EM), HashMap::from([(g("G1"), 6), (g("G2"), 4)]));
+    }
+
+    #[test]
+    fn test_mapping_cache() {
+        let g = |name: &str| Genename(name.to_string());
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![g("G1")])),
+            (EC(1), vec2set(vec![g("G2")])),
+            (EC(2), vec2set(vec![g("G1"), g("G2")])),
+            (EC(3), vec2set(vec![g("G2"), g("G3")])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        // all molecules of 1-3 records (in any order, with repeated ECs): unique, multimapped and inconsistent ones
+        let molecules: Vec<Vec<BusRecord>> = (1..=3)
+            .flat_map(|n| (0..n).map(|_| 0..4_u32).multi_cartesian_product())
+            .map(|ecs| ecs.into_iter().map(|ec| BusRecord { CB: 0, UMI: 0, EC: ec, COUNT: 1, FLAG: 0 }).collect())
+            .collect();
+
+        for ignore_multi_ec in [false, true] {
+            let mut cache = MappingCache::default();
+            // 2nd round: all hits
+            for _ in 0..2 {
+                for records in molecules.iter() {
+                    assert_eq!(
+                        cache.map_record_list(records, &es, ignore_multi_ec),
+                        map_record_list(records, &es, ignore_multi_ec),
+                        "{:?}", records
+                    );
+                }
+            }
+            // one entry per distinct set of (up to 3) ECs, just the single ECs if multiple ECs are ignored
+            assert_eq!(cache.cache.len(), if ignore_multi_ec { 4 } else { 4 + 6 + 4 });
+        }
+    }
+
+    #[test]
+    fn test_count_flag_filter() {
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![Genename("G1".to_string())])),
+            (EC(1), vec2set(vec![Genename("G2".to_string())])),
+        ]);
+
+        // half the records are flagged: one turns CB0/UMI1 inconsistent, the other is the only record of CB2
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 2, FLAG: 1 },
+            BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 2, UMI: 1, EC: 0, COUNT: 2, FLAG: 3 },
+        ];
+        let (_bname, _dir) = setup_busfile(&records);
+        let bfolder = BusFolder::new(_dir.path().to_str().unwrap());
+        let mapping_mode = || MappingMode::Gene(Ec2GeneMapper::new(ec_dict.clone()), InconsistentResolution::IgnoreInconsistent);
+
+        let exp: HashMap<_, _> = vec![((CB(0), GeneId(0)), 1), ((CB(1), GeneId(1)), 1)]
+            .into_iter()
+            .collect();
+        let exp_cmat = countmap_to_matrix(
+            &exp,
+            vec![Genename("G1".to_string()), Genename("G2".to_string())],
+        );
+
+        let CountOutput { matrix: cmat, stats, .. } = count(&bfolder, mapping_mode(), false, &CountOptions { flag_filter: Some(0), ..Default::default() }).unwrap();
+        assert_eq!(cmat, exp_cmat);
+        assert_eq!(stats, CountStats { mapped: 2, multimapped: 0, inconsistent: 0 });
+
+        assert_eq!(crate::count2::count(&bfolder, mapping_mode(), false, Some(0), CountMode::Umis, 0), exp_cmat);
+        assert_eq!(crate::count2::count_dense(&bfolder, mapping_mode(), false, Some(0), CountMode::Umis), exp_cmat);
+
+        // without the filter, the flagged records are counted
+        let CountOutput { stats, .. } = count(&bfolder, mapping_mode(), false, &CountOptions::default()).unwrap();
+        assert_eq!(stats.inconsistent, 1);
+    }
+
+    #[test]
+    fn test_count_ec_usage() {
+        let g = |name: &str| Genename(name.to_string());
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![g("G1")])),
+            (EC(1), vec2set(vec![g("G2")])),
+            (EC(2), vec2set(vec![g("G1"), g("G2")])),
+            (EC(3), vec2set(vec![g("G2"), g("G3")])),
+        ]);
+        let records = vec![
+            // G1, via EC 0 and 2
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 2, COUNT: 2, FLAG: 0 },
+            // G1, EC 0 twice (different FLAG): counts once
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 1 },
+            // G2, via EC 1 and 3
+            BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 3, COUNT: 2, FLAG: 0 },
+            // multimapped and inconsistent: no contribution
+            BusRecord { CB: 1, UMI: 2, EC: 3, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 3, EC: 0, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 3, EC: 1, COUNT: 2, FLAG: 0 },
+        ];
+        let (_bname, _dir) = setup_busfile(&records);
+        let bfolder = BusFolder::new(_dir.path().to_str().unwrap());
+        let mapping_mode = || MappingMode::Gene(Ec2GeneMapper::new(ec_dict.clone()), InconsistentResolution::IgnoreInconsistent);
+
+        let CountOutput { matrix: cmat, stats, ec_usage } =
+            count(&bfolder, mapping_mode(), false, &CountOptions { ec_usage: true, ..Default::default() }).unwrap();
+        let ec_usage = ec_usage.unwrap();
+        assert_eq!(ec_usage, HashMap::from([(0, 2), (1, 1), (2, 1), (3, 1)]));
+        assert_eq!(stats, CountStats { mapped: 3, multimapped: 1, inconsistent: 1 });
+
+        // same matrix as without the tally
+        let CountOutput { matrix: cmat2, ec_usage: no_usage, .. } = count(&bfolder, mapping_mode(), false, &CountOptions::default()).unwrap();
+        assert_eq!(cmat, cmat2);
+        assert_eq!(no_usage, None);
+
+        let outfile = _dir.path().join("ec_usage.tsv");
+        write_ec_usage(&ec_usage, outfile.to_str().unwrap()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(outfile).unwrap(),
+            "ec\tn_molecules\n0\t2\n1\t1\n2\t1\n3\t1\n"
+        );
+    }
+
+    #[test]
+    fn test_count_empty_mapper() {
+        let es = Ec2GeneMapper::new(HashMap::new());
+        let records = vec![BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 }];
+        let (busname, _dir) = setup_busfile(&records);
+        let res = count_from_parts(&busname, es, false, &CountOptions::default());
+        assert!(matches!(res, Err(CountError::EmptyMapping { .. })));
+    }
+
+    #[test]
+    fn test_count_whitelist() {
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![Genename("G1".to_string())])),
+            (EC(1), vec2set(vec![Genename("G2".to_string())])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 2, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 2, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 },
+        ];
+        let (_bname, _dir) = setup_busfile(&records);
+        let bfolder = BusFolder::new(_dir.path().to_str().unwrap());
+
+        let mapping_mode = MappingMode::Gene(es, InconsistentResolution::IgnoreInconsistent);
+        let whitelist: HashSet<u64> = vec2set(vec![1, 2]);
+        let CountOutput { matrix: cmat, stats, .. } = count(&bfolder, mapping_mode, false, &CountOptions { cb_whitelist: Some(whitelist), ..Default::default() }).unwrap();
+
+        let exp: HashMap<_, _> = vec![((CB(1), GeneId(1)), 2), ((CB(2), GeneId(0)), 1)]
+            .into_iter()
+            .collect();
+        let exp_cmat = countmap_to_matrix(
+            &exp,
+            vec![Genename("G1".to_string()), Genename("G2".to_string())],
+        );
+        assert_eq!(cmat, exp_cmat);
+        assert_eq!(stats, CountStats { mapped: 3, multimapped: 0, inconsistent: 0 });
+    }
+
+    #[test]
+    fn test_count_split_by_flag() {
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![Genename("G1".to_string())])),
+            (EC(1), vec2set(vec![Genename("G2".to_string())])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 },
+            // same UMI, different FLAG: counted in both partitions
+            BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 1, FLAG: 1 },
+            BusRecord { CB: 0, UMI: 2, EC: 1, COUNT: 2, FLAG: 1 },
+            BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 },
+        ];
+        let (_bname, _dir) = setup_busfile(&records);
+        let bfolder = BusFolder::new(_dir.path().to_str().unwrap());
+
+        let mapping_mode = MappingMode::Gene(es, InconsistentResolution::IgnoreInconsistent);
+        let per_flag = count_split_by_flag(&bfolder, mapping_mode, false, &CountOptions::default()).unwrap();
+        assert_eq!(per_flag.keys().cloned().collect::<Vec<_>>(), vec![0, 1]);
+
+        let genes = vec![Genename("G1".to_string()), Genename("G2".to_string())];
+        let exp0: HashMap<_, _> = vec![((CB(0), GeneId(0)), 1), ((CB(1), GeneId(1)), 1)]
+            .into_iter()
+            .collect();
+        assert_eq!(per_flag[&0].matrix, countmap_to_matrix(&exp0, genes.clone()));
+        assert_eq!(per_flag[&0].stats.mapped, 2);
+
+        let exp1: HashMap<_, _> = vec![((CB(0), GeneId(1)), 2)].into_iter().collect();
+        assert_eq!(per_flag[&1].matrix, countmap_to_matrix(&exp1, genes));
+        assert_eq!(per_flag[&1].stats.mapped, 2);
+    }
+
+    #[test]
+    fn test_count_reads() {
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![Genename("G1".to_string())])),
+            (EC(1), vec2set(vec![Genename("G2".to_string())])),
+        ]);
+
+        // a single molecule with 5 reads (across two records of the same gene)
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 1 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+
+        let CountOutput { matrix: cmat, .. } = count_from_parts(&busname, Ec2GeneMapper::new(ec_dict.clone()), false, &CountOptions::default()).unwrap();
+        assert_eq!(cmat.matrix.to_dense()[[0, 0]], 1);
+
+        let CountOutput { matrix: cmat, .. } = count_from_parts(&busname, Ec2GeneMapper::new(ec_dict.clone()), false, &CountOptions { count_mode: CountMode::Reads, ..Default::default() }).unwrap();
+        assert_eq!(cmat.matrix.to_dense()[[0, 0]], 5);
+
+        // each record is a single read
+        let CountOutput { matrix: cmat, .. } = count_from_parts(&busname, Ec2GeneMapper::new(ec_dict), false, &CountOptions { count_mode: CountMode::CollapsedReads, ..Default::default() }).unwrap();
+        assert_eq!(cmat.matrix.to_dense()[[0, 0]], 2);
+    }
+
+    #[test]
+    fn test_count_multi() {
+        // same ECs in both folders
+        let setup_folder = |records: &Vec<BusRecord>| {
+            let (_bname, dir) = setup_busfile(records);
+            std::fs::write(dir.path().join("matrix.ec"), "0\t0\n1\t1\n").unwrap();
+            std::fs::write(dir.path().join("transcripts.txt"), "T1\nT2\n").unwrap();
+            std::fs::write(dir.path().join("t2g.txt"), "T1\tG1\tgene1\nT2\tG2\tgene2\n").unwrap();
+            dir
+        };
+        // CB 0 is in both folders
+        let r1 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 };
+        let r2 = BusRecord { CB: 0, UMI: 2, EC: 1, COUNT: 1, FLAG: 0 };
+        let r3 = BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 3, FLAG: 0 };
+
+        let dir1 = setup_folder(&vec![r1.clone()]);
+        let dir2 = setup_folder(&vec![r2.clone(), r3.clone()]);
+        let dir_all = setup_folder(&vec![r1, r2, r3]);
+        let t2g = dir1.path().join("t2g.txt");
+        let t2g = t2g.to_str().unwrap();
+
+        let folders = vec![
+            BusFolder::new(dir1.path().to_str().unwrap()),
+            BusFolder::new(dir2.path().to_str().unwrap()),
+        ];
+        let CountOutput { matrix: cmat, stats, .. } = count_multi(&folders, t2g, false, &CountOptions::default()).unwrap();
+        assert_eq!(stats.mapped, 3);
+        assert_eq!(cmat.get_shape(), (2, 2));
+
+        // same as counting all records in one folder
+        let CountOutput { matrix: expected, .. } = count_multi(&[BusFolder::new(dir_all.path().to_str().unwrap())], t2g, false, &CountOptions::default()).unwrap();
+        assert_eq!(cmat, expected);
     }
 }
@@ -1,13 +1,17 @@
 //! This turns a busfolder into a count matrix, slightly different strategy than [crate::count]. Not sure which is fsater
-use crate::count::map_record_list;
+use crate::count::{filter_flag, map_record_list, CountMode};
 use crate::countmatrix::CountMatrix;
 use bustools::consistent_genes::{
-    GeneId, Genename, MappingResult, CB, MappingMode,
+    Ec2GeneMapper, GeneId, Genename, MappingResult, CB, EC, MappingMode,
 };
 use bustools::io::{BusFolder, BusRecord};
 use bustools::iterators::CbUmiGroupIterator;
 use crate::multinomial::multinomial_sample;
-use bustools::utils::{get_progressbar, int_to_seq};
+use crate::util::{get_progressbar, read_busfile};
+use itertools::Itertools;
+use bustools::utils::int_to_seq;
+use ndarray::Array2;
+use probability::source::{self, Xorshift128Plus};
 use sprs::DenseVector;
 use std::collections::{BTreeSet, HashMap};
 use std::time::Instant;
@@ -15,6 +19,10 @@ use std::time::Instant;
 /// Slightly different strategy as count.rs:
 /// 1. iterate over CB/UMI, turn into (possibly) count for a gene (if not multimapped) via count_from_record_list()
 /// 2. this creates  HashMap<(CB, GeneId), usize> directly, to be turned into a sparse CountMatrix
+///
+/// `gene_vector[i]` is the name of `GeneId(i)`.
+/// Just like [crate::count::count], rows are sorted by CB and columns by Genename,
+/// irrespective of the order of `gene_vector`.
 pub fn countmap_to_matrix(
     countmap: &HashMap<(CB, GeneId), usize>,
     gene_vector: Vec<Genename>,
@@ -38,6 +46,8 @@ pub fn countmap_to_matrix(
         .map(|(ix, cb)| (**cb, ix))
         .collect::<HashMap<_, _>>();
 
+    let (gene_ix, gene_seq) = gene_columns(&gene_vector);
+
     // sparse matrix indices
     let mut ii: Vec<usize> = Vec::new();
     let mut jj: Vec<usize> = Vec::new();
@@ -45,7 +55,7 @@ pub fn countmap_to_matrix(
 
     for ((cb, geneid), counter) in countmap {
         let cbi = cb_ix.get(cb).unwrap();
-        let genei = geneid.0 as usize;
+        let genei = gene_ix[geneid.0 as usize];
         ii.push(*cbi);
         jj.push(genei);
         vv.push(*counter as i32);
@@ -57,14 +67,57 @@ pub fn countmap_to_matrix(
     let b: sprs::CsMat<_> = c.to_csr();
 
     let cbs_seq: Vec<String> = all_cbs.into_iter().map(|x| int_to_seq(x.0, 16)).collect();
-    // let gene_seq: Vec<String> = gene_vector.into_iter().map(|x|x.clone()).collect();
-    let gene_seq: Vec<String> = gene_vector.into_iter().map(|x| x.0).collect(); //not sure if this does anything
 
     CountMatrix::new(b, cbs_seq, gene_seq)
 }
 
-#[allow(dead_code)]
-fn baysian_count(bfolder: BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bool, n_samples: usize) {
+/// Columns sorted by genename: the column of each GeneId (`gene_ix[geneid]`) and the genenames in column order
+fn gene_columns(gene_vector: &[Genename]) -> (Vec<usize>, Vec<String>) {
+    let mut gene_order: Vec<usize> = (0..gene_vector.len()).collect();
+    gene_order.sort_by(|&a, &b| gene_vector[a].cmp(&gene_vector[b]));
+    let mut gene_ix = vec![0; gene_vector.len()];
+    for (col, &geneid) in gene_order.iter().enumerate() {
+        gene_ix[geneid] = col;
+    }
+    let gene_seq = gene_order.into_iter().map(|i| gene_vector[i].0.clone()).collect();
+    (gene_ix, gene_seq)
+}
+
+/// Fractional counts of [count_em]: just like a [CountMatrix], but with `f32` entries
+#[derive(Debug)]
+pub struct EmCountMatrix {
+    /// cells-by-genes
+    pub matrix: sprs::CsMat<f32>,
+    /// the rows' CBs
+    pub cbs: Vec<String>,
+    /// the columns' genes
+    pub genes: Vec<String>,
+}
+
+/// Bayesian count: sample count matrices from the posterior of the read counts
+///
+/// The reads are resampled (Multinomial, with probabilities proportional to each record's COUNT),
+/// and the resampled busfile is counted just like [count]. Records whose resampled COUNT is zero are dropped.
+/// Repeating this `n_samples` times yields an ensemble of count matrices, reflecting the uncertainty due to sequencing depth.
+///
+/// # Parameters
+/// * `bfolder`: the busfolder to count
+/// * `mapping_mode`: how to map records to genes, see [count]
+/// * `ignore_multi_ec`: see [crate::count::count]
+/// * `n_samples`: number of posterior samples (count matrices)
+/// * `seed`: seed of the random number generator; identical seeds yield identical samples
+pub fn count_bayesian(bfolder: &BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bool, n_samples: usize, seed: u64) -> Vec<CountMatrix> {
+    count_bayesian_with_source(bfolder, mapping_mode, ignore_multi_ec, n_samples, &mut source::default(seed))
+}
+
+/// Same as [count_bayesian], drawing from the given random source instead of seeding one
+pub fn count_bayesian_with_source(
+    bfolder: &BusFolder,
+    mapping_mode: MappingMode,
+    ignore_multi_ec: bool,
+    n_samples: usize,
+    random_source: &mut Xorshift128Plus,
+) -> Vec<CountMatrix> {
     let bfile = bfolder.get_busfile();
     println!("{}", bfile);
 
@@ -93,7 +146,7 @@ fn baysian_count(bfolder: BusFolder, mapping_mode: MappingMode, ignore_multi_ec:
 
     // prep for the multinomial sample
     println!("Preparing the probability vector for mutlinomial");
-    let cbumi_iter_tmp = bfolder.get_iterator().groupby_cbumi();
+    let cbumi_iter_tmp = read_busfile(&bfile).groupby_cbumi();
 
     let count_vec: Vec<_> = cbumi_iter_tmp
         .flat_map(|(_cbumi, rlist)| rlist.into_iter().map(|r| r.COUNT as f64))
@@ -103,10 +156,8 @@ fn baysian_count(bfolder: BusFolder, mapping_mode: MappingMode, ignore_multi_ec:
     let p_vec: Vec<f64> = count_vec.into_iter().map(|c| c / total_counts).collect();
     println!("Done: {} rercods, {} counts", p_vec.len(), total_counts);
 
-    use probability::prelude::*;
-    let mut random_source = source::default(42);
-
     let mut counter = 0;
+    let mut samples = Vec::with_capacity(n_samples);
     for i in 0..n_samples {
         // CB,gene_id -> count
         let mut all_expression_vector: HashMap<(CB, GeneId), usize> = HashMap::new();
@@ -115,10 +166,10 @@ fn baysian_count(bfolder: BusFolder, mapping_mode: MappingMode, ignore_multi_ec:
 
         // subsample the count vector
         println!("Iteration {}: Mutlinomial sample", i);
-        let new_count_sample = multinomial_sample(total_counts as u64, &p_vec, &mut random_source);
+        let new_count_sample = multinomial_sample(total_counts as u64, &p_vec, random_source);
         println!("Done");
 
-        let cbumi_iter = bfolder.get_iterator().groupby_cbumi();
+        let cbumi_iter = read_busfile(&bfile).groupby_cbumi();
 
         let now = Instant::now();
         let bar = get_progressbar(total_records as u64);
@@ -193,19 +244,28 @@ fn baysian_count(bfolder: BusFolder, mapping_mode: MappingMode, ignore_multi_ec:
 
         let countmatrix = countmap_to_matrix(&all_expression_vector, genelist_vector);
         println!("{}", countmatrix);
-        println!("finished iteration {}", i)
+        println!("finished iteration {}", i);
+        samples.push(countmatrix);
     }
+    samples
 }
 
 /// count the busfile in the given folder, see [crate::count::count]
-pub fn count(bfolder: &BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bool) -> CountMatrix {
+///
+/// `flag_filter`: if set, only count records whose FLAG equals this value
+///
+/// `count_mode`: count molecules (UMIs) or reads per gene
+///
+/// `debug_samples`: print (up to) this many of the first inconsistent CB/UMIs, with the genes of each of their ECs,
+/// to diagnose why molecules get discarded (e.g. a t2g not matching the index). `0` turns this off.
+pub fn count(bfolder: &BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bool, flag_filter: Option<u32>, count_mode: CountMode, debug_samples: usize) -> CountMatrix {
     /*
     busfile to count matrix, analogous to "bustools count"
     */
     let bfile = bfolder.get_busfile();
     println!("{}", bfile);
 
-    let cbumi_iter = bfolder.get_iterator().groupby_cbumi();
+    let cbumi_iter = read_busfile(&bfile).groupby_cbumi();
 
     println!("determine size of iterator");
     let now = Instant::now();
@@ -228,20 +288,31 @@ pub fn count(bfolder: &BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bo
 
     let mut n_mapped = 0;
     let mut n_multi_inconsistent = 0;
+    let mut inconsistent_examples: Vec<(u64, u64, Vec<BusRecord>)> = Vec::with_capacity(debug_samples);
 
     let now = Instant::now();
 
-    for (counter, ((cb, _umi), record_list)) in cbumi_iter.enumerate() {
+    for (counter, ((cb, umi), mut record_list)) in cbumi_iter.enumerate() {
+        filter_flag(&mut record_list, flag_filter);
+        if record_list.is_empty() {
+            continue;
+        }
         // try to map the records of this CB/UMI into a single gene
         // if let Some(g) = count_from_record_list(&record_list, &bfolder.ec2gene, ignore_multi_ec)
         match map_record_list(&record_list, &ecmapper, ignore_multi_ec) {
             MappingResult::SingleGene(g) => {
                 let key = (CB(cb), g);
                 let current_count = all_expression_vector.entry(key).or_insert(0);
-                *current_count += 1;
+                *current_count += count_mode.molecule_value(&record_list) as usize;
                 n_mapped += 1;
             }
-            MappingResult::Multimapped(_) | MappingResult::Inconsistent => {
+            MappingResult::Inconsistent => {
+                n_multi_inconsistent += 1;
+                if inconsistent_examples.len() < debug_samples {
+                    inconsistent_examples.push((cb, umi, record_list));
+                }
+            }
+            MappingResult::Multimapped(_) => {
                 // multimapped, or not consistently mapped
                 n_multi_inconsistent += 1;
                 // let cbstr = int_to_seq(cb, 16);
@@ -263,6 +334,10 @@ pub fn count(bfolder: &BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bo
         "Mapped {}, multi-discard {} in {:?}",
         n_mapped, n_multi_inconsistent, elapsed_time
     );
+    if !inconsistent_examples.is_empty() {
+        let params = bfolder.get_iterator().get_params().clone();
+        print_inconsistent_examples(&inconsistent_examples, &ecmapper, params.cb_len as usize, params.umi_len as usize);
+    }
 
     let genelist_vector: Vec<Genename> = ecmapper.get_gene_list();
 
@@ -275,3 +350,317 @@ pub fn count(bfolder: &BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bo
 
     countmatrix
 }
+
+/// Same as [count], but accumulating into a dense cells x genes matrix instead of a `(CB, GeneId)`-HashMap.
+///
+/// Less overhead for targeted panels with a few hundred genes, but memory grows with cells * genes:
+/// only use it for small panels on filtered busfiles (few cells), never on unfiltered ones with millions of CBs
+pub fn count_dense(bfolder: &BusFolder, mapping_mode: MappingMode, ignore_multi_ec: bool, flag_filter: Option<u32>, count_mode: CountMode) -> CountMatrix {
+    let ecmapper = match mapping_mode {
+        MappingMode::Gene(ecmapper, _inconstsistent_mode) => ecmapper,
+        _ => panic!("not implemented"),
+    };
+    let genelist_vector: Vec<Genename> = ecmapper.get_gene_list();
+    let ngenes = genelist_vector.len();
+    let zero_row = vec![0_i32; ngenes];
+
+    // rows in order of appearance of the CBs
+    let mut counts: Array2<i32> = Array2::zeros((0, ngenes));
+    let mut cb_rows: HashMap<u64, usize> = HashMap::new();
+    let mut n_mapped = 0;
+    let mut n_multi_inconsistent = 0;
+
+    let bar = get_progressbar(bfolder.get_cbumi_size() as u64);
+    for (counter, ((cb, _umi), mut record_list)) in read_busfile(&bfolder.get_busfile()).groupby_cbumi().enumerate() {
+        filter_flag(&mut record_list, flag_filter);
+        if record_list.is_empty() {
+            continue;
+        }
+        match map_record_list(&record_list, &ecmapper, ignore_multi_ec) {
+            MappingResult::SingleGene(g) => {
+                let row = *cb_rows.entry(cb).or_insert_with(|| {
+                    counts.push_row(zero_row.as_slice().into()).unwrap();
+                    counts.nrows() - 1
+                });
+                counts[[row, g.0 as usize]] += count_mode.molecule_value(&record_list) as i32;
+                n_mapped += 1;
+            }
+            MappingResult::Multimapped(_) | MappingResult::Inconsistent => n_multi_inconsistent += 1,
+        }
+        if counter % 1_000_000 == 0 {
+            bar.inc(1_000_000);
+        }
+    }
+    println!("Mapped {}, multi-discard {}", n_mapped, n_multi_inconsistent);
+
+    // the non-zero entries, sorted into a CountMatrix just like count()
+    let countmap: HashMap<(CB, GeneId), usize> = cb_rows
+        .iter()
+        .flat_map(|(&cb, &row)| {
+            counts
+                .row(row)
+                .indexed_iter()
+                .filter(|(_gene, &c)| c > 0)
+                .map(|(gene, &c)| ((CB(cb), GeneId(gene as u32)), c as usize))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    countmap_to_matrix(&countmap, genelist_vector)
+}
+
+/// print inconsistent CB/UMIs, and the genes each of their records' EC maps to
+fn print_inconsistent_examples(examples: &[(u64, u64, Vec<BusRecord>)], ecmapper: &Ec2GeneMapper, cb_len: usize, umi_len: usize) {
+    println!("First {} inconsistent CB/UMIs:", examples.len());
+    for (cb, umi, records) in examples {
+        println!("{}/{}", int_to_seq(*cb, cb_len), int_to_seq(*umi, umi_len));
+        for r in records {
+            let genes = ecmapper.get_genenames(EC(r.EC)).into_iter().map(|g| g.0).sorted().join(",");
+            println!("\tEC {} (COUNT {}): {}", r.EC, r.COUNT, genes);
+        }
+    }
+}
+
+/// Count the busfile, redistributing multimapped CB/UMIs via Expectation-Maximization
+///
+/// Instead of discarding multimapped molecules (see [count]), each multimapped CB/UMI is split
+/// across its candidate genes, proportional to the current abundance estimates of those genes in the same cell:
+/// 1. initialize the per-cell abundances with the uniquely mapped counts
+/// 2. E-step: assign each multimapped molecule fractionally, proportional to the abundances of its candidate genes
+///    (equal split if none of the candidates has any abundance yet)
+/// 3. M-step: the new abundances are unique counts + fractional assignments
+///
+/// Inconsistent CB/UMIs are discarded.
+///
+/// # Parameters
+/// * `bfolder`: the busfolder to count
+/// * `mapper`: EC to gene mapping
+/// * `n_iter`: number of EM iterations
+///
+/// # Returns
+/// The fractional cells-by-genes counts, ordered just like [countmap_to_matrix]:
+/// rows sorted by CB, columns sorted by Genename
+pub fn count_em(bfolder: &BusFolder, mapper: &Ec2GeneMapper, n_iter: usize) -> EmCountMatrix {
+    // uniquely mapped counts per cell, and the candidate genes of each multimapped molecule per cell
+    let mut unique_counts: HashMap<CB, HashMap<GeneId, f32>> = HashMap::new();
+    let mut multimapped: HashMap<CB, Vec<(Vec<GeneId>, f32)>> = HashMap::new();
+
+    for ((cb, _umi), record_list) in read_busfile(&bfolder.get_busfile()).groupby_cbumi() {
+        match map_record_list(&record_list, mapper, false) {
+            MappingResult::SingleGene(g) => {
+                let c = unique_counts.entry(CB(cb)).or_default().entry(g).or_insert(0.0);
+                *c += 1.0;
+            }
+            MappingResult::Multimapped(genes) => {
+                let mut genes: Vec<GeneId> = genes.into_iter().collect();
+                genes.sort();
+                multimapped.entry(CB(cb)).or_default().push((genes, 1.0));
+            }
+            MappingResult::Inconsistent => {}
+        }
+    }
+
+    let all_cbs: BTreeSet<CB> = unique_counts.keys().chain(multimapped.keys()).copied().collect();
+    let (gene_ix, genes) = gene_columns(&mapper.get_gene_list());
+    let no_multimapped = Vec::new();
+    let no_unique = HashMap::new();
+
+    let mut ii: Vec<usize> = Vec::new();
+    let mut jj: Vec<usize> = Vec::new();
+    let mut vv: Vec<f32> = Vec::new();
+    for (i, cb) in all_cbs.iter().enumerate() {
+        let unique = unique_counts.get(cb).unwrap_or(&no_unique);
+        let multi = multimapped.get(cb).unwrap_or(&no_multimapped);
+        let abundance = em_single_cell(unique, multi, n_iter);
+        for (gene, v) in abundance {
+            ii.push(i);
+            jj.push(gene_ix[gene.0 as usize]);
+            vv.push(v);
+        }
+    }
+    let c: sprs::TriMat<f32> = sprs::TriMat::from_triplets((all_cbs.len(), genes.len()), ii, jj, vv);
+    let cbs = all_cbs.into_iter().map(|cb| int_to_seq(cb.0, 16)).collect();
+    EmCountMatrix { matrix: c.to_csr(), cbs, genes }
+}
+
+/// EM for the molecules of a single cell: `unique` counts are fixed,
+/// each entry in `multimapped` (the candidate genes of a molecule and its weight, e.g. 1 per UMI)
+/// gets distributed according to the current abundances
+pub(crate) fn em_single_cell(unique: &HashMap<GeneId, f32>, multimapped: &[(Vec<GeneId>, f32)], n_iter: usize) -> HashMap<GeneId, f32> {
+    let mut abundance = unique.clone();
+    for _ in 0..n_iter {
+        let mut new_abundance = unique.clone();
+        for (genes, weight) in multimapped {
+            let total: f32 = genes.iter().map(|g| abundance.get(g).unwrap_or(&0.0)).sum();
+            for g in genes {
+                let frac = if total > 0.0 {
+                    abundance.get(g).unwrap_or(&0.0) / total
+                } else {
+                    1.0 / genes.len() as f32
+                };
+                *new_abundance.entry(*g).or_insert(0.0) += frac * weight;
+            }
+        }
+        abundance = new_abundance;
+    }
+    abundance.retain(|_g, v| *v > 0.0);
+    abundance
+}
+
+#[cfg(test)]
+mod test {
+    use super::{count_bayesian, count_bayesian_with_source, count_dense, count_em, countmap_to_matrix};
+    use crate::count::{self, CountMode, CountOptions, CountOutput};
+    use bustools::{
+        consistent_genes::{Ec2GeneMapper, GeneId, Genename, InconsistentResolution, MappingMode, CB, EC},
+        io::{setup_busfile, BusFolder, BusRecord},
+        utils::vec2set,
+    };
+    use probability::source;
+    use statrs::assert_almost_eq;
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn test_count_em() {
+        let ec0: HashSet<Genename> = vec2set(vec![Genename("G1".to_string())]);
+        let ec1: HashSet<Genename> = vec2set(vec![Genename("G2".to_string())]);
+        let ec2: HashSet<Genename> =
+            vec2set(vec![Genename("G1".to_string()), Genename("G2".to_string())]);
+        let ec_dict: HashMap<EC, HashSet<Genename>> =
+            HashMap::from([(EC(0), ec0), (EC(1), ec1), (EC(2), ec2)]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        // Cell 0: G1 unique x3, G2 unique x1, two molecules ambigous between G1/G2
+        // EM fixpoint: G1 = 3 + 2 * G1/6 = 4.5, G2 = 1 + 2 * G2/6 = 1.5
+        // Cell 1: only a single ambigous molecule, no prior information -> equal split
+        let records = vec![
+            BusRecord { CB: 0, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 3, EC: 1, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 4, EC: 2, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 5, EC: 2, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 0, EC: 2, COUNT: 1, FLAG: 0 },
+        ];
+        let (_bname, _dir) = setup_busfile(&records);
+        let bfolder = BusFolder::new(_dir.path().to_str().unwrap());
+
+        let em = count_em(&bfolder, &es, 20);
+        // labeled/ordered just like countmap_to_matrix, irrespective of the mapper's GeneIds
+        assert_eq!(em.cbs, vec!["AAAAAAAAAAAAAAAA".to_string(), "AAAAAAAAAAAAAAAC".to_string()]);
+        assert_eq!(em.genes, vec!["G1".to_string(), "G2".to_string()]);
+
+        let cmat = em.matrix.to_dense();
+        assert_eq!(cmat.shape(), &[2, 2]);
+        assert_almost_eq!(cmat[[0, 0]] as f64, 4.5, 1e-5);
+        assert_almost_eq!(cmat[[0, 1]] as f64, 1.5, 1e-5);
+        assert_almost_eq!(cmat[[1, 0]] as f64, 0.5, 1e-5);
+        assert_almost_eq!(cmat[[1, 1]] as f64, 0.5, 1e-5);
+    }
+
+    #[test]
+    fn test_count_bayesian_seed() {
+        let ec0: HashSet<Genename> = vec2set(vec![Genename("G1".to_string())]);
+        let ec1: HashSet<Genename> = vec2set(vec![Genename("G2".to_string())]);
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([(EC(0), ec0), (EC(1), ec1)]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        let records: Vec<BusRecord> = (0..50)
+            .map(|i| BusRecord { CB: i / 10, UMI: i, EC: (i % 2) as u32, COUNT: 1 + (i % 3) as u32, FLAG: 0 })
+            .collect();
+        let (_bname, _dir) = setup_busfile(&records);
+        let bfolder = BusFolder::new(_dir.path().to_str().unwrap());
+
+        let mapping_mode = MappingMode::Gene(es.clone(), InconsistentResolution::IgnoreInconsistent);
+        let s1 = count_bayesian(&bfolder, mapping_mode, false, 2, 42);
+        let mapping_mode = MappingMode::Gene(es.clone(), InconsistentResolution::IgnoreInconsistent);
+        let s2 = count_bayesian(&bfolder, mapping_mode, false, 2, 42);
+
+        assert_eq!(s1.len(), 2);
+        assert_eq!(s1, s2);
+
+        let mapping_mode = MappingMode::Gene(es.clone(), InconsistentResolution::IgnoreInconsistent);
+        let s3 = count_bayesian(&bfolder, mapping_mode, false, 2, 43);
+        assert_ne!(s1, s3);
+
+        let mapping_mode = MappingMode::Gene(es, InconsistentResolution::IgnoreInconsistent);
+        let s4 = count_bayesian_with_source(&bfolder, mapping_mode, false, 2, &mut source::default(42));
+        assert_eq!(s1, s4);
+    }
+
+    #[test]
+    fn test_countmap_to_matrix_gene_order() {
+        // GeneId(0) = G2, GeneId(1) = G1: columns should still come out sorted by name
+        let countmap: HashMap<_, _> = vec![((CB(0), GeneId(0)), 5), ((CB(0), GeneId(1)), 1)]
+            .into_iter()
+            .collect();
+        let cmat = countmap_to_matrix(
+            &countmap,
+            vec![Genename("G2".to_string()), Genename("G1".to_string())],
+        );
+        assert_eq!(
+            cmat.gene_totals(),
+            vec![("G1".to_string(), 1), ("G2".to_string(), 5)]
+        );
+    }
+
+    #[test]
+    fn test_count_count2_same_genes() {
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![Genename("GeneB".to_string())])),
+            (EC(1), vec2set(vec![Genename("GeneA".to_string())])),
+            (EC(2), vec2set(vec![Genename("GeneC".to_string())])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        let records = vec![
+            BusRecord { CB: 0, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 2, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 0, EC: 1, COUNT: 1, FLAG: 0 },
+        ];
+        let (_bname, _dir) = setup_busfile(&records);
+        let bfolder = BusFolder::new(_dir.path().to_str().unwrap());
+
+        let mapping_mode = MappingMode::Gene(es.clone(), InconsistentResolution::IgnoreInconsistent);
+        let CountOutput { matrix: c1, .. } = count::count(&bfolder, mapping_mode, false, &CountOptions::default()).unwrap();
+        let mapping_mode = MappingMode::Gene(es, InconsistentResolution::IgnoreInconsistent);
+        let c2 = super::count(&bfolder, mapping_mode, false, None, CountMode::Umis, 0);
+
+        let out1 = _dir.path().join("count");
+        let out2 = _dir.path().join("count2");
+        std::fs::create_dir(&out1).unwrap();
+        std::fs::create_dir(&out2).unwrap();
+        c1.write(out1.to_str().unwrap()).unwrap();
+        c2.write(out2.to_str().unwrap()).unwrap();
+
+        let genes1 = std::fs::read(out1.join("gene.genes.txt")).unwrap();
+        let genes2 = std::fs::read(out2.join("gene.genes.txt")).unwrap();
+        assert_eq!(genes1, genes2);
+        assert_eq!(String::from_utf8(genes1).unwrap(), "GeneA\nGeneB\nGeneC\n");
+    }
+
+    #[test]
+    fn test_count_dense() {
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), vec2set(vec![Genename("G2".to_string())])),
+            (EC(1), vec2set(vec![Genename("G1".to_string())])),
+            (EC(2), vec2set(vec![Genename("G1".to_string()), Genename("G2".to_string())])),
+            (EC(3), vec2set(vec![Genename("G3".to_string())])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        let records: Vec<BusRecord> = (0..200)
+            .map(|i| BusRecord { CB: i / 20, UMI: i / 2, EC: (i % 4) as u32, COUNT: 1 + (i % 3) as u32, FLAG: 0 })
+            .collect();
+        let (_bname, _dir) = setup_busfile(&records);
+        let bfolder = BusFolder::new(_dir.path().to_str().unwrap());
+
+        for count_mode in [CountMode::Umis, CountMode::Reads] {
+            let mapping_mode = MappingMode::Gene(es.clone(), InconsistentResolution::IgnoreInconsistent);
+            let sparse = super::count(&bfolder, mapping_mode, false, None, count_mode, 0);
+            let mapping_mode = MappingMode::Gene(es.clone(), InconsistentResolution::IgnoreInconsistent);
+            let dense = count_dense(&bfolder, mapping_mode, false, None, count_mode);
+            assert_eq!(dense, sparse);
+            assert_eq!(dense.gene_totals(), sparse.gene_totals());
+        }
+    }
+}
@@ -5,15 +5,15 @@
 //! ## Example
 //! ```rust, no_run
 //! // load from disk
-//! # use bustools::countmatrix::CountMatrix;
+//! # use bustools_cli::countmatrix::CountMatrix;
 //! let path = "/path/to/bus/count/folder";
 //! let cmat = CountMatrix::from_disk(
 //!     &format!("{}/gene.mtx", path),
 //!     &format!("{}/gene.barcodes.txt", path),
 //!     &format!("{}/gene.genes.txt", path),
-//!  );
+//!  ).unwrap();
 //!  // shorter, assuming standard file names
-//!  let cmat = CountMatrix::from_folder(path);
+//!  let cmat = CountMatrix::from_folder(path, None).unwrap();
 //!
 //! // write to disk again
 //! // note that the folder must exist already
@@ -21,19 +21,217 @@
 //! if !std::path::Path::new(&outpath).exists() {
 //!     std::fs::create_dir(outpath).unwrap();
 //! }
-//! cmat.write(outpath);
+//! cmat.write(outpath).unwrap();
 //! ```
+use itertools::Itertools;
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     fmt,
     fs::File,
-    io::{BufRead, BufReader, Write},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
 };
 
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use serde::Serialize;
 use sprs::{
-    io::{read_matrix_market, write_matrix_market}, TriMat
+    io::{read_matrix_market_from_bufread, write_matrix_market_to_bufwrite}, TriMat
 };
 
+/// Largest number of entries (cells * genes) [CountMatrix::to_csv] writes without `force`
+pub const MAX_DENSE_ENTRIES: usize = 1_000_000;
+
+/// On-disk format of a [CountMatrix], see [CountMatrix::write_format]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum MatrixFormat {
+    /// `<prefix>.mtx` (MatrixMarket) + barcode/gene files, just like kallisto
+    #[default]
+    MatrixMarket,
+    /// a single dense `<prefix>.csv`, see [CountMatrix::to_csv]. Only for small matrices
+    Csv,
+    /// `<prefix>.coo.tsv` with a `cb_index gene_index count` line per non-zero entry (1-based, as in MatrixMarket)
+    /// + barcode/gene files
+    Coo,
+}
+
+/// Order in which the entries of a MatrixMarket file are written, see [CountMatrix::write_with_layout]
+///
+/// MatrixMarket itself is layout-agnostic (any order reads back into the same matrix),
+/// but loaders that build a compressed sparse matrix straight from the file are faster if the order matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Layout {
+    /// row-major, i.e. cell by cell
+    #[default]
+    Csr,
+    /// column-major, i.e. gene by gene
+    Csc,
+}
+
+/// Summary statistics of a [CountMatrix], see [CountMatrix::summary]
+///
+/// For a matrix without cells, density/mean/median are NaN.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MatrixSummary {
+    /// number of cells (rows)
+    pub ncells: usize,
+    /// number of genes (columns)
+    pub ngenes: usize,
+    /// number of non-zero entries
+    pub nnz: usize,
+    /// fraction of non-zero entries: nnz / (cells * genes)
+    pub density: f64,
+    /// mean total count per cell
+    pub mean_counts_per_cell: f64,
+    /// median number of detected genes (non-zero entries) per cell
+    pub median_genes_per_cell: f64,
+}
+
+/// Differences between two [CountMatrix]es, see [CountMatrix::diff]. All lists are sorted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatrixDiff {
+    /// barcodes only present in the first matrix
+    pub barcodes_only_a: Vec<String>,
+    /// barcodes only present in the second matrix
+    pub barcodes_only_b: Vec<String>,
+    /// genes only present in the first matrix
+    pub genes_only_a: Vec<String>,
+    /// genes only present in the second matrix
+    pub genes_only_b: Vec<String>,
+    /// `(cb, gene, a_count, b_count)` of the shared barcodes/genes with different counts (missing entries count as 0)
+    pub mismatches: Vec<(String, String, i32, i32)>,
+}
+
+impl MatrixDiff {
+    /// true if both matrices have the same barcodes, genes and counts
+    pub fn is_identical(&self) -> bool {
+        self.barcodes_only_a.is_empty()
+            && self.barcodes_only_b.is_empty()
+            && self.genes_only_a.is_empty()
+            && self.genes_only_b.is_empty()
+            && self.mismatches.is_empty()
+    }
+}
+
+/// Errors when reading/writing a [CountMatrix] from/to disk
+#[derive(Debug)]
+pub enum CountMatrixError {
+    /// a file couldn't be opened/read/written
+    Io {
+        /// the offending file
+        file: String,
+        /// the underlying error
+        source: std::io::Error,
+    },
+    /// the mtx file is malformed
+    Parse {
+        /// the offending file
+        file: String,
+        /// what went wrong
+        message: String,
+    },
+    /// the number of barcodes/genes doesn't match the matrix dimensions
+    DimensionMismatch {
+        /// the barcode/gene file
+        file: String,
+        /// number of rows/columns of the matrix
+        expected: usize,
+        /// number of entries in the file
+        found: usize,
+    },
+    /// the matrix has more than [MAX_DENSE_ENTRIES] entries to be written densely, see [check_dense_size]
+    TooLarge {
+        /// number of cells (rows)
+        ncells: usize,
+        /// number of genes (columns)
+        ngenes: usize,
+    },
+}
+
+impl fmt::Display for CountMatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CountMatrixError::Io { file, source } => write!(f, "cant access {}: {}", file, source),
+            CountMatrixError::Parse { file, message } => write!(f, "cant parse {}: {}", file, message),
+            CountMatrixError::DimensionMismatch { file, expected, found } => write!(
+                f,
+                "{} has {} entries, but the matrix has {}",
+                file, found, expected
+            ),
+            CountMatrixError::TooLarge { ncells, ngenes } => write!(
+                f,
+                "{}x{} matrix too large for a dense csv (more than {} entries)",
+                ncells, ngenes, MAX_DENSE_ENTRIES
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CountMatrixError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CountMatrixError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Check that a `ncells` x `ngenes` matrix is small enough to be written as a dense csv (see [CountMatrix::to_csv])
+pub fn check_dense_size(ncells: usize, ngenes: usize) -> Result<(), CountMatrixError> {
+    if ncells.saturating_mul(ngenes) > MAX_DENSE_ENTRIES {
+        return Err(CountMatrixError::TooLarge { ncells, ngenes });
+    }
+    Ok(())
+}
+
+/// open a file for reading, decompressing it on the fly if it ends in `.gz`
+fn open_maybe_gz(fname: &str) -> Result<Box<dyn BufRead>, CountMatrixError> {
+    let fh = File::open(fname).map_err(|source| CountMatrixError::Io { file: fname.to_string(), source })?;
+    if fname.ends_with(".gz") {
+        Ok(Box::new(BufReader::new(GzDecoder::new(fh))))
+    } else {
+        Ok(Box::new(BufReader::new(fh)))
+    }
+}
+
+/// create `fname` and write its content via `write`, gzipped if `fname` ends in `.gz`
+fn write_maybe_gz<F>(fname: &str, write: F) -> Result<(), CountMatrixError>
+where
+    F: FnOnce(&mut dyn Write) -> std::io::Result<()>,
+{
+    let io_err = |source| CountMatrixError::Io { file: fname.to_string(), source };
+    let fh = File::create(fname).map_err(io_err)?;
+    if fname.ends_with(".gz") {
+        let mut w = BufWriter::new(GzEncoder::new(fh, Compression::default()));
+        write(&mut w).map_err(io_err)?;
+        // finish() writes the gzip trailer
+        w.into_inner().map_err(|e| io_err(e.into_error()))?.finish().map_err(io_err)?;
+        Ok(())
+    } else {
+        let mut w = BufWriter::new(fh);
+        write(&mut w).map_err(io_err)?;
+        w.flush().map_err(io_err)
+    }
+}
+
+/// read a file line by line (barcodes, genes), optionally gzipped
+fn read_lines(fname: &str) -> Result<Vec<String>, CountMatrixError> {
+    open_maybe_gz(fname)?
+        .lines()
+        .collect::<Result<_, _>>()
+        .map_err(|source| CountMatrixError::Io { file: fname.to_string(), source })
+}
+
+/// write one entry per line (barcodes, genes), gzipped if `fname` ends in `.gz`
+fn write_lines(fname: &str, entries: &[String]) -> Result<(), CountMatrixError> {
+    write_maybe_gz(fname, |fh| {
+        for e in entries {
+            writeln!(fh, "{}", e)?;
+        }
+        Ok(())
+    })
+}
+
 /// Countmatrix, cells-by-genes
 ///
 /// Cells and genes are indexed via their string reprensentation
@@ -67,14 +265,299 @@ impl CountMatrix {
         self.matrix.shape()
     }
 
+    /// total counts per cell (row sums), in the same order as the matrix rows
+    pub fn cell_totals(&self) -> Vec<(String, i32)> {
+        let mut totals = vec![0; self.cbs.len()];
+        for (&v, (i, _j)) in self.matrix.iter() {
+            totals[i] += v;
+        }
+        self.cbs.iter().cloned().zip(totals).collect()
+    }
+
+    /// total counts per gene (column sums), in the same order as the matrix columns
+    pub fn gene_totals(&self) -> Vec<(String, i32)> {
+        let mut totals = vec![0; self.genes.len()];
+        for (&v, (_i, j)) in self.matrix.iter() {
+            totals[j] += v;
+        }
+        self.genes.iter().cloned().zip(totals).collect()
+    }
+
+    /// density, mean counts and median genes per cell, in a single pass over the non-zero entries
+    pub fn summary(&self) -> MatrixSummary {
+        let (ncells, ngenes) = self.get_shape();
+        let mut counts_per_cell = vec![0_i64; ncells];
+        let mut genes_per_cell = vec![0_usize; ncells];
+        // explicitly stored zeros dont count as detected
+        for (&v, (i, _j)) in self.matrix.iter() {
+            if v != 0 {
+                counts_per_cell[i] += v as i64;
+                genes_per_cell[i] += 1;
+            }
+        }
+        let nnz: usize = genes_per_cell.iter().sum();
+
+        genes_per_cell.sort_unstable();
+        let median_genes_per_cell = match ncells {
+            0 => f64::NAN,
+            n if n % 2 == 1 => genes_per_cell[n / 2] as f64,
+            n => (genes_per_cell[n / 2 - 1] + genes_per_cell[n / 2]) as f64 / 2.0,
+        };
+
+        MatrixSummary {
+            ncells,
+            ngenes,
+            nnz,
+            density: nnz as f64 / (ncells * ngenes) as f64,
+            mean_counts_per_cell: counts_per_cell.iter().sum::<i64>() as f64 / ncells as f64,
+            median_genes_per_cell,
+        }
+    }
+
+    /// Compare to `other`: barcodes/genes present in only one of them,
+    /// and the entries (of shared barcodes and genes) whose counts differ
+    pub fn diff(&self, other: &CountMatrix) -> MatrixDiff {
+        fn only_in(x: &[String], y: &HashSet<&String>) -> Vec<String> {
+            x.iter().filter(|s| !y.contains(s)).cloned().sorted().collect()
+        }
+        let (cbs_a, cbs_b): (HashSet<&String>, HashSet<&String>) = (self.cbs.iter().collect(), other.cbs.iter().collect());
+        let (genes_a, genes_b): (HashSet<&String>, HashSet<&String>) = (self.genes.iter().collect(), other.genes.iter().collect());
+        let shared_cbs: HashSet<&String> = cbs_a.intersection(&cbs_b).copied().collect();
+        let shared_genes: HashSet<&String> = genes_a.intersection(&genes_b).copied().collect();
+
+        let map_a = self.to_map();
+        let map_b = other.to_map();
+        let keys: BTreeSet<&(String, String)> = map_a
+            .keys()
+            .chain(map_b.keys())
+            .filter(|(cb, gene)| shared_cbs.contains(cb) && shared_genes.contains(gene))
+            .collect();
+        let mismatches = keys
+            .into_iter()
+            .filter_map(|key| {
+                let a = map_a.get(key).copied().unwrap_or(0);
+                let b = map_b.get(key).copied().unwrap_or(0);
+                (a != b).then(|| (key.0.clone(), key.1.clone(), a, b))
+            })
+            .collect();
+
+        MatrixDiff {
+            barcodes_only_a: only_in(&self.cbs, &cbs_b),
+            barcodes_only_b: only_in(&other.cbs, &cbs_a),
+            genes_only_a: only_in(&self.genes, &genes_b),
+            genes_only_b: only_in(&other.genes, &genes_a),
+            mismatches,
+        }
+    }
+
+    /// keep only the cells (rows) whose total count (summed over all genes) is at least `min_total`
+    pub fn filter_cells_by_total_counts(&self, min_total: i32) -> CountMatrix {
+        let totals = self.cell_totals();
+        let keep_rows: Vec<usize> = (0..self.cbs.len()).filter(|&i| totals[i].1 >= min_total).collect();
+        let keep_cols: Vec<usize> = (0..self.genes.len()).collect();
+        self.subset(&keep_rows, &keep_cols)
+    }
+
+    /// keep only the genes (columns) whose total count (summed over all cells) is at least `min_total`
+    pub fn filter_genes_by_total_counts(&self, min_total: i32) -> CountMatrix {
+        let totals = self.gene_totals();
+        let keep_rows: Vec<usize> = (0..self.cbs.len()).collect();
+        let keep_cols: Vec<usize> = (0..self.genes.len()).filter(|&j| totals[j].1 >= min_total).collect();
+        self.subset(&keep_rows, &keep_cols)
+    }
+
+    /// select/reorder the columns to match `genes` exactly (e.g. a reference panel).
+    /// Genes not present in the matrix become empty (all zero) columns, genes not in `genes` are dropped
+    pub fn subset_genes(&self, genes: &[String]) -> CountMatrix {
+        // if a gene name occurs multiple times in the matrix, the first column is taken
+        let mut old_ix: HashMap<&String, usize> = HashMap::new();
+        for (j, g) in self.genes.iter().enumerate() {
+            old_ix.entry(g).or_insert(j);
+        }
+        // old column -> new column(s)
+        let mut col_ix: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (new_j, g) in genes.iter().enumerate() {
+            if let Some(&j) = old_ix.get(g) {
+                col_ix.entry(j).or_default().push(new_j);
+            }
+        }
+
+        let mut ii: Vec<usize> = Vec::new();
+        let mut jj: Vec<usize> = Vec::new();
+        let mut vv: Vec<i32> = Vec::new();
+        for (&v, (i, j)) in self.matrix.iter() {
+            for &new_j in col_ix.get(&j).into_iter().flatten() {
+                ii.push(i);
+                jj.push(new_j);
+                vv.push(v);
+            }
+        }
+        let c: TriMat<i32> = TriMat::from_triplets((self.cbs.len(), genes.len()), ii, jj, vv);
+        CountMatrix { matrix: c.to_csr(), cbs: self.cbs.clone(), genes: genes.to_vec() }
+    }
+
+    /// stack matrices along the cell axis (rows), e.g. lanes counted separately.
+    ///
+    /// All matrices must have the same genes (in the same order), otherwise an error is returned.
+    /// Barcodes occuring in more than one matrix are kept as separate rows (a warning is printed);
+    /// disambiguating them is up to the caller.
+    pub fn vstack(matrices: &[CountMatrix]) -> Result<CountMatrix, String> {
+        let first = matrices.first().ok_or("vstack: no matrices given")?;
+        for (k, m) in matrices.iter().enumerate().skip(1) {
+            if m.genes != first.genes {
+                return Err(format!(
+                    "vstack: genes of matrix {} don't match matrix 0 ({} vs {} genes)",
+                    k,
+                    m.genes.len(),
+                    first.genes.len()
+                ));
+            }
+        }
+
+        let nrows: usize = matrices.iter().map(|m| m.cbs.len()).sum();
+        let mut ii: Vec<usize> = Vec::new();
+        let mut jj: Vec<usize> = Vec::new();
+        let mut vv: Vec<i32> = Vec::new();
+        let mut cbs: Vec<String> = Vec::with_capacity(nrows);
+        for m in matrices {
+            // rows of this matrix start after all previous ones
+            let offset = cbs.len();
+            for (&v, (i, j)) in m.matrix.iter() {
+                ii.push(i + offset);
+                jj.push(j);
+                vv.push(v);
+            }
+            cbs.extend(m.cbs.iter().cloned());
+        }
+
+        let n_duplicated = cbs.iter().duplicates().count();
+        if n_duplicated > 0 {
+            eprintln!("Warning: vstack: {} barcodes occur in more than one matrix", n_duplicated);
+        }
+
+        let c: TriMat<i32> = TriMat::from_triplets((nrows, first.genes.len()), ii, jj, vv);
+        Ok(CountMatrix { matrix: c.to_csr(), cbs, genes: first.genes.clone() })
+    }
+
+    /// sum matrices entry-wise, on the union of their barcodes and genes, e.g. the same sample counted in several busfolders.
+    ///
+    /// Barcodes and genes are ordered by first occurrence (across `matrices`); missing entries count as zero.
+    /// Unlike [CountMatrix::vstack], a barcode occuring in several matrices ends up as a single row
+    pub fn sum(matrices: &[CountMatrix]) -> CountMatrix {
+        let mut genes: Vec<String> = Vec::new();
+        let mut seen_genes: HashMap<&String, usize> = HashMap::new();
+        let mut cbs: Vec<String> = Vec::new();
+        let mut cb_ix: HashMap<&String, usize> = HashMap::new();
+        for m in matrices {
+            for g in m.genes.iter() {
+                seen_genes.entry(g).or_insert_with(|| {
+                    genes.push(g.clone());
+                    genes.len() - 1
+                });
+            }
+            for cb in m.cbs.iter() {
+                cb_ix.entry(cb).or_insert_with(|| {
+                    cbs.push(cb.clone());
+                    cbs.len() - 1
+                });
+            }
+        }
+
+        let mut ii: Vec<usize> = Vec::new();
+        let mut jj: Vec<usize> = Vec::new();
+        let mut vv: Vec<i32> = Vec::new();
+        for m in matrices {
+            // align the columns to the common genes first
+            let aligned = m.subset_genes(&genes);
+            for (&v, (i, j)) in aligned.matrix.iter() {
+                ii.push(cb_ix[&m.cbs[i]]);
+                jj.push(j);
+                vv.push(v);
+            }
+        }
+        // duplicate (i,j) entries get summed when converting to CSR
+        let c: TriMat<i32> = TriMat::from_triplets((cbs.len(), genes.len()), ii, jj, vv);
+        CountMatrix { matrix: c.to_csr(), cbs, genes }
+    }
+
+    /// relabel the genes (columns) via `mapping`, e.g. Ensembl id -> symbol, to compare with matrices from other sources.
+    /// Genes not in `mapping` keep their name.
+    ///
+    /// Several genes can end up with the same name; use [CountMatrix::collapse_duplicate_genes] to merge them
+    pub fn rename_genes(&mut self, mapping: &HashMap<String, String>) {
+        for g in self.genes.iter_mut() {
+            if let Some(new_name) = mapping.get(g) {
+                g.clone_from(new_name);
+            }
+        }
+    }
+
+    /// sum up columns with the same gene name (e.g. several gene ids mapping to the same symbol).
+    /// The resulting genes are unique, in order of their first occurrence
+    pub fn collapse_duplicate_genes(&self) -> CountMatrix {
+        let mut genes: Vec<String> = Vec::new();
+        let mut gene_ix: HashMap<&String, usize> = HashMap::new();
+        let col_ix: Vec<usize> = self
+            .genes
+            .iter()
+            .map(|g| {
+                *gene_ix.entry(g).or_insert_with(|| {
+                    genes.push(g.clone());
+                    genes.len() - 1
+                })
+            })
+            .collect();
+
+        let mut ii: Vec<usize> = Vec::new();
+        let mut jj: Vec<usize> = Vec::new();
+        let mut vv: Vec<i32> = Vec::new();
+        for (&v, (i, j)) in self.matrix.iter() {
+            ii.push(i);
+            jj.push(col_ix[j]);
+            vv.push(v);
+        }
+        // duplicate (i,j) entries get summed when converting to CSR
+        let c: TriMat<i32> = TriMat::from_triplets((self.cbs.len(), genes.len()), ii, jj, vv);
+        CountMatrix { matrix: c.to_csr(), cbs: self.cbs.clone(), genes }
+    }
+
+    /// creates a new matrix containing only the given rows and columns (in that order).
+    /// The sparse matrix is rebuilt from triplets, remapping the old indices to the new ones
+    fn subset(&self, keep_rows: &[usize], keep_cols: &[usize]) -> CountMatrix {
+        let row_ix: HashMap<usize, usize> = keep_rows.iter().enumerate().map(|(new, &old)| (old, new)).collect();
+        let col_ix: HashMap<usize, usize> = keep_cols.iter().enumerate().map(|(new, &old)| (old, new)).collect();
+
+        let mut ii: Vec<usize> = Vec::new();
+        let mut jj: Vec<usize> = Vec::new();
+        let mut vv: Vec<i32> = Vec::new();
+        for (&v, (i, j)) in self.matrix.iter() {
+            if let (Some(&new_i), Some(&new_j)) = (row_ix.get(&i), col_ix.get(&j)) {
+                ii.push(new_i);
+                jj.push(new_j);
+                vv.push(v);
+            }
+        }
+        let c: TriMat<i32> = TriMat::from_triplets((keep_rows.len(), keep_cols.len()), ii, jj, vv);
+
+        let cbs = keep_rows.iter().map(|&i| self.cbs[i].clone()).collect();
+        let genes = keep_cols.iter().map(|&j| self.genes[j].clone()).collect();
+        CountMatrix { matrix: c.to_csr(), cbs, genes }
+    }
+
     /// load a countmatrix from disk (kallisto format: mtx + barcodes.txt + genes)
     /// 
     /// Oddly kallisto stores counts are `real` in the mmFormat (bustools v0.43.2)
     /// Hence we need to read a f32-sparse matrix and convert to ints
-    pub fn from_disk(mtx_file: &str, cbfile: &str, genefile: &str) -> Self {
+    ///
+    /// Files ending in `.gz` are decompressed on the fly.
+    ///
+    /// Fails if a file can't be read, the mtx is malformed, or the number of barcodes/genes doesn't match the matrix shape
+    pub fn from_disk(mtx_file: &str, cbfile: &str, genefile: &str) -> Result<Self, CountMatrixError> {
         // load countmatrix from disk, from matrix-market format
-        let mat: TriMat<f32> =
-            read_matrix_market(mtx_file).unwrap_or_else(|e| panic!("cant load {}: {:?}", mtx_file, e));
+        let mat: TriMat<f32> = read_matrix_market_from_bufread(&mut open_maybe_gz(mtx_file)?).map_err(|e| match e {
+            sprs::io::IoError::Io(source) => CountMatrixError::Io { file: mtx_file.to_string(), source },
+            e => CountMatrixError::Parse { file: mtx_file.to_string(), message: e.to_string() },
+        })?;
 
         println!("Convertting f32 -> i32");
         // need to convert to i32
@@ -90,29 +573,46 @@ impl CountMatrix {
 
         let matrix: sprs::CsMat<i32> = intmat.to_csr();
 
+        let cbs = read_lines(cbfile)?;
+        let genes = read_lines(genefile)?;
 
-        let fh = File::open(cbfile).unwrap_or_else(|_| panic!("{} not found", cbfile));
-        // Read the file line by line, and return an iterator of the lines of the file.
-        let cbs: Vec<String> = BufReader::new(fh)
-            .lines()
-            .collect::<Result<_, _>>()
-            .unwrap();
+        let (nrows, ncols) = matrix.shape();
+        if cbs.len() != nrows {
+            return Err(CountMatrixError::DimensionMismatch { file: cbfile.to_string(), expected: nrows, found: cbs.len() });
+        }
+        if genes.len() != ncols {
+            return Err(CountMatrixError::DimensionMismatch { file: genefile.to_string(), expected: ncols, found: genes.len() });
+        }
 
-        let fh = File::open(genefile).unwrap_or_else(|_| panic!("{} not found", genefile));
-        let genes: Vec<String> = BufReader::new(fh)
-            .lines()
-            .collect::<Result<_, _>>()
-            .unwrap();
+        Ok(CountMatrix { matrix, cbs, genes })
+    }
 
-        CountMatrix { matrix, cbs, genes }
+    /// Same as [CountMatrix::from_disk], panicking on any error
+    pub fn from_disk_or_panic(mtx_file: &str, cbfile: &str, genefile: &str) -> Self {
+        CountMatrix::from_disk(mtx_file, cbfile, genefile).unwrap_or_else(|e| panic!("{}", e))
     }
 
-    /// load the countmatrix from a folder, assuming standatd file naming
-    pub fn from_folder(foldername: &str) -> Self {
-        let mfile = &format!("{}/gene.mtx", foldername);
-        let cbfile = &format!("{}/gene.barcodes.txt", foldername);
-        let genefile = &format!("{}/gene.genes.txt", foldername);
-        CountMatrix::from_disk(mfile, cbfile, genefile)
+    /// load the countmatrix from a folder, assuming standatd file naming:
+    /// `<prefix>.mtx`, `<prefix>.barcodes.txt`, `<prefix>.genes.txt`, with prefix `gene` unless specified.
+    ///
+    /// If `<prefix>.mtx` doesn't exist, but `<prefix>.mtx.gz` does (see [CountMatrix::write_gz_with_prefix]),
+    /// the gzipped files are read instead
+    pub fn from_folder(foldername: &str, prefix: Option<&str>) -> Result<Self, CountMatrixError> {
+        let prefix = prefix.unwrap_or("gene");
+        let mut mfile = format!("{}/{}.mtx", foldername, prefix);
+        let mut cbfile = format!("{}/{}.barcodes.txt", foldername, prefix);
+        let mut genefile = format!("{}/{}.genes.txt", foldername, prefix);
+        if !Path::new(&mfile).exists() && Path::new(&format!("{}.gz", mfile)).exists() {
+            for f in [&mut mfile, &mut cbfile, &mut genefile] {
+                f.push_str(".gz");
+            }
+        }
+        CountMatrix::from_disk(&mfile, &cbfile, &genefile)
+    }
+
+    /// Same as [CountMatrix::from_folder], panicking on any error
+    pub fn from_folder_or_panic(foldername: &str, prefix: Option<&str>) -> Self {
+        CountMatrix::from_folder(foldername, prefix).unwrap_or_else(|e| panic!("{}", e))
     }
 
     /// write the matrix to disk in
@@ -122,10 +622,35 @@ impl CountMatrix {
     /// * `gene.mtx`: the sparse matrix
     /// * `gene.barcodes.txt`: String representation fo the cell barcodes
     /// * `gene.genes.txt`: Gene names
-    pub fn write(&self, foldername: &str) {
-        let mfile = format!("{}/gene.mtx", foldername);
-        let cbfile = format!("{}/gene.barcodes.txt", foldername);
-        let genefile = format!("{}/gene.genes.txt", foldername);
+    pub fn write(&self, foldername: &str) -> Result<(), CountMatrixError> {
+        self.write_with_prefix(foldername, "gene")
+    }
+
+    /// Same as [CountMatrix::write], panicking on any error
+    pub fn write_or_panic(&self, foldername: &str) {
+        self.write(foldername).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as [CountMatrix::write], but naming the files `<prefix>.mtx`, `<prefix>.barcodes.txt`, `<prefix>.genes.txt`,
+    /// e.g. to keep several count matrices (intronic/exonic) in the same folder
+    pub fn write_with_prefix(&self, foldername: &str, prefix: &str) -> Result<(), CountMatrixError> {
+        self.write_with_layout(foldername, prefix, Layout::Csr, false)
+    }
+
+    /// Same as [CountMatrix::write_with_prefix], but gzipping each file:
+    /// `<prefix>.mtx.gz`, `<prefix>.barcodes.txt.gz`, `<prefix>.genes.txt.gz`.
+    /// [CountMatrix::from_folder] picks those up if there's no uncompressed `<prefix>.mtx`
+    pub fn write_gz_with_prefix(&self, foldername: &str, prefix: &str) -> Result<(), CountMatrixError> {
+        self.write_with_layout(foldername, prefix, Layout::Csr, true)
+    }
+
+    /// Same as [CountMatrix::write_with_prefix], writing the MatrixMarket entries in the given [Layout]
+    /// (gzipping each file if `gzip`, as [CountMatrix::write_gz_with_prefix])
+    pub fn write_with_layout(&self, foldername: &str, prefix: &str, layout: Layout, gzip: bool) -> Result<(), CountMatrixError> {
+        let suffix = if gzip { ".gz" } else { "" };
+        let mfile = format!("{}/{}.mtx{}", foldername, prefix, suffix);
+        let cbfile = format!("{}/{}.barcodes.txt{}", foldername, prefix, suffix);
+        let genefile = format!("{}/{}.genes.txt{}", foldername, prefix, suffix);
 
 
         // silly: kallisto stores `real`s in the mmFormat
@@ -134,7 +659,15 @@ impl CountMatrix {
         let mut floatdata: Vec<f32> = Vec::new();
         let mut rows: Vec<usize> = Vec::new();
         let mut cols: Vec<usize> = Vec::new();
-        for (&v, (r,c)) in self.matrix.iter() {
+        // only copy the matrix if its storage doesn't match the layout already
+        let converted;
+        let matrix = match layout {
+            Layout::Csr if self.matrix.is_csr() => &self.matrix,
+            Layout::Csc if self.matrix.is_csc() => &self.matrix,
+            Layout::Csr => { converted = self.matrix.to_csr(); &converted },
+            Layout::Csc => { converted = self.matrix.to_csc(); &converted },
+        };
+        for (&v, (r,c)) in matrix.iter() {
             floatdata.push(v as f32);
             rows.push(r);
             cols.push(c);
@@ -148,18 +681,66 @@ impl CountMatrix {
         );
         println!("Done Convertting f32 -> i32");
 
-        write_matrix_market(mfile, &fmat).unwrap();
+        write_maybe_gz(&mfile, |fh| write_matrix_market_to_bufwrite(fh, &fmat))?;
+        write_lines(&cbfile, &self.cbs)?;
+        write_lines(&genefile, &self.genes)?;
+        Ok(())
+    }
+
+    /// Same as [CountMatrix::write_with_prefix], panicking on any error
+    pub fn write_with_prefix_or_panic(&self, foldername: &str, prefix: &str) {
+        self.write_with_prefix(foldername, prefix).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Same as [CountMatrix::write_format_with_prefix] with the default prefix `gene`
+    pub fn write_format(&self, foldername: &str, format: MatrixFormat) -> Result<(), CountMatrixError> {
+        self.write_format_with_prefix(foldername, "gene", format)
+    }
 
-        let mut fh_cb = File::create(cbfile).unwrap();
-        let mut fh_gene = File::create(genefile).unwrap();
+    /// write the matrix into `foldername` in the given [MatrixFormat], files named `<prefix>.*`
+    pub fn write_format_with_prefix(&self, foldername: &str, prefix: &str, format: MatrixFormat) -> Result<(), CountMatrixError> {
+        match format {
+            MatrixFormat::MatrixMarket => self.write_with_prefix(foldername, prefix),
+            MatrixFormat::Csv => self.to_csv(&format!("{}/{}.csv", foldername, prefix), false),
+            MatrixFormat::Coo => {
+                let coofile = format!("{}/{}.coo.tsv", foldername, prefix);
+                let io_err = |source| CountMatrixError::Io { file: coofile.clone(), source };
+                let mut fh = BufWriter::new(File::create(&coofile).map_err(io_err)?);
+                writeln!(fh, "cb_index\tgene_index\tcount").map_err(io_err)?;
+                let mut entries: Vec<(usize, usize, i32)> = self.matrix.iter().map(|(&v, (i, j))| (i, j, v)).collect();
+                entries.sort_unstable();
+                for (i, j, v) in entries {
+                    writeln!(fh, "{}\t{}\t{}", i + 1, j + 1, v).map_err(io_err)?;
+                }
+                fh.flush().map_err(io_err)?;
+                write_lines(&format!("{}/{}.barcodes.txt", foldername, prefix), &self.cbs)?;
+                write_lines(&format!("{}/{}.genes.txt", foldername, prefix), &self.genes)
+            }
+        }
+    }
 
-        for cb in self.cbs.iter() {
-            fh_cb.write_all(format!("{}\n", cb).as_bytes()).unwrap();
+    /// write the matrix as a dense csv: a header row of gene names, one row per cell starting with its barcode.
+    ///
+    /// Meant for small/test datasets: fails with [CountMatrixError::TooLarge] if the matrix has more than [MAX_DENSE_ENTRIES] entries, unless `force` is set
+    pub fn to_csv(&self, path: &str, force: bool) -> Result<(), CountMatrixError> {
+        let (nrows, ncols) = self.get_shape();
+        if !force {
+            check_dense_size(nrows, ncols)?;
         }
 
-        for g in self.genes.iter() {
-            fh_gene.write_all(format!("{}\n", g).as_bytes()).unwrap();
+        let io_err = |source| CountMatrixError::Io { file: path.to_string(), source };
+        let mut fh = BufWriter::new(File::create(path).map_err(io_err)?);
+        writeln!(fh, "barcode,{}", self.genes.join(",")).map_err(io_err)?;
+
+        let csr = self.matrix.to_csr();
+        for (cb, row) in self.cbs.iter().zip(csr.outer_iterator()) {
+            let mut dense_row = vec![0; ncols];
+            for (j, &v) in row.iter() {
+                dense_row[j] = v;
+            }
+            writeln!(fh, "{},{}", cb, dense_row.iter().join(",")).map_err(io_err)?;
         }
+        fh.flush().map_err(io_err)
     }
 }
 
@@ -187,7 +768,7 @@ impl fmt::Display for CountMatrix {
 
 #[cfg(test)]
 mod test {
-    use super::CountMatrix;
+    use super::{check_dense_size, CountMatrix, CountMatrixError, Layout, MatrixDiff, MatrixFormat, MatrixSummary};
     use crate::count2::countmap_to_matrix;
     use bustools::consistent_genes::{GeneId, Genename, CB};
     use ndarray::arr2;
@@ -219,6 +800,291 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_collapse_duplicate_genes() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(0), GeneId(1)), 1);
+        countmap.insert((CB(0), GeneId(2)), 3);
+        countmap.insert((CB(1), GeneId(2)), 5);
+        let gene_vector = vec![
+            Genename("G1_a".to_string()),
+            Genename("G1_b".to_string()),
+            Genename("G2".to_string()),
+        ];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+        // two columns with the same name
+        let cmat = CountMatrix::new(
+            cmat.matrix,
+            cmat.cbs,
+            vec!["G1".to_string(), "G1".to_string(), "G2".to_string()],
+        );
+
+        let collapsed = cmat.collapse_duplicate_genes();
+        assert_eq!(collapsed.genes, vec!["G1".to_string(), "G2".to_string()]);
+        assert_eq!(collapsed.matrix.to_dense(), arr2(&[[11, 3], [0, 5]]));
+    }
+
+    #[test]
+    fn test_rename_genes() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(0), GeneId(1)), 1);
+        countmap.insert((CB(1), GeneId(2)), 5);
+        let gene_vector = vec![
+            Genename("ENSG01".to_string()),
+            Genename("ENSG02".to_string()),
+            Genename("ENSG03".to_string()),
+        ];
+        let mut cmat = countmap_to_matrix(&countmap, gene_vector);
+
+        let mapping = HashMap::from([
+            ("ENSG01".to_string(), "ACTB".to_string()),
+            ("ENSG03".to_string(), "ACTB".to_string()),
+        ]);
+        cmat.rename_genes(&mapping);
+        assert_eq!(cmat.genes, vec!["ACTB".to_string(), "ENSG02".to_string(), "ACTB".to_string()]);
+        // counts untouched
+        assert_eq!(cmat.matrix.to_dense(), arr2(&[[10, 1, 0], [0, 0, 5]]));
+
+        let collapsed = cmat.collapse_duplicate_genes();
+        assert_eq!(collapsed.genes, vec!["ACTB".to_string(), "ENSG02".to_string()]);
+        assert_eq!(collapsed.matrix.to_dense(), arr2(&[[10, 1], [5, 0]]));
+    }
+
+    #[test]
+    fn test_subset_genes() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(0), GeneId(1)), 1);
+        countmap.insert((CB(1), GeneId(1)), 5);
+        countmap.insert((CB(1), GeneId(2)), 2);
+        let gene_vector = vec![
+            Genename("geneA".to_string()),
+            Genename("geneB".to_string()),
+            Genename("geneC".to_string()),
+        ];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+
+        // subset, reordered
+        let genes = vec!["geneC".to_string(), "geneA".to_string()];
+        let sub = cmat.subset_genes(&genes);
+        assert_eq!(sub.genes, genes);
+        assert_eq!(sub.cbs, cmat.cbs);
+        assert_eq!(sub.matrix.to_dense(), arr2(&[[0, 10], [2, 0]]));
+
+        // superset: unknown genes are empty columns
+        let genes = vec![
+            "geneX".to_string(),
+            "geneA".to_string(),
+            "geneB".to_string(),
+            "geneC".to_string(),
+            "geneY".to_string(),
+        ];
+        let sup = cmat.subset_genes(&genes);
+        assert_eq!(sup.genes, genes);
+        assert_eq!(sup.matrix.to_dense(), arr2(&[[0, 10, 1, 0, 0], [0, 0, 5, 2, 0]]));
+    }
+
+    #[test]
+    fn test_vstack() {
+        let genes = vec!["geneA".to_string(), "geneB".to_string()];
+        let m1 = CountMatrix::new(
+            sprs::CsMat::csr_from_dense(arr2(&[[1, 0], [2, 3]]).view(), 0),
+            vec!["AAAA".to_string(), "CCCC".to_string()],
+            genes.clone(),
+        );
+        let m2 = CountMatrix::new(
+            sprs::CsMat::csr_from_dense(arr2(&[[0, 4], [5, 0]]).view(), 0),
+            vec!["GGGG".to_string(), "TTTT".to_string()],
+            genes.clone(),
+        );
+
+        let stacked = CountMatrix::vstack(&[m1, m2]).unwrap();
+        assert_eq!(stacked.get_shape(), (4, 2));
+        assert_eq!(stacked.genes, genes);
+        assert_eq!(
+            stacked.cbs,
+            vec!["AAAA".to_string(), "CCCC".to_string(), "GGGG".to_string(), "TTTT".to_string()]
+        );
+        assert_eq!(stacked.matrix.to_dense(), arr2(&[[1, 0], [2, 3], [0, 4], [5, 0]]));
+
+        // different genes
+        let m3 = CountMatrix::new(
+            sprs::CsMat::csr_from_dense(arr2(&[[1, 0]]).view(), 0),
+            vec!["AAAA".to_string()],
+            vec!["geneB".to_string(), "geneA".to_string()],
+        );
+        assert!(CountMatrix::vstack(&[stacked, m3]).is_err());
+    }
+
+    #[test]
+    fn test_sum() {
+        let m1 = CountMatrix::new(
+            sprs::CsMat::csr_from_dense(arr2(&[[1, 0], [2, 3]]).view(), 0),
+            vec!["AAAA".to_string(), "CCCC".to_string()],
+            vec!["geneA".to_string(), "geneB".to_string()],
+        );
+        // CCCC in both, geneB in both
+        let m2 = CountMatrix::new(
+            sprs::CsMat::csr_from_dense(arr2(&[[4, 10], [5, 0]]).view(), 0),
+            vec!["CCCC".to_string(), "TTTT".to_string()],
+            vec!["geneB".to_string(), "geneC".to_string()],
+        );
+        let summed = CountMatrix::sum(&[m1, m2]);
+        assert_eq!(summed.cbs, vec!["AAAA".to_string(), "CCCC".to_string(), "TTTT".to_string()]);
+        assert_eq!(summed.genes, vec!["geneA".to_string(), "geneB".to_string(), "geneC".to_string()]);
+        assert_eq!(summed.matrix.to_dense(), arr2(&[[1, 0, 0], [2, 7, 10], [0, 5, 0]]));
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(0), GeneId(1)), 1);
+        countmap.insert((CB(1), GeneId(0)), 0);
+        countmap.insert((CB(1), GeneId(1)), 5);
+        let gene_vector = vec![Genename("geneA".to_string()), Genename("geneB".to_string())];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("counts.csv");
+        cmat.to_csv(path.to_str().unwrap(), false).unwrap();
+
+        let expected = "barcode,geneA,geneB\nAAAAAAAAAAAAAAAA,10,1\nAAAAAAAAAAAAAAAC,0,5\n";
+        assert_eq!(std::fs::read_to_string(path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_write_format() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(1), GeneId(1)), 5);
+        let gene_vector = vec![Genename("geneA".to_string()), Genename("geneB".to_string())];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+        let dir = tempdir().unwrap();
+        let folder = dir.path().to_str().unwrap();
+        let read = |f: &str| std::fs::read_to_string(dir.path().join(f)).unwrap();
+
+        cmat.write_format(folder, MatrixFormat::MatrixMarket).unwrap();
+        assert_eq!(CountMatrix::from_folder(folder, None).unwrap(), cmat);
+
+        cmat.write_format_with_prefix(folder, "x", MatrixFormat::Csv).unwrap();
+        assert_eq!(read("x.csv"), "barcode,geneA,geneB\nAAAAAAAAAAAAAAAA,10,0\nAAAAAAAAAAAAAAAC,0,5\n");
+
+        cmat.write_format_with_prefix(folder, "y", MatrixFormat::Coo).unwrap();
+        assert_eq!(read("y.coo.tsv"), "cb_index\tgene_index\tcount\n1\t1\t10\n2\t2\t5\n");
+        assert_eq!(read("y.barcodes.txt"), "AAAAAAAAAAAAAAAA\nAAAAAAAAAAAAAAAC\n");
+        assert_eq!(read("y.genes.txt"), "geneA\ngeneB\n");
+    }
+
+    #[test]
+    fn test_to_csv_too_large() {
+        let cbs: Vec<String> = (0..1001).map(|i| i.to_string()).collect();
+        let genes: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+        let cmat = CountMatrix::new(sprs::CsMat::zero((1001, 1000)), cbs, genes);
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("counts.csv");
+        assert!(matches!(
+            cmat.to_csv(path.to_str().unwrap(), false),
+            Err(CountMatrixError::TooLarge { ncells: 1001, ngenes: 1000 })
+        ));
+        assert!(!path.exists());
+        assert!(matches!(
+            cmat.write_format(dir.path().to_str().unwrap(), MatrixFormat::Csv),
+            Err(CountMatrixError::TooLarge { .. })
+        ));
+        assert!(check_dense_size(1000, 1000).is_ok());
+    }
+
+    #[test]
+    fn test_totals() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(0), GeneId(1)), 1);
+        countmap.insert((CB(1), GeneId(0)), 0);
+        countmap.insert((CB(1), GeneId(1)), 5);
+
+        let gene_vector = vec![Genename("geneA".to_string()), Genename("geneB".to_string())];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+        assert_eq!(cmat.matrix.to_dense(), arr2(&[[10, 1], [0, 5]]));
+
+        assert_eq!(
+            cmat.cell_totals(),
+            vec![
+                ("AAAAAAAAAAAAAAAA".to_string(), 11),
+                ("AAAAAAAAAAAAAAAC".to_string(), 5)
+            ]
+        );
+        assert_eq!(
+            cmat.gene_totals(),
+            vec![("geneA".to_string(), 10), ("geneB".to_string(), 6)]
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(0), GeneId(1)), 1);
+        countmap.insert((CB(1), GeneId(0)), 0);
+        countmap.insert((CB(1), GeneId(1)), 5);
+        let gene_vector = vec![Genename("geneA".to_string()), Genename("geneB".to_string())];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+
+        // 3 of 4 entries non-zero, (11+5)/2 counts, (2+1)/2 genes per cell
+        assert_eq!(
+            cmat.summary(),
+            MatrixSummary {
+                ncells: 2,
+                ngenes: 2,
+                nnz: 3,
+                density: 0.75,
+                mean_counts_per_cell: 8.0,
+                median_genes_per_cell: 1.5
+            }
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(0), GeneId(1)), 1);
+        countmap.insert((CB(1), GeneId(1)), 5);
+        let gene_vector = vec![Genename("geneA".to_string()), Genename("geneB".to_string())];
+        let cmat = countmap_to_matrix(&countmap, gene_vector.clone());
+        assert!(cmat.diff(&cmat).is_identical());
+
+        // perturb a single entry
+        countmap.insert((CB(1), GeneId(1)), 6);
+        let perturbed = countmap_to_matrix(&countmap, gene_vector);
+        let d = cmat.diff(&perturbed);
+        assert_eq!(
+            d,
+            MatrixDiff {
+                barcodes_only_a: vec![],
+                barcodes_only_b: vec![],
+                genes_only_a: vec![],
+                genes_only_b: vec![],
+                mismatches: vec![("AAAAAAAAAAAAAAAC".to_string(), "geneB".to_string(), 5, 6)],
+            }
+        );
+
+        // an extra gene in the second matrix, not counted as mismatch
+        countmap.insert((CB(1), GeneId(1)), 5);
+        countmap.insert((CB(1), GeneId(2)), 3);
+        let extra_gene = countmap_to_matrix(
+            &countmap,
+            vec![Genename("geneA".to_string()), Genename("geneB".to_string()), Genename("geneC".to_string())],
+        );
+        let d = cmat.diff(&extra_gene);
+        assert_eq!(d.genes_only_b, vec!["geneC".to_string()]);
+        assert!(d.mismatches.is_empty());
+        assert!(!d.is_identical());
+    }
+
     #[test]
     fn test_read_write() {
         let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
@@ -237,17 +1103,162 @@ mod test {
         }
         let tmpfoldername = path.to_str().unwrap();
 
-        cmat.write(tmpfoldername);
+        cmat.write(tmpfoldername).unwrap();
 
         let cmat2 = CountMatrix::from_disk(
             &format!("{}/gene.mtx", tmpfoldername),
             &format!("{}/gene.barcodes.txt", tmpfoldername),
             &format!("{}/gene.genes.txt", tmpfoldername),
-        );
+        ).unwrap();
 
         assert!(cmat == cmat2);
     }
 
+    #[test]
+    fn test_read_write_prefix() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(1), GeneId(1)), 5);
+        let gene_vector = vec![Genename("geneA".to_string()), Genename("geneB".to_string())];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(1)), 3);
+        let gene_vector = vec![Genename("geneA".to_string()), Genename("geneB".to_string())];
+        let cmat_intron = countmap_to_matrix(&countmap, gene_vector);
+
+        let dir = tempdir().unwrap();
+        let tmpfoldername = dir.path().to_str().unwrap();
+
+        // two matrices in the same folder dont clobber each other
+        cmat.write(tmpfoldername).unwrap();
+        cmat_intron.write_with_prefix(tmpfoldername, "intron").unwrap();
+
+        assert!(dir.path().join("intron.mtx").exists());
+        assert_eq!(CountMatrix::from_folder(tmpfoldername, None).unwrap(), cmat);
+        assert_eq!(CountMatrix::from_folder(tmpfoldername, Some("gene")).unwrap(), cmat);
+        assert_eq!(CountMatrix::from_folder(tmpfoldername, Some("intron")).unwrap(), cmat_intron);
+    }
+
+    #[test]
+    fn test_read_write_gz() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(0), GeneId(1)), 1);
+        countmap.insert((CB(1), GeneId(1)), 5);
+        let gene_vector = vec![Genename("geneA".to_string()), Genename("geneB".to_string())];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+
+        let dir = tempdir().unwrap();
+        let tmpfoldername = dir.path().to_str().unwrap();
+        cmat.write_gz_with_prefix(tmpfoldername, "gene").unwrap();
+
+        assert!(!dir.path().join("gene.mtx").exists());
+        // actually gzipped
+        let raw = std::fs::read(dir.path().join("gene.barcodes.txt.gz")).unwrap();
+        assert_eq!(&raw[..2], &[0x1f, 0x8b]);
+
+        let cmat2 = CountMatrix::from_disk(
+            &format!("{}/gene.mtx.gz", tmpfoldername),
+            &format!("{}/gene.barcodes.txt.gz", tmpfoldername),
+            &format!("{}/gene.genes.txt.gz", tmpfoldername),
+        ).unwrap();
+        assert_eq!(cmat2, cmat);
+        assert_eq!(CountMatrix::from_folder(tmpfoldername, None).unwrap(), cmat);
+    }
+
+    #[test]
+    fn test_write_layout() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(1)), 1);
+        countmap.insert((CB(1), GeneId(0)), 2);
+        countmap.insert((CB(1), GeneId(1)), 3);
+        let gene_vector = vec![Genename("geneA".to_string()), Genename("geneB".to_string())];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+
+        let dir = tempdir().unwrap();
+        let tmpfoldername = dir.path().to_str().unwrap();
+        cmat.write_with_layout(tmpfoldername, "csr", Layout::Csr, false).unwrap();
+        cmat.write_with_layout(tmpfoldername, "csc", Layout::Csc, false).unwrap();
+
+        // same entries, different order
+        let entries = |prefix: &str| -> Vec<String> {
+            std::fs::read_to_string(dir.path().join(format!("{}.mtx", prefix))).unwrap()
+                .lines().filter(|l| !l.starts_with('%')).skip(1).map(|l| l.to_string()).collect()
+        };
+        assert_eq!(entries("csr"), vec!["1 2 1", "2 1 2", "2 2 3"]);
+        assert_eq!(entries("csc"), vec!["2 1 2", "1 2 1", "2 2 3"]);
+
+        let csr = CountMatrix::from_folder(tmpfoldername, Some("csr")).unwrap();
+        let csc = CountMatrix::from_folder(tmpfoldername, Some("csc")).unwrap();
+        assert_eq!(csr.to_map(), cmat.to_map());
+        assert_eq!(csc.to_map(), cmat.to_map());
+    }
+
+    #[test]
+    fn test_from_disk_errors() {
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(1), GeneId(1)), 5);
+        let gene_vector = vec![Genename("geneA".to_string()), Genename("geneB".to_string())];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+
+        let dir = tempdir().unwrap();
+        let tmpfoldername = dir.path().to_str().unwrap();
+
+        // missing files
+        assert!(matches!(CountMatrix::from_folder(tmpfoldername, None), Err(CountMatrixError::Io { .. })));
+
+        // corrupt mtx
+        cmat.write(tmpfoldername).unwrap();
+        std::fs::write(dir.path().join("gene.mtx"), "%%MatrixMarket matrix coordinate real general\nnot a matrix\n").unwrap();
+        assert!(matches!(CountMatrix::from_folder(tmpfoldername, None), Err(CountMatrixError::Parse { .. })));
+
+        // one barcode too many
+        cmat.write(tmpfoldername).unwrap();
+        std::fs::write(dir.path().join("gene.barcodes.txt"), "A\nC\nG\n").unwrap();
+        assert!(matches!(
+            CountMatrix::from_folder(tmpfoldername, None),
+            Err(CountMatrixError::DimensionMismatch { expected: 2, found: 3, .. })
+        ));
+
+        // writing into a non-existing folder
+        let missing = dir.path().join("missing");
+        assert!(matches!(cmat.write(missing.to_str().unwrap()), Err(CountMatrixError::Io { .. })));
+    }
+
+    #[test]
+    fn test_filter_by_total_counts() {
+        // cell 0: 11 counts, cell 1: 5 counts, cell 2: 1 count
+        let mut countmap: HashMap<(CB, GeneId), usize> = HashMap::new();
+        countmap.insert((CB(0), GeneId(0)), 10);
+        countmap.insert((CB(0), GeneId(1)), 1);
+        countmap.insert((CB(1), GeneId(1)), 5);
+        countmap.insert((CB(2), GeneId(2)), 1);
+
+        let gene_vector = vec![
+            Genename("geneA".to_string()),
+            Genename("geneB".to_string()),
+            Genename("geneC".to_string()),
+        ];
+        let cmat = countmap_to_matrix(&countmap, gene_vector);
+
+        let filtered = cmat.filter_cells_by_total_counts(5);
+        assert_eq!(filtered.get_shape(), (2, 3));
+        assert_eq!(filtered.genes, cmat.genes);
+        assert_eq!(filtered.matrix.to_dense(), arr2(&[[10, 1, 0], [0, 5, 0]]));
+
+        let m = filtered.to_map();
+        assert!(!m.contains_key(&("AAAAAAAAAAAAAAAG".to_string(), "geneC".to_string())));
+        assert_eq!(m.len(), 3);
+
+        let filtered = cmat.filter_genes_by_total_counts(6);
+        assert_eq!(filtered.get_shape(), (3, 2));
+        assert_eq!(filtered.genes, vec!["geneA".to_string(), "geneB".to_string()]);
+        assert_eq!(filtered.cbs, cmat.cbs);
+        assert_eq!(filtered.matrix.to_dense(), arr2(&[[10, 1], [0, 5], [0, 0]]));
+    }
+
     #[test]
     fn test_countmatrix_equal() {
         //testing the Eq implementation, which should be order invariant (doesnt matter how genes are ordered)
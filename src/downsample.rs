@@ -0,0 +1,296 @@
+//! Downsampling the reads of a busfile, e.g. for saturation analysis
+//!
+//! Each record's COUNT is treated as the number of reads supporting that record.
+//! Downsampling draws a random subset of those reads; records that end up with
+//! zero reads are dropped.
+//!
+//! Alternatively, [sample_molecules] subsamples entire molecules (CB/UMIs, with all their records and reads),
+//! e.g. to create small test fixtures.
+//!
+//! The randomness comes from a seeded [Xorshift128Plus] source: identical seeds yield identical outputs.
+//! The `*_with_source` variants take the source directly, e.g. to continue a random stream across calls
+use bustools::io::BusReader;
+use crate::util::create_buswriter;
+use bustools::iterators::CbUmiGroupIterator;
+use probability::prelude::*;
+use probability::source::{Source, Xorshift128Plus};
+
+/// Subsample the reads of `input` to exactly `target_reads` total reads and write the result to `output`
+///
+/// Reads are drawn without replacement: each record's new COUNT is at most its original COUNT.
+/// Going through the reads in file order, each one is kept with probability
+/// (reads still to draw) / (reads not yet seen), i.e. sequential hypergeometric draws over the COUNTs.
+/// If `target_reads` is larger than the number of reads in `input`, there is nothing to downsample
+/// and the records are written unchanged.
+///
+/// # Parameters
+/// * `input`: busfile to downsample
+/// * `output`: busfile to write the downsampled records into
+/// * `target_reads`: total number of reads after downsampling
+/// * `seed`: seed of the random number generator; identical seeds yield identical outputs
+pub fn downsample(input: &str, output: &str, target_reads: u64, seed: u64) {
+    downsample_with_source(input, output, target_reads, &mut source::default(seed))
+}
+
+/// Same as [downsample], drawing from the given random source instead of seeding one
+pub fn downsample_with_source(input: &str, output: &str, target_reads: u64, random_source: &mut Xorshift128Plus) {
+    let reader = BusReader::new(input);
+    let params = reader.get_params().clone();
+
+    let (n_records, total_reads) = reader.fold((0_usize, 0_u64), |(n, reads), r| (n + 1, reads + r.COUNT as u64));
+    println!("{} records, {} reads", n_records, total_reads);
+
+    let mut writer = create_buswriter(output, params);
+
+    if target_reads >= total_reads {
+        println!("target reads >= total reads, nothing to downsample");
+        writer.write_iterator(BusReader::new(input));
+        return;
+    }
+
+    let mut reads_left = total_reads;
+    let mut draws_left = target_reads;
+    let it = BusReader::new(input)
+        .filter_map(|mut r| {
+            let mut kept = 0;
+            for _ in 0..r.COUNT {
+                if draws_left > 0 && random_source.read_f64() * (reads_left as f64) < draws_left as f64 {
+                    kept += 1;
+                    draws_left -= 1;
+                }
+                reads_left -= 1;
+            }
+            r.COUNT = kept;
+            if r.COUNT > 0 { Some(r) } else { None }
+        });
+    writer.write_iterator(it);
+}
+
+/// Keep each read of `input` with probability `fraction` and write the result to `output`
+///
+/// Each record's new COUNT is drawn from a Binomial(COUNT, `fraction`).
+/// Unlike [downsample], this doesn't need to know the total reads up front and is a single pass over the file.
+///
+/// # Parameters
+/// * `input`: busfile to downsample
+/// * `output`: busfile to write the downsampled records into
+/// * `fraction`: fraction of reads to keep, `0.0 < fraction <= 1.0`
+/// * `seed`: seed of the random number generator; identical seeds yield identical outputs
+pub fn downsample_fraction(input: &str, output: &str, fraction: f64, seed: u64) {
+    downsample_fraction_with_source(input, output, fraction, &mut source::default(seed))
+}
+
+/// Same as [downsample_fraction], drawing from the given random source instead of seeding one
+pub fn downsample_fraction_with_source(input: &str, output: &str, fraction: f64, random_source: &mut Xorshift128Plus) {
+    assert!(
+        0.0 < fraction && fraction <= 1.0,
+        "fraction must be in (0, 1], got {}",
+        fraction
+    );
+
+    let reader = BusReader::new(input);
+    let mut writer = create_buswriter(output, reader.get_params().clone());
+
+    let it = reader
+        .filter_map(|mut r| {
+            // Binomial requires p<1
+            if fraction < 1.0 {
+                r.COUNT = probability::distribution::Binomial::new(r.COUNT as usize, fraction)
+                    .sample(random_source) as u32;
+            }
+            if r.COUNT > 0 { Some(r) } else { None }
+        });
+    writer.write_iterator(it);
+}
+
+/// Write `n_molecules` randomly chosen CB/UMIs of the (sorted) `input`, with all their records, to `output`
+///
+/// The CB/UMIs are reservoir-sampled in a first pass; a second pass writes the chosen ones,
+/// hence the output keeps the sort order of `input`. If `input` has at most `n_molecules` CB/UMIs, all are written.
+///
+/// # Parameters
+/// * `input`: busfile to sample from, sorted by CB/UMI
+/// * `output`: busfile to write the sampled molecules into
+/// * `n_molecules`: number of CB/UMIs to keep
+/// * `seed`: seed of the random number generator; identical seeds yield identical outputs
+pub fn sample_molecules(input: &str, output: &str, n_molecules: usize, seed: u64) {
+    sample_molecules_with_source(input, output, n_molecules, &mut source::default(seed))
+}
+
+/// Same as [sample_molecules], drawing from the given random source instead of seeding one
+pub fn sample_molecules_with_source(input: &str, output: &str, n_molecules: usize, random_source: &mut Xorshift128Plus) {
+    // reservoir of the CB/UMI indices (in file order)
+    let mut reservoir: Vec<usize> = Vec::with_capacity(n_molecules);
+    let mut n_total = 0;
+    for (i, _group) in BusReader::new(input).groupby_cbumi().enumerate() {
+        if i < n_molecules {
+            reservoir.push(i);
+        } else {
+            let j = (random_source.read_u64() % (i as u64 + 1)) as usize;
+            if j < n_molecules {
+                reservoir[j] = i;
+            }
+        }
+        n_total += 1;
+    }
+    println!("sampled {} of {} CB/UMIs", reservoir.len(), n_total);
+    reservoir.sort_unstable();
+
+    let reader = BusReader::new(input);
+    let mut writer = create_buswriter(output, reader.get_params().clone());
+    let mut chosen = reservoir.into_iter().peekable();
+    let it = reader
+        .groupby_cbumi()
+        .enumerate()
+        .filter(|(i, _group)| chosen.next_if_eq(i).is_some())
+        .flat_map(|(_i, (_cbumi, records))| records);
+    writer.write_iterator(it);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{downsample, downsample_fraction, downsample_fraction_with_source, sample_molecules};
+    use bustools::io::{setup_busfile, BusReader, BusRecord};
+    use itertools::Itertools;
+    use probability::source;
+
+    #[test]
+    fn test_downsample_full_depth() {
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 12, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 1, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+        let outpath = _dir.path().join("downsampled.bus");
+        let outfile = outpath.to_str().unwrap();
+
+        downsample(&busname, outfile, 15, 42);
+
+        let downsampled: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(downsampled, records);
+    }
+
+    #[test]
+    fn test_downsample_target() {
+        let records: Vec<BusRecord> = (0..100)
+            .map(|i| BusRecord { CB: 0, UMI: i, EC: 0, COUNT: 10, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+        let outpath = _dir.path().join("downsampled.bus");
+        let outfile = outpath.to_str().unwrap();
+
+        downsample(&busname, outfile, 300, 42);
+
+        let downsampled: Vec<BusRecord> = BusReader::new(outfile).collect();
+        let total: u32 = downsampled.iter().map(|r| r.COUNT).sum();
+        assert_eq!(total, 300);
+        assert!(downsampled.iter().all(|r| r.COUNT > 0));
+    }
+
+    #[test]
+    fn test_downsample_without_replacement() {
+        // very uneven COUNTs: sampling with replacement would inflate some records
+        let records: Vec<BusRecord> = (0..200)
+            .map(|i| BusRecord { CB: 0, UMI: i, EC: 0, COUNT: if i % 10 == 0 { 50 } else { 1 }, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+        let outpath = _dir.path().join("downsampled.bus");
+        let outfile = outpath.to_str().unwrap();
+
+        downsample(&busname, outfile, 900, 42);
+
+        let downsampled: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(downsampled.iter().map(|r| r.COUNT).sum::<u32>(), 900);
+        for r in downsampled.iter() {
+            let old = records.iter().find(|o| o.UMI == r.UMI).unwrap();
+            assert!(r.COUNT <= old.COUNT, "{:?} vs {:?}", r, old);
+        }
+    }
+
+    #[test]
+    fn test_downsample_fraction() {
+        let n_records = 10_000;
+        let records: Vec<BusRecord> = (0..n_records)
+            .map(|i| BusRecord { CB: 0, UMI: i, EC: 0, COUNT: 10, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+        let outpath = _dir.path().join("downsampled.bus");
+        let outfile = outpath.to_str().unwrap();
+
+        downsample_fraction(&busname, outfile, 0.3, 42);
+
+        // expected 30_000 reads, std ~145
+        let downsampled: Vec<BusRecord> = BusReader::new(outfile).collect();
+        let total: u32 = downsampled.iter().map(|r| r.COUNT).sum();
+        assert!((29_400..=30_600).contains(&total), "{}", total);
+        assert!(downsampled.iter().all(|r| r.COUNT > 0 && r.COUNT <= 10));
+
+        // keeping everything
+        downsample_fraction(&busname, outfile, 1.0, 42);
+        let downsampled: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(downsampled, records);
+    }
+
+    #[test]
+    fn test_downsample_seed() {
+        let records: Vec<BusRecord> = (0..100)
+            .map(|i| BusRecord { CB: 0, UMI: i, EC: 0, COUNT: 10, FLAG: 0 })
+            .collect();
+        let (busname, dir) = setup_busfile(&records);
+        let run = |seed: u64, name: &str| {
+            let outpath = dir.path().join(name);
+            downsample(&busname, outpath.to_str().unwrap(), 300, seed);
+            BusReader::new(outpath.to_str().unwrap()).collect::<Vec<BusRecord>>()
+        };
+        assert_eq!(run(1, "a.bus"), run(1, "b.bus"));
+        assert_ne!(run(1, "a.bus"), run(2, "b.bus"));
+
+        // seeding vs injecting the same source
+        let outpath = dir.path().join("c.bus");
+        let outfile = outpath.to_str().unwrap();
+        downsample_fraction(&busname, outfile, 0.5, 7);
+        let seeded: Vec<BusRecord> = BusReader::new(outfile).collect();
+        downsample_fraction_with_source(&busname, outfile, 0.5, &mut source::default(7));
+        let injected: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(seeded, injected);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_downsample_fraction_invalid() {
+        let records = vec![BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 12, FLAG: 0 }];
+        let (busname, _dir) = setup_busfile(&records);
+        let outpath = _dir.path().join("downsampled.bus");
+        downsample_fraction(&busname, outpath.to_str().unwrap(), 1.5, 42);
+    }
+
+    #[test]
+    fn test_sample_molecules() {
+        // 200 CB/UMIs with 1-3 records each
+        let records: Vec<BusRecord> = (0..200_u64)
+            .flat_map(|i| (0..(1 + i % 3)).map(move |ec| BusRecord { CB: i / 10, UMI: i % 10, EC: ec as u32, COUNT: 2, FLAG: 0 }))
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+        let outpath = _dir.path().join("sampled.bus");
+        let outfile = outpath.to_str().unwrap();
+
+        sample_molecules(&busname, outfile, 50, 42);
+
+        let sampled: Vec<BusRecord> = BusReader::new(outfile).collect();
+        let n_molecules = sampled.iter().map(|r| (r.CB, r.UMI)).dedup().count();
+        assert_eq!(n_molecules, 50);
+        assert!(sampled.is_sorted_by_key(|r| (r.CB, r.UMI, r.EC)));
+        // all records of a chosen molecule, unchanged
+        for (cb, umi) in sampled.iter().map(|r| (r.CB, r.UMI)).dedup() {
+            let original: Vec<&BusRecord> = records.iter().filter(|r| (r.CB, r.UMI) == (cb, umi)).collect();
+            let kept: Vec<&BusRecord> = sampled.iter().filter(|r| (r.CB, r.UMI) == (cb, umi)).collect();
+            assert_eq!(kept, original);
+        }
+
+        // fewer molecules than requested: everything
+        sample_molecules(&busname, outfile, 1000, 42);
+        let sampled: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(sampled, records);
+    }
+}
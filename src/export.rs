@@ -0,0 +1,71 @@
+//! Exporting busfiles into text formats, with CB/UMI decoded into sequences
+//!
+//! * [to_tsv]: tab separated `CB UMI EC COUNT FLAG`, with header
+//! * [to_jsonl]: JSON Lines (one object per record), e.g. for `pd.read_json(..., lines=True)` or `pl.read_ndjson`
+use crate::api::decoded_records;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Write the records of `input` as tab separated text into `output`
+pub fn to_tsv(input: &str, output: &str) {
+    let mut fh = BufWriter::new(File::create(output).unwrap());
+    writeln!(fh, "CB\tUMI\tEC\tCOUNT\tFLAG").unwrap();
+    for (cb, umi, ec, count, flag) in decoded_records(input) {
+        writeln!(fh, "{}\t{}\t{}\t{}\t{}", cb, umi, ec, count, flag).unwrap();
+    }
+}
+
+/// Write the records of `input` as JSON Lines into `output`, one record per line:
+/// ```text
+/// {"cb":"AAAAAAAAAAAAAAAC","umi":"AAAAAAAAAAAG","ec":3,"count":4,"flag":0}
+/// ```
+/// CB/UMI are plain ACGT sequences, hence no escaping is needed
+pub fn to_jsonl(input: &str, output: &str) {
+    let mut fh = BufWriter::new(File::create(output).unwrap());
+    for (cb, umi, ec, count, flag) in decoded_records(input) {
+        writeln!(
+            fh,
+            "{{\"cb\":\"{}\",\"umi\":\"{}\",\"ec\":{},\"count\":{},\"flag\":{}}}",
+            cb, umi, ec, count, flag
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_jsonl, to_tsv};
+    use bustools::io::{setup_busfile, BusRecord};
+
+    #[test]
+    fn test_to_jsonl() {
+        let records = vec![
+            BusRecord { CB: 1, UMI: 2, EC: 3, COUNT: 4, FLAG: 5 },
+            BusRecord { CB: 1, UMI: 3, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let (busfile, dir) = setup_busfile(&records);
+        let output = dir.path().join("records.jsonl");
+        let output = output.to_str().unwrap();
+        to_jsonl(&busfile, output);
+
+        let content = std::fs::read_to_string(output).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "{\"cb\":\"AAAAAAAAAAAAAAAC\",\"umi\":\"AAAAAAAAAAAG\",\"ec\":3,\"count\":4,\"flag\":5}"
+        );
+    }
+
+    #[test]
+    fn test_to_tsv() {
+        let records = vec![BusRecord { CB: 1, UMI: 2, EC: 3, COUNT: 4, FLAG: 5 }];
+        let (busfile, dir) = setup_busfile(&records);
+        let output = dir.path().join("records.tsv");
+        let output = output.to_str().unwrap();
+        to_tsv(&busfile, output);
+
+        let content = std::fs::read_to_string(output).unwrap();
+        assert_eq!(content, "CB\tUMI\tEC\tCOUNT\tFLAG\nAAAAAAAAAAAAAAAC\tAAAAAAAAAAAG\t3\t4\t5\n");
+    }
+}
@@ -0,0 +1,71 @@
+//! Per-cell summaries of a busfile: number of molecules or reads for each CB
+//!
+//! Output is a csv without header, one line per CB: `CB,count`
+use bustools::{io::BusReader, iterators::CellGroupIterator, utils::int_to_seq};
+use itertools::Itertools;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+/// What to count per CB in [per_cb_summary]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum CbCountMode {
+    /// number of molecules, i.e. distinct UMIs (the same UMI with different ECs counts once)
+    #[default]
+    Umis,
+    /// number of reads, i.e. the sum of COUNT
+    Reads,
+}
+
+/// For each CB in the (sorted) `input` busfile, write the number of UMIs or reads (see [CbCountMode]) to `output`
+pub fn per_cb_summary(input: &str, output: &str, count_mode: CbCountMode) {
+    let fh = File::create(output).unwrap();
+    let mut writer = BufWriter::new(fh);
+
+    let reader = BusReader::new(input);
+    let cb_len = reader.get_params().cb_len as usize;
+    let bus_cb = reader.groupby_cb().map(|(cb, records)| {
+        let n = match count_mode {
+            CbCountMode::Umis => records.iter().map(|r| r.UMI).unique().count(),
+            CbCountMode::Reads => records.iter().map(|r| r.COUNT as usize).sum(),
+        };
+        (int_to_seq(cb, cb_len), n)
+    });
+
+    for (cb, n) in bus_cb {
+        writeln!(writer, "{},{}", cb, n).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{per_cb_summary, CbCountMode};
+    use bustools::io::{setup_busfile, BusRecord};
+
+    #[test]
+    fn test_per_cb_summary() {
+        let records = vec![
+            // same UMI, different EC: a single molecule
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 10, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+        let outpath = _dir.path().join("cb.csv");
+        let outfile = outpath.to_str().unwrap();
+
+        per_cb_summary(&busname, outfile, CbCountMode::Umis);
+        assert_eq!(
+            std::fs::read_to_string(outfile).unwrap(),
+            "AAAAAAAAAAAAAAAA,2\nAAAAAAAAAAAAAAAC,1\n"
+        );
+
+        per_cb_summary(&busname, outfile, CbCountMode::Reads);
+        assert_eq!(
+            std::fs::read_to_string(outfile).unwrap(),
+            "AAAAAAAAAAAAAAAA,6\nAAAAAAAAAAAAAAAC,10\n"
+        );
+    }
+}
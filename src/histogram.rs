@@ -0,0 +1,61 @@
+//! Distribution of COUNT (number of reads) across all records of a busfile
+//!
+//! Unlike [crate::butterfly], this is per record, not per molecule:
+//! a molecule spread over several ECs contributes several records.
+use bustools::io::BusReader;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+/// Number of records for each COUNT value in the busfile (single pass, doesn't need to be sorted)
+pub fn count_histogram(busfile: &str) -> BTreeMap<u32, usize> {
+    let mut hist: BTreeMap<u32, usize> = BTreeMap::new();
+    for r in BusReader::new(busfile) {
+        *hist.entry(r.COUNT).or_insert(0) += 1;
+    }
+    hist
+}
+
+/// Write the [count_histogram] of `busfile` as csv (`count,frequency`) into `output`
+pub fn write_count_histogram(busfile: &str, output: &str) {
+    let hist = count_histogram(busfile);
+    let fh = File::create(output).unwrap();
+    let mut writer = BufWriter::new(fh);
+    writeln!(writer, "count,frequency").unwrap();
+    for (count, freq) in hist {
+        writeln!(writer, "{},{}", count, freq).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{count_histogram, write_count_histogram};
+    use bustools::io::{setup_busfile, BusRecord};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_count_histogram() {
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 2, EC: 0, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: 2, UMI: 1, EC: 0, COUNT: 10, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+
+        let hist = count_histogram(&busname);
+        assert_eq!(hist, BTreeMap::from([(1, 3), (3, 2), (10, 1)]));
+
+        let outpath = _dir.path().join("hist.csv");
+        let outfile = outpath.to_str().unwrap();
+        write_count_histogram(&busname, outfile);
+        assert_eq!(
+            std::fs::read_to_string(outfile).unwrap(),
+            "count,frequency\n1,3\n3,2\n10,1\n"
+        );
+    }
+}
@@ -1,54 +1,150 @@
 //! Inspecting a busfile for statistics
 //!
-//! just like `bustools inspect`
-use bustools::{
-    io::BusReader,
-    iterators::{CbUmiGroupIterator, CellGroupIterator},
-};
-
-#[derive(Debug, Eq, PartialEq)]
-struct BusStatistics {
-    cb_len: usize,
-    umi_len: usize,
-    nrecords: usize,
-    nreads: usize,
-    n_cells: usize,
-    n_cbumi: usize,
-}
+//! just like `bustools inspect`.
+//! [diff_stats] compares the statistics of two busfiles, e.g. before/after a pipeline change,
+//! [peek] shows the first/last few records to sanity-check sorting and encoding
+use crate::api::decoded_records;
+use crate::util::read_busfile;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::collections::VecDeque;
+use bustools::io::BusReader;
 
-fn _inspect(busfile: &str) -> BusStatistics {
-    let n_cbumi = BusReader::new(busfile).groupby_cbumi().count();
-    let n_cells = BusReader::new(busfile).groupby_cb().count();
+/// Summary statistics of a busfile, see [bus_statistics]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct BusStatistics {
+    /// length of the cell barcodes (BP)
+    pub cb_len: usize,
+    /// length of the UMIs (BP)
+    pub umi_len: usize,
+    /// number of records
+    pub nrecords: usize,
+    /// number of reads, i.e. summed COUNT
+    pub nreads: usize,
+    /// number of distinct CBs
+    pub n_cells: usize,
+    /// number of distinct CB/UMIs (molecules)
+    pub n_cbumi: usize,
+}
 
-    let bf = BusReader::new(busfile);
-    let params = bf.get_params();
+/// Calculate the [BusStatistics] of a (sorted) busfile
+///
+/// A single pass over the file, counting the CB and CB/UMI transitions.
+/// Panics if the file turns out to be unsorted
+pub fn bus_statistics(busfile: &str) -> BusStatistics {
+    let params = BusReader::new(busfile).get_params().clone();
     let cb_len = params.cb_len as usize;
     let umi_len = params.umi_len as usize;
 
     let mut nreads = 0;
     let mut nrecords = 0;
+    let mut n_cells = 0;
+    let mut n_cbumi = 0;
+    let mut previous: Option<(u64, u64)> = None;
 
-    let bus = BusReader::new(busfile);
-    for r in bus {
+    for r in read_busfile(busfile) {
+        let cbumi = (r.CB, r.UMI);
+        match previous {
+            Some(prev) if cbumi < prev => {
+                panic!("busfile {} not sorted by CB/UMI: {:?} after {:?}", busfile, cbumi, prev)
+            }
+            Some((prev_cb, prev_umi)) => {
+                if r.CB != prev_cb {
+                    n_cells += 1;
+                    n_cbumi += 1;
+                } else if r.UMI != prev_umi {
+                    n_cbumi += 1;
+                }
+            }
+            None => {
+                n_cells += 1;
+                n_cbumi += 1;
+            }
+        }
+        previous = Some(cbumi);
         nrecords += 1;
-        nreads += r.COUNT as usize
+        nreads += r.COUNT as usize;
     }
 
-    // match BusReader::new(busfile) {
-    //     BusReader::Plain(reader) => {reader.get_bus_header()}
-    // }
+    BusStatistics { cb_len, umi_len, nrecords, nreads, n_cells, n_cbumi }
+}
+
+/// Field-by-field comparison of the [BusStatistics] of two busfiles, see [diff_stats]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StatsDiff {
+    /// statistics of the first file
+    pub a: BusStatistics,
+    /// statistics of the second file
+    pub b: BusStatistics,
+}
+
+impl StatsDiff {
+    /// per field: (name, value in a, value in b, b - a)
+    pub fn deltas(&self) -> Vec<(&'static str, usize, usize, i64)> {
+        let fields = [
+            ("records", self.a.nrecords, self.b.nrecords),
+            ("reads", self.a.nreads, self.b.nreads),
+            ("cells", self.a.n_cells, self.b.n_cells),
+            ("cb_umis", self.a.n_cbumi, self.b.n_cbumi),
+        ];
+        fields
+            .into_iter()
+            .map(|(name, a, b)| (name, a, b, b as i64 - a as i64))
+            .collect()
+    }
+
+    /// true if both files have the same statistics
+    pub fn is_zero(&self) -> bool {
+        self.deltas().iter().all(|(_, _, _, d)| *d == 0)
+    }
+
+    /// human readable table, one line per field
+    pub fn to_table(&self) -> String {
+        let mut table = format!("{:<10}{:>15}{:>15}{:>15}\n", "", "a", "b", "b-a");
+        for (name, a, b, d) in self.deltas() {
+            table.push_str(&format!("{:<10}{:>15}{:>15}{:>15}\n", name, a, b, d));
+        }
+        table
+    }
+
+    /// the same as [StatsDiff::to_table], as json: `{"records": {"a": .., "b": .., "delta": ..}, ...}`
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
 
-    BusStatistics {cb_len,umi_len, nrecords, nreads, n_cells, n_cbumi }
+/// one field of a [StatsDiff], as serialized by [StatsDiff::to_json]
+#[derive(Serialize)]
+struct FieldDelta {
+    a: usize,
+    b: usize,
+    delta: i64,
+}
+
+/// serialized field by field (in the order of [StatsDiff::deltas]), rather than as the two [BusStatistics]
+impl Serialize for StatsDiff {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let deltas = self.deltas();
+        let mut map = serializer.serialize_map(Some(deltas.len()))?;
+        for (name, a, b, delta) in deltas {
+            map.serialize_entry(name, &FieldDelta { a, b, delta })?;
+        }
+        map.end()
+    }
+}
+
+/// Compare the statistics of busfiles `a` and `b`
+pub fn diff_stats(a: &str, b: &str) -> StatsDiff {
+    StatsDiff { a: bus_statistics(a), b: bus_statistics(b) }
 }
 
 /// Inspect a busfile, counting number of reads, records, cb-umi combinations and cell-barcodes
 /// # Example
 /// ```rust, no_run
-/// # use bustools::inspect::inspect;
+/// # use bustools_cli::inspect::inspect;
 /// inspect("somefile.bus")
 /// ```
 pub fn inspect(busfile: &str) {
-    let stats = _inspect(busfile);
+    let stats = bus_statistics(busfile);
     println!("CB: {} BP, UMI: {} BP", stats.cb_len, stats.umi_len);
     println!("{} BUS records", stats.nrecords);
     println!("{} reads", stats.nreads);
@@ -56,10 +152,54 @@ pub fn inspect(busfile: &str) {
     println!("{} CB-UMIs", stats.n_cbumi);
 }
 
+/// a decoded record: (CB, UMI, EC, COUNT, FLAG), see [decoded_records]
+pub type DecodedRecord = (String, String, u32, u32, u32);
+
+/// The first `head` and the last `tail` records of `busfile` (decoded), in a single pass.
+///
+/// For the tail, only a ring of the last `tail` records is kept in memory
+pub fn peek(busfile: &str, head: usize, tail: usize) -> (Vec<DecodedRecord>, Vec<DecodedRecord>) {
+    let mut records = decoded_records(busfile);
+    let first: Vec<DecodedRecord> = records.by_ref().take(head).collect();
+    if tail == 0 {
+        return (first, Vec::new());
+    }
+
+    // the last records might be among the first ones (short file)
+    let mut ring: VecDeque<DecodedRecord> = first.iter().rev().take(tail).rev().cloned().collect();
+    for r in records {
+        if ring.len() == tail {
+            ring.pop_front();
+        }
+        ring.push_back(r);
+    }
+    (first, ring.into())
+}
+
+/// print the first `head` and last `tail` records of `busfile`, see [peek]
+pub fn print_peek(busfile: &str, head: usize, tail: usize) {
+    let (first, last) = peek(busfile, head, tail);
+    let print = |label: &str, records: Vec<DecodedRecord>| {
+        println!("{}:", label);
+        println!("CB\tUMI\tEC\tCOUNT\tFLAG");
+        for (cb, umi, ec, count, flag) in records {
+            println!("{}\t{}\t{}\t{}\t{}", cb, umi, ec, count, flag);
+        }
+    };
+    if head > 0 {
+        print(&format!("first {} records", first.len()), first);
+    }
+    if tail > 0 {
+        print(&format!("last {} records", last.len()), last);
+    }
+}
+
 #[cfg(test)]
 mod testing {
-    use super::{BusStatistics, _inspect};
-    use bustools::io::{setup_busfile, BusRecord};
+    use super::{bus_statistics, diff_stats, peek, BusStatistics};
+    use bustools::utils::int_to_seq;
+    use bustools::io::{setup_busfile, BusReader, BusRecord};
+    use bustools::iterators::{CbUmiGroupIterator, CellGroupIterator};
 
     #[test]
     fn test_inspect() {
@@ -84,10 +224,83 @@ mod testing {
 
         let (busname, _dir) = setup_busfile(&records);
 
-        let r = _inspect(&busname);
+        let r = bus_statistics(&busname);
         assert_eq!(
             r,
             BusStatistics {cb_len: 16, umi_len: 12, nrecords: 7, nreads: 34, n_cells: 4, n_cbumi: 6 }
         );
     }
+
+    #[test]
+    fn test_bus_statistics_single_pass() {
+        // 20 cells with 5 UMIs each, 2 records per UMI
+        let records: Vec<BusRecord> = (0..200_u64)
+            .map(|i| BusRecord { CB: i / 10, UMI: (i % 10) / 2, EC: (i % 2) as u32, COUNT: 1 + (i % 3) as u32, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+
+        let stats = bus_statistics(&busname);
+        assert_eq!((stats.nrecords, stats.n_cells, stats.n_cbumi), (200, 20, 100));
+        assert_eq!(stats.nreads, records.iter().map(|r| r.COUNT as usize).sum::<usize>());
+        // same as grouping
+        assert_eq!(stats.n_cells, BusReader::new(&busname).groupby_cb().count());
+        assert_eq!(stats.n_cbumi, BusReader::new(&busname).groupby_cbumi().count());
+    }
+
+    #[test]
+    #[should_panic(expected = "not sorted by CB/UMI")]
+    fn test_bus_statistics_unsorted() {
+        let records = vec![
+            BusRecord { CB: 1, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+        bus_statistics(&busname);
+    }
+
+    #[test]
+    fn test_diff_stats() {
+        let records = vec![
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 12, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 2, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+
+        let diff = diff_stats(&busname, &busname);
+        assert!(diff.is_zero());
+        assert!(diff.deltas().iter().all(|(_, _, _, d)| *d == 0));
+        assert_eq!(
+            diff.to_json(),
+            "{\"records\":{\"a\":2,\"b\":2,\"delta\":0},\"reads\":{\"a\":13,\"b\":13,\"delta\":0},\"cells\":{\"a\":2,\"b\":2,\"delta\":0},\"cb_umis\":{\"a\":2,\"b\":2,\"delta\":0}}"
+        );
+
+        // one cell less
+        let (othername, _otherdir) = setup_busfile(&records[..1].to_vec());
+        let diff = diff_stats(&busname, &othername);
+        assert!(!diff.is_zero());
+        assert_eq!(diff.deltas()[1], ("reads", 13, 12, -1));
+        assert_eq!(diff.deltas()[2], ("cells", 2, 1, -1));
+    }
+
+    #[test]
+    fn test_peek() {
+        let records: Vec<BusRecord> = (0..10)
+            .map(|i| BusRecord { CB: i, UMI: 10 - i, EC: i as u32, COUNT: 1, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+        let decode = |r: &BusRecord| (int_to_seq(r.CB, 16), int_to_seq(r.UMI, 12), r.EC, r.COUNT, r.FLAG);
+
+        let (head, tail) = peek(&busname, 3, 2);
+        assert_eq!(head, records[..3].iter().map(decode).collect::<Vec<_>>());
+        assert_eq!(tail, records[8..].iter().map(decode).collect::<Vec<_>>());
+
+        // more than in the file
+        let (head, tail) = peek(&busname, 20, 20);
+        assert_eq!(head.len(), 10);
+        assert_eq!(tail, head);
+
+        // tail overlapping with the head
+        let (_head, tail) = peek(&busname, 9, 3);
+        assert_eq!(tail, records[7..].iter().map(decode).collect::<Vec<_>>());
+    }
 }
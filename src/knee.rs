@@ -0,0 +1,180 @@
+//! Barcode rank ("knee") plots for cell calling
+//!
+//! Barcodes are ranked by their number of UMIs (descending). Plotting rank vs #UMIs on a log-log scale
+//! usually shows a plateau of real cells, followed by a sharp drop (the knee) into empty droplets.
+//!
+//! # Example
+//! ```rust, no_run
+//! # use bustools_cli::knee::{barcode_rank, knee_point};
+//! let ranks = barcode_rank("/path/to/output.corrected.sort.bus");
+//! let ncells = knee_point(&ranks);
+//! println!("top barcode {:?}, suggesting {} cells", ranks[0], ncells);
+//! ```
+//!
+//! [call_cells] returns the called barcodes directly, either at the knee or around an expected number of cells.
+use bustools::{io::BusReader, iterators::CellGroupIterator, utils::int_to_seq};
+use itertools::Itertools;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+/// All barcodes of the (sorted) `busfile` with their number of UMIs, sorted by #UMIs (descending)
+pub fn barcode_rank(busfile: &str) -> Vec<(String, usize)> {
+    let reader = BusReader::new(busfile);
+    let cb_len = reader.get_params().cb_len as usize;
+
+    let mut ranks: Vec<(String, usize)> = reader
+        .groupby_cb()
+        .map(|(cb, records)| {
+            let n_umis = records.iter().map(|r| r.UMI).unique().count();
+            (int_to_seq(cb, cb_len), n_umis)
+        })
+        .collect();
+    // ties broken by barcode, to keep the output deterministic
+    ranks.sort_by(|(cb1, n1), (cb2, n2)| n2.cmp(n1).then(cb1.cmp(cb2)));
+    ranks
+}
+
+/// Suggests the number of cells from a barcode rank curve (as returned by [barcode_rank])
+///
+/// In log-log space, draws a line from the first to the last barcode and picks the barcode
+/// furthest above that line as the knee. All barcodes up to (and including) the knee are considered cells.
+pub fn knee_point(ranks: &[(String, usize)]) -> usize {
+    if ranks.len() < 3 {
+        return ranks.len();
+    }
+    let points: Vec<(f64, f64)> = ranks
+        .iter()
+        .enumerate()
+        .map(|(i, (_cb, n))| (((i + 1) as f64).log10(), (*n as f64).log10()))
+        .collect();
+
+    let (x0, y0) = points[0];
+    let (x1, y1) = points[points.len() - 1];
+    let slope = (y1 - y0) / (x1 - x0);
+
+    // vertical distance above the line; proportional to the perpendicular distance
+    let (knee, _dist) = points
+        .iter()
+        .enumerate()
+        .map(|(i, (x, y))| (i, y - (y0 + slope * (x - x0))))
+        .fold((0, f64::MIN), |acc, el| if el.1 > acc.1 { el } else { acc });
+    knee + 1
+}
+
+/// Calls cells in the (sorted) `busfile`, returning their barcodes (by decreasing #UMIs), e.g. as a whitelist for `count --cells`.
+///
+/// * `expected`: `None` calls all barcodes up to the knee of the rank curve (see [knee_point]).
+///   With `Some(n)`, the 99th percentile of #UMIs among the top `n` barcodes is taken as a typical cell,
+///   and all barcodes within an order of magnitude of it are called (as CellRanger's `--expect-cells`).
+///   Hence the result is only roughly `n` cells, following the data rather than `n`
+pub fn call_cells(busfile: &str, expected: Option<usize>) -> Vec<String> {
+    let ranks = barcode_rank(busfile);
+    let ncells = match expected {
+        None => knee_point(&ranks),
+        Some(n) => {
+            let top = &ranks[..n.min(ranks.len())];
+            if top.is_empty() {
+                return Vec::new();
+            }
+            // ranks are sorted descending: the 99th percentile is near the front
+            let typical = top[(top.len() - 1) / 100].1 as f64;
+            ranks.iter().take_while(|(_cb, n_umis)| *n_umis as f64 >= typical / 10.0).count()
+        }
+    };
+    ranks.into_iter().take(ncells).map(|(cb, _n_umis)| cb).collect()
+}
+
+/// Write the barcode rank table of `busfile` as csv (rank, barcode, #UMIs, cumulative #UMIs) into `output`
+///
+/// Returns the suggested number of cells, see [knee_point]
+pub fn write_barcode_rank(busfile: &str, output: &str) -> usize {
+    let ranks = barcode_rank(busfile);
+
+    let mut writer = BufWriter::new(File::create(output).unwrap());
+    writeln!(writer, "rank,barcode,n_umis,cumulative_umis").unwrap();
+    let mut cumulative = 0;
+    for (i, (cb, n_umis)) in ranks.iter().enumerate() {
+        cumulative += n_umis;
+        writeln!(writer, "{},{},{},{}", i + 1, cb, n_umis, cumulative).unwrap();
+    }
+    knee_point(&ranks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{barcode_rank, call_cells, knee_point, write_barcode_rank};
+    use bustools::io::{setup_busfile, BusRecord};
+
+    #[test]
+    fn test_barcode_rank() {
+        let records = vec![
+            BusRecord { CB: 0, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 0, EC: 1, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 2, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+        let ranks = barcode_rank(&busname);
+        assert_eq!(
+            ranks,
+            vec![
+                ("AAAAAAAAAAAAAAAC".to_string(), 2),
+                ("AAAAAAAAAAAAAAAA".to_string(), 1),
+                ("AAAAAAAAAAAAAAAG".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_knee_bimodal() {
+        // 50 cells with 100 UMIs, 500 empty droplets with 2 UMIs
+        let mut records = Vec::new();
+        for cb in 0..550 {
+            let n_umis = if cb < 50 { 100 } else { 2 };
+            for umi in 0..n_umis {
+                records.push(BusRecord { CB: cb, UMI: umi, EC: 0, COUNT: 1, FLAG: 0 });
+            }
+        }
+        let (busname, _dir) = setup_busfile(&records);
+
+        let ranks = barcode_rank(&busname);
+        assert_eq!(ranks.len(), 550);
+        assert_eq!(knee_point(&ranks), 50);
+
+        let outpath = _dir.path().join("knee.csv");
+        let ncells = write_barcode_rank(&busname, outpath.to_str().unwrap());
+        assert_eq!(ncells, 50);
+
+        let csv = std::fs::read_to_string(outpath).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 551);
+        assert_eq!(lines[1], "1,AAAAAAAAAAAAAAAA,100,100");
+        assert_eq!(lines[550].rsplit(',').next().unwrap(), "6000");
+    }
+
+    #[test]
+    fn test_call_cells() {
+        // 50 cells with 80-129 UMIs, 500 empty droplets with 1-3 UMIs
+        let mut records = Vec::new();
+        for cb in 0..550 {
+            let n_umis = if cb < 50 { 80 + cb } else { 1 + cb % 3 };
+            for umi in 0..n_umis {
+                records.push(BusRecord { CB: cb, UMI: umi, EC: 0, COUNT: 1, FLAG: 0 });
+            }
+        }
+        let (busname, _dir) = setup_busfile(&records);
+
+        let knee = call_cells(&busname, None);
+        assert_eq!(knee.len(), 50);
+        // the largest cell comes first
+        assert_eq!(knee[0], bustools::utils::int_to_seq(49, 16));
+
+        // the expected number is only a hint
+        assert_eq!(call_cells(&busname, Some(40)).len(), 50);
+        assert_eq!(call_cells(&busname, Some(60)).len(), 50);
+        assert_eq!(call_cells(&busname, Some(0)).len(), 0);
+    }
+}
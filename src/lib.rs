@@ -2,13 +2,30 @@
 //! in particular the [count], [count2] and [butterfly] modules.
 //!
 #![deny(missing_docs)]
+pub mod api;
 pub mod busmerger;
+pub mod collapse_umi;
+pub mod compress;
+pub mod compact_ecs;
 pub mod concat;
 pub mod butterfly;
+#[cfg(feature = "hdf5")]
+pub mod cellranger;
 pub mod correct;
 pub mod count;
 pub mod count2;
 pub mod countmatrix;
+pub mod downsample;
+pub mod export;
+pub mod getcb;
+pub mod histogram;
 pub mod inspect;
+pub mod knee;
+pub mod merge;
+pub mod metrics;
 pub mod sort;
-pub mod multinomial;
\ No newline at end of file
+pub mod split;
+pub mod subset;
+pub mod multinomial;
+pub mod util;
+pub mod validate;
\ No newline at end of file
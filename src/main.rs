@@ -11,27 +11,69 @@
 //! * `sort`: Sort the busfile by CB/UMI/EC
 //! * `count`: Create a count-matrix (CB vs gene)
 //! * `inspect`: Basic stats about a busfile (#records, #CBs etc..)
+//! * `diff-stats`: Compare the `inspect` stats of two busfiles
+//! * `matrix-diff`: Compare two count matrices
+//! * `downsample`: Subsample the reads of a busfile
+//! * `sample`: Subsample the molecules (CB/UMIs) of a busfile, e.g. for test fixtures
+//! * `count-bayesian`: Posterior samples of the count-matrix
+//! * `knee`: Barcode rank table and suggested number of cells
+//! * `call-cells`: Called barcodes (at the knee or around `--expected-cells`), usable as `count --cells`
+//! * `collapse-umi`: Merge UMIs with sequencing errors within a cell
+//! * `count-hist`: Distribution of COUNT across records
+//! * `metrics`: Per-cell QC (nUMI, nGene)
+//! * `subset`: Keep only the records of some ECs
+//! * `split`: Shard a busfile by CB
+//! * `validate`: Check that t2g and the EC matrix/transcripts of a busfolder match
+//! * `view`: Dump the records (decoded CB/UMI) as TSV or JSON Lines
 //!
 //! Check the CLI help for arguments.
 //!
-use bustools::busz::{BuszReader, BuszWriter};
-use bustools::consistent_genes::{MappingMode, InconsistentResolution, GeneId, Genename, EC};
-use bustools::io::{BusFolder, BusReader, BusReaderPlain, BusWriterPlain};
-use bustools::iterators::CellGroupIterator;
-use bustools::utils::int_to_seq;
-use bustools_cli::concat::concat_bus;
-use clap::{self, Args, Parser, Subcommand};
+use bustools::consistent_genes::{MappingMode, InconsistentResolution, EC};
+use bustools::io::BusFolder;
+use bustools::utils::seq_to_int;
+use bustools_cli::concat::concat_bus_streaming;
+use clap::{self, error::ErrorKind, Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use itertools::Itertools;
+use std::collections::HashSet;
 use std::fs::{self, File};
 use std::io::{BufWriter, Write};
 
+/// number of inconsistent CB/UMIs shown by `count2 --debug`
+const DEBUG_SAMPLES: usize = 10;
+/// number of examples per problem shown by `validate`
+const VALIDATE_EXAMPLES: usize = 5;
+/// number of differing entries shown by `matrix-diff`
+const MATRIX_DIFF_EXAMPLES: usize = 10;
+
+/// `--version`: also the linked `bustools` crate (see build.rs) and the busz format it reads/writes
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\nbustools crate ",
+    env!("BUSTOOLS_VERSION"),
+    "\nbusz format version 1 (https://github.com/BUStools/BUSZ-format)"
+);
+
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, long_version = LONG_VERSION, about, long_about = None)]
 struct Cli {
     /// Path to output file
     #[clap(short = 'o', long = "output")]
     output: String,
 
+    /// Don't show progress bars (e.g. for logs of non-interactive runs)
+    #[clap(long = "no-progress", visible_alias = "quiet", alias = "progress-off", global = true)]
+    no_progress: bool,
+
+    /// Only process the first N records of the busfile (`count`, `count2`, `count-bayesian`, `inspect`, `butterfly`), for a quick preview.
+    /// The last cell is likely cut off, and on an unsorted busfile the results are meaningless
+    #[clap(long = "limit", alias = "max-records", global = true)]
+    limit: Option<usize>,
+
+    /// Write buffer of output busfiles in MB (any subcommand writing uncompressed busfiles). Defaults to 0.8MB;
+    /// a larger buffer speeds up writing to network filesystems
+    #[clap(long = "write-buffer-mb", global = true)]
+    write_buffer_mb: Option<usize>,
+
     #[clap(subcommand)]
     command: MyCommand,
 }
@@ -44,6 +86,8 @@ enum MyCommand {
     count2(CountArgs),
     resolve_ec(ResolveArgs),
     inspect(InspectArgs),
+    diff_stats(DiffStatsArgs),
+    matrix_diff(MatrixDiffArgs),
     sort(SortArgs),
     getcb(GetCBArgs),
     butterfly(ButterflyArgs),
@@ -51,6 +95,18 @@ enum MyCommand {
     compress(CompressArgs),
     decompress(DecompressArgs),
     concat(ConcatArgs),
+    downsample(DownsampleArgs),
+    sample(SampleArgs),
+    count_bayesian(CountBayesianArgs),
+    knee(KneeArgs),
+    call_cells(CallCellsArgs),
+    collapse_umi(CollapseUmiArgs),
+    count_hist(CountHistArgs),
+    metrics(MetricsArgs),
+    subset(SubsetArgs),
+    split(SplitArgs),
+    validate(ValidateArgs),
+    view(ViewArgs),
 }
 
 /// compress a busfile
@@ -63,6 +119,18 @@ struct CompressArgs {
     /// Number of rows to compress as a single block.
     #[clap(long = "chunk-size", short='N')]
     chunksize: usize,
+
+    /// Report progress/throughput while compressing
+    #[clap(long = "progress")]
+    progress: bool,
+
+    /// After compressing, check that the busz decompresses into the original records
+    #[clap(long = "verify")]
+    verify: bool,
+
+    /// Compression codec; busz+zstd is smaller but slower
+    #[clap(long = "codec", value_enum, default_value_t = Codec::Busz)]
+    codec: Codec,
 }
 
 /// Decompress a busfile
@@ -71,6 +139,10 @@ struct DecompressArgs {
     /// Input: compressed busfile
     #[clap(long = "input", short = 'i')]
     input: String,
+
+    /// Report progress/throughput while decompressing
+    #[clap(long = "progress")]
+    progress: bool,
 }
 
 
@@ -78,58 +150,298 @@ struct DecompressArgs {
 #[derive(Args)]
 struct CorrectArgs {
     /// Input busfile
-    #[clap(long = "ifile", short = 'i')]
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
     inbus: String,
 
-    /// Cell Barcode Whitelist
-    #[clap(long = "whitelist")]
-    whitelist: String,
+    /// Cell Barcode Whitelist(s), merged into a single whitelist
+    #[clap(long = "whitelist", num_args = 1.., required_unless_present = "map")]
+    whitelist: Vec<String>,
+
+    /// Apply a precomputed uncorrected->corrected barcode mapping (as written by `--dump-map`) instead of using a whitelist
+    #[clap(long = "map", conflicts_with_all = ["whitelist", "dry_run", "dump_map"])]
+    map: Option<String>,
+
+    /// Correct CB by CB without collecting all CBs first (bounded memory). Requires a CB-sorted busfile
+    #[clap(long = "streaming", conflicts_with_all = ["map", "dry_run", "dump_map"])]
+    streaming: bool,
+
+    /// Only report how many CBs would be corrected, dont write the output busfile
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+
+    /// Save the uncorrected->corrected barcode mapping to this file (TSV)
+    #[clap(long = "dump-map")]
+    dump_map: Option<String>,
+
+    /// What to do with barcodes equally close to several whitelisted barcodes
+    #[clap(long = "ambiguity", value_enum, default_value_t = AmbiguityPolicy::Drop)]
+    ambiguity: AmbiguityPolicy,
+
+    /// Abundance of the whitelisted barcodes (barcode and count per line), required by `--ambiguity most-abundant`
+    #[clap(long = "abundance", required_if_eq("ambiguity", "most-abundant"))]
+    abundance: Option<String>,
+
+    /// Write the records with uncorrectable barcodes (dropped from the output) to this busfile, unmodified
+    #[clap(long = "uncorrected-out", conflicts_with_all = ["streaming", "dry_run"])]
+    uncorrected_out: Option<String>,
 }
 
 /// Buttefly/ amplification profile
 #[derive(Args)]
 struct ButterflyArgs {
-    /// input busfolder
-    #[clap(long = "ifile", short = 'i')]
+    /// input busfolder, or a busfile with matrix.ec/transcripts.txt next to it
+    #[clap(long = "ifile", alias = "ifolder", short = 'i')]
     inbus: String,
-    /// Transcript-to-gene file
+    /// Transcript-to-gene file, optionally gzipped (.gz)
     #[clap(long = "t2g")]
     t2g: String,
     /// CB-UMI entries with multiple ECs will be collapsed into a single record (if they are consistent with a single gene)
     #[clap(long = "collapse")]
     collapse_ec: bool,
+    /// Exit with an error if the fraction of single-copy molecules (FSCM) exceeds this value, i.e. the library is under-sequenced.
+    /// The histogram is written regardless
+    #[clap(long = "max-fscm")]
+    max_fscm: Option<f64>,
+    /// If the output exists already, merge the histogram into it instead of overwriting, e.g. to accumulate over chunks
+    #[clap(long = "append")]
+    append: bool,
+    /// One histogram per FLAG value, written to <output>.flag_<n>.csv (`.csv` of the output is stripped)
+    #[clap(long = "by-flag")]
+    by_flag: bool,
+    /// Ignore molecules with fewer reads, e.g. spurious single reads from index hopping
+    #[clap(long = "min-reads", default_value_t = 1)]
+    min_reads: usize,
 }
 
 /// Sort busfile by CB/UMI/EC
 #[derive(Args)]
 struct SortArgs {
-    /// input busfolder
-    #[clap(long = "ifile", short = 'i')]
+    /// input busfile (or busfolder)
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
     inbus: String,
+
+    /// Aggregate records with the same CB/UMI/EC even if their FLAG differs (FLAG is set to 0)
+    #[clap(long = "ignore-flag")]
+    ignore_flag: bool,
+
+    /// Only check if the busfile is sorted (exit code 1 if not), nothing is written
+    #[clap(long = "check")]
+    check: bool,
+
+    /// Keep records with identical CB/UMI/EC/FLAG as separate records (in input order) instead of summing their COUNT
+    #[clap(long = "no-aggregate")]
+    no_aggregate: bool,
+
+    /// Order of the sort keys (also used by `--check`). Most other subcommands expect cb-umi-ec
+    #[clap(long = "sort-by", value_enum, default_value_t = SortKey::CbUmiEc)]
+    sort_by: SortKey,
+
+    /// Number of records sorted in memory at once (10M records are roughly 300MB on disk).
+    /// Smaller files are sorted entirely in memory, without temporary files
+    #[clap(long = "chunksize", default_value_t = 10_000_000)]
+    chunksize: usize,
 }
 
 /// count the mRNAs  per cell and write to file
 #[derive(Args)]
 struct GetCBArgs {
+    /// input busfile (or busfolder)
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
+    inbus: String,
+
+    /// count molecules (distinct UMIs) or reads per CB
+    #[clap(long = "mode", value_enum, default_value_t = CbCountMode::Umis)]
+    mode: CbCountMode,
+}
+
+/// barcode rank table (knee plot) of a busfile, written as csv
+#[derive(Args)]
+struct KneeArgs {
+    /// input busfile, sorted
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
+    inbus: String,
+}
+
+/// call cells and write their barcodes (one per line), e.g. for `count --cells`
+#[derive(Args)]
+struct CallCellsArgs {
+    /// input busfile, sorted
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
+    inbus: String,
+
+    /// Expected number of cells; cells are called around this number instead of at the knee of the barcode rank curve
+    #[clap(long = "expected-cells")]
+    expected_cells: Option<usize>,
+}
+
+/// distribution of COUNT across all records, written as csv (count,frequency)
+#[derive(Args)]
+struct CountHistArgs {
+    /// input busfile
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
+    inbus: String,
+}
+
+/// per-cell QC metrics, written as csv (barcode,nUMI,nGene)
+#[derive(Args)]
+struct MetricsArgs {
+    /// input busfolder
+    #[clap(long = "ifolder")]
+    inbus: String,
+
+    /// Transcript-to-gene file, optionally gzipped (.gz)
+    #[clap(long = "t2g")]
+    t2g: String,
+}
+
+/// check t2g against the ECs/transcripts of a busfolder (exit code 1 if there are problems)
+#[derive(Args)]
+struct ValidateArgs {
     /// input busfolder
-    #[clap(long = "ifile", short = 'i')]
+    #[clap(long = "ifolder")]
+    inbus: String,
+
+    /// Transcript-to-gene file, optionally gzipped (.gz)
+    #[clap(long = "t2g")]
+    t2g: String,
+}
+
+/// keep only the records with the given ECs
+#[derive(Args)]
+struct SubsetArgs {
+    /// input busfile
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
+    inbus: String,
+
+    /// EC to keep (can be given multiple times)
+    #[clap(long = "ec", required_unless_present = "ec_file")]
+    ec: Vec<u32>,
+
+    /// File with ECs to keep, one per line
+    #[clap(long = "ec-file")]
+    ec_file: Option<String>,
+}
+
+/// shard a busfile by CB into <output>.0.bus ... <output>.{n-1}.bus
+#[derive(Args)]
+struct SplitArgs {
+    /// input busfile
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
     inbus: String,
+
+    /// number of shards
+    #[clap(long = "shards", short = 'n')]
+    n_shards: usize,
 }
 
 /// countmatrix from busfile
 #[derive(Args)]
 struct CountArgs {
+    /// input busfolder. Can be given multiple times to sum the counts of several busfolders (e.g. runs of the same sample).
+    /// Multiple folders are only supported by `count`, without `--cells`/`--split-by-flag`
+    #[clap(long = "ifolder", required = true)]
+    inbus: Vec<String>,
+
+    /// Transcript-to-gene file, optionally gzipped (.gz)
+    #[clap(long = "t2g")]
+    t2g: String,
+
+    /// ignore multimapped busrecords (same CB/UMI but different EC)
+    #[clap(long = "ignoremm")]
+    ignoremm: bool,
+
+    /// Only count the barcodes in this file (one CB per line), e.g. the called cells.
+    /// Only supported by `count`
+    #[clap(long = "cells")]
+    cells: Option<String>,
+
+    /// Prefix of the output files (<prefix>.mtx, <prefix>.barcodes.txt, <prefix>.genes.txt)
+    #[clap(long = "output-prefix", default_value = "gene")]
+    output_prefix: String,
+
+    /// Format of the count matrix
+    #[clap(long = "format", alias = "output-format", value_enum, default_value_t = MatrixFormat::MatrixMarket)]
+    format: MatrixFormat,
+
+    /// Gzip the count matrix files (<prefix>.mtx.gz, <prefix>.barcodes.txt.gz, <prefix>.genes.txt.gz).
+    /// Only with `--format matrix-market`
+    #[clap(long = "gzip")]
+    gzip: bool,
+
+    /// Order of the MatrixMarket entries: cell by cell (csr, default) or gene by gene (csc).
+    /// Only with `--format matrix-market`
+    #[clap(long = "layout", value_enum)]
+    layout: Option<Layout>,
+
+    /// Count each FLAG value separately, into <output>/flag_<n>/.
+    /// Only supported by `count`
+    #[clap(long = "split-by-flag")]
+    split_by_flag: bool,
+
+    /// Only count records with this FLAG (commonly 0), e.g. to exclude records flagged as low-quality
+    #[clap(long = "flag", conflicts_with = "split_by_flag")]
+    flag: Option<u32>,
+
+    /// What to do with multimapped molecules (consistent with several genes).
+    /// Only supported by `count`
+    #[clap(long = "multimap", value_enum, default_value_t = MultimapPolicy::Discard)]
+    multimap: MultimapPolicy,
+
+    /// Print some of the inconsistent CB/UMIs and the genes of their ECs, to diagnose an (almost) empty matrix.
+    /// Only supported by `count2`
+    #[clap(long = "debug")]
+    debug: bool,
+
+    /// Accumulate into a dense cells x genes matrix: faster for small gene panels, but memory grows with cells * genes.
+    /// Only supported by `count2`
+    #[clap(long = "dense", conflicts_with = "debug")]
+    dense: bool,
+
+    /// Count reads (summed COUNT of each molecule) instead of UMIs
+    #[clap(long = "reads")]
+    reads: bool,
+
+    /// Treat each record's COUNT as 1, e.g. for pre-deduplicated busfiles.
+    /// Only affects `--reads`; counting UMIs ignores COUNT anyway, so this is a no-op without `--reads`
+    #[clap(long = "collapse-reads")]
+    collapse_reads: bool,
+
+    /// Write into the output folder even if it is not empty, replacing existing files of the same name
+    #[clap(long = "overwrite")]
+    overwrite: bool,
+
+    /// Write <output>/ec_usage.tsv: for each EC, the number of counted molecules with a record of that EC.
+    /// Only supported by `count`, with a single --ifolder and without --split-by-flag
+    #[clap(long = "report-ec-usage", conflicts_with = "split_by_flag")]
+    report_ec_usage: bool,
+}
+
+/// posterior samples of the countmatrix, resampling the reads
+#[derive(Args)]
+struct CountBayesianArgs {
     /// input busfolder
     #[clap(long = "ifolder")]
     inbus: String,
 
-    /// Transcript-to-gene file
+    /// Transcript-to-gene file, optionally gzipped (.gz)
     #[clap(long = "t2g")]
     t2g: String,
 
     /// ignore multimapped busrecords (same CB/UMI but different EC)
     #[clap(long = "ignoremm")]
     ignoremm: bool,
+
+    /// Number of posterior samples
+    #[clap(long = "samples", short = 'n')]
+    n_samples: usize,
+
+    /// Seed for the random number generator
+    #[clap(long = "seed", default_value_t = 42)]
+    seed: u64,
+
+    /// Write into the output folder even if it is not empty, replacing existing files of the same name
+    #[clap(long = "overwrite")]
+    overwrite: bool,
 }
 
 /// find overlap between busfiles and write out overlapping molecules
@@ -148,6 +460,10 @@ struct BusMergeArgs {
     /// 2nd output busfile
     #[clap(long = "o2")]
     outbus2: String,
+
+    /// Write CB/UMIs present in both files (intersection) or in either file (union)
+    #[clap(long = "mode", value_enum, default_value_t = MergeMode::Intersection)]
+    mode: MergeMode,
 }
 
 /// resovle an EC into gene names
@@ -157,20 +473,68 @@ struct ResolveArgs {
     #[clap(long = "ifolder")]
     inbus: String,
     #[clap(long = "t2g")]
-    /// Transcript-to-gene file
+    /// Transcript-to-gene file, optionally gzipped (.gz)
     t2g: String,
 
     /// Equivalence class to query genes for
-    #[clap(long = "ec")]
-    ec: u32,
+    #[clap(long = "ec", required_unless_present = "ec_file")]
+    ec: Option<u32>,
+
+    /// File with one EC per line; writes a TSV (EC, gene_ids, gene_names) to the output file
+    #[clap(long = "ec-file", conflicts_with = "ec")]
+    ec_file: Option<String>,
 }
 
 /// Inspect busfile for stats
 #[derive(Args)]
 struct InspectArgs {
-    /// input busfolder
-    #[clap(short = 'i', long = "input")]
+    /// input busfile (or busfolder)
+    #[clap(short = 'i', long = "input", value_parser = parse_busfile)]
     inbus: String,
+
+    /// Also print the first N records (decoded)
+    #[clap(long = "head", default_value_t = 0)]
+    head: usize,
+
+    /// Also print the last N records (decoded)
+    #[clap(long = "tail", default_value_t = 0)]
+    tail: usize,
+
+    /// First check that all CBs/UMIs fit into cb_len/umi_len of the header; exit code 1 otherwise (corrupt file)
+    #[clap(long = "strict")]
+    strict: bool,
+}
+
+/// Compare the stats (as in inspect) of two busfiles
+#[derive(Args)]
+struct DiffStatsArgs {
+    /// first busfile
+    #[clap(short = 'a')]
+    a: String,
+
+    /// second busfile
+    #[clap(short = 'b')]
+    b: String,
+
+    /// print the diff as json instead of a table
+    #[clap(long = "json")]
+    json: bool,
+}
+
+/// Compare two count matrices (barcodes, genes, counts); exit code 1 if they differ
+#[derive(Args)]
+struct MatrixDiffArgs {
+    /// first count folder
+    #[clap(short = 'a')]
+    a: String,
+
+    /// second count folder
+    #[clap(short = 'b')]
+    b: String,
+
+    /// prefix of the matrix files, as in `count --output-prefix`
+    #[clap(long = "prefix", default_value = "gene")]
+    prefix: String,
 }
 
 
@@ -181,19 +545,194 @@ struct ConcatArgs {
     /// Input busfiles 
     #[clap(long = "files", short = 'i', num_args = 1..)]
     inbus: Vec<String>,
+
+    /// Maximum number of input files open at the same time.
+    /// With more inputs, files are concatenated in groups via temporary files
+    #[clap(long = "max-open-files", default_value_t = 256)]
+    max_open_files: usize,
 }
 
+/// Collapse UMIs with sequencing errors (directional, as in UMI-tools)
+#[derive(Args)]
+struct CollapseUmiArgs {
+    /// Input busfile, sorted
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
+    inbus: String,
+
+    /// Maximum Hamming distance of UMIs to be collapsed
+    #[clap(long = "max-dist", default_value_t = 1)]
+    max_dist: isize,
+}
+
+/// Subsample the reads of a busfile to a target depth (or a fraction of reads)
+#[derive(Args)]
+struct DownsampleArgs {
+    /// Input busfile
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
+    inbus: String,
+
+    /// Total number of reads after downsampling
+    #[clap(long = "target-reads", required_unless_present = "fraction")]
+    target_reads: Option<u64>,
 
-use bustools_cli::busmerger;
+    /// Fraction of reads to keep (0, 1]
+    #[clap(long = "fraction", conflicts_with = "target_reads")]
+    fraction: Option<f64>,
+
+    /// Seed for the random number generator
+    #[clap(long = "seed", default_value_t = 42)]
+    seed: u64,
+}
+
+/// Subsample the molecules (CB/UMIs, with all their records) of a sorted busfile, e.g. for small test fixtures
+#[derive(Args)]
+struct SampleArgs {
+    /// Input busfile, sorted
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
+    inbus: String,
+
+    /// Number of CB/UMIs to keep
+    #[clap(long = "molecules", short = 'n')]
+    n_molecules: usize,
+
+    /// Seed for the random number generator
+    #[clap(long = "seed", default_value_t = 42)]
+    seed: u64,
+}
+
+/// Dump the records of a busfile as text, with CB/UMI decoded into sequences
+#[derive(Args)]
+struct ViewArgs {
+    /// Input busfile
+    #[clap(long = "ifile", short = 'i', value_parser = parse_busfile)]
+    inbus: String,
+
+    /// Write JSON Lines (one object per record) instead of TSV
+    #[clap(long = "jsonl")]
+    jsonl: bool,
+}
+
+use bustools_cli::busmerger::{self, MergeMode};
+use bustools_cli::collapse_umi;
+use bustools_cli::compress::{compress_busfile, decompress_busfile, verify_compression, Codec};
+use bustools_cli::downsample;
+use bustools_cli::export;
+use bustools_cli::getcb::{self, CbCountMode};
+use bustools_cli::histogram;
 use bustools_cli::butterfly;
-use bustools_cli::correct;
-use bustools_cli::count;
+use bustools_cli::correct::{self, AmbiguityPolicy};
+use bustools_cli::count::{self, CountMode, MultimapPolicy};
 use bustools_cli::count2;
+use bustools_cli::countmatrix::{self, CountMatrix, Layout, MatrixFormat};
 use bustools_cli::inspect;
-use bustools_cli::sort;
+use bustools_cli::knee;
+use bustools_cli::metrics;
+use bustools_cli::sort::{self, SortKey};
+use bustools_cli::split;
+use bustools_cli::subset;
+use bustools_cli::util;
+use bustools_cli::validate;
+
+/// read ECs (one per line) from a file, exits on unparsable lines
+fn read_ec_file(ec_file: &str) -> Vec<u32> {
+    fs::read_to_string(ec_file)
+        .unwrap()
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            line.trim().parse::<u32>().unwrap_or_else(|_| {
+                eprintln!("cant parse EC {} in {}", line, ec_file);
+                std::process::exit(1);
+            })
+        })
+        .collect()
+}
+
+/// write the count matrix, exiting on errors
+fn write_matrix(c: &CountMatrix, folder: &str, prefix: &str, format: MatrixFormat, gzip: bool, layout: Layout) {
+    let written = if format == MatrixFormat::MatrixMarket {
+        c.write_with_layout(folder, prefix, layout, gzip)
+    } else {
+        c.write_format_with_prefix(folder, prefix, format)
+    };
+    written.unwrap_or_else(|e| {
+        match e {
+            countmatrix::CountMatrixError::TooLarge { .. } => eprintln!("{}, use --format matrix-market", e),
+            _ => eprintln!("{}", e),
+        }
+        std::process::exit(1);
+    });
+}
+
+/// `--gzip`/`--layout` only apply to MatrixMarket output: reject them with any other `--format`, as a usage error
+fn check_matrix_market_flags(args: &CountArgs) {
+    if args.format == MatrixFormat::MatrixMarket {
+        return;
+    }
+    for (flag, given) in [("--gzip", args.gzip), ("--layout", args.layout.is_some())] {
+        if given {
+            let format = args.format.to_possible_value().unwrap();
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("{} is only supported with --format matrix-market, not --format {}", flag, format.get_name()),
+                )
+                .exit();
+        }
+    }
+}
+
+/// `--limit` only applies to the subcommands reading via [util::read_busfile]: reject it with any other subcommand, as a usage error
+fn check_limit_flag(cli: &Cli) {
+    let supported = matches!(
+        cli.command,
+        MyCommand::count(_) | MyCommand::count2(_) | MyCommand::count_bayesian(_) | MyCommand::inspect(_) | MyCommand::butterfly(_)
+    );
+    if cli.limit.is_some() && !supported {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--limit is only supported by count, count2, count-bayesian, inspect and butterfly",
+            )
+            .exit();
+    }
+}
+
+/// write `count`'s [count::CountStats], exiting on errors
+fn write_count_stats(stats: &count::CountStats, fname: &str) {
+    stats.to_disk(fname).unwrap_or_else(|e| {
+        eprintln!("cant write {}: {}", fname, e);
+        std::process::exit(1);
+    });
+}
+
+/// `--ifile`: either a busfile or a busfolder (using its standard busfile)
+fn parse_busfile(path: &str) -> Result<String, String> {
+    Ok(util::busfile_from_path(path))
+}
+
+/// output folder of `count`/`count2`/`count-bayesian`, see [util::prepare_output_dir]
+fn prepare_output_dir_or_exit(output: &str, overwrite: bool) {
+    if let Err(e) = util::prepare_output_dir(output, overwrite) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// user input errors (e.g. a t2g not matching the busfolder): report them without a panic/backtrace
+fn unwrap_or_exit<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
 
 fn main() {
     let cli = Cli::parse();
+    check_limit_flag(&cli);
+    util::set_progress_enabled(!cli.no_progress);
+    util::set_record_limit(cli.limit);
+    util::set_write_buffer_size(cli.write_buffer_mb.map(|mb| mb * 1024 * 1024));
     match cli.command {
         MyCommand::busmerge(args) => {
             println!("Doing bus merging");
@@ -202,130 +741,384 @@ fn main() {
                 &args.inbus2,
                 &args.outbus1,
                 &args.outbus2,
+                args.mode,
             )
         }
         MyCommand::count(args) => {
+            check_matrix_market_flags(&args);
             println!("Doing count");
+            if args.debug || args.dense {
+                eprintln!("--debug/--dense are not supported by count, use count2");
+                std::process::exit(1);
+            }
+
+            if args.inbus.len() > 1 && (args.cells.is_some() || args.split_by_flag || args.report_ec_usage) {
+                eprintln!("--cells/--split-by-flag/--report-ec-usage are not supported with multiple --ifolder");
+                std::process::exit(1);
+            }
 
-            fs::create_dir(&cli.output).unwrap();
+            prepare_output_dir_or_exit(&cli.output, args.overwrite);
             
-           
-            let bfolder = BusFolder::new(&args.inbus);
-            let ecmapper = bfolder.make_mapper(&args.t2g);
-            let mapping_mode = MappingMode::Gene(ecmapper, InconsistentResolution::IgnoreInconsistent);
-            let c = count::count(&bfolder,mapping_mode, args.ignoremm);
+            let count_mode = match (args.reads, args.collapse_reads) {
+                (true, false) => CountMode::Reads,
+                (true, true) => CountMode::CollapsedReads,
+                (false, _) => CountMode::Umis,
+            };
+            let cb_whitelist: Option<HashSet<u64>> = args.cells.map(|cellfile| {
+                correct::load_whitelist(&cellfile).iter().map(|cb| seq_to_int(cb)).collect()
+            });
+            let options = count::CountOptions {
+                cb_whitelist,
+                flag_filter: args.flag,
+                multimap_policy: args.multimap,
+                count_mode,
+                ec_usage: args.report_ec_usage,
+            };
+            // dont clobber the stats of other prefixes in the same folder
+            let statsfile = |folder: &str| {
+                if args.output_prefix == "gene" {
+                    format!("{}/count_stats.json", folder)
+                } else {
+                    format!("{}/{}.count_stats.json", folder, args.output_prefix)
+                }
+            };
+
+            if args.inbus.len() > 1 {
+                let folders: Vec<BusFolder> = args.inbus.iter().map(|f| util::busfolder_from_path(f)).collect();
+                let out = unwrap_or_exit(count::count_multi(&folders, &args.t2g, args.ignoremm, &options));
+                write_matrix(&out.matrix, &cli.output, &args.output_prefix, args.format, args.gzip, args.layout.unwrap_or_default());
+                write_count_stats(&out.stats, &statsfile(&cli.output));
+                return;
+            }
 
-            c.write(&cli.output);
+            let bfolder = util::busfolder_from_path(&args.inbus[0]);
+            let ecmapper = util::make_mapper_maybe_gz(&bfolder, &args.t2g);
+            let mapping_mode = MappingMode::Gene(ecmapper, InconsistentResolution::IgnoreInconsistent);
+            if args.split_by_flag {
+                let per_flag = unwrap_or_exit(count::count_split_by_flag(&bfolder, mapping_mode, args.ignoremm, &options));
+                for (flag, out) in per_flag {
+                    let folder = format!("{}/flag_{}", cli.output, flag);
+                    fs::create_dir_all(&folder).unwrap();
+                    write_matrix(&out.matrix, &folder, &args.output_prefix, args.format, args.gzip, args.layout.unwrap_or_default());
+                    write_count_stats(&out.stats, &statsfile(&folder));
+                }
+            } else {
+                let out = unwrap_or_exit(count::count(&bfolder, mapping_mode, args.ignoremm, &options));
+                write_matrix(&out.matrix, &cli.output, &args.output_prefix, args.format, args.gzip, args.layout.unwrap_or_default());
+                write_count_stats(&out.stats, &statsfile(&cli.output));
+                if let Some(ec_usage) = out.ec_usage {
+                    let fname = format!("{}/ec_usage.tsv", cli.output);
+                    if let Err(e) = count::write_ec_usage(&ec_usage, &fname) {
+                        eprintln!("cant write {}: {}", fname, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
         }
         MyCommand::count2(args) => {
+            check_matrix_market_flags(&args);
             println!("Doing count");
-            fs::create_dir(&cli.output).unwrap();
+            if args.cells.is_some() {
+                eprintln!("--cells is not supported by count2, use count");
+                std::process::exit(1);
+            }
+            if args.split_by_flag {
+                eprintln!("--split-by-flag is not supported by count2, use count");
+                std::process::exit(1);
+            }
+            if args.multimap != MultimapPolicy::Discard {
+                eprintln!("--multimap is not supported by count2, use count");
+                std::process::exit(1);
+            }
+            if args.report_ec_usage {
+                eprintln!("--report-ec-usage is not supported by count2, use count");
+                std::process::exit(1);
+            }
+            if args.inbus.len() > 1 {
+                eprintln!("multiple --ifolder are not supported by count2, use count");
+                std::process::exit(1);
+            }
+            prepare_output_dir_or_exit(&cli.output, args.overwrite);
 
-            let bfolder = BusFolder::new(&args.inbus);
-            let ecmapper = bfolder.make_mapper(&args.t2g);
+            let bfolder = util::busfolder_from_path(&args.inbus[0]);
+            let ecmapper = util::make_mapper_maybe_gz(&bfolder, &args.t2g);
             let mapping_mode = MappingMode::Gene(ecmapper, InconsistentResolution::IgnoreInconsistent);
 
-            let c = count2::count(&bfolder,mapping_mode,  args.ignoremm);
-            c.write(&cli.output);
+            let count_mode = match (args.reads, args.collapse_reads) {
+                (true, false) => CountMode::Reads,
+                (true, true) => CountMode::CollapsedReads,
+                (false, _) => CountMode::Umis,
+            };
+            let debug_samples = if args.debug { DEBUG_SAMPLES } else { 0 };
+            let c = if args.dense {
+                count2::count_dense(&bfolder, mapping_mode, args.ignoremm, args.flag, count_mode)
+            } else {
+                count2::count(&bfolder,mapping_mode,  args.ignoremm, args.flag, count_mode, debug_samples)
+            };
+            write_matrix(&c, &cli.output, &args.output_prefix, args.format, args.gzip, args.layout.unwrap_or_default());
+        }
+        MyCommand::count_bayesian(args) => {
+            println!("Doing bayesian count");
+            prepare_output_dir_or_exit(&cli.output, args.overwrite);
+
+            let bfolder = util::busfolder_from_path(&args.inbus);
+            let ecmapper = util::make_mapper_maybe_gz(&bfolder, &args.t2g);
+            let mapping_mode = MappingMode::Gene(ecmapper, InconsistentResolution::IgnoreInconsistent);
+
+            let samples = count2::count_bayesian(&bfolder, mapping_mode, args.ignoremm, args.n_samples, args.seed);
+            for (i, c) in samples.iter().enumerate() {
+                let sample_folder = format!("{}/sample_{}", cli.output, i);
+                fs::create_dir_all(&sample_folder).unwrap();
+                c.write_or_panic(&sample_folder);
+            }
         }
 
         MyCommand::resolve_ec(args) => {
             println!("Doing resolve");
-            let bfolder = BusFolder::new(&args.inbus);
-            let ecmapper = bfolder.make_mapper(&args.t2g);
+            let bfolder = util::busfolder_from_path(&args.inbus);
+            let ecmapper = util::make_mapper_maybe_gz(&bfolder, &args.t2g);
 
-            let mut genes: Vec<&GeneId> = ecmapper.get_genes(EC(args.ec)).iter().collect();
-            genes.sort();
-            println!("EC {} -> {:?}", args.ec, genes);
+            let ecmatrix = bfolder.parse_ecmatrix();
 
-            let mut genenames: Vec<Genename> = ecmapper
-                .get_genenames(EC(args.ec))
-                .into_iter()
-                .collect();
-            genenames.sort();
+            let ecs: Vec<u32> = match &args.ec_file {
+                Some(ec_file) => read_ec_file(ec_file),
+                None => vec![args.ec.unwrap()],
+            };
+
+            if let Some(ec) = ecs.iter().find(|ec| !ecmatrix.contains_key(&EC(**ec))) {
+                eprintln!("EC {} not present in {}", ec, bfolder.get_ecmatrix_file());
+                std::process::exit(1);
+            }
 
-            println!("EC {} -> {:?}", args.ec, genenames);
+            if args.ec_file.is_some() {
+                let fh = File::create(cli.output).unwrap();
+                let mut writer = BufWriter::new(fh);
+                writeln!(writer, "EC\tgene_ids\tgene_names").unwrap();
+                for ec in ecs {
+                    let genes = util::resolve_ec(&ecmapper, EC(ec));
+                    writeln!(
+                        writer,
+                        "{}\t{}\t{}",
+                        ec,
+                        genes.iter().map(|(gid, _)| gid.0).join(","),
+                        genes.iter().map(|(_, name)| &name.0).join(",")
+                    )
+                    .unwrap();
+                }
+            } else {
+                let ec = ecs[0];
+                println!("EC {}", ec);
+                println!("gene_id\tgene_name");
+                for (gid, name) in util::resolve_ec(&ecmapper, EC(ec)) {
+                    println!("{}\t{}", gid.0, name.0);
+                }
+            }
+        }
+        MyCommand::diff_stats(args) => {
+            let diff = inspect::diff_stats(&args.a, &args.b);
+            if args.json {
+                println!("{}", diff.to_json());
+            } else {
+                print!("{}", diff.to_table());
+            }
+        }
+        MyCommand::matrix_diff(args) => {
+            let load = |folder: &str| {
+                CountMatrix::from_folder(folder, Some(&args.prefix)).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                })
+            };
+            let diff = load(&args.a).diff(&load(&args.b));
+            println!("barcodes only in a: {}, only in b: {}", diff.barcodes_only_a.len(), diff.barcodes_only_b.len());
+            println!("genes only in a: {}, only in b: {}", diff.genes_only_a.len(), diff.genes_only_b.len());
+            println!("differing entries: {}", diff.mismatches.len());
+            for (cb, gene, a, b) in diff.mismatches.iter().take(MATRIX_DIFF_EXAMPLES) {
+                println!("  {} {}: {} vs {}", cb, gene, a, b);
+            }
+            if !diff.is_identical() {
+                std::process::exit(1);
+            }
         }
         MyCommand::inspect(args) => {
+            if args.strict {
+                let mut checked = util::CheckedBusReader::new(&args.inbus, util::OutOfRangePolicy::Error);
+                if let Some(Err(e)) = checked.find(|r| r.is_err()) {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
             inspect::inspect(&args.inbus);
+            if args.head > 0 || args.tail > 0 {
+                inspect::print_peek(&args.inbus, args.head, args.tail);
+            }
         }
 
-        MyCommand::getcb(args) => {
-            let fh = File::create(cli.output).unwrap();
-            let mut writer = BufWriter::new(fh);
-            // let cb_len = 16;
-
-            let reader = BusReader::new(&args.inbus);
-            let params = reader.get_params();
-            let cb_len = params.cb_len as usize;
-            let bus_cb = reader
-                .groupby_cb()
-                .map(|(cb, records)| {
-                    (
-                        // CB,decoded
-                        int_to_seq(cb, cb_len),
-                        // number of UMIs
-                        records.iter().map(|r| r.UMI).unique().count(),
-                    )
-                });
-
-            for (cb, nrecords) in bus_cb {
-                writeln!(writer, "{},{}", cb, nrecords).unwrap();
+        MyCommand::collapse_umi(args) => {
+            collapse_umi::collapse_umis(&args.inbus, &cli.output, args.max_dist);
+        }
+        MyCommand::split(args) => {
+            split::split_by_cb(&args.inbus, &cli.output, args.n_shards);
+        }
+        MyCommand::subset(args) => {
+            let mut ecs: HashSet<u32> = args.ec.into_iter().collect();
+            if let Some(ec_file) = &args.ec_file {
+                ecs.extend(read_ec_file(ec_file));
+            }
+            let n = subset::subset_by_ec(&args.inbus, &cli.output, ecs);
+            println!("Kept {} records", n);
+        }
+        MyCommand::validate(args) => {
+            let bfolder = util::busfolder_from_path(&args.inbus);
+            let report = validate::validate_folder(&bfolder, &args.t2g);
+            report.print(VALIDATE_EXAMPLES);
+            if !report.is_ok() {
+                std::process::exit(1);
             }
         }
+        MyCommand::metrics(args) => {
+            let bfolder = util::busfolder_from_path(&args.inbus);
+            let ecmapper = util::make_mapper_maybe_gz(&bfolder, &args.t2g);
+            metrics::write_cell_metrics(&bfolder, &ecmapper, &cli.output);
+        }
+        MyCommand::count_hist(args) => {
+            histogram::write_count_histogram(&args.inbus, &cli.output);
+        }
+        MyCommand::knee(args) => {
+            let ncells = knee::write_barcode_rank(&args.inbus, &cli.output);
+            println!("Knee at {} cells", ncells);
+        }
+        MyCommand::call_cells(args) => {
+            let cells = knee::call_cells(&args.inbus, args.expected_cells);
+            let mut writer = BufWriter::new(File::create(&cli.output).unwrap());
+            for cb in cells.iter() {
+                writeln!(writer, "{}", cb).unwrap();
+            }
+            println!("Called {} cells", cells.len());
+        }
+        MyCommand::getcb(args) => {
+            getcb::per_cb_summary(&args.inbus, &cli.output, args.mode);
+        }
         MyCommand::sort(args) => {
-            let chunksize = 10_000_000; // roughly 300MB on disk
-            sort::sort_on_disk(&args.inbus, &cli.output, chunksize)
+            if args.check {
+                match sort::is_sorted(&args.inbus, args.sort_by) {
+                    Ok(()) => println!("{} is sorted", args.inbus),
+                    Err((pos, previous, current)) => {
+                        eprintln!("{} is not sorted: record {} {:?} after {:?}", args.inbus, pos, current, previous);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            sort::sort_on_disk(&args.inbus, &cli.output, args.chunksize, args.ignore_flag, args.no_aggregate, args.sort_by)
         }
         MyCommand::butterfly(args) => {
-            let bfolder = BusFolder::new(&args.inbus);
-            let ecmapper = bfolder.make_mapper(&args.t2g);
+            let bfolder = util::busfolder_from_path(&args.inbus);
+            let ecmapper = util::make_mapper_maybe_gz(&bfolder, &args.t2g);
             let mapping_mode =  if args.collapse_ec{
                  MappingMode::Gene(ecmapper, InconsistentResolution::IgnoreInconsistent)
             } else {
                 MappingMode::EC(InconsistentResolution::IgnoreInconsistent)
             };
 
-            let cuhist = butterfly::make_ecs(&bfolder.get_busfile(), mapping_mode);
-            cuhist.to_disk(&cli.output);
+            let histograms: Vec<(String, butterfly::CUHistogram)> = if args.by_flag {
+                let mut per_flag: Vec<_> = butterfly::make_ecs_by_flag(&bfolder.get_busfile(), mapping_mode, args.min_reads)
+                    .into_iter()
+                    .collect();
+                per_flag.sort_by_key(|(flag, _)| *flag);
+                let stem = cli.output.strip_suffix(".csv").unwrap_or(&cli.output);
+                per_flag
+                    .into_iter()
+                    .map(|(flag, h)| (format!("{}.flag_{}.csv", stem, flag), h))
+                    .collect()
+            } else {
+                vec![(cli.output.clone(), butterfly::make_ecs(&bfolder.get_busfile(), mapping_mode, args.min_reads))]
+            };
+
+            let mut fscm_exceeded = false;
+            for (fname, cuhist) in histograms {
+                if args.append {
+                    cuhist.to_disk_append(&fname).unwrap_or_else(|e| {
+                        eprintln!("cant append to {}: {}", fname, e);
+                        std::process::exit(1);
+                    });
+                } else {
+                    cuhist.to_disk(&fname);
+                }
+                if let Some(max_fscm) = args.max_fscm {
+                    let fscm = cuhist.get_fscm();
+                    if fscm > max_fscm {
+                        eprintln!("{}: FSCM {:.4} exceeds --max-fscm {}: library likely under-sequenced", fname, fscm, max_fscm);
+                        fscm_exceeded = true;
+                    }
+                }
+            }
+            if fscm_exceeded {
+                std::process::exit(1);
+            }
         }
         MyCommand::correct(args) => {
-            correct::correct(&args.inbus, &cli.output, &args.whitelist);
+            let abundance = args.abundance.as_deref().map(correct::load_abundance);
+            if let Some(map_file) = args.map {
+                correct::correct_with_map(&args.inbus, &cli.output, &map_file, args.uncorrected_out.as_deref());
+            } else if args.streaming {
+                unwrap_or_exit(correct::correct_streaming(&args.inbus, &cli.output, &args.whitelist, args.ambiguity, abundance.as_ref()));
+            } else if args.dry_run {
+                let stats = unwrap_or_exit(correct::correct_dry_run(&args.inbus, &args.whitelist, args.ambiguity, abundance.as_ref()));
+                println!(
+                    "Unique CBs: whitelisted {}, corrected {}, uncorrectable {}, ambiguous {}",
+                    stats.whitelisted, stats.corrected, stats.uncorrectable, stats.ambiguous
+                );
+            } else {
+                unwrap_or_exit(correct::correct(
+                    &args.inbus,
+                    &cli.output,
+                    &args.whitelist,
+                    args.ambiguity,
+                    abundance.as_ref(),
+                    args.dump_map.as_deref(),
+                    args.uncorrected_out.as_deref(),
+                ));
+            }
         }
         MyCommand::compress(args) => {
-            compress_busfile(&args.input, &cli.output, args.chunksize);
+            compress_busfile(&args.input, &cli.output, args.chunksize, args.progress, args.codec);
+            if args.verify {
+                match verify_compression(&args.input, &cli.output) {
+                    Ok(n) => println!("Verified {} records", n),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
         },
         MyCommand::decompress(args) => {
-            decompress_busfile(&args.input, &cli.output);
+            decompress_busfile(&args.input, &cli.output, args.progress);
         },
         MyCommand::concat(args) => {
-            concat_bus(args.inbus, &cli.output)
+            if let Err(e) = concat_bus_streaming(args.inbus, &cli.output, args.max_open_files) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        MyCommand::downsample(args) => {
+            if let Some(fraction) = args.fraction {
+                downsample::downsample_fraction(&args.inbus, &cli.output, fraction, args.seed)
+            } else {
+                downsample::downsample(&args.inbus, &cli.output, args.target_reads.unwrap(), args.seed)
+            }
+        },
+        MyCommand::sample(args) => {
+            downsample::sample_molecules(&args.inbus, &cli.output, args.n_molecules, args.seed)
+        },
+        MyCommand::view(args) => {
+            if args.jsonl {
+                export::to_jsonl(&args.inbus, &cli.output)
+            } else {
+                export::to_tsv(&args.inbus, &cli.output)
+            }
         },
-    }
-}
-
-
-/// Compress `input` busfile into `output` busz-file using `blocksize`
-/// 
-/// # Parameters
-/// * blocksize: How many elements are grouped together and compressed together
-pub fn compress_busfile(input: &str, output: &str, blocksize: usize) {
-
-    let reader = BusReaderPlain::new(input);
-    let mut writer = BuszWriter::new(output, reader.params.clone(), blocksize);
-    writer.write_iterator(reader.into_iter());
-}
-
-/// Decompress the `input` busz file into a plain busfile, `output`
-pub fn decompress_busfile(input: &str, output: &str) {
-    let reader = BuszReader::new(input);
-    let mut writer = BusWriterPlain::new(
-        output,
-        reader.get_params().clone()
-    );
-
-    for r in reader {
-        writer.write_record(&r);
     }
 }
 
@@ -0,0 +1,127 @@
+//! Merging several CB/UMI sorted streams of records into a single sorted stream
+//!
+//! Shared by `sort` (merging the sorted chunks) and `concat` (merging sorted busfiles):
+//! Each input is grouped by CB/UMI (e.g. via `groupby_cbumi()`), the groups are merged via [MultiIterator],
+//! and records of a CB/UMI that show up in several inputs are put back together.
+use bustools::{io::BusRecord, merger::MultiIterator};
+use std::collections::HashMap;
+
+/// Merge the CB/UMI-grouped iterators `iters` (each sorted by CB/UMI) into a single stream, sorted by CB/UMI/EC.
+///
+/// Records with the same CB/UMI/EC/FLAG are aggregated (summing their COUNT), across as well as within the iterators
+pub fn merge_and_aggregate<I>(iters: HashMap<String, I>) -> impl Iterator<Item = BusRecord>
+where
+    I: Iterator<Item = ((u64, u64), Vec<BusRecord>)>,
+{
+    merge_and_aggregate_with(iters, false, false)
+}
+
+/// Same as [merge_and_aggregate], but
+/// * `merge_across_flag`: aggregate records with the same CB/UMI/EC but different FLAG (FLAG is set to 0)
+/// * `no_aggregate`: keep records with identical CB/UMI/EC/FLAG separate, ordered by the keys of `iters`
+pub fn merge_and_aggregate_with<I>(
+    iters: HashMap<String, I>,
+    merge_across_flag: bool,
+    no_aggregate: bool,
+) -> impl Iterator<Item = BusRecord>
+where
+    I: Iterator<Item = ((u64, u64), Vec<BusRecord>)>,
+{
+    MultiIterator::new(iters).flat_map(move |(_cbumi, rdict)| merge_chunks(rdict, merge_across_flag, no_aggregate))
+}
+
+/// Merges records (CB/UMI/EC) that got split over different inputs, sorted by CB/UMI/EC/FLAG,
+/// aggregating records with the same CB/UMI/EC/FLAG (see [merge_and_aggregate_with] for the flags)
+///
+/// With `no_aggregate`, duplicates are kept in the order of the inputs' keys (and within an input, in their order in the input)
+pub(crate) fn merge_chunks(record_dict: HashMap<String, Vec<BusRecord>>, merge_across_flag: bool, no_aggregate: bool) -> Vec<BusRecord> {
+    let mut chunks: Vec<(String, Vec<BusRecord>)> = record_dict.into_iter().collect();
+    chunks.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+    let mut records: Vec<BusRecord> = chunks
+        .into_iter()
+        .flat_map(|(_k, records)| records)
+        .map(|mut r| {
+            if merge_across_flag {
+                r.FLAG = 0;
+            }
+            r
+        })
+        .collect();
+
+    let key = |r: &BusRecord| (r.CB, r.UMI, r.EC, r.FLAG);
+    // stable sort keeps duplicate keys in input order
+    records.sort_by_key(key);
+    if !no_aggregate {
+        // aggregate consecutive records with the same key into the first one
+        records.dedup_by(|current, kept| {
+            if key(current) == key(kept) {
+                kept.COUNT += current.COUNT;
+                true
+            } else {
+                false
+            }
+        });
+    }
+    records
+}
+
+#[cfg(test)]
+mod test {
+    use super::{merge_and_aggregate, merge_chunks};
+    use bustools::{
+        io::BusRecord,
+        iterators::CbUmiGroupIterator,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_merge_and_aggregate() {
+        let a = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 2, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 2, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let b = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 2, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 2, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+        ];
+        let iters = HashMap::from([
+            ("a".to_string(), a.into_iter().groupby_cbumi()),
+            ("b".to_string(), b.into_iter().groupby_cbumi()),
+        ]);
+
+        let merged: Vec<BusRecord> = merge_and_aggregate(iters).collect();
+        assert_eq!(
+            merged,
+            vec![
+                BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 },
+                BusRecord { CB: 0, UMI: 1, EC: 2, COUNT: 6, FLAG: 0 },
+                BusRecord { CB: 1, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+                BusRecord { CB: 2, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_sorted_aggregated(){
+        let input: HashMap<String, Vec<BusRecord>> = HashMap::from(
+            [
+                ("s1".to_string(), vec![
+                    BusRecord {CB:0 , UMI: 1, EC:0, COUNT:1 , FLAG:0},
+                    BusRecord {CB:0 , UMI: 0, EC:1, COUNT:1 , FLAG:0},
+                ]),
+                ("s2".to_string(), vec![
+                    BusRecord {CB:0 , UMI: 0, EC:0, COUNT:1 , FLAG:0},
+                    BusRecord {CB:0 , UMI: 1, EC:0, COUNT:1 , FLAG:0},
+                ]),                
+            ]);
+        let merged_records = merge_chunks(input, false, false);
+
+        assert_eq!(merged_records, vec![
+            BusRecord {CB:0 , UMI: 0, EC:0, COUNT:1 , FLAG:0},
+            BusRecord {CB:0 , UMI: 0, EC:1, COUNT:1 , FLAG:0},
+            BusRecord {CB:0 , UMI: 1, EC:0, COUNT:2 , FLAG:0}
+        ])
+    }
+}
@@ -0,0 +1,93 @@
+//! Per-cell QC metrics: number of molecules (nUMI) and number of detected genes (nGene)
+//!
+//! nGene needs the EC->gene mapping: a gene counts as detected in a cell
+//! if at least one molecule maps consistently (and uniquely) to it, just as in [crate::count::count()]
+use crate::count::{records_to_expression_vector, CountMode, MappingCache, MultimapPolicy};
+use bustools::{
+    consistent_genes::{Ec2GeneMapper, CB},
+    io::{BusFolder, BusReader, BusRecord},
+    iterators::CellGroupIterator,
+    utils::int_to_seq,
+};
+use itertools::Itertools;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+/// number of distinct genes in a single cell with at least one consistently mapped molecule
+fn ngenes(records: Vec<BusRecord>, ecmapper: &Ec2GeneMapper, cache: &mut MappingCache) -> usize {
+    // same as kallisto: try to resolve multimapped CB/UMIs
+    let (expression_vector, _stats) = records_to_expression_vector(records, ecmapper, false, MultimapPolicy::Discard, CountMode::Umis, cache, None);
+    expression_vector.values().filter(|&&c| c > 0).count()
+}
+
+/// For each cell in the (sorted) busfile of `bfolder`, the number of detected genes
+pub fn ngenes_per_cell(bfolder: &BusFolder, ecmapper: &Ec2GeneMapper) -> HashMap<CB, usize> {
+    let mut cache = MappingCache::default();
+    BusReader::new(&bfolder.get_busfile())
+        .groupby_cb()
+        .map(|(cb, records)| (CB(cb), ngenes(records, ecmapper, &mut cache)))
+        .collect()
+}
+
+/// Write nUMI and nGene of each cell as csv (`barcode,nUMI,nGene`) into `output`
+pub fn write_cell_metrics(bfolder: &BusFolder, ecmapper: &Ec2GeneMapper, output: &str) {
+    let fh = File::create(output).unwrap();
+    let mut writer = BufWriter::new(fh);
+    writeln!(writer, "barcode,nUMI,nGene").unwrap();
+
+    let reader = BusReader::new(&bfolder.get_busfile());
+    let cb_len = reader.get_params().cb_len as usize;
+    let mut cache = MappingCache::default();
+    for (cb, records) in reader.groupby_cb() {
+        let n_umis = records.iter().map(|r| r.UMI).unique().count();
+        let n_genes = ngenes(records, ecmapper, &mut cache);
+        writeln!(writer, "{},{},{}", int_to_seq(cb, cb_len), n_umis, n_genes).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ngenes_per_cell, write_cell_metrics};
+    use bustools::{
+        consistent_genes::{Ec2GeneMapper, Genename, CB, EC},
+        io::{setup_busfile, BusFolder, BusRecord},
+    };
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn test_ngenes_per_cell() {
+        let ec_dict: HashMap<EC, HashSet<Genename>> = HashMap::from([
+            (EC(0), HashSet::from([Genename("G1".to_string())])),
+            (EC(1), HashSet::from([Genename("G2".to_string())])),
+            (EC(2), HashSet::from([Genename("G1".to_string()), Genename("G2".to_string())])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        let records = vec![
+            // cell 0: G1 (twice), G2
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 3, EC: 1, COUNT: 1, FLAG: 0 },
+            // cell 1: one multimapped molecule, one resolved to G2 via its other EC
+            BusRecord { CB: 1, UMI: 1, EC: 2, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 2, EC: 1, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 2, EC: 2, COUNT: 1, FLAG: 0 },
+        ];
+        let (_busname, _dir) = setup_busfile(&records);
+        let bfolder = BusFolder::new(_dir.path().to_str().unwrap());
+
+        let ngenes = ngenes_per_cell(&bfolder, &es);
+        assert_eq!(ngenes, HashMap::from([(CB(0), 2), (CB(1), 1)]));
+
+        let outpath = _dir.path().join("metrics.csv");
+        let outfile = outpath.to_str().unwrap();
+        write_cell_metrics(&bfolder, &es, outfile);
+        assert_eq!(
+            std::fs::read_to_string(outfile).unwrap(),
+            "barcode,nUMI,nGene\nAAAAAAAAAAAAAAAA,3,2\nAAAAAAAAAAAAAAAC,2,1\n"
+        );
+    }
+}
@@ -105,7 +105,7 @@ pub fn multinomial_sample_binary_search(
     source: &mut Xorshift128Plus,
 ) -> Vec<f64> {
     // initialize to zero
-    let mut x: Vec<f64> = std::iter::repeat(0.0).take(pvec.len()).collect();
+    let mut x: Vec<f64> = vec![0.0; pvec.len()];
 
     // normalize the pvec
     let _sum: f64 = pvec.iter().sum();
@@ -228,8 +228,7 @@ mod test {
                 .try_into()
                 .expect("requested number of elements did not fit into T");
             let n_size: usize = n
-                .try_into()
-                .expect("requested number of elements exceeds usize");
+                .into();
 
             let dx = (xend - x0.clone()) / segments;
 
@@ -1,81 +1,243 @@
 //! `bustools sort` code. Sorts busfiles by CB/UMI/EC
 //!
+//! Other key orders (e.g. CB/EC/UMI) can be chosen via [SortKey].
+//! Note that most other code (counting, busz compression, ...) expects CB/UMI sorted busfiles
+//!
 //! # Merging records
 //! Note that this not only sorts records according to CB/UMI/EC,
-//! but also merges records with the same CB/UMI/EC/FLAG (adding up their counts)
+//! but also merges records with the same CB/UMI/EC/FLAG (adding up their counts).
+//! Optionally, the FLAG can be ignored, merging all records with the same CB/UMI/EC.
+//! Or, merging can be turned off entirely (`no_aggregate`), keeping duplicates in their input order.
 //!
 #![deny(missing_docs)]
 use bustools::{
-    io::{BusReader, BusRecord, BusWriter},
+    io::{BusReader, BusRecord},
     iterators::CbUmiGroupIterator,
-    merger::MultiIterator,
 };
+use crate::merge::merge_and_aggregate_with;
+use crate::util::{create_buswriter, pack_cb_umi};
 use itertools::Itertools;
 use std::collections::{BTreeMap, HashMap};
+use std::fs;
 use tempfile::tempdir;
 
+/// size of a single record in an (uncompressed) busfile
+const BUS_RECORD_SIZE: u64 = 32;
+
+/// Order in which records get sorted. FLAG always comes last
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SortKey {
+    /// CB, then UMI, then EC (the usual bustools order)
+    #[default]
+    CbUmiEc,
+    /// CB, then EC, then UMI
+    CbEcUmi,
+    /// EC, then CB, then UMI
+    EcCbUmi,
+}
+
+impl SortKey {
+    /// Key by which records are sorted and aggregated.
+    /// The two leading fields are packed into a single integer (see [pack_cb_umi])
+    pub fn key(&self, r: &BusRecord) -> (u128, u64, u32) {
+        match self {
+            SortKey::CbUmiEc => (pack_cb_umi(r.CB, r.UMI, 32), r.EC as u64, r.FLAG),
+            SortKey::CbEcUmi => (pack_cb_umi(r.CB, r.EC as u64, 32), r.UMI, r.FLAG),
+            SortKey::EcCbUmi => (pack_cb_umi(r.EC as u64, r.CB, 32), r.UMI, r.FLAG),
+        }
+    }
+}
+
+/// sorts an Iterator over records in memory, aggregating records with the same CB/UMI/EC/FLAG.
+///
+/// Same result as [sort_into_btree], but collects into a `Vec`, sorts it and aggregates
+/// equal keys in a single linear pass. This is much more cache friendly than the BTreeMap.
+///
+/// If `merge_across_flag`, the FLAG is reset to 0, i.e. records with the same CB/UMI/EC
+/// get aggregated regardless of their FLAG
+///
+/// If `no_aggregate`, records with the same key are kept as separate records, in their original order
+pub fn sort_into_vec<I: Iterator<Item = BusRecord>>(
+    iterator: I,
+    merge_across_flag: bool,
+    no_aggregate: bool,
+    sort_by: SortKey,
+) -> Vec<BusRecord> {
+    let mut records: Vec<BusRecord> = iterator
+        .map(|mut r| {
+            if merge_across_flag {
+                r.FLAG = 0;
+            }
+            r
+        })
+        .collect();
+
+    if no_aggregate {
+        // stable sort keeps duplicate keys in input order
+        records.sort_by_key(|r| sort_by.key(r));
+        return records;
+    }
+    records.sort_unstable_by_key(|r| sort_by.key(r));
+
+    // aggregate consecutive records with the same key into the first one
+    records.dedup_by(|current, kept| {
+        if sort_by.key(current) == sort_by.key(kept) {
+            kept.COUNT += current.COUNT;
+            true
+        } else {
+            false
+        }
+    });
+    records
+}
+
 /// sorts/inserts an Iterator over records into a BTreeMap,
-/// (CB,UMI,EC, FLAG) -> records
+/// key (see [SortKey::key]) -> records
 /// This effectively sorts the records in memory and aggregates records with the same CB/UMI/EC/FLAG
-fn sort_into_btree<I: Iterator<Item = BusRecord>>(
+///
+/// If `merge_across_flag`, the FLAG is reset to 0, i.e. records with the same CB/UMI/EC
+/// get aggregated regardless of their FLAG.
+/// Always aggregates, use [sort_into_vec] to keep duplicates
+pub fn sort_into_btree<I: Iterator<Item = BusRecord>>(
     iterator: I,
-) -> BTreeMap<(u64, u64, u32, u32), BusRecord> {
-    let mut in_mem_sort: BTreeMap<(u64, u64, u32, u32), BusRecord> = BTreeMap::new();
-
-    for record in iterator {
-        if let Some(r) = in_mem_sort.get_mut(&(record.CB, record.UMI, record.EC, record.FLAG)) {
+    merge_across_flag: bool,
+    sort_by: SortKey,
+) -> BTreeMap<(u128, u64, u32), BusRecord> {
+    let mut in_mem_sort: BTreeMap<(u128, u64, u32), BusRecord> = BTreeMap::new();
+
+    for mut record in iterator {
+        if merge_across_flag {
+            record.FLAG = 0;
+        }
+        let key = sort_by.key(&record);
+        if let Some(r) = in_mem_sort.get_mut(&key) {
             r.COUNT += record.COUNT
         }
         else {
-            in_mem_sort.insert((record.CB, record.UMI, record.EC, record.FLAG), record);
+            in_mem_sort.insert(key, record);
         }
     }
     in_mem_sort
 }
 
-/// Sort a busfile (via CB/UMI/EC) in memory, using BTreeMap's internal sorting!
-/// This gets quite bad for larger files!
+/// Sort a busfile in memory, as a single chunk of [sort_on_disk] (see [sort_into_vec]).
+/// The entire file is loaded into memory!
 ///
 /// # Parameters
 /// * `busfile`: file to be sorted in memory
 /// * `outfile`: file to be sorted into
-#[allow(dead_code)]
-fn sort_in_memory(busfile: &str, outfile: &str) {
+/// * `merge_across_flag`: aggregate records with the same CB/UMI/EC but different FLAG (FLAG is set to 0)
+/// * `no_aggregate`: keep records with identical CB/UMI/EC/FLAG separate (in their original order), instead of summing their COUNT
+/// * `sort_by`: order of the sort keys
+fn sort_in_memory(busfile: &str, outfile: &str, merge_across_flag: bool, no_aggregate: bool, sort_by: SortKey) {
     let reader = BusReader::new(busfile);
     let params = reader.get_params().clone();
 
-    let in_mem_sort = sort_into_btree(reader);
-
     // write out
-    let mut writer = BusWriter::new(outfile, params);
+    let mut writer = create_buswriter(outfile, params);
+    // same as the chunks of sort_on_disk
+    writer.write_iterator(sort_into_vec(reader, merge_across_flag, no_aggregate, sort_by).into_iter());
+}
 
-    writer.write_iterator(
-        // in_mem_sort.into_iter().map(|(_, rec)| rec )
-        in_mem_sort.into_values()
+/// Checks if a busfile is sorted by CB/UMI/EC (or another order, see [SortKey]), streaming the file once.
+/// The FLAG is ignored
+///
+/// # Returns
+/// `Ok(())` if sorted, otherwise the position (0-based record index) of the first out-of-order record,
+/// the record preceding it and the record itself
+pub fn is_sorted(busfile: &str, sort_by: SortKey) -> Result<(), (usize, BusRecord, BusRecord)> {
+    let key = |r: &BusRecord| {
+        let (k1, k2, _flag) = sort_by.key(r);
+        (k1, k2)
+    };
+    let mut reader = BusReader::new(busfile);
+    let mut previous = match reader.next() {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    for (i, record) in reader.enumerate() {
+        if key(&record) < key(&previous) {
+            return Err((i + 1, previous, record));
+        }
+        previous = record;
+    }
+    Ok(())
+}
 
-    );
+/// Panics unless the (temporary) chunk file is sorted by `sort_by`: the merge in [sort_on_disk] relies on sorted chunks
+/// and would silently produce a mis-sorted output otherwise.
+///
+/// Re-reads the entire chunk, hence only done in debug builds
+fn verify_chunk(chunkfile: &str, sort_by: SortKey) {
+    if let Err((i, previous, record)) = is_sorted(chunkfile, sort_by) {
+        panic!("chunk {} not sorted at record {}: {:?} followed by {:?}", chunkfile, i, previous, record);
+    }
 }
 
-/// Merges records (CB/UMI/EC) that got split over different chunks
-pub (crate) fn merge_chunks(record_dict: HashMap<String, Vec<BusRecord>>) -> Vec<BusRecord>{
-    let records_from_all_chunks = record_dict.into_values().flatten();
-    let btree_sorted: Vec<BusRecord> = sort_into_btree(records_from_all_chunks).into_values().collect();
-    btree_sorted
+/// k-way merge of chunk files, each sorted by `sort_by`, aggregating records with the same key (unless `no_aggregate`).
+///
+/// Ties are broken by the chunk's position in `chunkfiles`, so with `no_aggregate` duplicates keep their input order
+fn merge_sorted_chunks(chunkfiles: &[String], sort_by: SortKey, no_aggregate: bool) -> Box<dyn Iterator<Item = BusRecord>> {
+    let merged = chunkfiles
+        .iter()
+        .enumerate()
+        .map(|(i, file)| BusReader::new(file).map(move |r| (i, r)))
+        .kmerge_by(move |(i, a), (j, b)| (sort_by.key(a), i) < (sort_by.key(b), j))
+        .map(|(_i, r)| r);
+
+    if no_aggregate {
+        return Box::new(merged);
+    }
+    Box::new(merged.coalesce(move |mut kept, current| {
+        if sort_by.key(&kept) == sort_by.key(&current) {
+            kept.COUNT += current.COUNT;
+            Ok(kept)
+        } else {
+            Err((kept, current))
+        }
+    }))
 }
+
+/// Upper bound of the number of records of a plain busfile, from its size (the header counts as records too).
+///
+/// None for compressed `.busz` files, whose size says little about the number of records
+fn estimate_records(busfile: &str) -> Option<u64> {
+    if busfile.ends_with(".busz") {
+        return None;
+    }
+    let size = fs::metadata(busfile)
+        .unwrap_or_else(|e| panic!("cant access busfile {}: {}", busfile, e))
+        .len();
+    Some(size / BUS_RECORD_SIZE)
+}
+
 /// Sort a busfile on disk (i.e. without loading the entire thing into memory)
 /// Works via `mergesort`:
 /// 1. split the busfile into separate chunks on disk: Temporary directory is used
-/// 2. sort the chunks (in memory) individually
+/// 2. sort the chunks (in memory) individually, via [sort_into_vec]
 /// 3. merge the chunks: iterate over all chunks in parallel via [bustools::merger]
-/// and aggregate records that might have been split across chunks
+///    and aggregate records that might have been split across chunks
 ///
 /// # Parameters:
 /// * `busfile`: file to be sorted
 /// * `outfile`: file to be sorted into
 /// * `chunksize`: number of busrecords per chunk (this is how much is loaded into mem at any point).
-///    `chunksize=10_000_000` is roughly a 300MB chunk on disk
-/// 
-pub fn sort_on_disk(busfile: &str, outfile: &str, chunksize: usize) {
+///   `chunksize=10_000_000` is roughly a 300MB chunk on disk
+/// * `merge_across_flag`: aggregate records with the same CB/UMI/EC but different FLAG (FLAG is set to 0)
+/// * `no_aggregate`: keep records with identical CB/UMI/EC/FLAG separate (in their original order), instead of summing their COUNT
+/// * `sort_by`: order of the sort keys. Orders other than CB/UMI/EC are merged via a k-way merge of the sorted chunks
+///
+/// If a plain busfile fits into a single chunk (estimated from its size), it's sorted in memory right away,
+/// skipping the temporary files and the merge. Compressed `.busz` input always goes through the chunks
+pub fn sort_on_disk(busfile: &str, outfile: &str, chunksize: usize, merge_across_flag: bool, no_aggregate: bool, sort_by: SortKey) {
+    if let Some(estimated_records) = estimate_records(busfile) {
+        if estimated_records <= chunksize as u64 {
+            println!("Sorting in memory (~{} records)", estimated_records);
+            sort_in_memory(busfile, outfile, merge_across_flag, no_aggregate, sort_by);
+            return;
+        }
+    }
+
     let reader = BusReader::new(busfile);
     let params = reader.get_params().clone();
 
@@ -88,47 +250,45 @@ pub fn sort_on_disk(busfile: &str, outfile: &str, chunksize: usize) {
         println!("Sorting {}th chunks", i);
 
         // sort the chunk in memory
-        let in_mem_sort = sort_into_btree(record_chunk);
+        let in_mem_sort = sort_into_vec(record_chunk, merge_across_flag, no_aggregate, sort_by);
 
         //write current sorted file to disk
         let file_path = tmpdir.path().join(format!("tmp_{}.bus", i));
         let tmpfilename = file_path.to_str().unwrap().to_string();
 
-        let mut tmpwriter = BusWriter::new(&tmpfilename, params.clone());
-        tmpwriter.write_iterator(
-            // in_mem_sort.into_iter().map(|(_, rec)| rec )
-            in_mem_sort.into_values()
-        );
+        let mut tmpwriter = create_buswriter(&tmpfilename, params.clone());
+        tmpwriter.write_iterator(in_mem_sort.into_iter());
+        drop(tmpwriter);
+        if cfg!(debug_assertions) {
+            verify_chunk(&tmpfilename, sort_by);
+        }
 
         chunkfiles.push(tmpfilename);
     }
 
     // merge all chunks
     println!("Merging {} chunks", chunkfiles.len());
-    let mut writer = BusWriter::new(outfile, params);
+    let mut writer = create_buswriter(outfile, params);
+
+    // the CB/UMI grouping below only works for CB/UMI sorted chunks
+    if sort_by != SortKey::CbUmiEc {
+        writer.write_iterator(merge_sorted_chunks(&chunkfiles, sort_by, no_aggregate));
+        return;
+    }
 
     // gather the individual iterators for each chunk
+    // keyed by the (zero-padded) chunk index, so that the merge can restore the input order
     let mut iterator_map = HashMap::new();
-    for file in chunkfiles.iter() {
+    for (i, file) in chunkfiles.iter().enumerate() {
         let iter = BusReader::new(file).groupby_cbumi();
-        iterator_map.insert(file.to_string(), iter);
+        iterator_map.insert(format!("{:010}", i), iter);
     }
 
     // each file itself is sorted
     // now we only have to merge them
     // if a single cb/umi is split over multiple records, this will put them back together
     // however, we need to aggregate their counts and sort them by EC
-    let mi = MultiIterator::new(iterator_map);
-    // for (_cbumi, record_dict) in mi {
-    //     let merged_records = merge_chunks(record_dict);  //takes care of aggregating across chunks and sorting
-    //     writer.write_records(&merged_records);
-    // }
-
-    let it = mi
-        .flat_map(|(_cbumi, rdict)|
-            merge_chunks(rdict)
-        );
-
+    let it = merge_and_aggregate_with(iterator_map, merge_across_flag, no_aggregate);
     writer.write_iterator(it);
 
     //tmpfiles get clean up once tmpdir is dropped!
@@ -136,34 +296,46 @@ pub fn sort_on_disk(busfile: &str, outfile: &str, chunksize: usize) {
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashMap;
-
-    use super::{sort_in_memory, sort_on_disk};
+    use super::{estimate_records, is_sorted, sort_in_memory, sort_on_disk, verify_chunk, SortKey};
     use bustools::{
         io::{setup_busfile, BusReader, BusRecord, BusWriter},
         iterators::CbUmiGroupIterator,
     };
 
     #[test]
-    fn test_merge_sorted_aggregated(){
-        let input: HashMap<String, Vec<BusRecord>> = HashMap::from(
-            [
-                ("s1".to_string(), vec![
-                    BusRecord {CB:0 , UMI: 1, EC:0, COUNT:1 , FLAG:0},
-                    BusRecord {CB:0 , UMI: 0, EC:1, COUNT:1 , FLAG:0},
-                ]),
-                ("s2".to_string(), vec![
-                    BusRecord {CB:0 , UMI: 0, EC:0, COUNT:1 , FLAG:0},
-                    BusRecord {CB:0 , UMI: 1, EC:0, COUNT:1 , FLAG:0},
-                ]),                
-            ]);
-        let merged_records = super::merge_chunks(input);
-
-        assert_eq!(merged_records, vec![
-            BusRecord {CB:0 , UMI: 0, EC:0, COUNT:1 , FLAG:0},
-            BusRecord {CB:0 , UMI: 0, EC:1, COUNT:1 , FLAG:0},
-            BusRecord {CB:0 , UMI: 1, EC:0, COUNT:2 , FLAG:0}
-        ])
+    fn test_is_sorted() {
+        let r1 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 12, FLAG: 0 };
+        let r2 = BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 };
+        let r3 = BusRecord { CB: 1, UMI: 0, EC: 0, COUNT: 12, FLAG: 0 };
+
+        let (busname, _dir) = setup_busfile(&vec![r1.clone(), r2.clone(), r3.clone()]);
+        assert_eq!(is_sorted(&busname, SortKey::CbUmiEc), Ok(()));
+
+        // EC out of order
+        let (busname, _dir) = setup_busfile(&vec![r2.clone(), r1.clone(), r3.clone()]);
+        assert_eq!(is_sorted(&busname, SortKey::CbUmiEc), Err((1, r2.clone(), r1.clone())));
+
+        // CB out of order
+        let (busname, _dir) = setup_busfile(&vec![r1.clone(), r3.clone(), r2.clone()]);
+        assert_eq!(is_sorted(&busname, SortKey::CbUmiEc), Err((2, r3, r2)));
+    }
+
+    #[test]
+    fn test_verify_chunk() {
+        let r1 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 12, FLAG: 0 };
+        let r2 = BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 };
+        let r3 = BusRecord { CB: 1, UMI: 0, EC: 0, COUNT: 12, FLAG: 0 };
+        let (busname, _dir) = setup_busfile(&vec![r1, r2, r3]);
+        verify_chunk(&busname, SortKey::CbUmiEc);
+    }
+
+    #[test]
+    #[should_panic(expected = "not sorted at record 1")]
+    fn test_verify_chunk_unsorted() {
+        let r1 = BusRecord { CB: 1, UMI: 0, EC: 0, COUNT: 12, FLAG: 0 };
+        let r2 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 };
+        let (busname, _dir) = setup_busfile(&vec![r1, r2]);
+        verify_chunk(&busname, SortKey::CbUmiEc);
     }
 
     #[test]
@@ -189,7 +361,7 @@ mod test {
         let outpath = _dir.path().join("bustools_test_sorted.bus");
         let outfile = outpath.to_str().unwrap();
 
-        sort_in_memory(&busname, outfile);
+        sort_in_memory(&busname, outfile, false, false, SortKey::CbUmiEc);
 
         let b = BusReader::new(outfile);
         let v: Vec<BusRecord> = b.collect();
@@ -197,6 +369,164 @@ mod test {
         assert_eq!(v, vec![r1, r2, r3, r4, r5, r6]);
     }
 
+    #[test]
+    fn test_sort_merge_across_flag() {
+        // same CB/UMI/EC, only FLAG differs
+        let r1 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 12, FLAG: 1 };
+        let r2 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 };
+        let r3 = BusRecord { CB: 0, UMI: 0, EC: 0, COUNT: 1, FLAG: 1 };
+        let (busname, _dir) = setup_busfile(&vec![r1.clone(), r2.clone(), r3.clone()]);
+
+        let outpath = _dir.path().join("bustools_test_sorted.bus");
+        let outfile = outpath.to_str().unwrap();
+
+        // FLAGs kept apart
+        sort_in_memory(&busname, outfile, false, false, SortKey::CbUmiEc);
+        let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(v, vec![r3.clone(), r2.clone(), r1.clone()]);
+
+        sort_on_disk(&busname, outfile, 1, false, false, SortKey::CbUmiEc);
+        let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(v, vec![r3, r2, r1]);
+
+        // FLAGs merged
+        let expected = vec![
+            BusRecord { CB: 0, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 14, FLAG: 0 },
+        ];
+        sort_in_memory(&busname, outfile, true, false, SortKey::CbUmiEc);
+        let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(v, expected);
+
+        sort_on_disk(&busname, outfile, 1, true, false, SortKey::CbUmiEc);
+        let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn test_sort_no_aggregate() {
+        // duplicate keys, told apart by their COUNT
+        let r1 = BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 };
+        let r2 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 };
+        let r3 = BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 3, FLAG: 0 };
+        let r4 = BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 4, FLAG: 0 };
+        let r5 = BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 5, FLAG: 0 };
+        let (busname, _dir) = setup_busfile(&vec![r1.clone(), r2.clone(), r3.clone(), r4.clone(), r5.clone()]);
+
+        let outpath = _dir.path().join("bustools_test_sorted.bus");
+        let outfile = outpath.to_str().unwrap();
+
+        // kept separate, in input order
+        let expected = vec![r2.clone(), r4.clone(), r1.clone(), r3.clone(), r5.clone()];
+        sort_in_memory(&busname, outfile, false, true, SortKey::CbUmiEc);
+        let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(v, expected);
+
+        for chunksize in [1, 2, 10] {
+            sort_on_disk(&busname, outfile, chunksize, false, true, SortKey::CbUmiEc);
+            let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+            assert_eq!(v, expected, "chunksize {}", chunksize);
+        }
+
+        // aggregated
+        let expected = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 6, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 0, COUNT: 9, FLAG: 0 },
+        ];
+        sort_in_memory(&busname, outfile, false, false, SortKey::CbUmiEc);
+        let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(v, expected);
+
+        sort_on_disk(&busname, outfile, 2, false, false, SortKey::CbUmiEc);
+        let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn test_sort_by() {
+        let r1 = BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 1, FLAG: 0 };
+        let r2 = BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 2, FLAG: 0 };
+        let r3 = BusRecord { CB: 1, UMI: 0, EC: 0, COUNT: 3, FLAG: 0 };
+        let r4 = BusRecord { CB: 0, UMI: 0, EC: 1, COUNT: 4, FLAG: 0 };
+        // same CB/UMI/EC as r1
+        let r5 = BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 5, FLAG: 0 };
+        let (busname, _dir) = setup_busfile(&vec![r1.clone(), r2.clone(), r3.clone(), r4.clone(), r5.clone()]);
+        let r15 = BusRecord { CB: 0, UMI: 2, EC: 0, COUNT: 6, FLAG: 0 };
+
+        let outpath = _dir.path().join("bustools_test_sorted.bus");
+        let outfile = outpath.to_str().unwrap();
+
+        let cases = [
+            (SortKey::CbUmiEc, vec![r4.clone(), r2.clone(), r15.clone(), r3.clone()]),
+            (SortKey::CbEcUmi, vec![r15.clone(), r4.clone(), r2.clone(), r3.clone()]),
+            (SortKey::EcCbUmi, vec![r15.clone(), r3.clone(), r4.clone(), r2.clone()]),
+        ];
+        for (sort_by, expected) in cases {
+            sort_in_memory(&busname, outfile, false, false, sort_by);
+            let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+            assert_eq!(v, expected, "{:?}", sort_by);
+            assert_eq!(is_sorted(outfile, sort_by), Ok(()));
+
+            for chunksize in [1, 2, 10] {
+                sort_on_disk(&busname, outfile, chunksize, false, false, sort_by);
+                let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+                assert_eq!(v, expected, "{:?}, chunksize {}", sort_by, chunksize);
+            }
+        }
+        assert!(is_sorted(outfile, SortKey::CbUmiEc).is_err());
+
+        // duplicates in input order when not aggregating
+        sort_on_disk(&busname, outfile, 1, false, true, SortKey::EcCbUmi);
+        let v: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(v, vec![r1, r5, r3, r4, r2]);
+    }
+
+    #[test]
+    fn test_sort_on_disk_fast_path() {
+        // duplicates across and within CB/UMIs, some with different FLAGs
+        let records: Vec<BusRecord> = (0..100_u64)
+            .map(|i| BusRecord { CB: (i * 7) % 5, UMI: (i * 3) % 4, EC: (i % 3) as u32, COUNT: 1, FLAG: (i % 2) as u32 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+        let chunked = _dir.path().join("chunked.bus");
+        let in_memory = _dir.path().join("in_memory.bus");
+
+        for (merge_across_flag, no_aggregate) in [(false, false), (true, false), (false, true)] {
+            // chunks of 10 records vs. a single chunk, skipping the temp files
+            sort_on_disk(&busname, chunked.to_str().unwrap(), 10, merge_across_flag, no_aggregate, SortKey::CbUmiEc);
+            sort_on_disk(&busname, in_memory.to_str().unwrap(), 1000, merge_across_flag, no_aggregate, SortKey::CbUmiEc);
+
+            let a: Vec<BusRecord> = BusReader::new(chunked.to_str().unwrap()).collect();
+            let b: Vec<BusRecord> = BusReader::new(in_memory.to_str().unwrap()).collect();
+            assert_eq!(a, b, "merge_across_flag {}, no_aggregate {}", merge_across_flag, no_aggregate);
+        }
+    }
+
+    #[test]
+    fn test_sort_on_disk_busz() {
+        // busz needs CB/UMI sorted input; EC is unsorted within each CB/UMI, with duplicates
+        let records: Vec<BusRecord> = (0..100_u64)
+            .map(|i| BusRecord { CB: i / 20, UMI: (i / 4) % 5, EC: 2 - (i % 3) as u32, COUNT: 1, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+        let busz = _dir.path().join("input.busz");
+        let busz = busz.to_str().unwrap();
+        crate::compress::compress_busfile(&busname, busz, 10, false, crate::compress::Codec::Busz);
+
+        // the compressed size doesn't tell how many records there are
+        assert!(estimate_records(&busname).is_some());
+        assert_eq!(estimate_records(busz), None);
+
+        let from_busz = _dir.path().join("from_busz.bus");
+        let from_plain = _dir.path().join("from_plain.bus");
+        sort_on_disk(busz, from_busz.to_str().unwrap(), 10, false, false, SortKey::CbUmiEc);
+        sort_in_memory(&busname, from_plain.to_str().unwrap(), false, false, SortKey::CbUmiEc);
+
+        let a: Vec<BusRecord> = BusReader::new(from_busz.to_str().unwrap()).collect();
+        let b: Vec<BusRecord> = BusReader::new(from_plain.to_str().unwrap()).collect();
+        assert_eq!(a, b);
+    }
+
     #[test]
     fn test_sort_on_disk() {
         // lets use chunksize 2 and split records over chunks on purpose
@@ -227,7 +557,7 @@ mod test {
         let outpath = _dir.path().join("bustools_test_sorted.bus");
         let outfile = outpath.to_str().unwrap();
 
-        sort_on_disk(&busname, outfile, 2);
+        sort_on_disk(&busname, outfile, 2, false, false, SortKey::CbUmiEc);
 
         let b = BusReader::new(outfile);
 
@@ -261,12 +591,16 @@ mod test {
         let outfile = file_path.to_str().unwrap();
 
         let mut writer = BusWriter::new(outfile, bustools::io::BusParams {cb_len, umi_len});
+        // distinct CB/UMIs: duplicates would get aggregated into a single record
+        let mut seen = std::collections::HashSet::new();
         let mut records = vec![];
-        for _ in 0..n_records {
+        while records.len() < n_records {
             let cb = cb_distr.sample(&mut rng);
             let umi = umi_distr.sample(&mut rng);
-            let r = BusRecord { CB: cb, UMI: umi, EC: 0, COUNT: 1, FLAG: 0 };
-            records.push(r);
+            if seen.insert((cb, umi)) {
+                let r = BusRecord { CB: cb, UMI: umi, EC: 0, COUNT: 1, FLAG: 0 };
+                records.push(r);
+            }
         }
         writer.write_iterator(records.into_iter());
 
@@ -275,7 +609,7 @@ mod test {
         // sort it
         let sortec_path = dir.path().join("test_bus_sort_random_sorted.bus");
         let sorted_out = sortec_path.to_str().unwrap();
-        sort_on_disk(&outfile, sorted_out, chunksize);
+        sort_on_disk(outfile, sorted_out, chunksize, false, false, SortKey::CbUmiEc);
 
         // check if sorted
         let b = BusReader::new(sorted_out);
@@ -283,6 +617,33 @@ mod test {
         assert_eq!(n, n_records)
     }
 
+    #[test]
+    fn test_sort_into_vec_equals_btree() {
+        let cb_distr = Uniform::from(0..20);
+        let umi_distr = Uniform::from(0..20);
+        let ec_distr = Uniform::from(0..3);
+        let flag_distr = Uniform::from(0..2);
+        let mut rng = rand::thread_rng();
+
+        let records: Vec<BusRecord> = (0..2_000)
+            .map(|_| BusRecord {
+                CB: cb_distr.sample(&mut rng),
+                UMI: umi_distr.sample(&mut rng),
+                EC: ec_distr.sample(&mut rng),
+                COUNT: 1,
+                FLAG: flag_distr.sample(&mut rng),
+            })
+            .collect();
+
+        for merge_across_flag in [false, true] {
+            let v = crate::sort::sort_into_vec(records.clone().into_iter(), merge_across_flag, false, SortKey::CbUmiEc);
+            let b: Vec<BusRecord> = crate::sort::sort_into_btree(records.clone().into_iter(), merge_across_flag, SortKey::CbUmiEc)
+                .into_values()
+                .collect();
+            assert_eq!(v, b);
+        }
+    }
+
     mod sort_into_btree {
         use bustools::io::BusRecord;
 
@@ -293,10 +654,10 @@ mod test {
                 BusRecord {CB: 0, UMI: 0, EC: 0, COUNT:1, FLAG: 0},
                 BusRecord {CB: 0, UMI: 1, EC: 0, COUNT:1, FLAG: 0},
                 ];
-            let sorted_set = crate::sort::sort_into_btree(v.into_iter(), );
+            let sorted_set = crate::sort::sort_into_btree(v.into_iter(), false, crate::sort::SortKey::CbUmiEc);
             assert_eq!(sorted_set.len(), 3);
 
-            let umis: Vec<_> = sorted_set.iter().map(|(_,r)| r.UMI).collect();
+            let umis: Vec<_> = sorted_set.values().map(|r| r.UMI).collect();
             assert_eq!(umis, vec![0,1,0]);
         }
         #[test]
@@ -306,10 +667,10 @@ mod test {
                 BusRecord {CB: 0, UMI: 0, EC: 10, COUNT:1, FLAG: 0},
                 BusRecord {CB: 0, UMI: 0, EC: 1, COUNT:1, FLAG: 0},
                 ];
-            let sorted_set = crate::sort::sort_into_btree(v.into_iter(), );
+            let sorted_set = crate::sort::sort_into_btree(v.into_iter(), false, crate::sort::SortKey::CbUmiEc);
             assert_eq!(sorted_set.len(), 3);
 
-            let ecs: Vec<_> = sorted_set.iter().map(|(_,r)| r.EC).collect();
+            let ecs: Vec<_> = sorted_set.values().map(|r| r.EC).collect();
             assert_eq!(ecs, vec![1,10,100]);
         }
 
@@ -320,10 +681,10 @@ mod test {
                 BusRecord {CB: 0, UMI: 0, EC: 0, COUNT:1, FLAG: 0},
                 BusRecord {CB: 0, UMI: 0, EC: 0, COUNT:1, FLAG: 0},
                 ];
-            let sorted_set = crate::sort::sort_into_btree(v.into_iter(), );
+            let sorted_set = crate::sort::sort_into_btree(v.into_iter(), false, crate::sort::SortKey::CbUmiEc);
             assert_eq!(sorted_set.len(), 1);
 
-            let counts: Vec<_> = sorted_set.iter().map(|(_,r)| r.COUNT).collect();
+            let counts: Vec<_> = sorted_set.values().map(|r| r.COUNT).collect();
             assert_eq!(counts, vec![3]);
         }        
     }
@@ -0,0 +1,95 @@
+//! Sharding a busfile by CB, e.g. for distributed processing
+//!
+//! All records of a CB end up in the same shard, hence per-cell operations (count, collapse-umi, ...)
+//! can be run on each shard independently and the results combined afterwards.
+use bustools::io::{BusReader, BusWriterPlain};
+use crate::util::create_plain_buswriter;
+
+/// shard of a CB: multiplicative (Fibonacci) hashing, so that neighbouring CBs spread evenly.
+/// Fixed, so that the same CB goes into the same shard across files/runs
+fn cb_shard(cb: u64, n_shards: usize) -> usize {
+    let h = cb.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    ((h >> 32) % n_shards as u64) as usize
+}
+
+/// Split `input` into `n_shards` busfiles `{out_prefix}.0.bus` ... `{out_prefix}.{n_shards-1}.bus`,
+/// putting all records of a CB into the same shard (by hashing the CB).
+///
+/// Records keep their relative order, i.e. each shard is sorted if the input is.
+/// Returns the filenames of the shards.
+pub fn split_by_cb(input: &str, out_prefix: &str, n_shards: usize) -> Vec<String> {
+    assert!(n_shards > 0, "n_shards must be > 0");
+    let reader = BusReader::new(input);
+    let params = reader.get_params().clone();
+
+    let filenames: Vec<String> = (0..n_shards).map(|i| format!("{}.{}.bus", out_prefix, i)).collect();
+    let mut writers: Vec<BusWriterPlain> = filenames.iter().map(|f| create_plain_buswriter(f, params.clone())).collect();
+    let mut n_records = vec![0_usize; n_shards];
+
+    for r in reader {
+        let shard = cb_shard(r.CB, n_shards);
+        writers[shard].write_record(&r);
+        n_records[shard] += 1;
+    }
+    // drop the writers to flush
+    drop(writers);
+
+    for (f, n) in filenames.iter().zip(n_records) {
+        println!("{}: {} records", f, n);
+    }
+    filenames
+}
+
+#[cfg(test)]
+mod test {
+    use super::split_by_cb;
+    use crate::{count::{count_from_parts, CountOptions, CountOutput}, countmatrix::CountMatrix};
+    use bustools::{
+        consistent_genes::{Ec2GeneMapper, Genename, EC},
+        io::{setup_busfile, BusReader, BusRecord},
+    };
+    use std::collections::{HashMap, HashSet};
+
+    fn mapper() -> Ec2GeneMapper {
+        Ec2GeneMapper::new(HashMap::from([
+            (EC(0), HashSet::from([Genename("G1".to_string())])),
+            (EC(1), HashSet::from([Genename("G2".to_string())])),
+            (EC(2), HashSet::from([Genename("G1".to_string()), Genename("G2".to_string())])),
+        ]))
+    }
+
+    #[test]
+    fn test_split_by_cb() {
+        let records: Vec<BusRecord> = (0..200)
+            .map(|i| BusRecord { CB: i / 4, UMI: i % 4, EC: (i % 3) as u32, COUNT: 1, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+        let prefix = _dir.path().join("shard");
+
+        let shards = split_by_cb(&busname, prefix.to_str().unwrap(), 3);
+        assert_eq!(shards.len(), 3);
+
+        let mut n_total = 0;
+        let mut cbs_seen: HashSet<u64> = HashSet::new();
+        for shard in shards.iter() {
+            let shard_records: Vec<BusRecord> = BusReader::new(shard).collect();
+            assert!(!shard_records.is_empty());
+            // still sorted
+            assert!(shard_records.windows(2).all(|w| (w[0].CB, w[0].UMI) <= (w[1].CB, w[1].UMI)));
+            // each CB in exactly one shard
+            let cbs: HashSet<u64> = shard_records.iter().map(|r| r.CB).collect();
+            assert!(cbs.is_disjoint(&cbs_seen));
+            cbs_seen.extend(cbs);
+            n_total += shard_records.len();
+        }
+        assert_eq!(n_total, records.len());
+
+        // counting the shards separately == counting the original
+        let CountOutput { matrix: cmat, .. } = count_from_parts(&busname, mapper(), false, &CountOptions::default()).unwrap();
+        let shard_cmats: Vec<CountMatrix> = shards
+            .iter()
+            .map(|shard| count_from_parts(shard, mapper(), false, &CountOptions::default()).unwrap().matrix)
+            .collect();
+        assert_eq!(CountMatrix::vstack(&shard_cmats).unwrap(), cmat);
+    }
+}
@@ -0,0 +1,52 @@
+//! Extracting a subset of the records of a busfile
+use bustools::io::BusReader;
+use crate::util::create_buswriter;
+use std::collections::HashSet;
+
+/// Write the records of `input` whose EC is in `ecs` into `output`, in their original order
+/// (a sorted input results in a sorted output).
+///
+/// Returns the number of records kept.
+pub fn subset_by_ec(input: &str, output: &str, ecs: HashSet<u32>) -> usize {
+    let reader = BusReader::new(input);
+    let mut writer = create_buswriter(output, reader.get_params().clone());
+
+    let mut n_kept = 0;
+    writer.write_iterator(reader.filter(|r| ecs.contains(&r.EC)).inspect(|_| n_kept += 1));
+    n_kept
+}
+
+#[cfg(test)]
+mod test {
+    use super::subset_by_ec;
+    use bustools::io::{setup_busfile, BusReader, BusRecord};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_subset_by_ec() {
+        let records = vec![
+            BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 2, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 3, FLAG: 0 },
+            BusRecord { CB: 0, UMI: 2, EC: 2, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 2, EC: 3, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+        let outpath = _dir.path().join("subset.bus");
+        let outfile = outpath.to_str().unwrap();
+
+        let ecs: HashSet<u32> = HashSet::from([1, 3]);
+        let n = subset_by_ec(&busname, outfile, ecs);
+        assert_eq!(n, 3);
+
+        let subset: Vec<BusRecord> = BusReader::new(outfile).collect();
+        assert_eq!(
+            subset,
+            vec![
+                BusRecord { CB: 0, UMI: 1, EC: 1, COUNT: 3, FLAG: 0 },
+                BusRecord { CB: 1, UMI: 1, EC: 1, COUNT: 1, FLAG: 0 },
+                BusRecord { CB: 1, UMI: 2, EC: 3, COUNT: 1, FLAG: 0 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,580 @@
+//! Small helpers shared across subcommands
+//!
+//! * [ProgressIter], which reports the throughput of a long-running pass over a busfile.
+//! * [get_progressbar]/[get_spinner], which respect [set_progress_enabled], e.g. to keep logs of non-interactive runs clean
+//! * [busfolder_from_path]/[busfile_from_path], accepting either a busfolder or a busfile as input
+//! * [prepare_output_dir], creating the output folder of e.g. `count` without clobbering previous results
+//! * [make_mapper_maybe_gz], building the EC->gene mapping from a possibly gzipped t2g file
+//! * [resolve_ec], listing the genes of an EC
+//! * [pack_cb_umi]/[unpack_cb_umi], combining CB and UMI into a single integer key (cheaper to hash/compare than a tuple)
+//! * [read_busfile], which respects [set_record_limit], e.g. to preview a subcommand on the first records only
+//! * [create_buswriter]/[create_plain_buswriter], which respect [set_write_buffer_size], e.g. for a larger write buffer on network filesystems
+//! * [CheckedBusReader], catching records whose CB/UMI dont fit the header's `cb_len`/`umi_len` (corrupt files)
+use bustools::{
+    consistent_genes::{Ec2GeneMapper, GeneId, Genename, EC},
+    io::{BusFolder, BusParams, BusReader, BusRecord, BusWriter, BusWriterPlain, CUGIterator, DEFAULT_BUF_SIZE},
+};
+use flate2::read::GzDecoder;
+use indicatif::ProgressBar;
+use std::{
+    fmt,
+    fs::{self, File},
+    io::BufReader,
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use tempfile::tempdir;
+
+/// whether progress bars are drawn, process wide
+static PROGRESS_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turn all progress bars/spinners (created via this module) on or off
+pub fn set_progress_enabled(enabled: bool) {
+    PROGRESS_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Same as [bustools::utils::get_progressbar], but hidden if progress is disabled (see [set_progress_enabled])
+pub fn get_progressbar(total: u64) -> ProgressBar {
+    make_progressbar(total, PROGRESS_ENABLED.load(Ordering::Relaxed))
+}
+
+/// Same as [bustools::utils::get_spinner], but hidden if progress is disabled (see [set_progress_enabled])
+pub fn get_spinner() -> ProgressBar {
+    make_spinner(PROGRESS_ENABLED.load(Ordering::Relaxed))
+}
+
+/// [bustools::utils::get_progressbar] if `enabled`, a hidden one otherwise
+pub fn make_progressbar(total: u64, enabled: bool) -> ProgressBar {
+    if enabled {
+        bustools::utils::get_progressbar(total)
+    } else {
+        ProgressBar::hidden()
+    }
+}
+
+/// [bustools::utils::get_spinner] if `enabled`, a hidden one otherwise
+pub fn make_spinner(enabled: bool) -> ProgressBar {
+    if enabled {
+        bustools::utils::get_spinner()
+    } else {
+        ProgressBar::hidden()
+    }
+}
+
+/// max number of records read via [read_busfile], process wide. `usize::MAX` means no limit
+static RECORD_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Only read the first `limit` records of busfiles opened via [read_busfile] (`None`: all records), e.g. for a quick preview.
+///
+/// Results on such a prefix are a rough sample at best: the last cell is likely cut off,
+/// and for an unsorted busfile they are meaningless, as the records of a CB/UMI aren't consecutive in the first place.
+pub fn set_record_limit(limit: Option<usize>) {
+    RECORD_LIMIT.store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+}
+
+/// A [BusReader] of `busfile`, yielding at most the number of records set via [set_record_limit]
+pub fn read_busfile(busfile: &str) -> Limited<BusReader<'_>> {
+    let limit = RECORD_LIMIT.load(Ordering::Relaxed);
+    Limited::new(BusReader::new(busfile), (limit != usize::MAX).then_some(limit))
+}
+
+/// write buffer (in bytes) of busfiles created via [create_buswriter], process wide
+static WRITE_BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_BUF_SIZE);
+
+/// Buffer size (in bytes) of busfiles created via [create_buswriter] (`None`: bustools' default of 800KB).
+///
+/// A larger buffer means fewer, larger writes, which pays off on network filesystems.
+pub fn set_write_buffer_size(bytes: Option<usize>) {
+    WRITE_BUFFER_SIZE.store(bytes.unwrap_or(DEFAULT_BUF_SIZE), Ordering::Relaxed);
+}
+
+/// A [BusWriter] of `filename`, buffering as set via [set_write_buffer_size].
+///
+/// Compressed busfiles (`.busz`) are written with bustools' own blocking, ignoring the buffer size
+pub fn create_buswriter(filename: &str, params: BusParams) -> BusWriter {
+    if filename.ends_with(".busz") {
+        return BusWriter::new(filename, params);
+    }
+    BusWriter::Plain(create_plain_buswriter(filename, params))
+}
+
+/// Same as [create_buswriter], but always uncompressed, e.g. to write records one by one
+pub fn create_plain_buswriter(filename: &str, params: BusParams) -> BusWriterPlain {
+    plain_buswriter_with_capacity(filename, params, WRITE_BUFFER_SIZE.load(Ordering::Relaxed))
+}
+
+/// An uncompressed [BusWriterPlain] of `filename`, with a write buffer of `bufsize` bytes
+pub fn plain_buswriter_with_capacity(filename: &str, params: BusParams, bufsize: usize) -> BusWriterPlain {
+    let file_handle = File::create(filename).unwrap_or_else(|e| panic!("FAILED to open {}: {}", filename, e));
+    BusWriterPlain::new_with_capacity(file_handle, params, bufsize)
+}
+
+/// Yields at most `limit` items of the wrapped iterator.
+///
+/// Same as [Iterator::take], but a [CUGIterator] (if the wrapped one is), i.e. it can still be grouped by CB/UMI
+pub struct Limited<I> {
+    inner: I,
+    remaining: Option<usize>,
+}
+
+impl<I: Iterator> Limited<I> {
+    /// Wrap `inner`, stopping after `limit` items (`None`: no limit)
+    pub fn new(inner: I, limit: Option<usize>) -> Self {
+        Limited { inner, remaining: limit }
+    }
+}
+
+impl<I: Iterator> Iterator for Limited<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.remaining {
+            None => self.inner.next(),
+            Some(0) => None,
+            Some(n) => {
+                *n -= 1;
+                self.inner.next()
+            }
+        }
+    }
+}
+
+// a prefix of a sorted iterator is sorted
+impl<I: CUGIterator> CUGIterator for Limited<I> {}
+
+/// What [CheckedBusReader] does with a record whose CB/UMI can't be encoded in `cb_len`/`umi_len` bases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfRangePolicy {
+    /// skip the record, warning about it on stderr
+    #[default]
+    Skip,
+    /// yield an [OutOfRangeRecord] error
+    Error,
+}
+
+/// A record whose CB/UMI exceed what the header's `cb_len`/`umi_len` can encode, see [CheckedBusReader]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutOfRangeRecord {
+    /// position of the record in the busfile (0-based)
+    pub index: usize,
+    /// the offending record
+    pub record: BusRecord,
+    /// CB length from the header
+    pub cb_len: usize,
+    /// UMI length from the header
+    pub umi_len: usize,
+}
+
+impl fmt::Display for OutOfRangeRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {}: CB {} / UMI {} out of range for cb_len {} / umi_len {}",
+            self.index, self.record.CB, self.record.UMI, self.cb_len, self.umi_len
+        )
+    }
+}
+
+impl std::error::Error for OutOfRangeRecord {}
+
+/// does `x` fit into `len` bases (2 bits each)
+fn fits_bases(x: u64, len: usize) -> bool {
+    len >= 32 || x < 1 << (2 * len)
+}
+
+/// number of skipped records [CheckedBusReader] warns about individually
+const MAX_RANGE_WARNINGS: usize = 10;
+
+/// Reads a busfile, checking that each record's CB < 4^cb_len and UMI < 4^umi_len (from the header).
+///
+/// Corrupt files can contain larger values, which silently turn into garbled sequences via `int_to_seq`.
+/// Out-of-range records are skipped or reported as errors, according to the [OutOfRangePolicy]
+pub struct CheckedBusReader<'a> {
+    inner: BusReader<'a>,
+    cb_len: usize,
+    umi_len: usize,
+    policy: OutOfRangePolicy,
+    index: usize,
+    n_skipped: usize,
+}
+
+impl<'a> CheckedBusReader<'a> {
+    /// open `busfile`, handling out-of-range records according to `policy`
+    pub fn new(busfile: &'a str, policy: OutOfRangePolicy) -> Self {
+        let inner = BusReader::new(busfile);
+        let params = inner.get_params();
+        let (cb_len, umi_len) = (params.cb_len as usize, params.umi_len as usize);
+        CheckedBusReader { inner, cb_len, umi_len, policy, index: 0, n_skipped: 0 }
+    }
+}
+
+impl Iterator for CheckedBusReader<'_> {
+    type Item = Result<BusRecord, OutOfRangeRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(record) = self.inner.next() else {
+                if self.n_skipped > 0 {
+                    eprintln!("Warning: skipped {} out-of-range records", self.n_skipped);
+                    self.n_skipped = 0;
+                }
+                return None;
+            };
+            let index = self.index;
+            self.index += 1;
+            if fits_bases(record.CB, self.cb_len) && fits_bases(record.UMI, self.umi_len) {
+                return Some(Ok(record));
+            }
+            let err = OutOfRangeRecord { index, record, cb_len: self.cb_len, umi_len: self.umi_len };
+            match self.policy {
+                OutOfRangePolicy::Error => return Some(Err(err)),
+                OutOfRangePolicy::Skip => {
+                    if self.n_skipped < MAX_RANGE_WARNINGS {
+                        eprintln!("Warning: skipping {}", err);
+                    }
+                    self.n_skipped += 1;
+                }
+            }
+        }
+    }
+}
+
+/// A [BusFolder] from either a busfolder (standard kallisto layout, see [BusFolder::new])
+/// or a busfile, whose matrix.ec/transcripts.txt are expected next to it
+pub fn busfolder_from_path(path: &str) -> BusFolder {
+    let p = Path::new(path);
+    if p.is_dir() {
+        return BusFolder::new(path);
+    }
+    let folder = p.parent().unwrap_or(Path::new("."));
+    let sibling = |name: &str| folder.join(name).to_str().unwrap().to_string();
+    BusFolder::from_files(path, &sibling("matrix.ec"), &sibling("transcripts.txt"))
+}
+
+/// The busfile of `path`: the standard busfile if `path` is a busfolder (see [BusFolder::new]), `path` itself otherwise
+pub fn busfile_from_path(path: &str) -> String {
+    if Path::new(path).is_dir() {
+        BusFolder::new(path).get_busfile()
+    } else {
+        path.to_string()
+    }
+}
+
+/// Create the output directory `path` (including parents) of a subcommand writing several files, e.g. `count`.
+///
+/// Errors if `path` exists and is not empty, unless `overwrite` is set: In that case, the folder is used as is
+/// and files of the same name get replaced (other files are left alone)
+pub fn prepare_output_dir(path: &str, overwrite: bool) -> Result<(), String> {
+    let p = Path::new(path);
+    if p.exists() && !p.is_dir() {
+        return Err(format!("{} exists and is not a directory", path));
+    }
+    if !overwrite && p.is_dir() && fs::read_dir(p).map_err(|e| e.to_string())?.next().is_some() {
+        return Err(format!("{} is not empty, use --overwrite to write into it anyway", path));
+    }
+    fs::create_dir_all(p).map_err(|e| format!("cant create {}: {}", path, e))
+}
+
+/// Same as [BusFolder::make_mapper], but also accepts a gzipped t2g file (`.gz`),
+/// which gets decompressed into a temporary file first
+pub fn make_mapper_maybe_gz(bfolder: &BusFolder, t2g_file: &str) -> Ec2GeneMapper {
+    if !t2g_file.ends_with(".gz") {
+        return bfolder.make_mapper(t2g_file);
+    }
+    let tmpdir = tempdir().unwrap();
+    let tmpfile = tmpdir.path().join("t2g.txt");
+    let tmpname = tmpfile.to_str().unwrap();
+
+    let fh = File::open(t2g_file).unwrap_or_else(|_| panic!("{} not found", t2g_file));
+    let mut decoder = GzDecoder::new(BufReader::new(fh));
+    let mut out = File::create(tmpname).unwrap();
+    std::io::copy(&mut decoder, &mut out).unwrap_or_else(|e| panic!("cant decompress {}: {}", t2g_file, e));
+
+    bfolder.make_mapper(tmpname)
+}
+
+/// The genes an EC maps to, as (id, name) pairs sorted by id.
+/// Ids are assigned in alphabetical order of the gene names, so this is sorted by name as well
+pub fn resolve_ec(ecmapper: &Ec2GeneMapper, ec: EC) -> Vec<(GeneId, Genename)> {
+    let mut genes: Vec<(GeneId, Genename)> = ecmapper
+        .get_genes(ec)
+        .iter()
+        .map(|gid| (*gid, ecmapper.resolve_gene_id(*gid)))
+        .collect();
+    genes.sort();
+    genes
+}
+
+/// Combine CB and UMI into a single key: the UMI occupies the lower `2*umi_len` bits, the CB the bits above.
+///
+/// Keys order just like `(cb, umi)` tuples, as long as the UMI fits into `umi_len` bases (2 bits per base).
+/// `umi_len` can be at most 32, i.e. a u64 UMI
+pub fn pack_cb_umi(cb: u64, umi: u64, umi_len: usize) -> u128 {
+    assert!(umi_len <= 32, "umi_len must be <= 32");
+    debug_assert!(umi_len == 32 || umi < 1 << (2 * umi_len), "UMI {} doesnt fit into {} bases", umi, umi_len);
+    ((cb as u128) << (2 * umi_len)) | umi as u128
+}
+
+/// Inverse of [pack_cb_umi], returning `(cb, umi)`
+pub fn unpack_cb_umi(key: u128, umi_len: usize) -> (u64, u64) {
+    assert!(umi_len <= 32, "umi_len must be <= 32");
+    let umi_mask: u128 = (1 << (2 * umi_len)) - 1;
+    ((key >> (2 * umi_len)) as u64, (key & umi_mask) as u64)
+}
+
+/// Wraps an iterator (usually over [BusRecord]s) and reports elapsed time and items/sec while it's consumed
+///
+/// The items are passed through unchanged; the progress bar is only updated every `every` items to keep the overhead low.
+/// Once the iterator is exhausted, the bar is set to the actual number of items and finished.
+/// # Example
+/// ```rust, no_run
+/// # use bustools::io::BusReader;
+/// # use bustools_cli::util::ProgressIter;
+/// let reader = BusReader::new("/path/to/some.bus");
+/// let n = ProgressIter::new(reader, None, 1_000_000).count();
+/// ```
+pub struct ProgressIter<I> {
+    inner: I,
+    bar: ProgressBar,
+    every: u64,
+    n: u64,
+}
+
+impl<I: Iterator> ProgressIter<I> {
+    /// Wrap `inner`, updating the progress every `every` items.
+    ///
+    /// If the number of items is known (`total`), a progressbar (incl. ETA and throughput) is shown, otherwise a spinner.
+    pub fn new(inner: I, total: Option<u64>, every: u64) -> Self {
+        assert!(every > 0, "every must be > 0");
+        let bar = match total {
+            Some(total) => get_progressbar(total),
+            None => get_spinner(),
+        };
+        ProgressIter { inner, bar, every, n: 0 }
+    }
+}
+
+impl<I: Iterator> Iterator for ProgressIter<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.inner.next() {
+            Some(r) => {
+                self.n += 1;
+                if self.n.is_multiple_of(self.every) {
+                    self.bar.inc(self.every);
+                }
+                Some(r)
+            }
+            None => {
+                if !self.bar.is_finished() {
+                    self.bar.set_position(self.n);
+                    self.bar.finish();
+                }
+                None
+            }
+        }
+    }
+}
+
+// passing records through unchanged, so a sorted iterator stays sorted
+impl<I: CUGIterator> CUGIterator for ProgressIter<I> {}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        create_buswriter, make_mapper_maybe_gz, make_progressbar, make_spinner, pack_cb_umi, plain_buswriter_with_capacity,
+        prepare_output_dir, resolve_ec, unpack_cb_umi, CheckedBusReader, Limited, OutOfRangePolicy, ProgressIter,
+    };
+    use bustools::{
+        consistent_genes::{Ec2GeneMapper, GeneId, Genename, EC},
+        io::{setup_busfile, BusFolder, BusReader, BusRecord},
+        iterators::{CbUmiGroupIterator, CellGroupIterator},
+    };
+    use flate2::{write::GzEncoder, Compression};
+    use std::{
+        collections::{HashMap, HashSet},
+        io::Write,
+    };
+
+    #[test]
+    fn test_progress_iter() {
+        let records: Vec<BusRecord> = (0..10)
+            .map(|i| BusRecord { CB: i / 3, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 })
+            .collect();
+
+        let mut it = ProgressIter::new(records.clone().into_iter(), Some(10), 3);
+        let passed: Vec<BusRecord> = it.by_ref().collect();
+        assert_eq!(passed, records);
+        assert_eq!(it.n, 10);
+        assert_eq!(it.bar.position(), 10);
+        assert!(it.bar.is_finished());
+
+        // still usable with the grouping iterators
+        let n_groups = ProgressIter::new(records.clone().into_iter(), None, 4)
+            .groupby_cbumi()
+            .count();
+        assert_eq!(n_groups, 4);
+
+        // any iterator, e.g. cells: the bar ends up at the number of items, even if not a multiple of `every`
+        let (busname, _dir) = setup_busfile(&records);
+        let mut it = ProgressIter::new(BusReader::new(&busname).groupby_cb(), Some(4), 3);
+        assert_eq!(it.by_ref().count(), 4);
+        assert_eq!(it.bar.position(), it.bar.length().unwrap());
+        assert!(it.bar.is_finished());
+    }
+
+    #[test]
+    fn test_progress_disabled() {
+        // not via set_progress_enabled: the global is shared with the tests running in parallel
+        assert!(make_progressbar(10, false).is_hidden());
+        assert!(make_spinner(false).is_hidden());
+        assert_eq!(make_progressbar(10, true).length(), Some(10));
+    }
+
+    #[test]
+    fn test_make_mapper_gz() {
+        let records = vec![BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 }];
+        let (_busname, dir) = setup_busfile(&records);
+        let folder = dir.path().to_str().unwrap();
+        std::fs::write(dir.path().join("matrix.ec"), "0\t0\n1\t0,1\n").unwrap();
+        std::fs::write(dir.path().join("transcripts.txt"), "T1\nT2\n").unwrap();
+        let t2g = "T1\tG1\tgene1\nT2\tG2\tgene2\n";
+
+        let t2g_plain = dir.path().join("t2g.txt");
+        std::fs::write(&t2g_plain, t2g).unwrap();
+        let t2g_gz = dir.path().join("t2g.txt.gz");
+        let mut encoder = GzEncoder::new(std::fs::File::create(&t2g_gz).unwrap(), Compression::default());
+        encoder.write_all(t2g.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let bfolder = BusFolder::new(folder);
+        let plain = make_mapper_maybe_gz(&bfolder, t2g_plain.to_str().unwrap());
+        let gz = make_mapper_maybe_gz(&bfolder, t2g_gz.to_str().unwrap());
+
+        assert_eq!(gz.get_gene_list(), plain.get_gene_list());
+        assert_eq!(gz.get_genenames(EC(1)), plain.get_genenames(EC(1)));
+        assert_eq!(gz.get_genenames(EC(1)).len(), 2);
+    }
+
+    #[test]
+    fn test_pack_cb_umi() {
+        for umi_len in [10, 12, 28, 32] {
+            let max_umi = if umi_len == 32 { u64::MAX } else { (1 << (2 * umi_len)) - 1 };
+            for (cb, umi) in [(0, 0), (1, 0), (0, 1), (12345, max_umi / 3), (u64::MAX, max_umi)] {
+                let key = pack_cb_umi(cb, umi, umi_len);
+                assert_eq!(unpack_cb_umi(key, umi_len), (cb, umi));
+            }
+        }
+        // same ordering as the tuple
+        let mut pairs = vec![(2_u64, 0_u64), (1, 4095), (1, 3), (0, 4095)];
+        let mut keys: Vec<u128> = pairs.iter().map(|&(cb, umi)| pack_cb_umi(cb, umi, 6)).collect();
+        pairs.sort();
+        keys.sort();
+        assert_eq!(keys.into_iter().map(|k| unpack_cb_umi(k, 6)).collect::<Vec<_>>(), pairs);
+    }
+
+    #[test]
+    fn test_limited() {
+        let records: Vec<BusRecord> = (0..10)
+            .map(|i| BusRecord { CB: i / 3, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 })
+            .collect();
+        let (busname, _dir) = setup_busfile(&records);
+
+        assert_eq!(Limited::new(BusReader::new(&busname), None).count(), 10);
+        assert_eq!(Limited::new(BusReader::new(&busname), Some(4)).count(), 4);
+        assert_eq!(Limited::new(BusReader::new(&busname), Some(20)).count(), 10);
+
+        // still groupable: 4 records -> CB 0 complete, CB 1 cut off after a single record
+        let cells: Vec<(u64, usize)> = Limited::new(BusReader::new(&busname), Some(4))
+            .groupby_cb()
+            .map(|(cb, rs)| (cb, rs.len()))
+            .collect();
+        assert_eq!(cells, vec![(0, 3), (1, 1)]);
+    }
+
+    #[test]
+    fn test_create_buswriter() {
+        let records: Vec<BusRecord> = (0..100)
+            .map(|i| BusRecord { CB: i, UMI: i % 7, EC: 0, COUNT: 1, FLAG: 0 })
+            .collect();
+        let (busname, dir) = setup_busfile(&records);
+        let params = BusReader::new(&busname).get_params().clone();
+        let outfile = dir.path().join("out.bus");
+
+        // a buffer smaller than a single record: every write hits the file, same content.
+        // Not via set_write_buffer_size: the global is shared with the tests running in parallel
+        plain_buswriter_with_capacity(outfile.to_str().unwrap(), params.clone(), 16).write_iterator(records.iter().cloned());
+        let written: Vec<BusRecord> = BusReader::new(outfile.to_str().unwrap()).collect();
+        assert_eq!(written, records);
+
+        // default buffer
+        create_buswriter(outfile.to_str().unwrap(), params).write_iterator(records.iter().cloned());
+
+        let written: Vec<BusRecord> = BusReader::new(outfile.to_str().unwrap()).collect();
+        assert_eq!(written, records);
+    }
+
+    #[test]
+    fn test_prepare_output_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("nested/count_out");
+        let out = out.to_str().unwrap();
+
+        // missing (including parents): created
+        assert!(prepare_output_dir(out, false).is_ok());
+        // existing but empty: fine, e.g. a rerun that crashed before writing anything
+        assert!(prepare_output_dir(out, false).is_ok());
+
+        std::fs::write(format!("{}/gene.mtx", out), "").unwrap();
+        assert!(prepare_output_dir(out, false).is_err());
+        assert!(prepare_output_dir(out, true).is_ok());
+
+        // a file in place of the folder
+        let file = dir.path().join("nested/a_file");
+        std::fs::write(&file, "").unwrap();
+        assert!(prepare_output_dir(file.to_str().unwrap(), true).is_err());
+    }
+
+    #[test]
+    fn test_checked_bus_reader() {
+        // cb_len 16, umi_len 12
+        let records = vec![
+            BusRecord { CB: 0, UMI: 0, EC: 0, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1 << 32, UMI: 0, EC: 1, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: (1 << 32) - 1, UMI: (1 << 24) - 1, EC: 2, COUNT: 1, FLAG: 0 },
+            BusRecord { CB: 1, UMI: 1 << 24, EC: 3, COUNT: 1, FLAG: 0 },
+        ];
+        let (busname, _dir) = setup_busfile(&records);
+
+        let skipped: Vec<BusRecord> = CheckedBusReader::new(&busname, OutOfRangePolicy::Skip)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(skipped, vec![records[0].clone(), records[2].clone()]);
+
+        let errors: Vec<usize> = CheckedBusReader::new(&busname, OutOfRangePolicy::Error)
+            .filter_map(|r| r.err())
+            .map(|e| e.index)
+            .collect();
+        assert_eq!(errors, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_resolve_ec() {
+        let ec_dict = HashMap::from([
+            (EC(0), HashSet::from([Genename("B".to_string())])),
+            (EC(1), HashSet::from([Genename("C".to_string()), Genename("A".to_string()), Genename("B".to_string())])),
+        ]);
+        let es = Ec2GeneMapper::new(ec_dict);
+
+        assert_eq!(resolve_ec(&es, EC(0)), vec![(GeneId(1), Genename("B".to_string()))]);
+        assert_eq!(
+            resolve_ec(&es, EC(1)),
+            vec![
+                (GeneId(0), Genename("A".to_string())),
+                (GeneId(1), Genename("B".to_string())),
+                (GeneId(2), Genename("C".to_string())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Sanity checks of a busfolder's reference (matrix.ec, transcripts.txt) against a t2g file
+//!
+//! A t2g that doesn't cover the transcripts of the index silently drops genes during counting:
+//! transcripts without a gene are removed from the ECs (just like kallisto/bustools does),
+//! and ECs left without any gene never get counted. [validate_folder] reports those cases upfront.
+use bustools::{
+    consistent_genes::EC,
+    io::BusFolder,
+};
+use flate2::read::GzDecoder;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, Read},
+};
+
+/// Result of [validate_folder]. All lists are sorted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// number of ECs in matrix.ec
+    pub n_ecs: usize,
+    /// number of transcripts in transcripts.txt
+    pub n_transcripts: usize,
+    /// number of genes in the t2g
+    pub n_genes: usize,
+    /// transcripts occuring in some EC, but missing from the t2g
+    pub missing_transcripts: Vec<String>,
+    /// genes of the t2g, none of whose transcripts is in transcripts.txt
+    pub genes_without_transcripts: Vec<String>,
+    /// ECs whose transcripts map to zero genes
+    pub empty_ecs: Vec<u32>,
+}
+
+impl ValidationReport {
+    /// true if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.missing_transcripts.is_empty() && self.genes_without_transcripts.is_empty() && self.empty_ecs.is_empty()
+    }
+
+    /// print the counts of each problem and up to `n_examples` examples
+    pub fn print(&self, n_examples: usize) {
+        println!(
+            "{} ECs, {} transcripts, {} genes",
+            self.n_ecs, self.n_transcripts, self.n_genes
+        );
+        fn print_problem<T: std::fmt::Display>(what: &str, items: &[T], n_examples: usize) {
+            let examples: Vec<String> = items.iter().take(n_examples).map(|x| x.to_string()).collect();
+            if items.is_empty() {
+                println!("{}: 0", what);
+            } else {
+                println!("{}: {}, e.g. {}", what, items.len(), examples.join(", "));
+            }
+        }
+        print_problem("transcripts in ECs missing from t2g", &self.missing_transcripts, n_examples);
+        print_problem("genes without transcripts", &self.genes_without_transcripts, n_examples);
+        print_problem("ECs mapping to no gene", &self.empty_ecs, n_examples);
+    }
+}
+
+/// transcript -> gene from a (optionally gzipped) t2g file: whitespace separated, transcript and gene in the first two columns
+fn parse_t2g(t2g_file: &str) -> HashMap<String, String> {
+    let fh = File::open(t2g_file).unwrap_or_else(|_| panic!("{} not found", t2g_file));
+    let reader: Box<dyn Read> = if t2g_file.ends_with(".gz") {
+        Box::new(GzDecoder::new(fh))
+    } else {
+        Box::new(fh)
+    };
+    BufReader::new(reader)
+        .lines()
+        .map(|line| line.unwrap_or_else(|e| panic!("Error reading lines from {}: {}", t2g_file, e)))
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let transcript = fields.next().unwrap().to_string();
+            let gene = fields
+                .next()
+                .unwrap_or_else(|| panic!("no gene for transcript {} in {}", transcript, t2g_file))
+                .to_string();
+            (transcript, gene)
+        })
+        .collect()
+}
+
+/// Check the ECs/transcripts of `bfolder` against the t2g file (optionally gzipped)
+pub fn validate_folder(bfolder: &BusFolder, t2g_file: &str) -> ValidationReport {
+    let ec_dict = bfolder.parse_ecmatrix();
+    let transcripts = bfolder.parse_transcript();
+    let t2g = parse_t2g(t2g_file);
+
+    let mut missing_transcripts: BTreeSet<String> = BTreeSet::new();
+    let mut empty_ecs: Vec<u32> = Vec::new();
+    for (EC(ec), tids) in ec_dict.iter() {
+        let mut n_genes = 0;
+        for tid in tids {
+            let tname = &transcripts
+                .get(tid)
+                .unwrap_or_else(|| panic!("EC {} refers to transcript {}, not in transcripts.txt", ec, tid.0))
+                .0;
+            match t2g.get(tname) {
+                Some(_) => n_genes += 1,
+                None => {
+                    missing_transcripts.insert(tname.clone());
+                }
+            }
+        }
+        if n_genes == 0 {
+            empty_ecs.push(*ec);
+        }
+    }
+    empty_ecs.sort_unstable();
+
+    let index_transcripts: HashSet<&String> = transcripts.values().map(|t| &t.0).collect();
+    let all_genes: BTreeSet<&String> = t2g.values().collect();
+    let genes_with_transcripts: HashSet<&String> = t2g
+        .iter()
+        .filter(|(t, _g)| index_transcripts.contains(t))
+        .map(|(_t, g)| g)
+        .collect();
+    let genes_without_transcripts = all_genes
+        .iter()
+        .filter(|g| !genes_with_transcripts.contains(*g))
+        .map(|g| g.to_string())
+        .collect();
+
+    ValidationReport {
+        n_ecs: ec_dict.len(),
+        n_transcripts: transcripts.len(),
+        n_genes: all_genes.len(),
+        missing_transcripts: missing_transcripts.into_iter().collect(),
+        genes_without_transcripts,
+        empty_ecs,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::validate_folder;
+    use bustools::io::{setup_busfile, BusFolder, BusRecord};
+
+    #[test]
+    fn test_validate_folder() {
+        let records = vec![BusRecord { CB: 0, UMI: 1, EC: 0, COUNT: 1, FLAG: 0 }];
+        let (_busname, dir) = setup_busfile(&records);
+        std::fs::write(dir.path().join("matrix.ec"), "0\t0\n1\t0,1\n2\t2\n").unwrap();
+        std::fs::write(dir.path().join("transcripts.txt"), "T1\nT2\nT3\n").unwrap();
+        let bfolder = BusFolder::new(dir.path().to_str().unwrap());
+
+        // complete t2g
+        let t2g = dir.path().join("t2g.txt");
+        std::fs::write(&t2g, "T1\tG1\tgene1\nT2\tG2\tgene2\nT3\tG2\tgene2\n").unwrap();
+        let report = validate_folder(&bfolder, t2g.to_str().unwrap());
+        assert!(report.is_ok());
+        assert_eq!((report.n_ecs, report.n_transcripts, report.n_genes), (3, 3, 2));
+
+        // T3 missing (EC 2 becomes empty), G3 not in the index
+        std::fs::write(&t2g, "T1\tG1\tgene1\nT2\tG2\tgene2\nT4\tG3\tgene3\n").unwrap();
+        let report = validate_folder(&bfolder, t2g.to_str().unwrap());
+        assert!(!report.is_ok());
+        assert_eq!(report.missing_transcripts, vec!["T3".to_string()]);
+        assert_eq!(report.genes_without_transcripts, vec!["G3".to_string()]);
+        assert_eq!(report.empty_ecs, vec![2]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{fs, time::Instant};
 use bustools::consistent_genes::{MappingMode, InconsistentResolution};
-use bustools_cli::{count::count, count2, correct::correct, butterfly::make_ecs};
+use bustools_cli::{count::{count, CountMode, CountOptions, CountOutput}, count2, correct::{correct, AmbiguityPolicy}, butterfly::make_ecs};
 use bustools::io::{BusFolder, BusReader, write_partial_busfile};
 use bustools::iterators::CellGroupIterator;
 use bustools_cli::countmatrix::CountMatrix;
@@ -51,17 +51,17 @@ fn test_count_vs_bustools() {
 
     println!("Doing count::count");
     let now = Instant::now();
-    let c = count(&bfolder, mapping_mode, IGNOREMULTIMAPPED);
+    let CountOutput { matrix: c, .. } = count(&bfolder, mapping_mode, IGNOREMULTIMAPPED, &CountOptions::default()).unwrap();
     let elapsed_time = now.elapsed();
     println!("count::count in in {:?}", elapsed_time);
-    c.write(outfolder);
+    c.write_or_panic(outfolder);
 
 
     let ecmapper = bfolder.make_mapper(TEST_T2G);
     let mapping_mode = MappingMode::Gene(ecmapper, InconsistentResolution::IgnoreInconsistent);
     println!("Doing count::count2");
     let now = Instant::now();
-    let c2 = count2::count(&bfolder, mapping_mode, IGNOREMULTIMAPPED);
+    let c2 = count2::count(&bfolder, mapping_mode, IGNOREMULTIMAPPED, None, CountMode::Umis, 0);
     let elapsed_time = now.elapsed();
     println!("count2::count in in {:?}", elapsed_time);
     assert_eq!(c2, c);
@@ -95,7 +95,7 @@ fn test_count_vs_bustools() {
     // -------------------
     // Comparing results
     // -------------------
-    let cmat_kallisto = CountMatrix::from_disk(
+    let cmat_kallisto = CountMatrix::from_disk_or_panic(
         &format!("{outfolder_kallisto}/gene.mtx"),
         &format!("{outfolder_kallisto}/gene.barcodes.txt"),
         &format!("{outfolder_kallisto}/gene.genes.txt"),
@@ -119,13 +119,13 @@ fn test_compare() {
     // -------------------
     // Comparing results
     // -------------------
-    let cmat_kallisto = CountMatrix::from_disk(
+    let cmat_kallisto = CountMatrix::from_disk_or_panic(
         &format!("{outfolder_kallisto}/gene.mtx"),
         &format!("{outfolder_kallisto}/gene.barcodes.txt"),
         &format!("{outfolder_kallisto}/gene.genes.txt"),
     );
 
-    let cmat_rust = CountMatrix::from_disk(
+    let cmat_rust = CountMatrix::from_disk_or_panic(
         &format!("{outfolder}/gene.mtx"),
         &format!("{outfolder}/gene.barcodes.txt"),
         &format!("{outfolder}/gene.genes.txt"),
@@ -170,14 +170,14 @@ fn test_count() {
     let b = BusFolder::new(TEST_BUSFOLDER);
     let ecmapper = b.make_mapper(TEST_T2G);
     let mapping_mode = MappingMode::Gene(ecmapper, InconsistentResolution::IgnoreInconsistent);
-    let count_matrix: CountMatrix = count(&b, mapping_mode, false);
-    count_matrix.write("/tmp");
+    let CountOutput { matrix: count_matrix, .. } = count(&b, mapping_mode, false, &CountOptions::default()).unwrap();
+    count_matrix.write_or_panic("/tmp");
     // count_bayesian(b)
 }
 
 #[test]
 fn test_correct_real_file() {
-    correct(TEST_BUSFILE, "/tmp/corrected.bus", TEST_WHITELIST)
+    correct(TEST_BUSFILE, "/tmp/corrected.bus", &[TEST_WHITELIST.to_string()], AmbiguityPolicy::Drop, None, None, None).unwrap();
 }
 
 // #[test]
@@ -199,6 +199,6 @@ pub fn test_butterfly() {
 
     let ecmapper = b.make_mapper(TEST_T2G);
     let mapping_mode = MappingMode::Gene(ecmapper, InconsistentResolution::IgnoreInconsistent);
-    let h = make_ecs(&b.get_busfile(), mapping_mode);
+    let h = make_ecs(&b.get_busfile(), mapping_mode, 1);
     println!("{:?}", h);
 }
\ No newline at end of file
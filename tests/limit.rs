@@ -0,0 +1,95 @@
+//! End-to-end check of the global `--limit`, running the binary on a small busfolder
+use bustools::io::{setup_busfile, BusRecord};
+use std::{fs, path::Path, process::Command};
+
+const N_CELLS: u64 = 5;
+const LIMIT: &str = "2";
+
+/// busfolder with one molecule (a single record, mapping to G1) in each of `N_CELLS` cells
+fn setup_folder() -> tempfile::TempDir {
+    let records: Vec<BusRecord> = (0..N_CELLS)
+        .map(|cb| BusRecord { CB: cb, UMI: 1, EC: 0, COUNT: 3, FLAG: 0 })
+        .collect();
+    let (_busname, dir) = setup_busfile(&records);
+    fs::write(dir.path().join("matrix.ec"), "0\t0\n").unwrap();
+    fs::write(dir.path().join("transcripts.txt"), "T1\n").unwrap();
+    fs::write(dir.path().join("t2g.txt"), "T1\tG1\tgene1\n").unwrap();
+    dir
+}
+
+/// run the binary, failing the test unless it succeeds; returns stdout
+fn run(args: &[&str]) -> String {
+    let out = Command::new(env!("CARGO_BIN_EXE_bustools_cli")).args(args).output().unwrap();
+    assert!(out.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&out.stderr));
+    String::from_utf8(out.stdout).unwrap()
+}
+
+fn count_mapped(folder: &Path, limit: Option<&str>) -> u64 {
+    let t2g = folder.join("t2g.txt");
+    let outdir = folder.join(format!("count_{}", limit.unwrap_or("all")));
+    let mut args = vec!["-o", outdir.to_str().unwrap(), "count", "--ifolder", folder.to_str().unwrap(), "--t2g", t2g.to_str().unwrap()];
+    if let Some(l) = limit {
+        args.extend(["--limit", l]);
+    }
+    run(&args);
+    let stats: serde_json::Value = serde_json::from_str(&fs::read_to_string(outdir.join("count_stats.json")).unwrap()).unwrap();
+    stats["mapped"].as_u64().unwrap()
+}
+
+fn inspect_nrecords(busfile: &str, limit: Option<&str>) -> String {
+    let mut args = vec!["-o", "unused", "inspect", "-i", busfile];
+    if let Some(l) = limit {
+        args.extend(["--limit", l]);
+    }
+    run(&args).lines().find(|l| l.ends_with("BUS records")).unwrap().to_string()
+}
+
+/// total number of molecules in the butterfly histogram
+fn butterfly_molecules(folder: &Path, limit: Option<&str>) -> usize {
+    let t2g = folder.join("t2g.txt");
+    let outfile = folder.join(format!("butterfly_{}.csv", limit.unwrap_or("all")));
+    let mut args = vec!["-o", outfile.to_str().unwrap(), "butterfly", "-i", folder.to_str().unwrap(), "--t2g", t2g.to_str().unwrap()];
+    if let Some(l) = limit {
+        args.extend(["--limit", l]);
+    }
+    run(&args);
+    fs::read_to_string(outfile)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').nth(1).unwrap().parse::<usize>().unwrap())
+        .sum()
+}
+
+#[test]
+fn test_limit_count() {
+    let dir = setup_folder();
+    assert_eq!(count_mapped(dir.path(), None), N_CELLS);
+    assert_eq!(count_mapped(dir.path(), Some(LIMIT)), 2);
+}
+
+#[test]
+fn test_limit_inspect() {
+    let dir = setup_folder();
+    let busfile = dir.path().to_str().unwrap();
+    assert_eq!(inspect_nrecords(busfile, None), format!("{} BUS records", N_CELLS));
+    assert_eq!(inspect_nrecords(busfile, Some(LIMIT)), "2 BUS records");
+}
+
+#[test]
+fn test_limit_butterfly() {
+    let dir = setup_folder();
+    assert_eq!(butterfly_molecules(dir.path(), None), N_CELLS as usize);
+    assert_eq!(butterfly_molecules(dir.path(), Some(LIMIT)), 2);
+}
+
+#[test]
+fn test_limit_rejected() {
+    let dir = setup_folder();
+    let out = Command::new(env!("CARGO_BIN_EXE_bustools_cli"))
+        .args(["-o", dir.path().join("sorted.bus").to_str().unwrap(), "--limit", LIMIT, "sort", "-i", dir.path().to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--limit is only supported by"));
+}